@@ -0,0 +1,177 @@
+use chat::{DONE_MESSAGE, sse::SseParser};
+use clap::Parser;
+use futures::{StreamExt, stream};
+use std::time::{Duration, Instant};
+
+/// Drives concurrent streaming chat completions against the proxy and
+/// reports time-to-first-token, inter-token latency and throughput.
+#[derive(Parser)]
+#[command(name = "llm-proxy-bench")]
+struct Args {
+    #[arg(long, default_value = "http://127.0.0.1:3000/chat/completions")]
+    url: String,
+    #[arg(long, default_value = "mock")]
+    model: String,
+    #[arg(long, default_value = "Write a short poem about the sea.")]
+    prompt: String,
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+    #[arg(long, default_value_t = 64)]
+    requests: usize,
+    #[arg(long)]
+    max_tokens: Option<i32>,
+    #[arg(long)]
+    api_key: Option<String>,
+}
+
+struct RequestStats {
+    ttft: Option<Duration>,
+    inter_token: Vec<Duration>,
+    duration: Duration,
+    chunks: usize,
+}
+
+async fn run_request(
+    client: &reqwest::Client,
+    args: &Args,
+    body: &serde_json::Value,
+) -> anyhow::Result<RequestStats> {
+    let start = Instant::now();
+    let mut request = client.post(&args.url).json(body);
+    if let Some(api_key) = &args.api_key {
+        request = request.bearer_auth(api_key);
+    }
+
+    let response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("{} - {}", status, response.text().await?);
+    }
+
+    let mut body = response.bytes_stream();
+    let mut parser = SseParser::new();
+    let mut ttft = None;
+    let mut last_chunk: Option<Instant> = None;
+    let mut inter_token = Vec::new();
+    let mut chunks = 0;
+
+    'outer: while let Some(bytes) = body.next().await {
+        for event in parser.feed(&bytes?) {
+            if event.data == DONE_MESSAGE {
+                break 'outer;
+            }
+
+            let chunk: serde_json::Value = serde_json::from_str(&event.data)?;
+            let has_content = chunk["choices"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .any(|choice| choice["delta"]["content"].is_string());
+            if !has_content {
+                continue;
+            }
+
+            let now = Instant::now();
+            match last_chunk {
+                Some(last) => inter_token.push(now - last),
+                None => ttft = Some(now - start),
+            }
+            last_chunk = Some(now);
+            chunks += 1;
+        }
+    }
+
+    Ok(RequestStats {
+        ttft,
+        inter_token,
+        duration: start.elapsed(),
+        chunks,
+    })
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank.min(sorted.len() - 1)]
+}
+
+fn report(name: &str, mut samples: Vec<Duration>) {
+    samples.sort();
+    println!(
+        "{:<16} p50={:>9.2?} p90={:>9.2?} p99={:>9.2?} max={:>9.2?} (n={})",
+        name,
+        percentile(&samples, 50.0),
+        percentile(&samples, 90.0),
+        percentile(&samples, 99.0),
+        samples.last().copied().unwrap_or_default(),
+        samples.len()
+    );
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let client = reqwest::Client::new();
+
+    let mut body = serde_json::json!({
+        "model": args.model,
+        "stream": true,
+        "messages": [{ "role": "user", "content": args.prompt }],
+    });
+    if let Some(max_tokens) = args.max_tokens {
+        body["max_tokens"] = max_tokens.into();
+    }
+
+    println!(
+        "Running {} requests against {} with concurrency {}",
+        args.requests, args.url, args.concurrency
+    );
+
+    let start = Instant::now();
+    let results: Vec<_> = stream::iter(0..args.requests)
+        .map(|_| run_request(&client, &args, &body))
+        .buffer_unordered(args.concurrency.max(1))
+        .collect()
+        .await;
+    let elapsed = start.elapsed();
+
+    let mut ttft = Vec::new();
+    let mut inter_token = Vec::new();
+    let mut durations = Vec::new();
+    let mut chunks = 0;
+    let mut errors = 0;
+
+    for result in results {
+        match result {
+            Ok(stats) => {
+                ttft.extend(stats.ttft);
+                inter_token.extend(stats.inter_token);
+                durations.push(stats.duration);
+                chunks += stats.chunks;
+            }
+            Err(e) => {
+                errors += 1;
+                eprintln!("Request failed: {}", e);
+            }
+        }
+    }
+
+    println!(
+        "Completed {} requests ({} failed) in {:.2?}",
+        durations.len(),
+        errors,
+        elapsed
+    );
+    report("ttft", ttft);
+    report("inter-token", inter_token);
+    report("duration", durations);
+    println!(
+        "throughput       {:.1} req/s, {:.1} chunks/s",
+        (args.requests - errors) as f64 / elapsed.as_secs_f64(),
+        chunks as f64 / elapsed.as_secs_f64()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use crate::{
+    StreamEvent,
+    error::{ErrorKind, ProviderError},
+    keys::Priority,
+};
+use futures::{StreamExt, stream::BoxStream};
+use std::{
+    collections::HashMap,
+    pin::pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+pub const ADMISSION_REJECTED_METRIC: &str = "llm_proxy_admission_rejected_total";
+pub const ADMISSION_FALLBACK_METRIC: &str = "llm_proxy_admission_fallback_total";
+
+#[derive(Clone, Debug)]
+pub struct AdmissionConfig {
+    /// Requests served at once. Zero admits everything.
+    pub max_concurrent: usize,
+    /// How long a request may wait for a slot before it is turned away.
+    pub queue_timeout: Duration,
+    /// Cheaper models that batch and background traffic moves to while
+    /// the proxy is busy, keyed by the requested model.
+    pub fallback_models: HashMap<String, String>,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 0,
+            queue_timeout: Duration::from_secs(30),
+            fallback_models: HashMap::new(),
+        }
+    }
+}
+
+/// Caps the requests in flight, reserving headroom for higher priorities:
+/// background traffic only gets a slot while at most half are taken and
+/// batch traffic while at most three quarters are, so interactive requests
+/// keep being served when the proxy is saturated.
+pub struct Admission {
+    config: AdmissionConfig,
+    in_flight: AtomicUsize,
+    released: Notify,
+}
+
+/// A slot held for the lifetime of one response.
+pub struct Permit(Arc<Admission>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+        self.0.released.notify_waiters();
+    }
+}
+
+impl Admission {
+    pub fn new(config: AdmissionConfig) -> Self {
+        Self {
+            config,
+            in_flight: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    fn limit(&self, priority: Priority) -> usize {
+        let max = self.config.max_concurrent;
+        match priority {
+            Priority::Interactive => max,
+            Priority::Batch => (max * 3 / 4).max(1),
+            Priority::Background => (max / 2).max(1),
+        }
+    }
+
+    fn try_acquire(&self, priority: Priority) -> bool {
+        if self.config.max_concurrent == 0 {
+            self.in_flight.fetch_add(1, Ordering::AcqRel);
+            return true;
+        }
+        let limit = self.limit(priority);
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_flight| {
+                (in_flight < limit).then_some(in_flight + 1)
+            })
+            .is_ok()
+    }
+
+    /// Whether a request of this priority would have to wait for a slot.
+    pub fn contended(&self, priority: Priority) -> bool {
+        self.config.max_concurrent > 0
+            && self.in_flight.load(Ordering::Acquire) >= self.limit(priority)
+    }
+
+    /// The model to use instead of `model` while the proxy is too busy for
+    /// this priority. Interactive traffic always keeps its model.
+    pub fn fallback(&self, model: &str, priority: Priority) -> Option<&str> {
+        if priority == Priority::Interactive || !self.contended(priority) {
+            return None;
+        }
+        let fallback = self.config.fallback_models.get(model)?;
+        debug!(
+            "Moving {} request for {} to {} under contention",
+            priority, model, fallback
+        );
+        metrics::counter!(ADMISSION_FALLBACK_METRIC, "priority" => priority.to_string())
+            .increment(1);
+        Some(fallback)
+    }
+
+    /// Waits for a slot, failing once the queue timeout passes.
+    pub async fn acquire(self: &Arc<Self>, priority: Priority) -> Result<Permit, ProviderError> {
+        let wait = async {
+            loop {
+                let mut released = pin!(self.released.notified());
+                released.as_mut().enable();
+                if self.try_acquire(priority) {
+                    return;
+                }
+                released.await;
+            }
+        };
+        match tokio::time::timeout(self.config.queue_timeout, wait).await {
+            Ok(()) => Ok(Permit(self.clone())),
+            Err(_) => {
+                warn!(
+                    "Turning away {} request after waiting {:?} for a slot",
+                    priority, self.config.queue_timeout
+                );
+                metrics::counter!(ADMISSION_REJECTED_METRIC, "priority" => priority.to_string())
+                    .increment(1);
+                Err(ProviderError::new(
+                    ErrorKind::RateLimited,
+                    "The proxy is at capacity, please retry later.",
+                )
+                .code("server_busy"))
+            }
+        }
+    }
+}
+
+/// Keeps the permit until the response stream has been consumed or dropped.
+pub fn hold<'a>(
+    stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    permit: Permit,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+    stream
+        .map(move |event| {
+            let _ = &permit;
+            event
+        })
+        .boxed()
+}
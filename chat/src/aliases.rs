@@ -0,0 +1,57 @@
+use crate::StreamEvent;
+use bytes::Bytes;
+use futures::{StreamExt, stream::BoxStream};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Friendly model names for backend model ids or inference profile ARNs.
+#[derive(Clone, Debug, Default)]
+pub struct ModelAliases {
+    aliases: HashMap<String, String>,
+}
+
+impl ModelAliases {
+    pub fn new(aliases: HashMap<String, String>) -> Self {
+        Self {
+            aliases: aliases
+                .into_iter()
+                .map(|(alias, model)| (alias.to_lowercase(), model))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aliases.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.aliases.len()
+    }
+
+    pub fn resolve(&self, model: &str) -> Option<&str> {
+        self.aliases.get(&model.to_lowercase()).map(String::as_str)
+    }
+}
+
+fn rename_chunk(data: &Bytes, model: &str) -> Option<Bytes> {
+    let mut chunk: Value = serde_json::from_slice(data).ok()?;
+    let field = chunk.get_mut("model")?;
+    *field = Value::from(model);
+    serde_json::to_vec(&chunk).ok().map(Bytes::from)
+}
+
+/// Reports `model` in every chunk naming one, so clients see the alias they
+/// asked for rather than the backend model id.
+pub fn rename_model<'a>(
+    stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    model: String,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+    stream
+        .map(move |item| match item {
+            Ok(StreamEvent::Chunk(data)) => Ok(StreamEvent::Chunk(
+                rename_chunk(&data, &model).unwrap_or(data),
+            )),
+            item => item,
+        })
+        .boxed()
+}
@@ -0,0 +1,708 @@
+use crate::{
+    StreamEvent, create_stream_event,
+    error::{ErrorKind, ProviderError, from_anthropic_response, from_reqwest_error},
+    image::{ImageLimits, preprocess_images_blocking},
+    memory::DEFAULT_MAX_STREAM_BYTES,
+    providers::ChatCompletionsProvider,
+    sse::SseParser,
+};
+use async_stream::stream;
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::BytesMut;
+use chrono::offset::Utc;
+use futures::{StreamExt, stream::BoxStream};
+use request::{
+    CacheControl, ChatCompletionsRequest, Content, Contents, InputFile, MIN_THINKING_BUDGET,
+    Message, Role, ToolChoice, ToolChoiceMode, file::file_media_type,
+};
+use response::{
+    ChatCompletionsResponse, Choice, ChoiceBuilder, Delta, FunctionCall, ToolCall, Usage,
+    UsageBuilder,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
+
+pub const ANTHROPIC_API_MESSAGES_URL: &str = "https://api.anthropic.com/v1/messages";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// The Messages API requires `max_tokens`.
+const DEFAULT_MAX_TOKENS: i32 = 4096;
+
+pub struct AnthropicChatCompletionsProvider {
+    client: reqwest::Client,
+    url: String,
+    api_key: String,
+    max_buffered_bytes: usize,
+    image_limits: ImageLimits,
+}
+
+impl AnthropicChatCompletionsProvider {
+    pub fn new(client: &reqwest::Client, api_key: &str) -> Self {
+        Self {
+            client: client.clone(),
+            url: ANTHROPIC_API_MESSAGES_URL.to_string(),
+            api_key: api_key.to_string(),
+            max_buffered_bytes: DEFAULT_MAX_STREAM_BYTES,
+            image_limits: ImageLimits::bedrock(),
+        }
+    }
+
+    pub fn max_buffered_bytes(mut self, max_buffered_bytes: usize) -> Self {
+        self.max_buffered_bytes = max_buffered_bytes;
+        self
+    }
+
+    pub fn image_limits(mut self, image_limits: ImageLimits) -> Self {
+        self.image_limits = image_limits;
+        self
+    }
+}
+
+fn text_of(contents: &Option<Contents>) -> String {
+    match contents {
+        Some(Contents::String(text)) => text.clone(),
+        Some(Contents::Array(parts)) => parts
+            .iter()
+            .filter_map(|part| match part {
+                Content::Text { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => String::new(),
+    }
+}
+
+/// The marker on the last cached part of a message, which the proxy sends
+/// as one block.
+fn cache_control_of(message: &Message) -> Option<&CacheControl> {
+    match &message.contents {
+        Some(Contents::Array(parts)) => parts.iter().rev().find_map(Content::cache_control),
+        _ => None,
+    }
+}
+
+fn image_block(url: &str) -> Result<Value, ProviderError> {
+    if !url.starts_with("data:") {
+        return Ok(json!({ "type": "image", "source": { "type": "url", "url": url } }));
+    }
+    let (media_type, data) = url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+        .ok_or_else(|| {
+            ProviderError::invalid_request("data URL must be base64 encoded", Some("messages"))
+        })?;
+    Ok(json!({
+        "type": "image",
+        "source": { "type": "base64", "media_type": media_type, "data": data },
+    }))
+}
+
+/// The Messages API reads PDFs and plain text documents.
+fn document_block(file: &InputFile) -> Result<Value, ProviderError> {
+    let invalid = |message: &str| ProviderError::invalid_request(message, Some("messages"));
+    let data = file
+        .file_data
+        .as_deref()
+        .and_then(|url| url.strip_prefix("data:"))
+        .and_then(|rest| rest.split_once(";base64,"))
+        .map(|(_, data)| data)
+        .ok_or_else(|| invalid("files must be sent as base64 data URLs in file_data"))?;
+    let source = match file_media_type(file).as_deref() {
+        Some("application/pdf") => {
+            json!({ "type": "base64", "media_type": "application/pdf", "data": data })
+        }
+        Some("text/plain") => {
+            let text = STANDARD
+                .decode(data.trim())
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or_else(|| invalid("text files must be valid UTF-8"))?;
+            json!({ "type": "text", "media_type": "text/plain", "data": text })
+        }
+        _ => {
+            return Err(invalid(
+                "Anthropic models only accept PDF and plain text files",
+            ));
+        }
+    };
+    let mut block = json!({ "type": "document", "source": source });
+    if let Some(filename) = &file.filename {
+        block["title"] = json!(filename);
+    }
+    Ok(block)
+}
+
+fn content_blocks(message: &Message) -> Result<Vec<Value>, ProviderError> {
+    let mut blocks = Vec::new();
+    // The thinking of an assistant turn goes back ahead of its answer, as
+    // it was streamed, for the API to check its signature.
+    if matches!(message.role, Role::Assistant) {
+        blocks.extend(message.thinking_blocks.iter().flatten().cloned());
+    }
+    match &message.contents {
+        Some(Contents::String(text)) if !text.is_empty() => {
+            blocks.push(json!({ "type": "text", "text": text }))
+        }
+        Some(Contents::Array(parts)) => {
+            for part in parts {
+                let mut block = match part {
+                    Content::Text { text, .. } => json!({ "type": "text", "text": text }),
+                    Content::ImageUrl { image_url, .. } => image_block(&image_url.url)?,
+                    Content::File { file, .. } => document_block(file)?,
+                    Content::Video { .. } => {
+                        return Err(ProviderError::invalid_request(
+                            "Anthropic models do not accept videos",
+                            Some("messages"),
+                        ));
+                    }
+                };
+                if let Some(cache_control) = part.cache_control() {
+                    block["cache_control"] = json!(cache_control);
+                }
+                blocks.push(block);
+            }
+        }
+        _ => {}
+    }
+    for tool_call in message.tool_calls.iter().flatten() {
+        let input: Value =
+            serde_json::from_str(&tool_call.function.arguments).unwrap_or_else(|_| json!({}));
+        blocks.push(json!({
+            "type": "tool_use",
+            "id": tool_call.id,
+            "name": tool_call.function.name,
+            "input": input,
+        }));
+    }
+    Ok(blocks)
+}
+
+/// Translates an OpenAI request into a Messages API body. System messages
+/// become the system prompt, tool results become `tool_result` blocks of a
+/// user turn, and consecutive turns of one role are merged.
+pub fn to_anthropic_request(request: &ChatCompletionsRequest) -> Result<Value, ProviderError> {
+    let mut system = Vec::new();
+    let mut messages: Vec<(&'static str, Vec<Value>)> = Vec::new();
+    for message in &request.messages {
+        let (role, blocks) = match message.role {
+            Role::Developer | Role::System => {
+                system.push((text_of(&message.contents), cache_control_of(message)));
+                continue;
+            }
+            Role::Tool => {
+                // Results with images or documents are sent as blocks.
+                let content = match &message.contents {
+                    Some(Contents::Array(parts))
+                        if parts
+                            .iter()
+                            .any(|part| !matches!(part, Content::Text { .. })) =>
+                    {
+                        json!(content_blocks(message)?)
+                    }
+                    _ => json!(text_of(&message.contents)),
+                };
+                let mut block = json!({
+                    "type": "tool_result",
+                    "tool_use_id": message.tool_call_id,
+                    "content": content,
+                });
+                // Blocks carry their own markers.
+                if block["content"].is_string()
+                    && let Some(cache_control) = cache_control_of(message)
+                {
+                    block["cache_control"] = json!(cache_control);
+                }
+                ("user", vec![block])
+            }
+            Role::User => ("user", content_blocks(message)?),
+            Role::Assistant => ("assistant", content_blocks(message)?),
+        };
+        if blocks.is_empty() {
+            continue;
+        }
+        match messages.last_mut() {
+            Some((last_role, last_blocks)) if *last_role == role => last_blocks.extend(blocks),
+            _ => messages.push((role, blocks)),
+        }
+    }
+
+    // A trailing assistant turn is a prefill the model continues; the API
+    // rejects one that ends in whitespace.
+    if let Some(("assistant", blocks)) = messages.last_mut()
+        && let Some(Value::String(text)) = blocks.last_mut().and_then(|block| block.get_mut("text"))
+    {
+        text.truncate(text.trim_end().len());
+    }
+
+    let thinking = match request.reasoning_effort {
+        Some(effort) => Some(effort.thinking_tokens(request.max_tokens).ok_or_else(|| {
+            ProviderError::invalid_request(
+                format!(
+                    "max_tokens must be above {} to leave room for thinking",
+                    MIN_THINKING_BUDGET
+                ),
+                Some("max_tokens"),
+            )
+        })?),
+        None => None,
+    };
+    let max_tokens = match thinking {
+        Some((_, max_tokens)) => max_tokens,
+        None => request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+    };
+
+    let mut body = json!({
+        "model": request.model,
+        "messages": messages
+            .into_iter()
+            .map(|(role, content)| json!({ "role": role, "content": content }))
+            .collect::<Vec<_>>(),
+        "max_tokens": max_tokens,
+        "stream": true,
+    });
+    // The system prompt is sent as blocks only when some of it is cached.
+    if system
+        .iter()
+        .any(|(_, cache_control)| cache_control.is_some())
+    {
+        body["system"] = system
+            .into_iter()
+            .map(|(text, cache_control)| {
+                let mut block = json!({ "type": "text", "text": text });
+                if let Some(cache_control) = cache_control {
+                    block["cache_control"] = json!(cache_control);
+                }
+                block
+            })
+            .collect();
+    } else if !system.is_empty() {
+        let system: Vec<String> = system.into_iter().map(|(text, _)| text).collect();
+        body["system"] = json!(system.join("\n\n"));
+    }
+    if let Some((budget, _)) = thinking {
+        body["thinking"] = json!({ "type": "enabled", "budget_tokens": budget });
+    }
+    if let Some(temperature) = request.temperature {
+        if thinking.is_some() && temperature != 1.0 {
+            warn!("Dropping temperature, which extended thinking does not allow");
+        } else {
+            // OpenAI temperatures go up to 2, Anthropic's to 1.
+            body["temperature"] = json!(temperature.clamp(0.0, 1.0));
+        }
+    }
+    if let Some(top_p) = request.top_p {
+        body["top_p"] = json!(top_p);
+    }
+    if let Some(stop) = request.stop.as_ref().filter(|stop| !stop.is_empty()) {
+        body["stop_sequences"] = json!(stop);
+    }
+    if let Some(user) = &request.user {
+        body["metadata"] = json!({ "user_id": user });
+    }
+    if let Some(tools) = request.tools.as_ref().filter(|tools| !tools.is_empty()) {
+        body["tools"] = tools
+            .iter()
+            .map(|tool| {
+                let mut spec = json!({
+                    "name": tool.function.name,
+                    "input_schema": tool
+                        .function
+                        .parameters
+                        .clone()
+                        .unwrap_or_else(|| json!({ "type": "object" })),
+                });
+                if let Some(description) = &tool.function.description {
+                    spec["description"] = json!(description);
+                }
+                spec
+            })
+            .collect();
+    }
+    if let Some(tool_choice) = &request.tool_choice {
+        body["tool_choice"] = match tool_choice {
+            ToolChoice::Mode(ToolChoiceMode::Auto) => json!({ "type": "auto" }),
+            ToolChoice::Mode(ToolChoiceMode::None) => json!({ "type": "none" }),
+            ToolChoice::Mode(ToolChoiceMode::Required) => json!({ "type": "any" }),
+            ToolChoice::Named(named) => json!({ "type": "tool", "name": named.function.name }),
+        };
+    }
+    Ok(body)
+}
+
+fn stop_reason_to_finish_reason(stop_reason: &str) -> &'static str {
+    match stop_reason {
+        "end_turn" | "stop_sequence" => "stop",
+        "max_tokens" => "length",
+        "tool_use" => "tool_calls",
+        "refusal" => "content_filter",
+        other => {
+            warn!("Unknown Anthropic stop reason: {}", other);
+            "stop"
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: i32,
+    #[serde(default)]
+    output_tokens: i32,
+    #[serde(default)]
+    cache_creation_input_tokens: i32,
+    #[serde(default)]
+    cache_read_input_tokens: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicMessage {
+    id: String,
+    model: String,
+    #[serde(default)]
+    usage: AnthropicUsage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text,
+    Thinking,
+    RedactedThinking {
+        data: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    ThinkingDelta {
+        thinking: String,
+    },
+    SignatureDelta {
+        signature: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageDelta {
+    stop_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicError {
+    r#type: String,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicEvent {
+    MessageStart {
+        message: AnthropicMessage,
+    },
+    ContentBlockStart {
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        delta: BlockDelta,
+    },
+    ContentBlockStop,
+    MessageDelta {
+        delta: MessageDelta,
+        #[serde(default)]
+        usage: AnthropicUsage,
+    },
+    MessageStop,
+    Error {
+        error: AnthropicError,
+    },
+    /// Pings and event types added later.
+    #[serde(other)]
+    Other,
+}
+
+/// The tool call of the content block being streamed.
+struct PendingToolCall {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+/// The thinking block being streamed, which is sent whole with its
+/// signature once it ends.
+#[derive(Default)]
+struct PendingThinking {
+    thinking: String,
+    signature: String,
+}
+
+fn chunk(delta: Option<Delta>, finish_reason: Option<String>) -> Choice {
+    ChoiceBuilder::default()
+        .delta(delta)
+        .finish_reason(finish_reason)
+        .build()
+}
+
+#[async_trait]
+impl ChatCompletionsProvider for AnthropicChatCompletionsProvider {
+    async fn chat_completions_stream<F>(
+        self,
+        request: ChatCompletionsRequest,
+        usage_callback: F,
+    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<StreamEvent>>>
+    where
+        F: Fn(&Usage) + Send + Sync + 'static,
+    {
+        debug!(
+            "Starting Anthropic chat completion request with model: {}",
+            request.model
+        );
+
+        let request = preprocess_images_blocking(request, &self.image_limits).await?;
+        let body = to_anthropic_request(&request)?;
+        let response = self
+            .client
+            .post(&self.url)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&body)
+            .send()
+            .await
+            .map_err(from_reqwest_error)?;
+
+        let status = response.status();
+        debug!("Anthropic API response status: {}", status);
+        if !status.is_success() {
+            let error_text = response.text().await.map_err(from_reqwest_error)?;
+            error!("Anthropic API error: {} - {}", status, error_text);
+            return Err(
+                from_anthropic_response(status.as_u16(), &error_text, &request.model).into(),
+            );
+        }
+
+        info!("Successfully connected to Anthropic API, starting stream processing");
+
+        let max_buffered_bytes = self.max_buffered_bytes;
+        let created = Utc::now().timestamp();
+        let stream = stream! {
+            let mut body = response.bytes_stream();
+            let mut parser = SseParser::new();
+            let mut buffer = BytesMut::new();
+            let mut id: Option<Arc<str>> = None;
+            let mut model = Some(request.model.clone());
+            let mut usage = AnthropicUsage::default();
+            let mut finish_reason = None;
+            let mut tool_call: Option<PendingToolCall> = None;
+            let mut thinking: Option<PendingThinking> = None;
+            let mut tool_calls_sent = 0;
+
+            let mut finished = false;
+            'outer: while !finished {
+                let sse_events = match body.next().await {
+                    Some(Ok(chunk_bytes)) => parser.feed(&chunk_bytes),
+                    Some(Err(e)) => {
+                        error!("Error receiving from Anthropic stream: {}", e);
+                        yield Err(anyhow::anyhow!("Stream receive error: {}", e));
+                        break;
+                    }
+                    // The last event may end without a blank line.
+                    None => {
+                        finished = true;
+                        parser.finish().into_iter().collect()
+                    }
+                };
+                if parser.buffered_len() > max_buffered_bytes {
+                    error!("Anthropic stream event exceeded {} bytes", max_buffered_bytes);
+                    yield Err(anyhow::anyhow!(
+                        "Upstream event exceeded memory limit of {} bytes",
+                        max_buffered_bytes
+                    ));
+                    break;
+                }
+
+                for sse_event in sse_events {
+                    let event = match serde_json::from_str::<AnthropicEvent>(&sse_event.data) {
+                        Ok(event) => event,
+                        Err(e) => {
+                            error!("Failed to parse Anthropic event: {}", e);
+                            yield Err(anyhow::anyhow!("Failed to parse response: {}", e));
+                            continue;
+                        }
+                    };
+
+                    let choice = match event {
+                        AnthropicEvent::MessageStart { message } => {
+                            id = Some(message.id.into());
+                            model = Some(message.model);
+                            usage = message.usage;
+                            Some(chunk(Some(Delta::Role { role: "assistant".to_string() }), None))
+                        }
+                        AnthropicEvent::ContentBlockStart { content_block } => match content_block {
+                            ContentBlock::ToolUse { id, name } => {
+                                tool_call = Some(PendingToolCall { id, name, arguments: String::new() });
+                                None
+                            }
+                            ContentBlock::Thinking => {
+                                thinking = Some(PendingThinking::default());
+                                None
+                            }
+                            ContentBlock::RedactedThinking { data } => Some(chunk(
+                                Some(Delta::ThinkingBlocks {
+                                    thinking_blocks: vec![
+                                        json!({ "type": "redacted_thinking", "data": data }),
+                                    ],
+                                }),
+                                None,
+                            )),
+                            ContentBlock::Text | ContentBlock::Other => None,
+                        },
+                        AnthropicEvent::ContentBlockDelta { delta } => match delta {
+                            BlockDelta::TextDelta { text } => {
+                                Some(chunk(Some(Delta::Content { content: text }), None))
+                            }
+                            BlockDelta::InputJsonDelta { partial_json } => {
+                                if let Some(tool_call) = &mut tool_call {
+                                    tool_call.arguments.push_str(&partial_json);
+                                }
+                                None
+                            }
+                            BlockDelta::ThinkingDelta { thinking: text } => {
+                                if let Some(thinking) = &mut thinking {
+                                    thinking.thinking.push_str(&text);
+                                }
+                                Some(chunk(Some(Delta::Reasoning { reasoning_content: text }), None))
+                            }
+                            BlockDelta::SignatureDelta { signature } => {
+                                if let Some(thinking) = &mut thinking {
+                                    thinking.signature.push_str(&signature);
+                                }
+                                None
+                            }
+                            BlockDelta::Other => None,
+                        },
+                        AnthropicEvent::ContentBlockStop if thinking.is_some() => {
+                            let PendingThinking { thinking, signature } = thinking.take().unwrap_or_default();
+                            Some(chunk(
+                                Some(Delta::ThinkingBlocks {
+                                    thinking_blocks: vec![json!({
+                                        "type": "thinking",
+                                        "thinking": thinking,
+                                        "signature": signature,
+                                    })],
+                                }),
+                                None,
+                            ))
+                        }
+                        AnthropicEvent::ContentBlockStop => tool_call.take().map(|tool_call| {
+                            let arguments = if tool_call.arguments.is_empty() {
+                                "{}".to_string()
+                            } else {
+                                tool_call.arguments
+                            };
+                            let index = tool_calls_sent;
+                            tool_calls_sent += 1;
+                            chunk(
+                                Some(Delta::ToolCalls {
+                                    tool_calls: vec![ToolCall {
+                                        index: Some(index),
+                                        id: tool_call.id,
+                                        r#type: "function".to_string(),
+                                        function: FunctionCall { name: tool_call.name, arguments },
+                                    }],
+                                }),
+                                None,
+                            )
+                        }),
+                        AnthropicEvent::MessageDelta { delta, usage: delta_usage } => {
+                            usage.output_tokens = delta_usage.output_tokens;
+                            finish_reason = delta
+                                .stop_reason
+                                .as_deref()
+                                .map(stop_reason_to_finish_reason)
+                                .map(str::to_string);
+                            None
+                        }
+                        AnthropicEvent::MessageStop => break 'outer,
+                        AnthropicEvent::Error { error } => {
+                            error!("Anthropic stream error: {} - {}", error.r#type, error.message);
+                            let kind = match error.r#type.as_str() {
+                                "overloaded_error" => ErrorKind::Unavailable,
+                                "rate_limit_error" => ErrorKind::RateLimited,
+                                _ => ErrorKind::Upstream,
+                            };
+                            yield Err(ProviderError::new(kind, error.message).into());
+                            break 'outer;
+                        }
+                        AnthropicEvent::Other => None,
+                    };
+
+                    if let Some(choice) = choice {
+                        let response = ChatCompletionsResponse::builder()
+                            .id(id.clone())
+                            .created(Some(created))
+                            .model(model.clone())
+                            .object(Some("chat.completion.chunk".to_string()))
+                            .choice(choice)
+                            .build();
+                        match create_stream_event(&response, &mut buffer) {
+                            Ok(event) => yield Ok(event),
+                            Err(e) => {
+                                error!("Failed to create stream event: {}", e);
+                                yield Err(e);
+                            }
+                        }
+                    }
+                }
+            }
+
+            let prompt_tokens = usage.input_tokens
+                + usage.cache_creation_input_tokens
+                + usage.cache_read_input_tokens;
+            let usage = UsageBuilder::default()
+                .prompt_tokens(prompt_tokens)
+                .completion_tokens(usage.output_tokens)
+                .total_tokens(prompt_tokens + usage.output_tokens)
+                .cache_read_tokens(usage.cache_read_input_tokens)
+                .cache_write_tokens(usage.cache_creation_input_tokens)
+                .build();
+            usage_callback(&usage);
+            let response = ChatCompletionsResponse::builder()
+                .id(id)
+                .created(Some(created))
+                .model(model)
+                .object(Some("chat.completion.chunk".to_string()))
+                .choice(chunk(None, finish_reason.or(Some("stop".to_string()))))
+                .usage(Some(usage))
+                .build();
+            match create_stream_event(&response, &mut buffer) {
+                Ok(event) => yield Ok(event),
+                Err(e) => {
+                    error!("Failed to create stream event: {}", e);
+                    yield Err(e);
+                }
+            }
+
+            info!("Anthropic stream completed, sending DONE message");
+            yield Ok(StreamEvent::Done);
+        };
+
+        Ok(stream.boxed())
+    }
+}
@@ -0,0 +1,317 @@
+use crate::upstream::UpstreamClients;
+use chrono::Utc;
+use flate2::{Compression, write::GzEncoder};
+use ring::digest::{SHA256, digest};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+pub const ARCHIVE_DROPPED_METRIC: &str = "llm_proxy_archive_records_dropped_total";
+
+/// Which records an object holds; each kind goes under its own prefix.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ArchiveKind {
+    Audit,
+    Usage,
+}
+
+impl ArchiveKind {
+    fn name(&self) -> &'static str {
+        match self {
+            ArchiveKind::Audit => "audit",
+            ArchiveKind::Usage => "usage",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ArchiveConfig {
+    pub bucket: String,
+    /// Prepended to object keys, like `llm-proxy/`.
+    pub prefix: String,
+    /// Defaults to the region of the default AWS account.
+    pub region: Option<String>,
+    /// An object is uploaded once this many bytes of JSON lines are in it,
+    /// before compression.
+    pub max_bytes: usize,
+    /// or once its first record is this old.
+    pub max_age: Duration,
+    pub audit: bool,
+    pub usage: bool,
+    pub max_retries: u32,
+    /// Records waiting to be batched; more are dropped.
+    pub queue_capacity: usize,
+}
+
+impl ArchiveConfig {
+    pub fn new(bucket: String) -> Self {
+        Self {
+            bucket,
+            prefix: String::new(),
+            region: None,
+            max_bytes: 16 * 1024 * 1024,
+            max_age: Duration::from_secs(300),
+            audit: true,
+            usage: true,
+            max_retries: 3,
+            queue_capacity: 10_000,
+        }
+    }
+}
+
+/// Ships records to S3 as gzipped JSON lines, one object per batch under
+/// `<prefix><kind>/<yyyy>/<mm>/<dd>/`. Recording never waits on S3;
+/// closing the archiver uploads what is still batched.
+pub struct Archiver {
+    config: ArchiveConfig,
+    records: mpsc::Sender<(ArchiveKind, String)>,
+    shutdown: Mutex<Option<oneshot::Sender<()>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Archiver {
+    pub fn new(config: ArchiveConfig, clients: &UpstreamClients) -> Self {
+        info!(
+            "Archiving {} to s3://{}/{}",
+            match (config.audit, config.usage) {
+                (true, true) => "audit records and usage events",
+                (true, false) => "audit records",
+                _ => "usage events",
+            },
+            config.bucket,
+            config.prefix
+        );
+        let (records, receiver) = mpsc::channel(config.queue_capacity.max(1));
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let uploader = Uploader {
+            config: config.clone(),
+            clients: clients.clone(),
+        };
+        let worker = tokio::spawn(uploader.run(receiver, shutdown_receiver));
+        Self {
+            config,
+            records,
+            shutdown: Mutex::new(Some(shutdown)),
+            worker: Mutex::new(Some(worker)),
+        }
+    }
+
+    /// Whether records of this kind are archived.
+    pub fn archives(&self, kind: ArchiveKind) -> bool {
+        match kind {
+            ArchiveKind::Audit => self.config.audit,
+            ArchiveKind::Usage => self.config.usage,
+        }
+    }
+
+    pub fn record(&self, kind: ArchiveKind, record: &impl Serialize) {
+        if !self.archives(kind) {
+            return;
+        }
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to encode {} record: {}", kind.name(), e);
+                return;
+            }
+        };
+        if let Err(e) = self.records.try_send((kind, line)) {
+            warn!("Dropping {} record for S3: {}", kind.name(), e);
+            metrics::counter!(ARCHIVE_DROPPED_METRIC).increment(1);
+        }
+    }
+
+    /// Stops taking records and waits up to `timeout` for the batched ones
+    /// to be uploaded.
+    pub async fn close(&self, timeout: Duration) {
+        if let Some(shutdown) = self.shutdown.lock().unwrap().take() {
+            let _ = shutdown.send(());
+        }
+        let Some(worker) = self.worker.lock().unwrap().take() else {
+            return;
+        };
+        if tokio::time::timeout(timeout, worker).await.is_err() {
+            warn!("Timed out uploading the remaining records to S3");
+        }
+    }
+}
+
+struct Batch {
+    encoder: GzEncoder<Vec<u8>>,
+    records: usize,
+    bytes: usize,
+    started: Instant,
+}
+
+impl Batch {
+    fn new() -> Self {
+        Self {
+            encoder: GzEncoder::new(Vec::new(), Compression::default()),
+            records: 0,
+            bytes: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn push(&mut self, line: &str) -> std::io::Result<()> {
+        self.encoder.write_all(line.as_bytes())?;
+        self.encoder.write_all(b"\n")?;
+        self.records += 1;
+        self.bytes += line.len() + 1;
+        Ok(())
+    }
+}
+
+struct Uploader {
+    config: ArchiveConfig,
+    clients: UpstreamClients,
+}
+
+impl Uploader {
+    async fn run(
+        self,
+        mut receiver: mpsc::Receiver<(ArchiveKind, String)>,
+        mut shutdown: oneshot::Receiver<()>,
+    ) {
+        let mut batches: HashMap<ArchiveKind, Batch> = HashMap::new();
+        let mut ticks = tokio::time::interval(self.config.max_age.min(Duration::from_secs(1)));
+        let mut closing = false;
+        loop {
+            tokio::select! {
+                record = receiver.recv() => {
+                    let Some((kind, line)) = record else {
+                        break;
+                    };
+                    let batch = batches.entry(kind).or_insert_with(Batch::new);
+                    if let Err(e) = batch.push(&line) {
+                        error!("Failed to compress {} record: {}", kind.name(), e);
+                    }
+                    if batch.bytes >= self.config.max_bytes
+                        && let Some(batch) = batches.remove(&kind)
+                    {
+                        self.upload(kind, batch).await;
+                    }
+                }
+                _ = &mut shutdown, if !closing => {
+                    // What is queued is still received, then None.
+                    closing = true;
+                    receiver.close();
+                }
+                _ = ticks.tick() => {
+                    let due: Vec<ArchiveKind> = batches
+                        .iter()
+                        .filter(|(_, batch)| batch.started.elapsed() >= self.config.max_age)
+                        .map(|(kind, _)| *kind)
+                        .collect();
+                    for kind in due {
+                        if let Some(batch) = batches.remove(&kind) {
+                            self.upload(kind, batch).await;
+                        }
+                    }
+                }
+            }
+        }
+        for (kind, batch) in batches {
+            self.upload(kind, batch).await;
+        }
+        debug!("Records for s3://{} flushed", self.config.bucket);
+    }
+
+    async fn upload(&self, kind: ArchiveKind, batch: Batch) {
+        let records = batch.records;
+        let body = match batch.encoder.finish() {
+            Ok(body) => body,
+            Err(e) => {
+                error!(
+                    "Failed to compress {} {} records: {}",
+                    records,
+                    kind.name(),
+                    e
+                );
+                metrics::counter!(ARCHIVE_DROPPED_METRIC).increment(records as u64);
+                return;
+            }
+        };
+        let now = Utc::now();
+        let key = format!(
+            "{}{}/{}/{}-{}.jsonl.gz",
+            self.config.prefix,
+            kind.name(),
+            now.format("%Y/%m/%d"),
+            now.format("%Y%m%dT%H%M%SZ"),
+            Uuid::new_v4().simple()
+        );
+        let mut attempt = 0;
+        loop {
+            let error = match self.put_object(&key, body.clone()).await {
+                Ok(()) => {
+                    debug!("Uploaded {} {} records to {}", records, kind.name(), key);
+                    return;
+                }
+                Err(e) => e,
+            };
+            if attempt >= self.config.max_retries {
+                error!(
+                    "Giving up on uploading {} {} records after {} attempts: {}",
+                    records,
+                    kind.name(),
+                    attempt + 1,
+                    error
+                );
+                metrics::counter!(ARCHIVE_DROPPED_METRIC).increment(records as u64);
+                return;
+            }
+            attempt += 1;
+            warn!(
+                "Failed to upload {} ({}), retrying in {}s",
+                key,
+                error,
+                1 << attempt
+            );
+            tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+        }
+    }
+
+    async fn put_object(&self, key: &str, body: Vec<u8>) -> anyhow::Result<()> {
+        let region = match &self.config.region {
+            Some(region) => region.clone(),
+            None => self.clients.region()?,
+        };
+        let content_sha256: String = digest(&SHA256, &body)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.clients
+            .signed(
+                "s3",
+                &region,
+                reqwest::Method::PUT,
+                &format!(
+                    "https://{}.s3.{}.amazonaws.com/{}",
+                    self.config.bucket, region, key
+                ),
+                &[
+                    ("content-type", "application/x-ndjson"),
+                    ("content-encoding", "gzip"),
+                    ("x-amz-content-sha256", &content_sha256),
+                ],
+                body,
+            )
+            .await?
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
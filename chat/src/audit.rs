@@ -0,0 +1,293 @@
+use crate::{
+    StreamEvent,
+    archive::{ArchiveKind, Archiver},
+    replay::{REDACTED, mask_secrets},
+    store::CompletionAccumulator,
+};
+use futures::stream::{BoxStream, StreamExt};
+use ring::digest::{SHA256, digest};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tracing::{error, info, warn};
+
+pub const AUDIT_DROPPED_METRIC: &str = "llm_proxy_audit_records_dropped_total";
+
+/// Records waiting to be written; more are dropped.
+const QUEUE_CAPACITY: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactAction {
+    /// Removes the field.
+    Drop,
+    /// Replaces the field with `[REDACTED]`.
+    Mask,
+    /// Replaces the field with the salted SHA-256 of its value, so records
+    /// of the same value can still be matched.
+    Hash,
+}
+
+/// Redacts the fields of a record at a JSON pointer, where `*` stands for
+/// every key or array index, like `/request/messages/*/content`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedactRule {
+    pub field: String,
+    pub action: RedactAction,
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditConfig {
+    /// Records are appended as JSON lines. Without a file, they are only
+    /// archived.
+    pub file: Option<PathBuf>,
+    pub rules: Vec<RedactRule>,
+    /// Masks API keys, AWS access keys, bearer tokens and email addresses
+    /// everywhere, as replay captures do.
+    pub mask_secrets: bool,
+    pub hash_salt: String,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            rules: Vec::new(),
+            mask_secrets: true,
+            hash_salt: String::new(),
+        }
+    }
+}
+
+/// One request as the proxy sent it upstream, with the completion it
+/// assembled or the error it failed with.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditRecord {
+    pub request_id: Option<String>,
+    pub timestamp: i64,
+    pub caller: String,
+    pub model: String,
+    pub request: Value,
+    pub response: Option<Value>,
+    /// The request's `metadata`, kept apart from the redacted request for
+    /// filtering records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn hash(salt: &str, value: &Value) -> Value {
+    let text = match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    };
+    let hashed: String = digest(&SHA256, format!("{}{}", salt, text).as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Value::from(format!("sha256:{}", hashed))
+}
+
+fn apply(value: &mut Value, path: &[&str], action: RedactAction, salt: &str) {
+    let Some((segment, rest)) = path.split_first() else {
+        return;
+    };
+    let last = rest.is_empty();
+    let redact_field = |field: &mut Value| match action {
+        RedactAction::Mask => *field = Value::from(REDACTED),
+        RedactAction::Hash => *field = hash(salt, field),
+        RedactAction::Drop => {}
+    };
+    match value {
+        Value::Object(fields) if last && action == RedactAction::Drop => match *segment {
+            "*" => fields.clear(),
+            key => {
+                fields.remove(key);
+            }
+        },
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                if *segment == "*" || key == segment {
+                    if last {
+                        redact_field(field);
+                    } else {
+                        apply(field, rest, action, salt);
+                    }
+                }
+            }
+        }
+        Value::Array(items) if last && action == RedactAction::Drop => match *segment {
+            "*" => items.clear(),
+            index => {
+                if let Ok(index) = index.parse::<usize>()
+                    && index < items.len()
+                {
+                    items.remove(index);
+                }
+            }
+        },
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                if *segment == "*" || index.to_string() == *segment {
+                    if last {
+                        redact_field(item);
+                    } else {
+                        apply(item, rest, action, salt);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Writes audit records to a JSON lines file and to the archive, redacted
+/// as configured. The file is written in the background; nothing waits on
+/// it.
+pub struct AuditLog {
+    config: AuditConfig,
+    records: Option<mpsc::Sender<Value>>,
+    archive: Option<Arc<Archiver>>,
+}
+
+impl AuditLog {
+    pub async fn open(config: AuditConfig, archive: Option<Arc<Archiver>>) -> anyhow::Result<Self> {
+        let archive = archive.filter(|archive| archive.archives(ArchiveKind::Audit));
+        let Some(path) = config.file.clone() else {
+            return Ok(Self {
+                config,
+                records: None,
+                archive,
+            });
+        };
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        info!(
+            "Writing audit records to {} with {} redaction rules",
+            path.display(),
+            config.rules.len()
+        );
+        let (records, mut receiver) = mpsc::channel::<Value>(QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                let mut line = record.to_string();
+                line.push('\n');
+                let write = async {
+                    file.write_all(line.as_bytes()).await?;
+                    file.flush().await
+                };
+                if let Err(e) = write.await {
+                    error!("Failed to write audit record to {}: {}", path.display(), e);
+                }
+            }
+        });
+        Ok(Self {
+            config,
+            records: Some(records),
+            archive,
+        })
+    }
+
+    /// The record as it is written: the rules are applied first, so that
+    /// hashes are of the original values, then secrets are masked.
+    pub fn redacted(&self, record: &AuditRecord) -> anyhow::Result<Value> {
+        let mut value = serde_json::to_value(record)?;
+        for rule in &self.config.rules {
+            let path: Vec<&str> = rule.field.split('/').skip(1).collect();
+            apply(&mut value, &path, rule.action, &self.config.hash_salt);
+        }
+        if self.config.mask_secrets {
+            mask_secrets(&mut value);
+        }
+        Ok(value)
+    }
+
+    pub fn record(&self, record: &AuditRecord) {
+        let value = match self.redacted(record) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Failed to encode audit record: {}", e);
+                return;
+            }
+        };
+        if let Some(archive) = &self.archive {
+            archive.record(ArchiveKind::Audit, &value);
+        }
+        if let Some(records) = &self.records
+            && let Err(e) = records.try_send(value)
+        {
+            warn!("Dropping audit record: {}", e);
+            metrics::counter!(AUDIT_DROPPED_METRIC).increment(1);
+        }
+    }
+}
+
+/// A record whose stream has not ended yet. It is written when the
+/// stream ends, or else when the stream is dropped, as when the client
+/// disconnects, with what was assembled by then.
+struct PendingRecord {
+    log: Arc<AuditLog>,
+    record: AuditRecord,
+    accumulator: CompletionAccumulator,
+    written: bool,
+}
+
+impl PendingRecord {
+    fn finish(&mut self, error: Option<String>) {
+        if self.written {
+            return;
+        }
+        self.written = true;
+        let accumulator = std::mem::take(&mut self.accumulator);
+        self.record.response = Some(accumulator.into_completion(&self.record.model));
+        self.record.error = error;
+        self.log.record(&self.record);
+    }
+}
+
+impl Drop for PendingRecord {
+    fn drop(&mut self) {
+        self.finish(Some("The stream was closed before it ended".to_string()));
+    }
+}
+
+/// Passes a completion stream through unchanged and audits the request
+/// with its assembled response once the stream ends.
+pub fn audit<'a>(
+    mut stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    log: Arc<AuditLog>,
+    record: AuditRecord,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+    async_stream::stream! {
+        let mut pending = PendingRecord {
+            log,
+            record,
+            accumulator: CompletionAccumulator::default(),
+            written: false,
+        };
+
+        while let Some(item) = stream.next().await {
+            match &item {
+                Ok(StreamEvent::Chunk(data)) => match serde_json::from_slice(data) {
+                    Ok(response) => pending.accumulator.push(response),
+                    Err(e) => warn!("Failed to parse chunk for the audit log: {}", e),
+                },
+                Ok(StreamEvent::Done) => pending.finish(None),
+                Err(e) => pending.finish(Some(e.to_string())),
+                _ => {}
+            }
+            yield item;
+        }
+        pending.finish(None);
+    }
+    .boxed()
+}
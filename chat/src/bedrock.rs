@@ -1,37 +1,708 @@
-use aws_sdk_bedrockruntime::types::{Message, SystemContentBlock};
-use request::{ChatCompletionsRequest, Role};
+use crate::{error::ProviderError, tokens::estimate_tokens};
+use aws_sdk_bedrockruntime::types::{
+    ContentBlock, ConversationRole, DocumentBlock, DocumentSource, ImageBlock, ImageSource,
+    InferenceConfiguration, Message, SystemContentBlock, Tool, ToolChoice, ToolConfiguration,
+    ToolInputSchema, ToolResultContentBlock, VideoBlock, VideoSource,
+};
+use aws_smithy_types::Document;
+use request::{
+    ChatCompletionsRequest, Guardrail, MAX_CACHE_POINTS, MIN_THINKING_BUDGET, Role, cache_point,
+    document::{document_to_json, json_to_document},
+};
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use tracing::{debug, error, warn};
 
 pub struct BedrockChatCompletion {
     pub model_id: String,
     pub system_content_blocks: Vec<SystemContentBlock>,
     pub messages: Vec<Message>,
+    pub tool_config: Option<ToolConfiguration>,
+    pub inference_config: Option<InferenceConfiguration>,
+    pub additional_model_request_fields: Option<Document>,
+    pub client_stop_sequences: Vec<String>,
+    pub guardrail: Option<Guardrail>,
+    pub request_metadata: Option<HashMap<String, String>>,
+}
+
+pub const MAX_BEDROCK_STOP_SEQUENCES: usize = 4;
+
+/// Bedrock model families that take OpenAI-style repetition penalties as
+/// additional model request fields. The Converse API itself has none.
+const PENALTY_MODEL_PREFIXES: [&str; 2] = ["cohere.command-r", "ai21.jamba"];
+
+/// Claude models with extended thinking, which `reasoning_effort` turns on.
+const THINKING_MODEL_PREFIXES: [&str; 3] = [
+    "anthropic.claude-3-7-sonnet",
+    "anthropic.claude-sonnet-4",
+    "anthropic.claude-opus-4",
+];
+
+/// Models Bedrock caches prompts for. The others reject cache points.
+const CACHE_MODEL_PREFIXES: [&str; 5] = [
+    "anthropic.claude-3-5-haiku",
+    "anthropic.claude-3-7-sonnet",
+    "anthropic.claude-sonnet-4",
+    "anthropic.claude-opus-4",
+    "amazon.nova-",
+];
+
+/// The model id without its cross-region inference profile prefix.
+fn base_model_id(model_id: &str) -> &str {
+    model_id
+        .split_once('.')
+        .filter(|(prefix, _)| matches!(*prefix, "us" | "eu" | "apac" | "us-gov" | "global"))
+        .map_or(model_id, |(_, rest)| rest)
+}
+
+fn supports_penalties(model_id: &str) -> bool {
+    let model_id = base_model_id(model_id);
+    PENALTY_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+
+fn supports_thinking(model_id: &str) -> bool {
+    let model_id = base_model_id(model_id);
+    THINKING_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+
+fn supports_cache_points(model_id: &str) -> bool {
+    let model_id = base_model_id(model_id);
+    CACHE_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+
+fn unsupported_parameter(request: &ChatCompletionsRequest, param: &str) -> ProviderError {
+    ProviderError::invalid_request(
+        format!("Model {} does not support {}", request.model, param),
+        Some(param),
+    )
+    .code("unsupported_parameter")
+}
+
+/// Drops a parameter the model cannot honor with a warning, or rejects the
+/// request when `strict` is set.
+fn unsupported(
+    request: &ChatCompletionsRequest,
+    param: &str,
+    strict: bool,
+) -> Result<(), ProviderError> {
+    if strict {
+        return Err(unsupported_parameter(request, param));
+    }
+    warn!(
+        "Dropping {} for model {}, which does not support it",
+        param, request.model
+    );
+    Ok(())
+}
+
+/// Bedrock has no token biasing. Requests with `logit_bias` are rejected
+/// even without strict parameters, as biases that ban tokens would
+/// otherwise be silently lost.
+pub fn check_logit_bias(request: &ChatCompletionsRequest) -> Result<(), ProviderError> {
+    match &request.logit_bias {
+        Some(logit_bias) if !logit_bias.is_empty() => {
+            Err(unsupported_parameter(request, "logit_bias"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Maps `frequency_penalty` and `presence_penalty` to additional model
+/// request fields for models that support them. For other models they are
+/// dropped with a warning, or rejected when `strict` is set.
+pub fn penalty_fields(
+    request: &ChatCompletionsRequest,
+    strict: bool,
+) -> Result<Option<Document>, ProviderError> {
+    let penalties = [
+        ("frequency_penalty", request.frequency_penalty),
+        ("presence_penalty", request.presence_penalty),
+    ];
+    let mut fields = Map::new();
+    for (param, value) in penalties {
+        let Some(value) = value else {
+            continue;
+        };
+        if !supports_penalties(&request.model.to_lowercase()) {
+            unsupported(request, param, strict)?;
+            continue;
+        }
+        fields.insert(param.to_string(), Value::from(value));
+    }
+    Ok((!fields.is_empty()).then(|| json_to_document(&Value::Object(fields))))
+}
+
+/// Maps `reasoning_effort` to an extended thinking budget in the additional
+/// model request fields, raising `max_tokens` above the budget when the
+/// client did not set it. Thinking rules out a `temperature` other than 1
+/// and a `top_p` below 0.95, which are dropped with a warning or rejected
+/// when `strict` is set. Models without extended thinking drop or reject
+/// `reasoning_effort` itself.
+pub fn apply_reasoning_effort(
+    completion: &mut BedrockChatCompletion,
+    request: &ChatCompletionsRequest,
+    strict: bool,
+) -> Result<(), ProviderError> {
+    let Some(effort) = request.reasoning_effort else {
+        return Ok(());
+    };
+    if !supports_thinking(&request.model.to_lowercase()) {
+        return unsupported(request, "reasoning_effort", strict);
+    }
+    let (budget, max_tokens) = effort.thinking_tokens(request.max_tokens).ok_or_else(|| {
+        ProviderError::invalid_request(
+            format!(
+                "max_tokens must be above {} to leave room for thinking",
+                MIN_THINKING_BUDGET
+            ),
+            Some("max_tokens"),
+        )
+    })?;
+
+    let mut temperature = request.temperature;
+    if temperature.is_some_and(|temperature| temperature != 1.0) {
+        unsupported(request, "temperature with reasoning_effort", strict)?;
+        temperature = None;
+    }
+    let mut top_p = request.top_p;
+    if top_p.is_some_and(|top_p| top_p < 0.95) {
+        unsupported(request, "top_p with reasoning_effort", strict)?;
+        top_p = None;
+    }
+    let stop_sequences = completion
+        .inference_config
+        .take()
+        .and_then(|config| config.stop_sequences);
+    completion.inference_config = Some(
+        InferenceConfiguration::builder()
+            .max_tokens(max_tokens)
+            .set_temperature(temperature)
+            .set_top_p(top_p)
+            .set_stop_sequences(stop_sequences)
+            .build(),
+    );
+
+    let mut thinking = Map::new();
+    thinking.insert(
+        "thinking".to_string(),
+        json!({ "type": "enabled", "budget_tokens": budget }),
+    );
+    extend_additional_fields(completion, thinking);
+    Ok(())
+}
+
+/// Merges the request's `extra_body` into the additional model request
+/// fields, where its fields take the place of any the proxy set.
+pub fn apply_extra_body(completion: &mut BedrockChatCompletion, request: &ChatCompletionsRequest) {
+    if let Some(extra_body) = request.extra_body.clone().filter(|extra| !extra.is_empty()) {
+        debug!(
+            "Passing extra_body fields {:?} to Bedrock",
+            extra_body.keys().collect::<Vec<_>>()
+        );
+        extend_additional_fields(completion, extra_body);
+    }
+}
+
+/// Drops the cache points of models without prompt caching, as caching
+/// changes no answer. Given a threshold, also caches system prompts of at
+/// least that many estimated tokens that set no cache point themselves.
+pub fn apply_prompt_caching(
+    completion: &mut BedrockChatCompletion,
+    cache_system_prompt_tokens: Option<i32>,
+) {
+    let is_cache_point = |block: &ContentBlock| matches!(block, ContentBlock::CachePoint(_));
+    let is_system_cache_point =
+        |block: &SystemContentBlock| matches!(block, SystemContentBlock::CachePoint(_));
+    let message_cache_points = completion
+        .messages
+        .iter()
+        .flat_map(|message| &message.content)
+        .filter(|block| is_cache_point(block))
+        .count();
+    let system_cached = completion
+        .system_content_blocks
+        .iter()
+        .any(is_system_cache_point);
+
+    if !supports_cache_points(&completion.model_id) {
+        if system_cached || message_cache_points > 0 {
+            debug!(
+                "Dropping cache points for model {}, which has no prompt caching",
+                completion.model_id
+            );
+            completion
+                .system_content_blocks
+                .retain(|block| !is_system_cache_point(block));
+            for message in &mut completion.messages {
+                message.content.retain(|block| !is_cache_point(block));
+            }
+        }
+        return;
+    }
+
+    let Some(min_tokens) = cache_system_prompt_tokens.filter(|_| !system_cached) else {
+        return;
+    };
+    let tokens: i32 = completion
+        .system_content_blocks
+        .iter()
+        .filter_map(|block| block.as_text().ok())
+        .map(|text| estimate_tokens(text, None))
+        .sum();
+    if tokens > 0 && tokens >= min_tokens && message_cache_points < MAX_CACHE_POINTS {
+        debug!("Caching a system prompt of about {} tokens", tokens);
+        completion
+            .system_content_blocks
+            .extend(cache_point().map(SystemContentBlock::CachePoint));
+    }
+}
+
+fn extend_additional_fields(completion: &mut BedrockChatCompletion, extra: Map<String, Value>) {
+    let mut fields = match completion
+        .additional_model_request_fields
+        .as_ref()
+        .map(document_to_json)
+    {
+        Some(Value::Object(fields)) => fields,
+        _ => Map::new(),
+    };
+    fields.extend(extra);
+    completion.additional_model_request_fields = Some(json_to_document(&Value::Object(fields)));
+}
+
+fn build_inference_config(
+    request: &ChatCompletionsRequest,
+) -> (Option<InferenceConfiguration>, Vec<String>) {
+    let mut stop_sequences = request.stop.clone().unwrap_or_default();
+    let client_stop_sequences = if stop_sequences.len() > MAX_BEDROCK_STOP_SEQUENCES {
+        debug!(
+            "Enforcing {} stop sequences beyond the Bedrock limit in the proxy",
+            stop_sequences.len() - MAX_BEDROCK_STOP_SEQUENCES
+        );
+        stop_sequences.split_off(MAX_BEDROCK_STOP_SEQUENCES)
+    } else {
+        Vec::new()
+    };
+
+    let inference_config = (request.max_tokens.is_some()
+        || request.temperature.is_some()
+        || request.top_p.is_some()
+        || !stop_sequences.is_empty())
+    .then(|| {
+        InferenceConfiguration::builder()
+            .set_max_tokens(request.max_tokens)
+            .set_temperature(request.temperature)
+            .set_top_p(request.top_p)
+            .set_stop_sequences((!stop_sequences.is_empty()).then_some(stop_sequences))
+            .build()
+    });
+
+    (inference_config, client_stop_sequences)
+}
+
+fn build_tool_config(request: &ChatCompletionsRequest) -> Option<ToolConfiguration> {
+    let tools = request.tools.as_ref().filter(|tools| !tools.is_empty())?;
+
+    let tools = tools
+        .iter()
+        .filter_map(|tool| match Tool::try_from(tool) {
+            Ok(tool) => Some(tool),
+            Err(e) => {
+                error!("Failed to convert tool to Bedrock format: {}", e);
+                None
+            }
+        })
+        .collect();
+
+    let tool_choice =
+        request
+            .tool_choice
+            .as_ref()
+            .and_then(|tool_choice| match tool_choice.try_into() {
+                Ok(tool_choice) => Some(tool_choice),
+                Err(e) => {
+                    error!("Failed to convert tool choice to Bedrock format: {}", e);
+                    None
+                }
+            });
+
+    match ToolConfiguration::builder()
+        .set_tools(Some(tools))
+        .set_tool_choice(tool_choice)
+        .build()
+    {
+        Ok(tool_config) => Some(tool_config),
+        Err(e) => {
+            error!("Failed to build Bedrock tool configuration: {}", e);
+            None
+        }
+    }
+}
+
+/// Bedrock rejects tool blocks in a conversation sent without a tool
+/// configuration, so when tools are disabled for a turn the earlier tool
+/// calls and results are replayed as plain text. Images, documents and
+/// videos of results follow their text as blocks of their own.
+fn tool_blocks_to_text(messages: &mut [Message]) {
+    for message in messages {
+        let mut content = Vec::with_capacity(message.content.len());
+        for block in std::mem::take(&mut message.content) {
+            match block {
+                ContentBlock::ToolUse(tool_use) => content.push(ContentBlock::Text(format!(
+                    "[Called tool {} with arguments {}]",
+                    tool_use.name,
+                    document_to_json(&tool_use.input)
+                ))),
+                ContentBlock::ToolResult(tool_result) => {
+                    let mut output = Vec::new();
+                    let mut attachments = Vec::new();
+                    for c in tool_result.content {
+                        match c {
+                            ToolResultContentBlock::Text(text) => output.push(text),
+                            ToolResultContentBlock::Image(image) => {
+                                attachments.push(ContentBlock::Image(image))
+                            }
+                            ToolResultContentBlock::Document(document) => {
+                                attachments.push(ContentBlock::Document(document))
+                            }
+                            ToolResultContentBlock::Video(video) => {
+                                attachments.push(ContentBlock::Video(video))
+                            }
+                            _ => {}
+                        }
+                    }
+                    content.push(ContentBlock::Text(format!(
+                        "[Tool result]\n{}",
+                        output.join("\n")
+                    )));
+                    content.extend(attachments);
+                }
+                block => content.push(block),
+            }
+        }
+        message.content = content;
+    }
+}
+
+/// Whether Bedrock takes the text as a request metadata key or value.
+fn valid_metadata_text(text: &str, max_len: usize) -> bool {
+    text.chars().count() <= max_len
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c.is_whitespace() || ":_@$#=/+,-.".contains(c))
+}
+
+/// The request's metadata, for Bedrock's invocation logs. Pairs Bedrock
+/// would reject the request over are left out.
+fn request_metadata(request: &ChatCompletionsRequest) -> Option<HashMap<String, String>> {
+    let metadata: HashMap<String, String> = request
+        .metadata
+        .iter()
+        .flatten()
+        .filter(|(key, value)| {
+            let valid =
+                !key.is_empty() && valid_metadata_text(key, 256) && valid_metadata_text(value, 256);
+            if !valid {
+                debug!(
+                    "Not sending metadata key {:?} to Bedrock, which rejects it",
+                    key
+                );
+            }
+            valid
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    (!metadata.is_empty()).then_some(metadata)
+}
+
+/// Converse takes conversations that open with a user turn and alternate
+/// from there, so consecutive messages of one role, such as the results of
+/// parallel tool calls or several user messages, are merged into one. In a
+/// merged user turn the tool results, each with the cache point after it,
+/// go first, as Claude expects them right after the calls they answer.
+fn alternate_turns(messages: Vec<Message>) -> Vec<Message> {
+    let mut turns: Vec<Message> = Vec::with_capacity(messages.len());
+    for message in messages {
+        match turns.last_mut() {
+            Some(last) if last.role == message.role => {
+                debug!("Merging consecutive {} messages", message.role.as_str());
+                last.content.extend(message.content);
+            }
+            _ => turns.push(message),
+        }
+    }
+
+    for turn in &mut turns {
+        if turn.role != ConversationRole::User {
+            continue;
+        }
+        let mut results = Vec::new();
+        let mut rest = Vec::new();
+        let mut after_result = false;
+        for block in std::mem::take(&mut turn.content) {
+            match block {
+                ContentBlock::ToolResult(_) => {
+                    results.push(block);
+                    after_result = true;
+                }
+                ContentBlock::CachePoint(_) if after_result => results.push(block),
+                block => {
+                    rest.push(block);
+                    after_result = false;
+                }
+            }
+        }
+        results.extend(rest);
+        turn.content = results;
+    }
+    turns
+}
+
+fn invalid_message(index: usize, error: impl std::fmt::Display) -> ProviderError {
+    let param = format!("messages[{}]", index);
+    ProviderError::invalid_request(format!("{}: {}", param, error), Some(&param))
 }
 
 pub fn process_chat_completions_request_to_bedrock_chat_completion(
     request: &ChatCompletionsRequest,
-) -> BedrockChatCompletion {
+) -> Result<BedrockChatCompletion, ProviderError> {
     let mut system_content_blocks = Vec::new();
     let mut messages = Vec::new();
     let model_id = request.model.clone();
 
-    for request_message in &request.messages {
+    for (i, request_message) in request.messages.iter().enumerate() {
         match request_message.role {
-            Role::Assistant | Role::User => {
-                if let Ok(message) = Message::try_from(request_message) {
-                    messages.push(message);
+            Role::Assistant | Role::Tool | Role::User => messages
+                .push(Message::try_from(request_message).map_err(|e| invalid_message(i, e))?),
+            Role::Developer | Role::System => {
+                if !messages.is_empty() {
+                    debug!("Hoisting mid-conversation system message into system content blocks");
+                }
+                if let Some(contents) = &request_message.contents {
+                    let new_system_content_blocks: Vec<SystemContentBlock> = contents.into();
+                    system_content_blocks.extend(new_system_content_blocks);
                 }
-            }
-            Role::System => {
-                let new_system_content_blocks: Vec<SystemContentBlock> =
-                    (&request_message.contents).into();
-                system_content_blocks.extend(new_system_content_blocks);
             }
         }
     }
 
-    BedrockChatCompletion {
+    // Made-up user text would put words in the user's mouth, so a
+    // conversation must open with the user itself.
+    if let Some(i) = request
+        .messages
+        .iter()
+        .position(|message| !matches!(message.role, Role::Developer | Role::System))
+        .filter(|&i| matches!(request.messages[i].role, Role::Assistant))
+    {
+        return Err(invalid_message(
+            i,
+            "Bedrock models need the conversation to open with a user message",
+        ));
+    }
+    let mut messages = alternate_turns(messages);
+
+    // The model continues a trailing assistant message, which Claude
+    // rejects when it ends in whitespace.
+    if let Some(Message {
+        role: ConversationRole::Assistant,
+        content,
+        ..
+    }) = messages.last_mut()
+        && let Some(ContentBlock::Text(text)) = content.last_mut()
+    {
+        debug!("Continuing from an assistant prefill");
+        text.truncate(text.trim_end().len());
+    }
+
+    let tools_disabled = request
+        .tool_choice
+        .as_ref()
+        .is_some_and(|tool_choice| tool_choice.disables_tools());
+    let tool_config = if tools_disabled {
+        debug!("tool_choice is \"none\", omitting Bedrock tool configuration");
+        tool_blocks_to_text(&mut messages);
+        None
+    } else {
+        build_tool_config(request)
+    };
+
+    let (inference_config, client_stop_sequences) = build_inference_config(request);
+
+    Ok(BedrockChatCompletion {
         model_id,
         system_content_blocks,
         messages,
+        tool_config,
+        inference_config,
+        additional_model_request_fields: None,
+        client_stop_sequences,
+        guardrail: request.guardrail.clone(),
+        request_metadata: request_metadata(request),
+    })
+}
+
+/// The text of a trailing assistant message, which the model continues
+/// rather than answers.
+pub fn prefill(messages: &[Message]) -> Option<String> {
+    let message = messages
+        .last()
+        .filter(|message| message.role == ConversationRole::Assistant)?;
+    let text: String = message
+        .content
+        .iter()
+        .filter_map(|block| block.as_text().ok())
+        .map(String::as_str)
+        .collect();
+    (!text.is_empty()).then_some(text)
+}
+
+fn image_json(image: &ImageBlock) -> Value {
+    let source = match &image.source {
+        Some(ImageSource::Bytes(bytes)) => {
+            json!({ "bytes": format!("<{} bytes>", bytes.as_ref().len()) })
+        }
+        other => json!({ "unknown": format!("{:?}", other) }),
+    };
+    json!({ "image": { "format": image.format.as_str(), "source": source } })
+}
+
+fn document_json(document: &DocumentBlock) -> Value {
+    let source = match &document.source {
+        Some(DocumentSource::Bytes(bytes)) => {
+            json!({ "bytes": format!("<{} bytes>", bytes.as_ref().len()) })
+        }
+        other => json!({ "unknown": format!("{:?}", other) }),
+    };
+    json!({
+        "document": {
+            "format": document.format.as_str(),
+            "name": document.name,
+            "source": source,
+        }
+    })
+}
+
+fn video_json(video: &VideoBlock) -> Value {
+    let source = match &video.source {
+        Some(VideoSource::Bytes(bytes)) => {
+            json!({ "bytes": format!("<{} bytes>", bytes.as_ref().len()) })
+        }
+        Some(VideoSource::S3Location(location)) => json!({
+            "s3Location": { "uri": location.uri, "bucketOwner": location.bucket_owner }
+        }),
+        other => json!({ "unknown": format!("{:?}", other) }),
+    };
+    json!({ "video": { "format": video.format.as_str(), "source": source } })
+}
+
+fn content_block_json(block: &ContentBlock) -> Value {
+    match block {
+        ContentBlock::Text(text) => json!({ "text": text }),
+        ContentBlock::Image(image) => image_json(image),
+        ContentBlock::Document(document) => document_json(document),
+        ContentBlock::Video(video) => video_json(video),
+        ContentBlock::CachePoint(cache_point) => {
+            json!({ "cachePoint": { "type": cache_point.r#type.as_str() } })
+        }
+        ContentBlock::ToolUse(tool_use) => json!({
+            "toolUse": {
+                "toolUseId": tool_use.tool_use_id,
+                "name": tool_use.name,
+                "input": document_to_json(&tool_use.input),
+            }
+        }),
+        ContentBlock::ToolResult(result) => json!({
+            "toolResult": {
+                "toolUseId": result.tool_use_id,
+                "content": result.content.iter().map(|content| match content {
+                    ToolResultContentBlock::Text(text) => json!({ "text": text }),
+                    ToolResultContentBlock::Json(document) => json!({ "json": document_to_json(document) }),
+                    ToolResultContentBlock::Image(image) => image_json(image),
+                    other => json!({ "unknown": format!("{:?}", other) }),
+                }).collect::<Vec<_>>(),
+                "status": result.status.as_ref().map(|status| status.as_str()),
+            }
+        }),
+        other => json!({ "unknown": format!("{:?}", other) }),
+    }
+}
+
+impl BedrockChatCompletion {
+    /// The Converse request as JSON, in the shape of the Bedrock API. Image,
+    /// document and video bytes are shown by size only.
+    pub fn to_json(&self) -> Value {
+        let system: Vec<Value> = self
+            .system_content_blocks
+            .iter()
+            .map(|block| match block {
+                SystemContentBlock::Text(text) => json!({ "text": text }),
+                SystemContentBlock::CachePoint(cache_point) => {
+                    json!({ "cachePoint": { "type": cache_point.r#type.as_str() } })
+                }
+                other => json!({ "unknown": format!("{:?}", other) }),
+            })
+            .collect();
+        let messages: Vec<Value> = self
+            .messages
+            .iter()
+            .map(|message| {
+                json!({
+                    "role": message.role.as_str(),
+                    "content": message.content.iter().map(content_block_json).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        let tool_config = self.tool_config.as_ref().map(|config| {
+            let tools: Vec<Value> = config
+                .tools
+                .iter()
+                .map(|tool| match tool {
+                    Tool::ToolSpec(spec) => json!({
+                        "toolSpec": {
+                            "name": spec.name,
+                            "description": spec.description,
+                            "inputSchema": match &spec.input_schema {
+                                Some(ToolInputSchema::Json(schema)) => json!({ "json": document_to_json(schema) }),
+                                other => json!({ "unknown": format!("{:?}", other) }),
+                            },
+                        }
+                    }),
+                    other => json!({ "unknown": format!("{:?}", other) }),
+                })
+                .collect();
+            let tool_choice = config.tool_choice.as_ref().map(|choice| match choice {
+                ToolChoice::Auto(_) => json!({ "auto": {} }),
+                ToolChoice::Any(_) => json!({ "any": {} }),
+                ToolChoice::Tool(tool) => json!({ "tool": { "name": tool.name } }),
+                other => json!({ "unknown": format!("{:?}", other) }),
+            });
+            json!({ "tools": tools, "toolChoice": tool_choice })
+        });
+        let inference_config = self.inference_config.as_ref().map(|config| {
+            json!({
+                "maxTokens": config.max_tokens,
+                "temperature": config.temperature,
+                "topP": config.top_p,
+                "stopSequences": config.stop_sequences,
+            })
+        });
+
+        json!({
+            "modelId": self.model_id,
+            "system": system,
+            "messages": messages,
+            "toolConfig": tool_config,
+            "inferenceConfig": inference_config,
+            "additionalModelRequestFields": self.additional_model_request_fields.as_ref().map(document_to_json),
+            "requestMetadata": self.request_metadata,
+            "clientStopSequences": self.client_stop_sequences,
+            "guardrailConfig": self.guardrail,
+        })
     }
 }
@@ -0,0 +1,333 @@
+use crate::error::{ErrorKind, ProviderError};
+use chrono::{DateTime, Datelike, Duration, Months, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+use tracing::{debug, error, info, warn};
+
+pub const BUDGET_EXCEEDED_METRIC: &str = "llm_proxy_budget_exceeded_total";
+pub const QUOTA_EXCEEDED_METRIC: &str = "llm_proxy_quota_exceeded_total";
+
+/// Usage is kept per UTC hour for as long as the longest window needs it.
+const BUCKET_SECS: i64 = 3600;
+const RETAINED_DAYS: i64 = 32;
+/// How long the file is written after a change, so that a burst of
+/// requests is written once.
+const WRITE_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Period::Day => "daily",
+            Period::Week => "weekly",
+            Period::Month => "monthly",
+        })
+    }
+}
+
+/// Limits on a key's usage within a period. Calendar periods start at UTC
+/// midnight, on Mondays and on the first of the month; rolling ones cover
+/// the last 24 hours, 7 days or 30 days, to the hour.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Quota {
+    pub period: Period,
+    #[serde(default)]
+    pub rolling: bool,
+    pub requests: Option<u64>,
+    pub tokens: Option<u64>,
+    /// In USD.
+    pub cost: Option<f64>,
+}
+
+impl Quota {
+    /// When the window ending `now` starts, and for calendar periods when
+    /// the next one does.
+    fn window(&self, now: DateTime<Utc>) -> (DateTime<Utc>, Option<DateTime<Utc>>) {
+        if self.rolling {
+            let length = match self.period {
+                Period::Day => Duration::days(1),
+                Period::Week => Duration::weeks(1),
+                Period::Month => Duration::days(30),
+            };
+            return (now - length, None);
+        }
+        let midnight = Utc.from_utc_datetime(&now.date_naive().and_hms_opt(0, 0, 0).unwrap());
+        let (start, end) = match self.period {
+            Period::Day => (midnight, midnight + Duration::days(1)),
+            Period::Week => {
+                let monday = midnight - Duration::days(now.weekday().num_days_from_monday() as i64);
+                (monday, monday + Duration::weeks(1))
+            }
+            Period::Month => {
+                let first = midnight - Duration::days(now.day0() as i64);
+                (first, first + Months::new(1))
+            }
+        };
+        (start, Some(end))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+struct PeriodUsage {
+    requests: u64,
+    tokens: u64,
+    cost: f64,
+}
+
+impl PeriodUsage {
+    fn add(&mut self, other: &PeriodUsage) {
+        self.requests += other.requests;
+        self.tokens += other.tokens;
+        self.cost += other.cost;
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct CallerSpend {
+    /// Spend in USD since the ledger was started.
+    total: f64,
+    /// Usage per hour, by the start of the hour.
+    #[serde(default)]
+    hours: BTreeMap<i64, PeriodUsage>,
+}
+
+impl CallerSpend {
+    fn since(&self, start: DateTime<Utc>) -> PeriodUsage {
+        let first = start.timestamp() - start.timestamp().rem_euclid(BUCKET_SECS);
+        let mut usage = PeriodUsage::default();
+        for hour in self.hours.range(first..).map(|(_, hour)| hour) {
+            usage.add(hour);
+        }
+        usage
+    }
+}
+
+/// Spend in USD per caller since the ledger was started, priced like
+/// sessions are, and recent usage for quotas. With a file both survive
+/// restarts.
+pub struct SpendLedger {
+    file: Option<PathBuf>,
+    spend: Arc<Mutex<HashMap<String, CallerSpend>>>,
+    /// Serializes writes so the file always reflects the latest spend.
+    writes: Arc<tokio::sync::Mutex<()>>,
+    /// Wakes the writer after a change.
+    changed: Arc<Notify>,
+}
+
+async fn persist(
+    file: &PathBuf,
+    spend: &Mutex<HashMap<String, CallerSpend>>,
+    writes: &tokio::sync::Mutex<()>,
+) {
+    let _write = writes.lock().await;
+    let snapshot = spend.lock().unwrap().clone();
+    let write = async {
+        if let Some(dir) = file.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let tmp = file.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&snapshot)?).await?;
+        tokio::fs::rename(&tmp, file).await?;
+        anyhow::Ok(())
+    };
+    if let Err(e) = write.await {
+        error!("Failed to persist spend to {}: {}", file.display(), e);
+    }
+}
+
+fn quota_exceeded(
+    quota: &Quota,
+    owner: &str,
+    limit: String,
+    resets: Option<DateTime<Utc>>,
+) -> ProviderError {
+    let message = match resets {
+        Some(resets) => format!(
+            "The {} quota of {} for {} is used up. It resets at {}.",
+            quota.period,
+            limit,
+            owner,
+            resets.to_rfc3339()
+        ),
+        None => format!(
+            "The rolling {} quota of {} for {} is used up.",
+            quota.period, limit, owner
+        ),
+    };
+    warn!("{}", message);
+    metrics::counter!(QUOTA_EXCEEDED_METRIC).increment(1);
+    ProviderError::new(ErrorKind::RateLimited, message).code("quota_exceeded")
+}
+
+impl SpendLedger {
+    pub async fn load(file: Option<PathBuf>) -> anyhow::Result<Self> {
+        let mut spend = HashMap::new();
+        if let Some(file) = &file
+            && tokio::fs::try_exists(file).await?
+        {
+            spend = serde_json::from_slice(&tokio::fs::read(file).await?)?;
+            info!(
+                "Loaded the spend of {} callers from {}",
+                spend.len(),
+                file.display()
+            );
+        }
+        let ledger = Self {
+            file,
+            spend: Arc::new(Mutex::new(spend)),
+            writes: Arc::default(),
+            changed: Arc::default(),
+        };
+        if let Some(file) = ledger.file.clone() {
+            let (spend, writes, changed) = (
+                ledger.spend.clone(),
+                ledger.writes.clone(),
+                ledger.changed.clone(),
+            );
+            tokio::spawn(async move {
+                loop {
+                    changed.notified().await;
+                    tokio::time::sleep(WRITE_DELAY).await;
+                    persist(&file, &spend, &writes).await;
+                }
+            });
+        }
+        Ok(ledger)
+    }
+
+    /// Writes changes not yet written, for shutdown.
+    pub async fn flush(&self) {
+        if let Some(file) = &self.file {
+            persist(file, &self.spend, &self.writes).await;
+        }
+    }
+
+    pub fn spent(&self, caller: &str) -> f64 {
+        self.spend
+            .lock()
+            .unwrap()
+            .get(caller)
+            .map_or(0.0, |spend| spend.total)
+    }
+
+    /// Fails once the caller has spent its whole budget or used up one of
+    /// its quotas, naming `owner` as the one whose limit it is. A request
+    /// that starts under the limits is served in full, so usage may end up
+    /// slightly above them.
+    pub fn check(
+        &self,
+        caller: &str,
+        owner: &str,
+        budget: Option<f64>,
+        quotas: &[Quota],
+    ) -> Result<(), ProviderError> {
+        let spend = self.spend.lock().unwrap();
+        let Some(spend) = spend.get(caller) else {
+            return Ok(());
+        };
+        if let Some(budget) = budget
+            && spend.total >= budget
+        {
+            warn!(
+                "{} has spent ${:.4} of its ${:.2} budget",
+                caller, spend.total, budget
+            );
+            metrics::counter!(BUDGET_EXCEEDED_METRIC).increment(1);
+            return Err(ProviderError::new(
+                ErrorKind::RateLimited,
+                format!("The budget of ${:.2} for {} is exhausted.", budget, owner),
+            )
+            .code("budget_exceeded"));
+        }
+
+        let now = Utc::now();
+        for quota in quotas {
+            let (start, resets) = quota.window(now);
+            let used = spend.since(start);
+            if let Some(requests) = quota.requests
+                && used.requests >= requests
+            {
+                return Err(quota_exceeded(
+                    quota,
+                    owner,
+                    format!("{} requests", requests),
+                    resets,
+                ));
+            }
+            if let Some(tokens) = quota.tokens
+                && used.tokens >= tokens
+            {
+                return Err(quota_exceeded(
+                    quota,
+                    owner,
+                    format!("{} tokens", tokens),
+                    resets,
+                ));
+            }
+            if let Some(cost) = quota.cost
+                && used.cost >= cost
+            {
+                return Err(quota_exceeded(
+                    quota,
+                    owner,
+                    format!("${:.2}", cost),
+                    resets,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a request against the caller's request quotas as it is
+    /// admitted, so requests still being served count as well.
+    pub fn admit(&self, caller: &str) {
+        self.add(
+            caller,
+            PeriodUsage {
+                requests: 1,
+                ..PeriodUsage::default()
+            },
+        );
+    }
+
+    /// Adds what a served request used to the caller's usage.
+    pub fn record(&self, caller: &str, tokens: u64, cost: f64) {
+        self.add(
+            caller,
+            PeriodUsage {
+                requests: 0,
+                tokens,
+                cost,
+            },
+        );
+    }
+
+    /// Adds to the caller's usage and has the ledger written shortly after.
+    fn add(&self, caller: &str, usage: PeriodUsage) {
+        let now = Utc::now().timestamp();
+        let mut spend = self.spend.lock().unwrap();
+        let spend = spend.entry(caller.to_string()).or_default();
+        spend.total += usage.cost;
+        spend
+            .hours
+            .entry(now - now.rem_euclid(BUCKET_SECS))
+            .or_default()
+            .add(&usage);
+        spend.hours = spend.hours.split_off(&(now - RETAINED_DAYS * 86_400));
+        debug!("{} has spent ${:.4}", caller, spend.total);
+        self.changed.notify_one();
+    }
+}
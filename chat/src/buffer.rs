@@ -0,0 +1,144 @@
+use crate::{
+    StreamEvent,
+    memory::{DEFAULT_MAX_STREAM_BYTES, StreamMemory},
+};
+use futures::stream::{BoxStream, StreamExt};
+use std::str::FromStr;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{Instrument, Span, debug, error, warn};
+
+pub const DEFAULT_STREAM_BUFFER_CAPACITY: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OverflowPolicy {
+    #[default]
+    Backpressure,
+    DropAndMark,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "backpressure" => Ok(OverflowPolicy::Backpressure),
+            "drop_and_mark" => Ok(OverflowPolicy::DropAndMark),
+            _ => anyhow::bail!("Unknown stream buffer overflow policy: {}", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StreamBufferConfig {
+    pub capacity: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub max_stream_bytes: usize,
+}
+
+impl Default for StreamBufferConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_STREAM_BUFFER_CAPACITY,
+            overflow_policy: OverflowPolicy::default(),
+            max_stream_bytes: DEFAULT_MAX_STREAM_BYTES,
+        }
+    }
+}
+
+/// Decouples the upstream receive loop from the client writer through a
+/// bounded channel, so a slow client cannot make the proxy buffer without
+/// limit.
+///
+/// With `DropAndMark`, events that do not fit are discarded and a
+/// `StreamEvent::Dropped` reporting the count is sent once the client catches
+/// up. Errors and the final `StreamEvent::Done` are never dropped.
+///
+/// Every chunk is reserved against `memory` as it enters the buffer; the
+/// writer releases it once written. A stream that exceeds its limit is
+/// terminated with an error.
+pub fn buffered(
+    mut stream: BoxStream<'static, anyhow::Result<StreamEvent>>,
+    config: StreamBufferConfig,
+    memory: StreamMemory,
+) -> BoxStream<'static, anyhow::Result<StreamEvent>> {
+    let (tx, rx) = mpsc::channel(config.capacity.max(1));
+
+    let forward = async move {
+        let mut dropped = 0;
+        let mut pending: Option<anyhow::Result<StreamEvent>> = None;
+
+        while let Some(item) = stream.next().await {
+            if let Ok(event) = &item
+                && let Err(e) = memory.reserve(event.buffered_size())
+            {
+                error!("Terminating stream: {}", e);
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+
+            match config.overflow_policy {
+                OverflowPolicy::Backpressure => {
+                    if tx.send(item).await.is_err() {
+                        debug!("Client disconnected, stopping upstream stream");
+                        return;
+                    }
+                }
+                OverflowPolicy::DropAndMark => {
+                    let Some(previous) = pending.replace(item) else {
+                        continue;
+                    };
+
+                    if previous.is_err() {
+                        if tx.send(previous).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+
+                    if dropped > 0 {
+                        match tx.try_send(Ok(StreamEvent::Dropped(dropped))) {
+                            Ok(()) => dropped = 0,
+                            Err(TrySendError::Full(_)) => {
+                                if let Ok(event) = previous {
+                                    memory.release(event.buffered_size());
+                                }
+                                dropped += 1;
+                                continue;
+                            }
+                            Err(TrySendError::Closed(_)) => return,
+                        }
+                    }
+
+                    match tx.try_send(previous) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(previous)) => {
+                            if let Ok(event) = previous {
+                                memory.release(event.buffered_size());
+                            }
+                            if dropped == 0 {
+                                warn!("Stream buffer full, dropping events for slow client");
+                            }
+                            dropped += 1;
+                        }
+                        Err(TrySendError::Closed(_)) => {
+                            debug!("Client disconnected, stopping upstream stream");
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+
+        if dropped > 0 && tx.send(Ok(StreamEvent::Dropped(dropped))).await.is_err() {
+            return;
+        }
+        if let Some(last) = pending {
+            let _ = tx.send(last).await;
+        }
+    };
+    // Read in its own task, the upstream still logs within the request's span.
+    tokio::spawn(forward.instrument(Span::current()));
+
+    ReceiverStream::new(rx).boxed()
+}
@@ -0,0 +1,139 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+pub const EMBEDDINGS_CACHE_HITS_METRIC: &str = "llm_proxy_embeddings_cache_hits_total";
+pub const EMBEDDINGS_CACHE_MISSES_METRIC: &str = "llm_proxy_embeddings_cache_misses_total";
+
+#[derive(Clone, Debug)]
+pub struct EmbeddingsCacheConfig {
+    pub enabled: bool,
+    pub ttl: Duration,
+    pub max_entries: usize,
+}
+
+impl Default for EmbeddingsCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl: Duration::from_secs(24 * 3600),
+            max_entries: 100_000,
+        }
+    }
+}
+
+type CacheKey = (String, String);
+type Vector = Arc<[f32]>;
+
+/// Inputs that only differ in surrounding whitespace or line endings embed
+/// to the same vector, so they share a cache entry.
+fn normalize(input: &str) -> String {
+    input.trim().replace("\r\n", "\n")
+}
+
+#[derive(Default)]
+struct Entries {
+    vectors: HashMap<CacheKey, (Vector, Instant)>,
+    order: VecDeque<(CacheKey, Instant)>,
+}
+
+/// Embedding vectors keyed by model and normalized input, for ingestion
+/// pipelines that embed the same chunks over and over.
+pub struct EmbeddingsCache {
+    config: EmbeddingsCacheConfig,
+    entries: Mutex<Entries>,
+}
+
+impl EmbeddingsCache {
+    pub fn new(config: EmbeddingsCacheConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn get(&self, model: &str, input: &str) -> Option<Vector> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        let key = (model.to_string(), normalize(input));
+        let mut entries = self.entries.lock().unwrap();
+        let vector = match entries.vectors.get(&key) {
+            Some((vector, inserted)) if inserted.elapsed() < self.config.ttl => {
+                Some(vector.clone())
+            }
+            Some(_) => {
+                entries.vectors.remove(&key);
+                None
+            }
+            None => None,
+        };
+
+        match &vector {
+            Some(_) => metrics::counter!(EMBEDDINGS_CACHE_HITS_METRIC).increment(1),
+            None => metrics::counter!(EMBEDDINGS_CACHE_MISSES_METRIC).increment(1),
+        }
+        vector
+    }
+
+    /// Looks up a batch of inputs, returning the cached vectors in input
+    /// order along with the indices that still need embedding.
+    pub fn get_many(&self, model: &str, inputs: &[String]) -> (Vec<Option<Vector>>, Vec<usize>) {
+        let vectors: Vec<Option<Vector>> =
+            inputs.iter().map(|input| self.get(model, input)).collect();
+        let misses = vectors
+            .iter()
+            .enumerate()
+            .filter(|(_, vector)| vector.is_none())
+            .map(|(i, _)| i)
+            .collect();
+        (vectors, misses)
+    }
+
+    pub fn insert(&self, model: &str, input: &str, vector: Vector) {
+        if !self.config.enabled {
+            return;
+        }
+
+        let key = (model.to_string(), normalize(input));
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        // A live entry keeps its place in the queue, so embedding the same
+        // input again does not grow it.
+        if let Some((current, inserted)) = entries.vectors.get_mut(&key)
+            && inserted.elapsed() < self.config.ttl
+        {
+            *current = vector;
+            return;
+        }
+        entries.vectors.insert(key.clone(), (vector, now));
+        entries.order.push_back((key, now));
+
+        // The order queue may hold stale records for keys that expired and
+        // were inserted again; only the matching timestamp evicts.
+        while entries.vectors.len() > self.config.max_entries
+            || entries
+                .order
+                .front()
+                .is_some_and(|(_, inserted)| inserted.elapsed() >= self.config.ttl)
+        {
+            let Some((key, inserted)) = entries.order.pop_front() else {
+                break;
+            };
+            if entries
+                .vectors
+                .get(&key)
+                .is_some_and(|(_, current)| *current == inserted)
+            {
+                entries.vectors.remove(&key);
+            }
+        }
+    }
+}
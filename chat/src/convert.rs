@@ -0,0 +1,227 @@
+//! The OpenAI ↔ Bedrock mappings the proxy uses, for batch jobs, evaluators
+//! and other tools that need the same conversion without running the
+//! server. Enabled with the `convert` feature.
+//!
+//! ```no_run
+//! # async fn run(client: aws_sdk_bedrockruntime::Client) -> anyhow::Result<()> {
+//! use chat::convert::{ChunkConverter, converse, converse_output_to_completion, to_bedrock};
+//!
+//! let request: request::ChatCompletionsRequest = serde_json::from_str(
+//!     r#"{"model": "anthropic.claude-3-5-haiku-20241022-v1:0",
+//!         "messages": [{"role": "user", "content": "Hello"}]}"#,
+//! )?;
+//! let completion = to_bedrock(&request)?;
+//!
+//! // A whole response, as a `chat.completion` object.
+//! let output = converse(&client, &completion).send().await?;
+//! let body = converse_output_to_completion(output, &request.model);
+//!
+//! // Or streamed, as `chat.completion.chunk` objects.
+//! let mut stream = chat::convert::converse_stream(&client, &completion).send().await?.stream;
+//! let mut converter = ChunkConverter::new(&request.model);
+//! while let Some(output) = stream.recv().await? {
+//!     let chunk = converter.convert(output);
+//!     println!("{}", serde_json::to_string(&chunk)?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    bedrock::{
+        apply_extra_body, apply_prompt_caching, apply_reasoning_effort, check_logit_bias,
+        penalty_fields, process_chat_completions_request_to_bedrock_chat_completion,
+    },
+    error::ProviderError,
+    guardrails::{guardrail_config, guardrail_stream_config},
+    store::CompletionAccumulator,
+};
+use aws_sdk_bedrockruntime::{
+    Client,
+    operation::{
+        converse::{ConverseOutput, builders::ConverseFluentBuilder},
+        converse_stream::builders::ConverseStreamFluentBuilder,
+    },
+    types::{
+        ContentBlock, ConverseOutput as ConverseOutputType, ConverseStreamOutput,
+        ReasoningContentBlock, StopReason,
+    },
+};
+use chrono::Utc;
+use request::{ChatCompletionsRequest, document::document_to_json};
+use response::{
+    ChatCompletionsResponse, ChoiceBuilder, Delta, FunctionCall, ToolCall, ToolCallTracker,
+    bedrock_usage, converse_stream_output_to_chat_completions_response_builder,
+    stop_reason_to_finish_reason,
+};
+use serde_json::Value;
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub use crate::bedrock::BedrockChatCompletion;
+
+/// Converts an OpenAI chat completions request into the parts of a Converse
+/// request. Parameters the model cannot honor are dropped, as the proxy
+/// does outside of strict mode.
+pub fn to_bedrock(
+    request: &ChatCompletionsRequest,
+) -> Result<BedrockChatCompletion, ProviderError> {
+    check_logit_bias(request)?;
+    let mut completion = process_chat_completions_request_to_bedrock_chat_completion(request)?;
+    completion.additional_model_request_fields = penalty_fields(request, false)?;
+    apply_reasoning_effort(&mut completion, request, false)?;
+    apply_extra_body(&mut completion, request);
+    apply_prompt_caching(&mut completion, None);
+    Ok(completion)
+}
+
+/// A Converse request for the converted parts, ready to send.
+pub fn converse(client: &Client, completion: &BedrockChatCompletion) -> ConverseFluentBuilder {
+    client
+        .converse()
+        .model_id(&completion.model_id)
+        .set_system(Some(completion.system_content_blocks.clone()))
+        .set_messages(Some(completion.messages.clone()))
+        .set_tool_config(completion.tool_config.clone())
+        .set_inference_config(completion.inference_config.clone())
+        .set_additional_model_request_fields(completion.additional_model_request_fields.clone())
+        .set_request_metadata(completion.request_metadata.clone())
+        .set_guardrail_config(completion.guardrail.as_ref().and_then(guardrail_config))
+}
+
+/// A ConverseStream request for the converted parts, ready to send.
+pub fn converse_stream(
+    client: &Client,
+    completion: &BedrockChatCompletion,
+) -> ConverseStreamFluentBuilder {
+    client
+        .converse_stream()
+        .model_id(&completion.model_id)
+        .set_system(Some(completion.system_content_blocks.clone()))
+        .set_messages(Some(completion.messages.clone()))
+        .set_tool_config(completion.tool_config.clone())
+        .set_inference_config(completion.inference_config.clone())
+        .set_additional_model_request_fields(completion.additional_model_request_fields.clone())
+        .set_request_metadata(completion.request_metadata.clone())
+        .set_guardrail_config(
+            completion
+                .guardrail
+                .as_ref()
+                .and_then(guardrail_stream_config),
+        )
+}
+
+/// Turns ConverseStream events into `chat.completion.chunk` objects that
+/// share one id, as the proxy streams them.
+pub struct ChunkConverter {
+    id: Arc<str>,
+    created: i64,
+    model: String,
+    tool_calls: ToolCallTracker,
+}
+
+impl ChunkConverter {
+    pub fn new(model: &str) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string().into(),
+            created: Utc::now().timestamp(),
+            model: model.to_string(),
+            tool_calls: ToolCallTracker::default(),
+        }
+    }
+
+    pub fn convert(&mut self, output: ConverseStreamOutput) -> ChatCompletionsResponse {
+        converse_stream_output_to_chat_completions_response_builder(
+            output,
+            &mut self.tool_calls,
+            &|_| {},
+        )
+        .id(Some(self.id.clone()))
+        .created(Some(self.created))
+        .model(Some(self.model.clone()))
+        .build()
+    }
+}
+
+/// Maps a whole Converse response to a `chat.completion` object.
+pub fn converse_output_to_completion(output: ConverseOutput, model: &str) -> Value {
+    let id: Arc<str> = Uuid::new_v4().to_string().into();
+    let mut accumulator = CompletionAccumulator::default();
+    let response = |choice| {
+        ChatCompletionsResponse::builder()
+            .id(Some(id.clone()))
+            .created(Some(Utc::now().timestamp()))
+            .model(Some(model.to_string()))
+            .choice(choice)
+    };
+
+    // A guardrail that intervenes replaces the whole output with its
+    // blocked message, which is a refusal rather than an answer.
+    let refused = output.stop_reason == StopReason::GuardrailIntervened;
+    if let Some(ConverseOutputType::Message(message)) = output.output {
+        let mut tool_calls = Vec::new();
+        for block in message.content {
+            match block {
+                ContentBlock::Text(text) => accumulator.push(
+                    response(
+                        ChoiceBuilder::default()
+                            .delta(Some(if refused {
+                                Delta::Refusal { refusal: text }
+                            } else {
+                                Delta::Content { content: text }
+                            }))
+                            .build(),
+                    )
+                    .build(),
+                ),
+                ContentBlock::ReasoningContent(ReasoningContentBlock::ReasoningText(reasoning)) => {
+                    accumulator.push(
+                        response(
+                            ChoiceBuilder::default()
+                                .delta(Some(Delta::Reasoning {
+                                    reasoning_content: reasoning.text,
+                                }))
+                                .build(),
+                        )
+                        .build(),
+                    )
+                }
+                ContentBlock::ToolUse(tool_use) => tool_calls.push(ToolCall {
+                    index: Some(tool_calls.len() as i32),
+                    id: tool_use.tool_use_id,
+                    r#type: "function".to_string(),
+                    function: FunctionCall {
+                        name: tool_use.name,
+                        arguments: document_to_json(&tool_use.input).to_string(),
+                    },
+                }),
+                _ => {}
+            }
+        }
+        if !tool_calls.is_empty() {
+            accumulator.push(
+                response(
+                    ChoiceBuilder::default()
+                        .delta(Some(Delta::ToolCalls { tool_calls }))
+                        .build(),
+                )
+                .build(),
+            );
+        }
+    }
+
+    let usage = output.usage.as_ref().map(bedrock_usage);
+    accumulator.push(
+        response(
+            ChoiceBuilder::default()
+                .finish_reason(Some(
+                    stop_reason_to_finish_reason(&output.stop_reason).to_string(),
+                ))
+                .build(),
+        )
+        .usage(usage)
+        .build(),
+    );
+
+    accumulator.into_completion(model)
+}
@@ -0,0 +1,246 @@
+use crate::{
+    cache::EmbeddingsCache,
+    error::{ProviderError, from_bedrock_error},
+    tokens::estimate_tokens,
+};
+use async_trait::async_trait;
+use aws_sdk_bedrockruntime::{Client, primitives::Blob};
+use futures::{StreamExt, TryStreamExt, stream};
+use request::embeddings::{EmbeddingsRequest, EncodingFormat};
+use response::embeddings::{Embedding, EmbeddingVector, EmbeddingsResponse, EmbeddingsUsage};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use std::sync::Arc;
+use tracing::debug;
+
+/// Cohere Embed takes at most this many texts per call.
+const COHERE_MAX_TEXTS: usize = 96;
+/// Embedding calls in flight at once for one request.
+const MAX_CONCURRENT_CALLS: usize = 8;
+
+#[async_trait]
+pub trait EmbeddingsProvider {
+    async fn embeddings(self, request: EmbeddingsRequest) -> anyhow::Result<EmbeddingsResponse>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EmbeddingsFamily {
+    Titan,
+    Cohere,
+}
+
+impl EmbeddingsFamily {
+    /// Also matches inference profiles, which prefix the id with a region.
+    fn of(model: &str) -> Option<Self> {
+        let model = model.to_lowercase();
+        if model.contains("amazon.titan-embed") {
+            Some(Self::Titan)
+        } else if model.contains("cohere.embed") {
+            Some(Self::Cohere)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn is_embeddings_model(model: &str) -> bool {
+    EmbeddingsFamily::of(model).is_some()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanOutput {
+    embedding: Vec<f32>,
+    input_text_token_count: i32,
+}
+
+#[derive(Deserialize)]
+struct CohereOutput {
+    embeddings: Vec<Vec<f32>>,
+}
+
+fn encode(vector: &[f32], format: EncodingFormat) -> EmbeddingVector {
+    match format {
+        EncodingFormat::Float => EmbeddingVector::Float(vector.to_vec()),
+        EncodingFormat::Base64 => {
+            let bytes: Vec<u8> = vector
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect();
+            EmbeddingVector::Base64(aws_smithy_types::base64::encode(bytes))
+        }
+    }
+}
+
+/// Embeds with Bedrock Titan or Cohere Embed models through `InvokeModel`.
+pub struct BedrockEmbeddingsProvider {
+    client: Client,
+    cache: Option<Arc<EmbeddingsCache>>,
+}
+
+impl BedrockEmbeddingsProvider {
+    pub fn new(client: &Client) -> Self {
+        Self {
+            client: client.clone(),
+            cache: None,
+        }
+    }
+
+    pub fn cache(mut self, cache: Arc<EmbeddingsCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    async fn invoke<T: DeserializeOwned>(&self, model: &str, body: Value) -> anyhow::Result<T> {
+        let output = self
+            .client
+            .invoke_model()
+            .model_id(model)
+            .content_type("application/json")
+            .accept("application/json")
+            .body(Blob::new(serde_json::to_vec(&body)?))
+            .send()
+            .await
+            .map_err(|e| from_bedrock_error(e, model))?;
+        Ok(serde_json::from_slice(output.body().as_ref())?)
+    }
+
+    /// Titan embeds one text per call, so a few calls run at a time.
+    async fn titan(
+        &self,
+        model: &str,
+        inputs: &[&str],
+        dimensions: Option<u32>,
+    ) -> anyhow::Result<(Vec<Vec<f32>>, i32)> {
+        let calls: Vec<_> = inputs
+            .iter()
+            .map(|input| {
+                let mut body = json!({ "inputText": input });
+                if let Some(dimensions) = dimensions {
+                    body["dimensions"] = json!(dimensions);
+                }
+                self.invoke::<TitanOutput>(model, body)
+            })
+            .collect();
+        let outputs: Vec<TitanOutput> = stream::iter(calls)
+            .buffered(MAX_CONCURRENT_CALLS)
+            .try_collect()
+            .await?;
+        let tokens = outputs
+            .iter()
+            .map(|output| output.input_text_token_count)
+            .sum();
+        Ok((
+            outputs.into_iter().map(|output| output.embedding).collect(),
+            tokens,
+        ))
+    }
+
+    /// Cohere reports no token counts, so they are estimated.
+    async fn cohere(&self, model: &str, inputs: &[&str]) -> anyhow::Result<(Vec<Vec<f32>>, i32)> {
+        let calls: Vec<_> = inputs
+            .chunks(COHERE_MAX_TEXTS)
+            .map(|texts| {
+                self.invoke::<CohereOutput>(
+                    model,
+                    json!({ "texts": texts, "input_type": "search_document" }),
+                )
+            })
+            .collect();
+        let outputs: Vec<CohereOutput> = stream::iter(calls)
+            .buffered(MAX_CONCURRENT_CALLS)
+            .try_collect()
+            .await?;
+        let tokens = inputs
+            .iter()
+            .map(|input| estimate_tokens(input, None))
+            .sum();
+        Ok((
+            outputs
+                .into_iter()
+                .flat_map(|output| output.embeddings)
+                .collect(),
+            tokens,
+        ))
+    }
+}
+
+#[async_trait]
+impl EmbeddingsProvider for BedrockEmbeddingsProvider {
+    /// Cached inputs are not sent upstream and do not count towards usage.
+    async fn embeddings(self, request: EmbeddingsRequest) -> anyhow::Result<EmbeddingsResponse> {
+        let family = EmbeddingsFamily::of(&request.model)
+            .ok_or_else(|| ProviderError::model_not_found(&request.model))?;
+        if family == EmbeddingsFamily::Cohere && request.dimensions.is_some() {
+            return Err(ProviderError::invalid_request(
+                format!("Model {} does not take dimensions", request.model),
+                Some("dimensions"),
+            )
+            .into());
+        }
+
+        let model = request.model;
+        let format = request.encoding_format.unwrap_or_default();
+        let inputs = request.input.into_vec();
+        // Vectors of another size are another cache entry.
+        let cache_model = match request.dimensions {
+            Some(dimensions) => format!("{}:{}", model, dimensions),
+            None => model.clone(),
+        };
+        let (mut vectors, misses) = match &self.cache {
+            Some(cache) => cache.get_many(&cache_model, &inputs),
+            None => (vec![None; inputs.len()], (0..inputs.len()).collect()),
+        };
+        debug!(
+            "Embedding {} of {} inputs with model {}",
+            misses.len(),
+            inputs.len(),
+            model
+        );
+
+        let mut prompt_tokens = 0;
+        if !misses.is_empty() {
+            let pending: Vec<&str> = misses.iter().map(|&i| inputs[i].as_str()).collect();
+            let (embedded, tokens) = match family {
+                EmbeddingsFamily::Titan => self.titan(&model, &pending, request.dimensions).await?,
+                EmbeddingsFamily::Cohere => self.cohere(&model, &pending).await?,
+            };
+            if embedded.len() != pending.len() {
+                anyhow::bail!(
+                    "Model {} returned {} embeddings for {} inputs",
+                    model,
+                    embedded.len(),
+                    pending.len()
+                );
+            }
+            prompt_tokens = tokens;
+            for (index, vector) in misses.into_iter().zip(embedded) {
+                let vector: Arc<[f32]> = vector.into();
+                if let Some(cache) = &self.cache {
+                    cache.insert(&cache_model, &inputs[index], vector.clone());
+                }
+                vectors[index] = Some(vector);
+            }
+        }
+
+        let data = vectors
+            .into_iter()
+            .enumerate()
+            .map(|(index, vector)| Embedding {
+                embedding: encode(vector.as_deref().unwrap_or_default(), format),
+                index,
+                object: "embedding".to_string(),
+            })
+            .collect();
+
+        Ok(EmbeddingsResponse {
+            data,
+            model,
+            object: "list".to_string(),
+            usage: EmbeddingsUsage {
+                prompt_tokens,
+                total_tokens: prompt_tokens,
+            },
+        })
+    }
+}
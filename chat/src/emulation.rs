@@ -0,0 +1,399 @@
+use request::{ChatCompletionsRequest, Contents, Message, Role, ToolChoice, ToolChoiceMode};
+use response::{ChatCompletionsResponse, Delta, FunctionCall, ToolCall};
+use serde_json::{Value, json};
+use std::{fmt::Write, mem, str::FromStr};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+const JSON_OPEN: &str = "<tool_calls>";
+const JSON_CLOSE: &str = "</tool_calls>";
+const REACT_ACTION: &str = "Action:";
+const REACT_INPUT: &str = "Action Input:";
+const REACT_OBSERVATION: &str = "Observation:";
+
+/// How tool calls are described to, and parsed back from, a model that has
+/// no native tool support.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EmulationFormat {
+    /// Calls are a JSON array inside a `<tool_calls>` block.
+    #[default]
+    Json,
+    /// Calls are `Action:` / `Action Input:` lines, ReAct style.
+    React,
+}
+
+impl FromStr for EmulationFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(EmulationFormat::Json),
+            "react" => Ok(EmulationFormat::React),
+            _ => Err(anyhow::anyhow!("Unknown tool emulation format: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ToolEmulationConfig {
+    pub models: Vec<String>,
+    pub format: EmulationFormat,
+}
+
+impl ToolEmulationConfig {
+    pub fn format_for(&self, model: &str) -> Option<EmulationFormat> {
+        self.models
+            .iter()
+            .any(|prefix| model.starts_with(prefix.as_str()))
+            .then_some(self.format)
+    }
+}
+
+fn tool_prompt(request: &ChatCompletionsRequest, format: EmulationFormat) -> String {
+    let mut prompt = String::from("You have access to the following tools:\n");
+    for tool in request.tools.iter().flatten() {
+        let _ = write!(prompt, "\n- {}", tool.function.name);
+        if let Some(description) = &tool.function.description {
+            let _ = write!(prompt, ": {}", description);
+        }
+        let parameters = tool
+            .function
+            .parameters
+            .clone()
+            .unwrap_or_else(|| json!({ "type": "object", "properties": {} }));
+        let _ = write!(prompt, "\n  Parameters (JSON Schema): {}", parameters);
+    }
+
+    prompt.push_str("\n\n");
+    match format {
+        EmulationFormat::Json => prompt.push_str(
+            "To call tools, reply with a <tool_calls> block containing a JSON array of calls \
+             and nothing after it, for example:\n\
+             <tool_calls>[{\"name\": \"tool_name\", \"arguments\": {\"argument\": \"value\"}}]</tool_calls>\n",
+        ),
+        EmulationFormat::React => prompt.push_str(
+            "To use a tool, reply in this format and stop:\n\
+             Thought: your reasoning\n\
+             Action: the tool name\n\
+             Action Input: the arguments as a JSON object\n",
+        ),
+    }
+    prompt.push_str(
+        "The results will be sent back to you in the next message. If no tool is needed, answer normally.",
+    );
+
+    match &request.tool_choice {
+        Some(ToolChoice::Mode(ToolChoiceMode::Required)) => {
+            prompt.push_str(" You must call at least one tool.")
+        }
+        Some(ToolChoice::Named(named)) => {
+            let _ = write!(prompt, " You must call the {} tool.", named.function.name);
+        }
+        _ => {}
+    }
+
+    prompt
+}
+
+fn render_tool_calls(message: &Message, format: EmulationFormat) -> String {
+    let mut text = message
+        .contents
+        .as_ref()
+        .map(Contents::to_text)
+        .unwrap_or_default();
+
+    let calls = message.tool_calls.iter().flatten();
+    match format {
+        EmulationFormat::Json => {
+            let calls: Vec<Value> = calls
+                .map(|call| {
+                    let arguments: Value = serde_json::from_str(&call.function.arguments)
+                        .unwrap_or_else(|_| json!({}));
+                    json!({ "name": call.function.name, "arguments": arguments })
+                })
+                .collect();
+            let _ = write!(text, "\n{}{}{}", JSON_OPEN, Value::from(calls), JSON_CLOSE);
+        }
+        EmulationFormat::React => {
+            for call in calls {
+                let _ = write!(
+                    text,
+                    "\n{} {}\n{} {}",
+                    REACT_ACTION, call.function.name, REACT_INPUT, call.function.arguments
+                );
+            }
+        }
+    }
+
+    text.trim_start().to_string()
+}
+
+fn render_tool_result(message: &Message, format: EmulationFormat) -> String {
+    let output = message
+        .contents
+        .as_ref()
+        .map(Contents::to_text)
+        .unwrap_or_default();
+    match format {
+        EmulationFormat::Json => format!(
+            "Result of tool call {}:\n{}",
+            message.tool_call_id.as_deref().unwrap_or_default(),
+            output
+        ),
+        EmulationFormat::React => format!("{} {}", REACT_OBSERVATION, output),
+    }
+}
+
+/// Rewrites a request with tools into one a model without tool support can
+/// take: the tools are described in a system prompt, and earlier tool calls
+/// and results are replayed as text in the same format.
+pub fn emulate_tools(request: &mut ChatCompletionsRequest, format: EmulationFormat) {
+    debug!("Emulating tool calls with the {:?} format", format);
+    let prompt = tool_prompt(request, format);
+
+    let mut messages: Vec<Message> = Vec::with_capacity(request.messages.len() + 1);
+    messages.push(Message {
+        contents: Some(Contents::String(prompt)),
+        name: None,
+        role: Role::System,
+        tool_call_id: None,
+        tool_calls: None,
+        thinking_blocks: None,
+    });
+
+    for mut message in mem::take(&mut request.messages) {
+        match message.role {
+            Role::Assistant if message.tool_calls.is_some() => {
+                message.contents = Some(Contents::String(render_tool_calls(&message, format)));
+                message.tool_calls = None;
+                messages.push(message);
+            }
+            Role::Tool => {
+                let result = render_tool_result(&message, format);
+                match messages.last_mut() {
+                    Some(Message {
+                        role: Role::User,
+                        contents: Some(Contents::String(previous)),
+                        tool_call_id: Some(_),
+                        ..
+                    }) => {
+                        previous.push_str("\n\n");
+                        previous.push_str(&result);
+                    }
+                    _ => messages.push(Message {
+                        contents: Some(Contents::String(result)),
+                        name: None,
+                        role: Role::User,
+                        // Marks the message as holding tool results so that
+                        // parallel results are merged into it.
+                        tool_call_id: message.tool_call_id.take(),
+                        tool_calls: None,
+                        thinking_blocks: None,
+                    }),
+                }
+            }
+            _ => messages.push(message),
+        }
+    }
+
+    request.messages = messages;
+    request.tools = None;
+    request.tool_choice = None;
+
+    // Keeps the model from inventing the tool results itself.
+    let stop = match format {
+        EmulationFormat::Json => JSON_CLOSE,
+        EmulationFormat::React => REACT_OBSERVATION,
+    };
+    request
+        .stop
+        .get_or_insert_default()
+        .insert(0, stop.to_string());
+}
+
+fn parse_json_calls(text: &str) -> Option<Vec<(String, Value)>> {
+    let value: Value = serde_json::from_str(text.trim()).ok()?;
+    let calls = match value {
+        Value::Array(calls) => calls,
+        call @ Value::Object(_) => vec![call],
+        _ => return None,
+    };
+
+    calls
+        .into_iter()
+        .map(|mut call| {
+            let name = call["name"].as_str()?.to_string();
+            let arguments = match call["arguments"].take() {
+                Value::String(arguments) => serde_json::from_str(&arguments).ok()?,
+                Value::Null => json!({}),
+                arguments => arguments,
+            };
+            Some((name, arguments))
+        })
+        .collect()
+}
+
+fn parse_react_calls(text: &str) -> Option<Vec<(String, Value)>> {
+    let mut calls = Vec::new();
+    let mut name: Option<String> = None;
+
+    for line in text.lines().map(str::trim) {
+        if let Some(action) = line.strip_prefix(REACT_ACTION) {
+            name = Some(action.trim().to_string());
+        } else if let Some(input) = line.strip_prefix(REACT_INPUT) {
+            let input = input.trim();
+            let arguments = if input.is_empty() {
+                json!({})
+            } else {
+                serde_json::from_str(input).ok()?
+            };
+            calls.push((name.take()?, arguments));
+        }
+    }
+    if let Some(name) = name {
+        calls.push((name, json!({})));
+    }
+
+    (!calls.is_empty()).then_some(calls)
+}
+
+/// Finds emulated tool calls in the streamed text and turns them into
+/// `tool_calls` deltas. Text that could start a call is held back until
+/// the next delta decides it, like the stop sequence filter does.
+pub struct ToolCallParser {
+    format: EmulationFormat,
+    held: String,
+    call: Option<String>,
+    calls: Vec<(String, Value)>,
+}
+
+impl ToolCallParser {
+    pub fn new(format: EmulationFormat) -> Self {
+        Self {
+            format,
+            held: String::new(),
+            call: None,
+            calls: Vec::new(),
+        }
+    }
+
+    fn marker(&self) -> &'static str {
+        match self.format {
+            EmulationFormat::Json => JSON_OPEN,
+            EmulationFormat::React => REACT_ACTION,
+        }
+    }
+
+    fn push(&mut self, text: &str) -> String {
+        let mut emit = String::new();
+        self.held.push_str(text);
+
+        loop {
+            if let Some(call) = self.call.as_mut() {
+                call.push_str(&mem::take(&mut self.held));
+                if self.format == EmulationFormat::Json
+                    && let Some(end) = call.find(JSON_CLOSE)
+                {
+                    self.held = call.split_off(end + JSON_CLOSE.len());
+                    call.truncate(end);
+                    let call = self.call.take().unwrap_or_default();
+                    self.finish_call(call);
+                    continue;
+                }
+                return emit;
+            }
+
+            let marker = self.marker();
+            if let Some(start) = self.held.find(marker) {
+                let rest = self.held.split_off(start);
+                emit.push_str(&mem::take(&mut self.held));
+                self.call = Some(match self.format {
+                    EmulationFormat::Json => rest[marker.len()..].to_string(),
+                    EmulationFormat::React => rest,
+                });
+                continue;
+            }
+
+            let hold = (1..marker.len().min(self.held.len() + 1))
+                .rev()
+                .filter(|&len| self.held.is_char_boundary(self.held.len() - len))
+                .find(|&len| self.held.ends_with(&marker[..len]))
+                .unwrap_or(0);
+            let rest = self.held.split_off(self.held.len() - hold);
+            emit.push_str(&mem::replace(&mut self.held, rest));
+            return emit;
+        }
+    }
+
+    fn finish_call(&mut self, call: String) {
+        let parsed = match self.format {
+            EmulationFormat::Json => parse_json_calls(&call),
+            EmulationFormat::React => parse_react_calls(&call),
+        };
+        match parsed {
+            Some(calls) => self.calls.extend(calls),
+            None => {
+                warn!("Could not parse emulated tool call, returning it as text");
+                let marker = match self.format {
+                    EmulationFormat::Json => JSON_OPEN,
+                    EmulationFormat::React => "",
+                };
+                self.held.insert_str(0, &format!("{}{}", marker, call));
+            }
+        }
+    }
+
+    fn finish(&mut self) -> String {
+        if let Some(call) = self.call.take() {
+            self.finish_call(call);
+        }
+        mem::take(&mut self.held)
+    }
+
+    /// Applies the parser to one outgoing chunk. Returns `false` when the
+    /// chunk has nothing left to send.
+    pub fn filter_response(&mut self, response: &mut ChatCompletionsResponse) -> bool {
+        for choice in &mut response.choices {
+            if let Some(Delta::Content { content }) = &mut choice.delta {
+                *content = self.push(content);
+            }
+
+            if choice.finish_reason.is_none() {
+                continue;
+            }
+
+            let held = self.finish();
+            if self.calls.is_empty() {
+                if !held.is_empty() {
+                    choice.delta = Some(Delta::Content { content: held });
+                }
+                continue;
+            }
+
+            if !held.trim().is_empty() {
+                debug!("Dropping text the model wrote after its tool calls");
+            }
+            let tool_calls = mem::take(&mut self.calls)
+                .into_iter()
+                .enumerate()
+                .map(|(index, (name, arguments))| ToolCall {
+                    index: Some(index as i32),
+                    id: format!("call_{}", Uuid::new_v4().simple()),
+                    r#type: "function".to_string(),
+                    function: FunctionCall {
+                        name,
+                        arguments: arguments.to_string(),
+                    },
+                })
+                .collect();
+            choice.delta = Some(Delta::ToolCalls { tool_calls });
+            choice.finish_reason = Some("tool_calls".to_string());
+        }
+
+        response.choices.retain(|choice| {
+            choice.finish_reason.is_some()
+                || !matches!(&choice.delta, Some(Delta::Content { content }) if content.is_empty())
+        });
+        !response.choices.is_empty() || response.usage.is_some()
+    }
+}
@@ -0,0 +1,216 @@
+use aws_sdk_bedrockruntime::error::{ProvideErrorMetadata, SdkError};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    InvalidRequest,
+    Authentication,
+    PermissionDenied,
+    ModelNotFound,
+    NotFound,
+    RateLimited,
+    Timeout,
+    Unavailable,
+    Upstream,
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ErrorKind::InvalidRequest => 400,
+            ErrorKind::Authentication => 401,
+            ErrorKind::PermissionDenied => 403,
+            ErrorKind::ModelNotFound | ErrorKind::NotFound => 404,
+            ErrorKind::RateLimited => 429,
+            ErrorKind::Internal => 500,
+            ErrorKind::Upstream => 502,
+            ErrorKind::Unavailable => 503,
+            ErrorKind::Timeout => 504,
+        }
+    }
+
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            ErrorKind::InvalidRequest | ErrorKind::ModelNotFound | ErrorKind::NotFound => {
+                "invalid_request_error"
+            }
+            ErrorKind::Authentication => "authentication_error",
+            ErrorKind::PermissionDenied => "permission_error",
+            ErrorKind::RateLimited => "rate_limit_error",
+            ErrorKind::Timeout => "timeout_error",
+            ErrorKind::Upstream => "api_error",
+            ErrorKind::Unavailable | ErrorKind::Internal => "server_error",
+        }
+    }
+
+    fn from_status_code(status: u16) -> Self {
+        match status {
+            400 | 422 => ErrorKind::InvalidRequest,
+            401 => ErrorKind::Authentication,
+            403 => ErrorKind::PermissionDenied,
+            408 | 504 => ErrorKind::Timeout,
+            429 => ErrorKind::RateLimited,
+            503 => ErrorKind::Unavailable,
+            _ => ErrorKind::Upstream,
+        }
+    }
+}
+
+/// An error classified into an OpenAI error class, shared by all providers
+/// so the server can answer with the matching HTTP status and error body.
+#[derive(Debug)]
+pub struct ProviderError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+}
+
+impl ProviderError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            param: None,
+            code: None,
+        }
+    }
+
+    pub fn invalid_request(message: impl Into<String>, param: Option<&str>) -> Self {
+        Self {
+            param: param.map(str::to_string),
+            ..Self::new(ErrorKind::InvalidRequest, message)
+        }
+    }
+
+    pub fn model_not_found(model: &str) -> Self {
+        Self {
+            param: Some("model".to_string()),
+            code: Some("model_not_found".to_string()),
+            ..Self::new(
+                ErrorKind::ModelNotFound,
+                format!(
+                    "The model `{}` does not exist or you do not have access to it.",
+                    model
+                ),
+            )
+        }
+    }
+
+    pub fn param(mut self, param: &str) -> Self {
+        self.param = Some(param.to_string());
+        self
+    }
+
+    pub fn code(mut self, code: &str) -> Self {
+        self.code = Some(code.to_string());
+        self
+    }
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+pub fn from_bedrock_error<E, R>(error: SdkError<E, R>, model: &str) -> ProviderError
+where
+    E: ProvideErrorMetadata + fmt::Debug,
+    R: fmt::Debug,
+{
+    let service_error = error.as_service_error();
+    let message = service_error
+        .and_then(|e| e.message())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("Bedrock request failed: {:?}", error));
+
+    let kind = match &error {
+        SdkError::TimeoutError(_) => ErrorKind::Timeout,
+        SdkError::DispatchFailure(e) if e.is_timeout() => ErrorKind::Timeout,
+        SdkError::DispatchFailure(_) => ErrorKind::Upstream,
+        SdkError::ServiceError(_) => match service_error.and_then(|e| e.code()) {
+            Some("AccessDeniedException") => ErrorKind::PermissionDenied,
+            Some("ResourceNotFoundException") => ErrorKind::ModelNotFound,
+            Some("ThrottlingException" | "ServiceQuotaExceededException") => ErrorKind::RateLimited,
+            Some("ValidationException") if message.contains("model identifier is invalid") => {
+                ErrorKind::ModelNotFound
+            }
+            Some("ValidationException") => ErrorKind::InvalidRequest,
+            Some("ModelTimeoutException") => ErrorKind::Timeout,
+            Some("ServiceUnavailableException" | "ModelNotReadyException") => {
+                ErrorKind::Unavailable
+            }
+            Some("InternalServerException") => ErrorKind::Internal,
+            _ => ErrorKind::Upstream,
+        },
+        _ => ErrorKind::Internal,
+    };
+
+    if kind == ErrorKind::ModelNotFound {
+        return ProviderError::model_not_found(model);
+    }
+    ProviderError::new(kind, message)
+}
+
+pub fn from_openai_response(status: u16, body: &str, model: &str) -> ProviderError {
+    let body: Option<serde_json::Value> = serde_json::from_str(body).ok();
+    let error = body.as_ref().map(|body| &body["error"]);
+    let field = |name: &str| error.and_then(|e| e[name].as_str()).map(str::to_string);
+
+    if field("code").as_deref() == Some("model_not_found") {
+        return ProviderError::model_not_found(model);
+    }
+
+    ProviderError {
+        kind: ErrorKind::from_status_code(status),
+        message: field("message").unwrap_or_else(|| format!("OpenAI API error: {}", status)),
+        param: field("param"),
+        code: field("code"),
+    }
+}
+
+pub fn from_anthropic_response(status: u16, body: &str, model: &str) -> ProviderError {
+    let body: Option<serde_json::Value> = serde_json::from_str(body).ok();
+    let error = body.as_ref().map(|body| &body["error"]);
+    let field = |name: &str| error.and_then(|e| e[name].as_str()).map(str::to_string);
+    let message = field("message").unwrap_or_else(|| format!("Anthropic API error: {}", status));
+
+    if status == 404 && message.contains("model") {
+        return ProviderError::model_not_found(model);
+    }
+
+    let kind = match status {
+        // Anthropic answers 529 while overloaded.
+        529 => ErrorKind::Unavailable,
+        status => ErrorKind::from_status_code(status),
+    };
+    ProviderError::new(kind, message)
+}
+
+pub fn from_gemini_response(status: u16, body: &str, model: &str) -> ProviderError {
+    let body: Option<serde_json::Value> = serde_json::from_str(body).ok();
+    let error = body.as_ref().map(|body| &body["error"]);
+    let field = |name: &str| error.and_then(|e| e[name].as_str()).map(str::to_string);
+
+    if status == 404 {
+        return ProviderError::model_not_found(model);
+    }
+
+    ProviderError::new(
+        ErrorKind::from_status_code(status),
+        field("message").unwrap_or_else(|| format!("Gemini API error: {}", status)),
+    )
+}
+
+pub fn from_reqwest_error(error: reqwest::Error) -> ProviderError {
+    let kind = if error.is_timeout() {
+        ErrorKind::Timeout
+    } else {
+        ErrorKind::Upstream
+    };
+    ProviderError::new(kind, format!("Upstream request failed: {}", error))
+}
@@ -0,0 +1,325 @@
+use crate::upstream::UpstreamClients;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use ring::hmac;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{debug, error, info, warn};
+
+pub const SIGNATURE_HEADER: &str = "x-llm-proxy-signature";
+pub const EVENTS_DROPPED_METRIC: &str = "llm_proxy_usage_events_dropped_total";
+
+/// Kinesis takes at most this many records per `PutRecords` call.
+const KINESIS_MAX_RECORDS: usize = 500;
+
+/// What one request used, sent once its stream has ended.
+#[derive(Clone, Debug, Serialize)]
+pub struct UsageEvent {
+    pub id: String,
+    pub timestamp: i64,
+    /// The caller label, as in stats: the key name or a redacted key.
+    pub key: String,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    /// In USD.
+    pub cost: f64,
+    pub latency_ms: u64,
+    /// From when the request went upstream to its first chunk.
+    pub time_to_first_token_ms: Option<u64>,
+    pub chunks: u64,
+    pub mean_inter_chunk_ms: Option<f64>,
+    pub max_inter_chunk_ms: Option<f64>,
+    /// The request's `metadata`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Clone, Debug)]
+pub enum EventDestination {
+    /// Batches are posted as `{"events": [...]}`. With a secret, each body
+    /// is signed with HMAC-SHA256, sent hex-encoded in the
+    /// `x-llm-proxy-signature` header.
+    Webhook { url: String, secret: Option<String> },
+    /// Records go to the stream with `PutRecords`, partitioned by key.
+    /// Defaults to the region of the default AWS account.
+    Kinesis {
+        stream: String,
+        region: Option<String>,
+    },
+    /// Records go to the topic through a Kafka REST Proxy (v2 API), keyed
+    /// by key.
+    KafkaRest { rest_url: String, topic: String },
+}
+
+impl EventDestination {
+    fn describe(&self) -> String {
+        match self {
+            EventDestination::Webhook { url, .. } => url.clone(),
+            EventDestination::Kinesis { stream, .. } => format!("Kinesis stream {}", stream),
+            EventDestination::KafkaRest { topic, .. } => format!("Kafka topic {}", topic),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EventSinkConfig {
+    pub destination: EventDestination,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    pub max_retries: u32,
+    /// Events waiting to be sent; more are dropped.
+    pub queue_capacity: usize,
+}
+
+impl EventSinkConfig {
+    pub fn new(destination: EventDestination) -> Self {
+        Self {
+            destination,
+            batch_size: 100,
+            flush_interval: Duration::from_secs(5),
+            max_retries: 3,
+            queue_capacity: 10_000,
+        }
+    }
+}
+
+/// Publishes usage events in batches. A batch is sent when it is full or
+/// the flush interval has passed, and what failed of it is retried with
+/// backoff before it is given up on. Recording never waits on the
+/// destination; closing the sink sends what is still queued.
+pub struct EventSink {
+    description: String,
+    events: mpsc::Sender<UsageEvent>,
+    shutdown: Mutex<Option<oneshot::Sender<()>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl EventSink {
+    pub fn new(config: EventSinkConfig, clients: &UpstreamClients) -> Self {
+        let description = config.destination.describe();
+        info!("Sending usage events to {}", description);
+        let (events, receiver) = mpsc::channel(config.queue_capacity.max(1));
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let publisher = Publisher {
+            config,
+            clients: clients.clone(),
+        };
+        let worker = tokio::spawn(publisher.run(receiver, shutdown_receiver));
+        Self {
+            description,
+            events,
+            shutdown: Mutex::new(Some(shutdown)),
+            worker: Mutex::new(Some(worker)),
+        }
+    }
+
+    pub fn record(&self, event: UsageEvent) {
+        if let Err(e) = self.events.try_send(event) {
+            warn!("Dropping usage event for {}: {}", self.description, e);
+            metrics::counter!(EVENTS_DROPPED_METRIC).increment(1);
+        }
+    }
+
+    /// Stops taking events and waits up to `timeout` for the queued ones
+    /// to be sent.
+    pub async fn close(&self, timeout: Duration) {
+        if let Some(shutdown) = self.shutdown.lock().unwrap().take() {
+            let _ = shutdown.send(());
+        }
+        let Some(worker) = self.worker.lock().unwrap().take() else {
+            return;
+        };
+        if tokio::time::timeout(timeout, worker).await.is_err() {
+            warn!(
+                "Timed out sending the remaining usage events to {}",
+                self.description
+            );
+        }
+    }
+}
+
+struct Publisher {
+    config: EventSinkConfig,
+    clients: UpstreamClients,
+}
+
+impl Publisher {
+    async fn run(
+        self,
+        mut receiver: mpsc::Receiver<UsageEvent>,
+        mut shutdown: oneshot::Receiver<()>,
+    ) {
+        let batch_size = match self.config.destination {
+            EventDestination::Kinesis { .. } => self.config.batch_size.min(KINESIS_MAX_RECORDS),
+            _ => self.config.batch_size,
+        }
+        .max(1);
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut open = true;
+        let mut closing = false;
+        while open {
+            let deadline = tokio::time::sleep(self.config.flush_interval);
+            tokio::pin!(deadline);
+            while batch.len() < batch_size {
+                tokio::select! {
+                    event = receiver.recv() => match event {
+                        Some(event) => batch.push(event),
+                        None => {
+                            open = false;
+                            break;
+                        }
+                    },
+                    _ = &mut shutdown, if !closing => {
+                        // What is queued is still received, then None.
+                        closing = true;
+                        receiver.close();
+                    }
+                    _ = &mut deadline => break,
+                }
+            }
+            if !batch.is_empty() {
+                self.send(&mut batch).await;
+            }
+        }
+        debug!(
+            "Usage events to {} flushed",
+            self.config.destination.describe()
+        );
+    }
+
+    async fn send(&self, batch: &mut Vec<UsageEvent>) {
+        let mut attempt = 0;
+        loop {
+            let error = match self.publish(batch).await {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            if attempt >= self.config.max_retries {
+                error!(
+                    "Giving up on {} usage events after {} attempts: {}",
+                    batch.len(),
+                    attempt + 1,
+                    error
+                );
+                metrics::counter!(EVENTS_DROPPED_METRIC).increment(batch.len() as u64);
+                batch.clear();
+                return;
+            }
+            attempt += 1;
+            warn!(
+                "Failed to send {} usage events ({}), retrying in {}s",
+                batch.len(),
+                error,
+                1 << attempt
+            );
+            tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+        }
+    }
+
+    /// Sends the batch, leaving in it the events that failed.
+    async fn publish(&self, batch: &mut Vec<UsageEvent>) -> anyhow::Result<()> {
+        let count = batch.len();
+        match &self.config.destination {
+            EventDestination::Webhook { url, secret } => {
+                let body = serde_json::to_vec(&json!({ "events": batch }))?;
+                let mut request = self
+                    .clients
+                    .http
+                    .post(url)
+                    .header("content-type", "application/json");
+                if let Some(secret) = secret {
+                    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+                    let signature: String = hmac::sign(&key, &body)
+                        .as_ref()
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect();
+                    request = request.header(SIGNATURE_HEADER, signature);
+                }
+                request.body(body).send().await?.error_for_status()?;
+            }
+            EventDestination::Kinesis { stream, region } => {
+                let region = match region {
+                    Some(region) => region.clone(),
+                    None => self.clients.region()?,
+                };
+                let records = batch
+                    .iter()
+                    .map(|event| {
+                        Ok(json!({
+                            "Data": STANDARD.encode(serde_json::to_vec(event)?),
+                            "PartitionKey": event.key,
+                        }))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let body = serde_json::to_vec(&json!({
+                    "StreamName": stream,
+                    "Records": records,
+                }))?;
+                let response: Value = self
+                    .clients
+                    .signed(
+                        "kinesis",
+                        &region,
+                        reqwest::Method::POST,
+                        &format!("https://kinesis.{}.amazonaws.com/", region),
+                        &[
+                            ("content-type", "application/x-amz-json-1.1"),
+                            ("x-amz-target", "Kinesis_20131202.PutRecords"),
+                        ],
+                        body,
+                    )
+                    .await?
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                // Records are answered in order; failed ones carry an ErrorCode.
+                let results = response["Records"].as_array().cloned().unwrap_or_default();
+                let mut index = 0;
+                batch.retain(|_| {
+                    let failed = results
+                        .get(index)
+                        .is_some_and(|result| result.get("ErrorCode").is_some());
+                    index += 1;
+                    failed
+                });
+                if !batch.is_empty() {
+                    anyhow::bail!("Kinesis rejected {} records", batch.len());
+                }
+            }
+            EventDestination::KafkaRest { rest_url, topic } => {
+                let records: Vec<Value> = batch
+                    .iter()
+                    .map(|event| json!({ "key": event.key, "value": event }))
+                    .collect();
+                self.clients
+                    .http
+                    .post(format!(
+                        "{}/topics/{}",
+                        rest_url.trim_end_matches('/'),
+                        topic
+                    ))
+                    .header("content-type", "application/vnd.kafka.json.v2+json")
+                    .json(&json!({ "records": records }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        debug!(
+            "Sent {} usage events to {}",
+            count,
+            self.config.destination.describe()
+        );
+        batch.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,595 @@
+use crate::{
+    StreamEvent, create_stream_event,
+    error::{ProviderError, from_gemini_response, from_reqwest_error},
+    image::{ImageLimits, preprocess_images_blocking},
+    memory::DEFAULT_MAX_STREAM_BYTES,
+    providers::ChatCompletionsProvider,
+    sse::SseParser,
+};
+use async_stream::stream;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use chrono::offset::Utc;
+use futures::{StreamExt, stream::BoxStream};
+use request::{
+    ChatCompletionsRequest, Content, Contents, InputFile, Message, Role, ToolChoice,
+    ToolChoiceMode, VideoInput, file::file_media_type,
+};
+use response::{
+    ChatCompletionsResponse, ChoiceBuilder, Delta, FunctionCall, ToolCall, Usage, UsageBuilder,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::{collections::HashMap, sync::Arc};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+pub const GEMINI_API_MODELS_URL: &str = "https://generativelanguage.googleapis.com/v1beta/models";
+
+/// Gemini rejects requests with more stop sequences; the proxy enforces the
+/// rest.
+pub const MAX_GEMINI_STOP_SEQUENCES: usize = 5;
+
+/// How requests authenticate: an API key for the Gemini API, or an OAuth
+/// access token for Vertex AI.
+#[derive(Clone)]
+pub enum GeminiAuth {
+    ApiKey(String),
+    Bearer(String),
+}
+
+pub struct GeminiChatCompletionsProvider {
+    client: reqwest::Client,
+    models_url: String,
+    auth: GeminiAuth,
+    max_buffered_bytes: usize,
+    image_limits: ImageLimits,
+}
+
+impl GeminiChatCompletionsProvider {
+    pub fn new(client: &reqwest::Client, api_key: &str) -> Self {
+        Self {
+            client: client.clone(),
+            models_url: GEMINI_API_MODELS_URL.to_string(),
+            auth: GeminiAuth::ApiKey(api_key.to_string()),
+            max_buffered_bytes: DEFAULT_MAX_STREAM_BYTES,
+            image_limits: ImageLimits::openai(),
+        }
+    }
+
+    /// Sends requests to another endpoint speaking the same API, such as a
+    /// Vertex AI publisher model URL. Model ids are appended to it.
+    pub fn models_url(mut self, models_url: impl Into<String>) -> Self {
+        self.models_url = models_url.into();
+        self
+    }
+
+    pub fn auth(mut self, auth: GeminiAuth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn max_buffered_bytes(mut self, max_buffered_bytes: usize) -> Self {
+        self.max_buffered_bytes = max_buffered_bytes;
+        self
+    }
+
+    pub fn image_limits(mut self, image_limits: ImageLimits) -> Self {
+        self.image_limits = image_limits;
+        self
+    }
+}
+
+fn text_of(contents: &Option<Contents>) -> String {
+    match contents {
+        Some(Contents::String(text)) => text.clone(),
+        Some(Contents::Array(parts)) => parts
+            .iter()
+            .filter_map(|part| match part {
+                Content::Text { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => String::new(),
+    }
+}
+
+/// Gemini only reads images sent inline; it cannot fetch URLs.
+fn image_part(url: &str) -> Result<Value, ProviderError> {
+    let (mime_type, data) = url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+        .ok_or_else(|| {
+            ProviderError::invalid_request(
+                "Gemini models only accept images as base64 data URLs",
+                Some("messages"),
+            )
+        })?;
+    Ok(json!({ "inlineData": { "mimeType": mime_type, "data": data } }))
+}
+
+fn file_part(file: &InputFile) -> Result<Value, ProviderError> {
+    let data = file
+        .file_data
+        .as_deref()
+        .and_then(|url| url.strip_prefix("data:"))
+        .and_then(|rest| rest.split_once(";base64,"))
+        .map(|(_, data)| data)
+        .ok_or_else(|| {
+            ProviderError::invalid_request(
+                "Gemini models only accept files as base64 data URLs in file_data",
+                Some("messages"),
+            )
+        })?;
+    Ok(json!({ "inlineData": { "mimeType": file_media_type(file), "data": data } }))
+}
+
+/// Gemini reads inline videos; S3 is out of its reach.
+fn video_part(video: &VideoInput) -> Result<Value, ProviderError> {
+    let (mime_type, data) = video
+        .url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+        .ok_or_else(|| {
+            ProviderError::invalid_request(
+                "Gemini models only accept videos as base64 data URLs",
+                Some("messages"),
+            )
+        })?;
+    Ok(json!({ "inlineData": { "mimeType": mime_type, "data": data } }))
+}
+
+fn parts(message: &Message) -> Result<Vec<Value>, ProviderError> {
+    let mut parts = Vec::new();
+    match &message.contents {
+        Some(Contents::String(text)) if !text.is_empty() => parts.push(json!({ "text": text })),
+        Some(Contents::Array(contents)) => {
+            for content in contents {
+                match content {
+                    Content::Text { text, .. } => parts.push(json!({ "text": text })),
+                    Content::ImageUrl { image_url, .. } => parts.push(image_part(&image_url.url)?),
+                    Content::File { file, .. } => parts.push(file_part(file)?),
+                    Content::Video { video, .. } => parts.push(video_part(video)?),
+                }
+            }
+        }
+        _ => {}
+    }
+    for tool_call in message.tool_calls.iter().flatten() {
+        let args: Value =
+            serde_json::from_str(&tool_call.function.arguments).unwrap_or_else(|_| json!({}));
+        parts.push(json!({
+            "functionCall": { "name": tool_call.function.name, "args": args },
+        }));
+    }
+    Ok(parts)
+}
+
+/// Drops the JSON Schema keywords Gemini rejects in function parameters.
+fn sanitize_schema(schema: &mut Value) {
+    match schema {
+        Value::Object(fields) => {
+            fields.remove("$schema");
+            fields.remove("additionalProperties");
+            fields.values_mut().for_each(sanitize_schema);
+        }
+        Value::Array(items) => items.iter_mut().for_each(sanitize_schema),
+        _ => {}
+    }
+}
+
+/// Translates an OpenAI request into a `generateContent` body. System
+/// messages become the system instruction, and tool results become
+/// `functionResponse` parts named after the call they answer.
+pub fn to_gemini_request(request: &ChatCompletionsRequest) -> Result<Value, ProviderError> {
+    let mut system = Vec::new();
+    let mut contents: Vec<(&'static str, Vec<Value>)> = Vec::new();
+    let mut tool_names: HashMap<&str, &str> = HashMap::new();
+    for message in &request.messages {
+        let (role, message_parts) = match message.role {
+            Role::Developer | Role::System => {
+                system.push(json!({ "text": text_of(&message.contents) }));
+                continue;
+            }
+            Role::Tool => {
+                let name = message
+                    .tool_call_id
+                    .as_deref()
+                    .and_then(|id| tool_names.get(id).copied())
+                    .unwrap_or_default();
+                let text = text_of(&message.contents);
+                let response = match serde_json::from_str::<Value>(&text) {
+                    Ok(Value::Object(fields)) => Value::Object(fields),
+                    _ => json!({ "content": text }),
+                };
+                // Function responses are JSON only, so images and files of
+                // the result follow as parts of the same turn.
+                let mut tool_parts =
+                    vec![json!({ "functionResponse": { "name": name, "response": response } })];
+                tool_parts.extend(
+                    parts(message)?
+                        .into_iter()
+                        .filter(|part| part.get("text").is_none()),
+                );
+                ("user", tool_parts)
+            }
+            Role::User => ("user", parts(message)?),
+            Role::Assistant => {
+                for tool_call in message.tool_calls.iter().flatten() {
+                    tool_names.insert(&tool_call.id, &tool_call.function.name);
+                }
+                ("model", parts(message)?)
+            }
+        };
+        if message_parts.is_empty() {
+            continue;
+        }
+        match contents.last_mut() {
+            Some((last_role, last_parts)) if *last_role == role => last_parts.extend(message_parts),
+            _ => contents.push((role, message_parts)),
+        }
+    }
+
+    let mut body = json!({
+        "contents": contents
+            .into_iter()
+            .map(|(role, parts)| json!({ "role": role, "parts": parts }))
+            .collect::<Vec<_>>(),
+    });
+    if !system.is_empty() {
+        body["systemInstruction"] = json!({ "parts": system });
+    }
+
+    let mut generation_config = Map::new();
+    if let Some(temperature) = request.temperature {
+        generation_config.insert("temperature".to_string(), json!(temperature));
+    }
+    if let Some(top_p) = request.top_p {
+        generation_config.insert("topP".to_string(), json!(top_p));
+    }
+    if let Some(max_tokens) = request.max_tokens {
+        generation_config.insert("maxOutputTokens".to_string(), json!(max_tokens));
+    }
+    if let Some(stop) = request.stop.as_ref().filter(|stop| !stop.is_empty()) {
+        let stop = &stop[..stop.len().min(MAX_GEMINI_STOP_SEQUENCES)];
+        generation_config.insert("stopSequences".to_string(), json!(stop));
+    }
+    if let Some(n) = request.n.filter(|n| *n > 1) {
+        generation_config.insert("candidateCount".to_string(), json!(n));
+    }
+    if !generation_config.is_empty() {
+        body["generationConfig"] = Value::Object(generation_config);
+    }
+
+    if let Some(tools) = request.tools.as_ref().filter(|tools| !tools.is_empty()) {
+        let declarations: Vec<Value> = tools
+            .iter()
+            .map(|tool| {
+                let mut declaration = json!({ "name": tool.function.name });
+                if let Some(description) = &tool.function.description {
+                    declaration["description"] = json!(description);
+                }
+                if let Some(parameters) = &tool.function.parameters {
+                    let mut parameters = parameters.clone();
+                    sanitize_schema(&mut parameters);
+                    declaration["parameters"] = parameters;
+                }
+                declaration
+            })
+            .collect();
+        body["tools"] = json!([{ "functionDeclarations": declarations }]);
+    }
+    if let Some(tool_choice) = &request.tool_choice {
+        let config = match tool_choice {
+            ToolChoice::Mode(ToolChoiceMode::Auto) => json!({ "mode": "AUTO" }),
+            ToolChoice::Mode(ToolChoiceMode::None) => json!({ "mode": "NONE" }),
+            ToolChoice::Mode(ToolChoiceMode::Required) => json!({ "mode": "ANY" }),
+            ToolChoice::Named(named) => json!({
+                "mode": "ANY",
+                "allowedFunctionNames": [named.function.name],
+            }),
+        };
+        body["toolConfig"] = json!({ "functionCallingConfig": config });
+    }
+    Ok(body)
+}
+
+fn finish_reason(reason: &str, called_tools: bool) -> &'static str {
+    match reason {
+        "STOP" if called_tools => "tool_calls",
+        "STOP" => "stop",
+        "MAX_TOKENS" => "length",
+        "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" | "IMAGE_SAFETY" => {
+            "content_filter"
+        }
+        other => {
+            warn!("Unknown Gemini finish reason: {}", other);
+            "stop"
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateContentResponse {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    usage_metadata: Option<UsageMetadata>,
+    model_version: Option<String>,
+    response_id: Option<String>,
+    prompt_feedback: Option<PromptFeedback>,
+}
+
+/// Set instead of candidates when the prompt itself was blocked.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    block_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    content: Option<CandidateContent>,
+    finish_reason: Option<String>,
+    #[serde(default)]
+    index: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct CandidateContent {
+    #[serde(default)]
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Part {
+    text: Option<String>,
+    /// Thought summaries are not part of the answer.
+    #[serde(default)]
+    thought: bool,
+    function_call: Option<GeminiFunctionCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiFunctionCall {
+    id: Option<String>,
+    name: String,
+    #[serde(default)]
+    args: Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: i32,
+    #[serde(default)]
+    candidates_token_count: i32,
+    #[serde(default)]
+    thoughts_token_count: i32,
+    /// The part of the prompt Gemini read from its cache.
+    #[serde(default)]
+    cached_content_token_count: i32,
+}
+
+#[async_trait]
+impl ChatCompletionsProvider for GeminiChatCompletionsProvider {
+    async fn chat_completions_stream<F>(
+        self,
+        request: ChatCompletionsRequest,
+        usage_callback: F,
+    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<StreamEvent>>>
+    where
+        F: Fn(&Usage) + Send + Sync + 'static,
+    {
+        debug!(
+            "Starting Gemini chat completion request with model: {}",
+            request.model
+        );
+
+        let request = preprocess_images_blocking(request, &self.image_limits).await?;
+        let body = to_gemini_request(&request)?;
+        let url = format!(
+            "{}/{}:streamGenerateContent?alt=sse",
+            self.models_url.trim_end_matches('/'),
+            request.model
+        );
+        let builder = match &self.auth {
+            GeminiAuth::ApiKey(api_key) => self.client.post(url).header("x-goog-api-key", api_key),
+            GeminiAuth::Bearer(token) => self.client.post(url).bearer_auth(token),
+        };
+        let response = builder
+            .json(&body)
+            .send()
+            .await
+            .map_err(from_reqwest_error)?;
+
+        let status = response.status();
+        debug!("Gemini API response status: {}", status);
+        if !status.is_success() {
+            let error_text = response.text().await.map_err(from_reqwest_error)?;
+            error!("Gemini API error: {} - {}", status, error_text);
+            return Err(from_gemini_response(status.as_u16(), &error_text, &request.model).into());
+        }
+
+        info!("Successfully connected to Gemini API, starting stream processing");
+
+        let max_buffered_bytes = self.max_buffered_bytes;
+        let created = Utc::now().timestamp();
+        let stream = stream! {
+            let mut body = response.bytes_stream();
+            let mut parser = SseParser::new();
+            let mut buffer = BytesMut::new();
+            let mut id: Option<Arc<str>> = Some(Uuid::new_v4().to_string().into());
+            let mut model = Some(request.model.clone());
+            let mut usage = UsageMetadata::default();
+            let mut called_tools: HashMap<i32, i32> = HashMap::new();
+            let mut started = false;
+
+            let mut finished = false;
+            while !finished {
+                let sse_events = match body.next().await {
+                    Some(Ok(chunk)) => parser.feed(&chunk),
+                    Some(Err(e)) => {
+                        error!("Error receiving from Gemini stream: {}", e);
+                        yield Err(anyhow::anyhow!("Stream receive error: {}", e));
+                        break;
+                    }
+                    // The last event may end without a blank line.
+                    None => {
+                        finished = true;
+                        parser.finish().into_iter().collect()
+                    }
+                };
+                if parser.buffered_len() > max_buffered_bytes {
+                    error!("Gemini stream event exceeded {} bytes", max_buffered_bytes);
+                    yield Err(anyhow::anyhow!(
+                        "Upstream event exceeded memory limit of {} bytes",
+                        max_buffered_bytes
+                    ));
+                    break;
+                }
+
+                for sse_event in sse_events {
+                    let output = match serde_json::from_str::<GenerateContentResponse>(&sse_event.data) {
+                        Ok(output) => output,
+                        Err(e) => {
+                            error!("Failed to parse Gemini response: {}", e);
+                            yield Err(anyhow::anyhow!("Failed to parse response: {}", e));
+                            continue;
+                        }
+                    };
+                    if let Some(response_id) = output.response_id {
+                        id = Some(response_id.into());
+                    }
+                    model = output.model_version.or(model);
+                    if let Some(metadata) = output.usage_metadata {
+                        usage = metadata;
+                    }
+
+                    let mut response = ChatCompletionsResponse::builder()
+                        .id(id.clone())
+                        .created(Some(created))
+                        .model(model.clone())
+                        .object(Some("chat.completion.chunk".to_string()));
+                    if !started {
+                        started = true;
+                        response = response.choice(
+                            ChoiceBuilder::default()
+                                .delta(Some(Delta::Role { role: "assistant".to_string() }))
+                                .build(),
+                        );
+                    }
+
+                    if let Some(reason) = output
+                        .prompt_feedback
+                        .and_then(|feedback| feedback.block_reason)
+                    {
+                        warn!("Gemini blocked the prompt: {}", reason);
+                        response = response.choice(
+                            ChoiceBuilder::default()
+                                .finish_reason(Some("content_filter".to_string()))
+                                .build(),
+                        );
+                    }
+
+                    for candidate in output.candidates {
+                        let mut text = String::new();
+                        let mut tool_calls = Vec::new();
+                        for part in candidate.content.map(|content| content.parts).unwrap_or_default() {
+                            if let Some(function_call) = part.function_call {
+                                let sent = called_tools.entry(candidate.index).or_default();
+                                *sent += 1;
+                                tool_calls.push(ToolCall {
+                                    index: Some(*sent - 1),
+                                    id: function_call
+                                        .id
+                                        .unwrap_or_else(|| format!("call_{}", Uuid::new_v4().simple())),
+                                    r#type: "function".to_string(),
+                                    function: FunctionCall {
+                                        name: function_call.name,
+                                        arguments: function_call.args.to_string(),
+                                    },
+                                });
+                            } else if let Some(part_text) = part.text.filter(|_| !part.thought) {
+                                text.push_str(&part_text);
+                            }
+                        }
+                        let finish = candidate.finish_reason.as_deref().map(|reason| {
+                            finish_reason(reason, called_tools.contains_key(&candidate.index))
+                                .to_string()
+                        });
+
+                        if !text.is_empty() {
+                            response = response.choice(
+                                ChoiceBuilder::default()
+                                    .index(candidate.index)
+                                    .delta(Some(Delta::Content { content: text }))
+                                    .build(),
+                            );
+                        }
+                        if !tool_calls.is_empty() {
+                            response = response.choice(
+                                ChoiceBuilder::default()
+                                    .index(candidate.index)
+                                    .delta(Some(Delta::ToolCalls { tool_calls }))
+                                    .build(),
+                            );
+                        }
+                        if finish.is_some() {
+                            response = response.choice(
+                                ChoiceBuilder::default()
+                                    .index(candidate.index)
+                                    .finish_reason(finish)
+                                    .build(),
+                            );
+                        }
+                    }
+
+                    let response = response.build();
+                    if response.choices.is_empty() {
+                        continue;
+                    }
+                    match create_stream_event(&response, &mut buffer) {
+                        Ok(event) => yield Ok(event),
+                        Err(e) => {
+                            error!("Failed to create stream event: {}", e);
+                            yield Err(e);
+                        }
+                    }
+                }
+            }
+
+            let completion_tokens = usage.candidates_token_count + usage.thoughts_token_count;
+            let usage = UsageBuilder::default()
+                .prompt_tokens(usage.prompt_token_count)
+                .completion_tokens(completion_tokens)
+                .total_tokens(usage.prompt_token_count + completion_tokens)
+                .cache_read_tokens(usage.cached_content_token_count)
+                .build();
+            usage_callback(&usage);
+            let response = ChatCompletionsResponse::builder()
+                .id(id)
+                .created(Some(created))
+                .model(model)
+                .object(Some("chat.completion.chunk".to_string()))
+                .usage(Some(usage))
+                .build();
+            match create_stream_event(&response, &mut buffer) {
+                Ok(event) => yield Ok(event),
+                Err(e) => {
+                    error!("Failed to create stream event: {}", e);
+                    yield Err(e);
+                }
+            }
+
+            info!("Gemini stream completed, sending DONE message");
+            yield Ok(StreamEvent::Done);
+        };
+
+        Ok(stream.boxed())
+    }
+}
@@ -0,0 +1,152 @@
+use aws_sdk_bedrockruntime::types::{
+    GuardrailAssessment, GuardrailConfiguration, GuardrailStreamConfiguration, GuardrailTrace,
+    GuardrailTraceAssessment,
+};
+use request::Guardrail;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+/// A guardrail for the Bedrock models matching any of the prefixes.
+#[derive(Clone, Debug, Deserialize)]
+pub struct GuardrailRule {
+    pub models: Vec<String>,
+    #[serde(flatten)]
+    pub guardrail: Guardrail,
+}
+
+/// The guardrails the config sets per model. A request may name its own
+/// guardrail only for models without one, so clients can't lift a
+/// guardrail by naming another.
+#[derive(Clone, Debug, Default)]
+pub struct Guardrails {
+    pub rules: Vec<GuardrailRule>,
+}
+
+impl Guardrails {
+    /// The first rule matching the model wins.
+    pub fn for_model(&self, model: &str) -> Option<&Guardrail> {
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.models
+                    .iter()
+                    .any(|prefix| model.starts_with(prefix.as_str()))
+            })
+            .map(|rule| &rule.guardrail)
+    }
+}
+
+fn trace(guardrail: &Guardrail) -> GuardrailTrace {
+    if guardrail.trace {
+        GuardrailTrace::Enabled
+    } else {
+        GuardrailTrace::Disabled
+    }
+}
+
+pub fn guardrail_config(guardrail: &Guardrail) -> Option<GuardrailConfiguration> {
+    GuardrailConfiguration::builder()
+        .guardrail_identifier(&guardrail.id)
+        .guardrail_version(&guardrail.version)
+        .trace(trace(guardrail))
+        .build()
+        .ok()
+}
+
+pub fn guardrail_stream_config(guardrail: &Guardrail) -> Option<GuardrailStreamConfiguration> {
+    GuardrailStreamConfiguration::builder()
+        .guardrail_identifier(&guardrail.id)
+        .guardrail_version(&guardrail.version)
+        .trace(trace(guardrail))
+        .build()
+        .ok()
+}
+
+fn assessment_json(assessment: &GuardrailAssessment) -> Value {
+    let mut policies = json!({});
+    if let Some(policy) = &assessment.topic_policy {
+        policies["topics"] = policy
+            .topics
+            .iter()
+            .map(|topic| {
+                json!({
+                    "name": topic.name,
+                    "type": topic.r#type.as_str(),
+                    "action": topic.action.as_str(),
+                })
+            })
+            .collect();
+    }
+    if let Some(policy) = &assessment.content_policy {
+        policies["content_filters"] = policy
+            .filters
+            .iter()
+            .map(|filter| {
+                json!({
+                    "type": filter.r#type.as_str(),
+                    "confidence": filter.confidence.as_str(),
+                    "action": filter.action.as_str(),
+                })
+            })
+            .collect();
+    }
+    if let Some(policy) = &assessment.word_policy {
+        let custom = policy
+            .custom_words
+            .iter()
+            .map(|word| json!({ "match": word.r#match, "action": word.action.as_str() }));
+        let managed = policy.managed_word_lists.iter().map(|word| {
+            json!({
+                "match": word.r#match,
+                "type": word.r#type.as_str(),
+                "action": word.action.as_str(),
+            })
+        });
+        policies["words"] = custom.chain(managed).collect();
+    }
+    if let Some(policy) = &assessment.sensitive_information_policy {
+        let entities = policy.pii_entities.iter().map(|entity| {
+            json!({
+                "match": entity.r#match,
+                "type": entity.r#type.as_str(),
+                "action": entity.action.as_str(),
+            })
+        });
+        let regexes = policy.regexes.iter().map(|regex| {
+            json!({
+                "match": regex.r#match,
+                "name": regex.name,
+                "action": regex.action.as_str(),
+            })
+        });
+        policies["sensitive_information"] = entities.chain(regexes).collect();
+    }
+    policies
+}
+
+/// The policies a guardrail assessed the input and output with, and what
+/// they did. Trace fields the proxy does not map are left out.
+pub fn guardrail_trace_json(trace: &GuardrailTraceAssessment) -> Value {
+    let input: serde_json::Map<String, Value> = trace
+        .input_assessment
+        .iter()
+        .flatten()
+        .map(|(guardrail, assessment)| (guardrail.clone(), assessment_json(assessment)))
+        .collect();
+    let output: serde_json::Map<String, Value> = trace
+        .output_assessments
+        .iter()
+        .flatten()
+        .map(|(guardrail, assessments)| {
+            (
+                guardrail.clone(),
+                assessments.iter().map(assessment_json).collect(),
+            )
+        })
+        .collect();
+    json!({
+        "input": input,
+        "output": output,
+        "action_reason": trace.action_reason,
+    })
+}
@@ -0,0 +1,567 @@
+use crate::error::{ErrorKind, ProviderError};
+use futures::StreamExt;
+use image::{
+    DynamicImage, ImageFormat, ImageReader, codecs::jpeg::JpegEncoder, imageops::FilterType,
+};
+use request::{
+    ChatCompletionsRequest, Content, Contents,
+    image::{decode_data_url, encode_data_url},
+};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::{
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::debug;
+
+const JPEG_QUALITY: u8 = 85;
+const MAX_SHRINK_ATTEMPTS: usize = 4;
+const MAX_REDIRECTS: usize = 5;
+
+/// What a backend accepts for inline images. Images outside these limits
+/// are transcoded and downscaled before they are sent. Only the formats the
+/// proxy can decode (PNG, JPEG, GIF and WebP) are transcoded; HEIC, HEIF
+/// and AVIF images are refused with `unsupported_image_format`.
+#[derive(Clone, Debug)]
+pub struct ImageLimits {
+    pub max_bytes: usize,
+    pub max_dimension: u32,
+    pub formats: Vec<ImageFormat>,
+}
+
+impl ImageLimits {
+    pub fn bedrock() -> Self {
+        Self {
+            max_bytes: 3_750_000,
+            max_dimension: 8000,
+            formats: vec![
+                ImageFormat::Png,
+                ImageFormat::Jpeg,
+                ImageFormat::Gif,
+                ImageFormat::WebP,
+            ],
+        }
+    }
+
+    pub fn openai() -> Self {
+        Self {
+            max_bytes: 20 * 1024 * 1024,
+            max_dimension: 2048,
+            formats: vec![
+                ImageFormat::Png,
+                ImageFormat::Jpeg,
+                ImageFormat::Gif,
+                ImageFormat::WebP,
+            ],
+        }
+    }
+}
+
+/// How remote image URLs are fetched for backends that only take inline
+/// images, like Bedrock.
+#[derive(Clone, Debug)]
+pub struct ImageFetchConfig {
+    /// Hosts images may be fetched from, with their subdomains. Empty
+    /// allows any host.
+    pub allowlist: Vec<String>,
+    /// Fetch from loopback, private and link-local addresses too, which
+    /// are refused by default so that callers can't reach the proxy's
+    /// network.
+    pub allow_private: bool,
+    /// Larger images are refused before they are transcoded.
+    pub max_bytes: usize,
+    pub timeout: Duration,
+}
+
+impl Default for ImageFetchConfig {
+    fn default() -> Self {
+        Self {
+            allowlist: Vec::new(),
+            allow_private: false,
+            max_bytes: 20 * 1024 * 1024,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The IPv4 address an IPv6 address carries: mapped (`::ffff:0:0/96`),
+/// IPv4-compatible (`::/96`), NAT64 (`64:ff9b::/96`) or 6to4 (`2002::/16`).
+fn embedded_ipv4(addr: Ipv6Addr) -> Option<Ipv4Addr> {
+    let ipv4 = |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    match addr.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(ipv4(high, low)),
+        [0x2002, high, low, ..] => Some(ipv4(high, low)),
+        _ => addr.to_ipv4(),
+    }
+}
+
+fn is_private(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, _] = addr.octets();
+            addr.is_loopback()
+                || addr.is_private()
+                || addr.is_link_local()
+                || addr.is_broadcast()
+                // This network, 0.0.0.0/8.
+                || a == 0
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (a == 100 && b & 0xc0 == 64)
+                // IETF protocol assignments, 192.0.0.0/24.
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking, 198.18.0.0/15.
+                || (a == 198 && b & 0xfe == 18)
+        }
+        IpAddr::V6(addr) => {
+            addr.is_loopback()
+                || addr.is_unspecified()
+                || addr.is_unique_local()
+                || addr.is_unicast_link_local()
+                || embedded_ipv4(addr).is_some_and(|addr| is_private(IpAddr::V4(addr)))
+        }
+    }
+}
+
+/// Resolves names to their public addresses only. Filtering the addresses
+/// the connection is made to, rather than checking a lookup of its own
+/// beforehand, leaves no window for the name to be rebound to a private
+/// address in between.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0)).await?.collect();
+            let public: Vec<SocketAddr> = addrs
+                .iter()
+                .copied()
+                .filter(|addr| !is_private(addr.ip()))
+                .collect();
+            if public.is_empty() {
+                let message = match addrs.first() {
+                    Some(addr) => format!(
+                        "fetching images from private address {} is not allowed",
+                        addr.ip()
+                    ),
+                    None => format!("failed to resolve {}", host),
+                };
+                return Err(message.into());
+            }
+            let addrs: Addrs = Box::new(public.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Downloads the remote images of requests and inlines them as data URLs.
+/// Redirects are followed here rather than by the client, so that every
+/// hop is checked.
+#[derive(Clone)]
+pub struct ImageFetcher {
+    client: reqwest::Client,
+    config: ImageFetchConfig,
+}
+
+impl ImageFetcher {
+    pub fn new(config: ImageFetchConfig) -> anyhow::Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(config.timeout);
+        if !config.allow_private {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+        let client = builder.build()?;
+        Ok(Self { client, config })
+    }
+
+    fn check(&self, url: &reqwest::Url) -> Result<(), String> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("only http, https and data image URLs are supported".to_string());
+        }
+        let Some(host) = url.host_str() else {
+            return Err("image URL has no host".to_string());
+        };
+        let allowed = self.config.allowlist.is_empty()
+            || self.config.allowlist.iter().any(|allowed| {
+                host == allowed
+                    || host
+                        .strip_suffix(allowed.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('.'))
+            });
+        if !allowed {
+            return Err(format!("fetching images from {} is not allowed", host));
+        }
+        // Names are resolved by `PublicResolver` as the connection is made,
+        // so only literal addresses are checked here.
+        if let Ok(addr) = host.trim_matches(['[', ']']).parse::<IpAddr>()
+            && !self.config.allow_private
+            && is_private(addr)
+        {
+            return Err(format!(
+                "fetching images from private address {} is not allowed",
+                addr
+            ));
+        }
+        Ok(())
+    }
+
+    /// The image at a URL, as a data URL.
+    async fn fetch(&self, url: &str) -> Result<String, String> {
+        let mut url: reqwest::Url = url
+            .parse()
+            .map_err(|e| format!("invalid image URL: {}", e))?;
+        let mut redirects = 0;
+        let response = loop {
+            self.check(&url)?;
+            let response = self.client.get(url.clone()).send().await.map_err(|e| {
+                // A refused address is only named by the innermost error.
+                let mut cause: &dyn std::error::Error = &e;
+                while let Some(source) = cause.source() {
+                    cause = source;
+                }
+                format!("failed to fetch image: {}", cause)
+            })?;
+            if !response.status().is_redirection() {
+                break response;
+            }
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| "image URL redirected without a location".to_string())?;
+            url = url
+                .join(location)
+                .map_err(|e| format!("invalid image redirect: {}", e))?;
+            redirects += 1;
+            if redirects > MAX_REDIRECTS {
+                return Err("image URL redirected too many times".to_string());
+            }
+        };
+        if !response.status().is_success() {
+            return Err(format!(
+                "fetching image failed with status {}",
+                response.status()
+            ));
+        }
+        let too_large = || format!("image is larger than {} bytes", self.config.max_bytes);
+        if response
+            .content_length()
+            .is_some_and(|length| length > self.config.max_bytes as u64)
+        {
+            return Err(too_large());
+        }
+        // The media type is checked against the bytes when the image is
+        // preprocessed, so the header is only a first guess.
+        let media_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|media_type| media_type.trim().to_ascii_lowercase())
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+        let mut bytes = Vec::new();
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| format!("failed to fetch image: {}", e))?;
+            if bytes.len() + chunk.len() > self.config.max_bytes {
+                return Err(too_large());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        debug!("Fetched {} byte image from {}", bytes.len(), url);
+        Ok(encode_data_url(&media_type, &bytes))
+    }
+
+    /// Replaces the request's remote image URLs with the images they point
+    /// to, fetched concurrently.
+    pub async fn inline_images(
+        &self,
+        request: &mut ChatCompletionsRequest,
+    ) -> Result<(), ProviderError> {
+        let mut urls = Vec::new();
+        for (i, message) in request.messages.iter_mut().enumerate() {
+            let Some(Contents::Array(parts)) = &mut message.contents else {
+                continue;
+            };
+            for (j, part) in parts.iter_mut().enumerate() {
+                if let Content::ImageUrl { image_url, .. } = part
+                    && !image_url.url.starts_with("data:")
+                {
+                    urls.push((i, j, &mut image_url.url));
+                }
+            }
+        }
+        let fetched =
+            futures::future::join_all(urls.iter().map(|(_, _, url)| self.fetch(url.as_str())))
+                .await;
+        for ((i, j, url), result) in urls.into_iter().zip(fetched) {
+            match result {
+                Ok(data_url) => *url = data_url,
+                Err(e) => {
+                    let param = format!("messages[{}].content[{}]", i, j);
+                    return Err(ProviderError::invalid_request(
+                        format!("{}: {}", param, e),
+                        Some(&param),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn has_images(request: &ChatCompletionsRequest) -> bool {
+    request.messages.iter().any(|message| {
+        matches!(&message.contents, Some(Contents::Array(parts))
+            if parts.iter().any(|part| matches!(part, Content::ImageUrl { .. })))
+    })
+}
+
+pub fn has_videos(request: &ChatCompletionsRequest) -> bool {
+    request.messages.iter().any(|message| {
+        matches!(&message.contents, Some(Contents::Array(parts))
+            if parts.iter().any(|part| matches!(part, Content::Video { .. })))
+    })
+}
+
+/// Media types of HEIF containers, which the proxy has no decoder for.
+const HEIF_MEDIA_TYPES: [&str; 4] = [
+    "image/heic",
+    "image/heif",
+    "image/heic-sequence",
+    "image/avif",
+];
+
+/// Why an inline image can't be sent.
+enum ImageError {
+    /// The image is in a format the proxy can't decode.
+    Unsupported(&'static str),
+    Invalid(String),
+}
+
+impl From<String> for ImageError {
+    fn from(message: String) -> Self {
+        Self::Invalid(message)
+    }
+}
+
+/// HEIF containers start with an `ftyp` box naming a HEIF brand.
+fn is_heif(bytes: &[u8]) -> bool {
+    bytes.len() >= 12
+        && &bytes[4..8] == b"ftyp"
+        && matches!(
+            &bytes[8..12],
+            b"heic" | b"heix" | b"hevc" | b"heim" | b"heis" | b"mif1" | b"msf1" | b"avif"
+        )
+}
+
+fn encode(image: &DynamicImage) -> Result<(&'static str, Vec<u8>), String> {
+    let mut output = Cursor::new(Vec::new());
+    if image.color().has_alpha() {
+        image
+            .write_to(&mut output, ImageFormat::Png)
+            .map_err(|e| e.to_string())?;
+        Ok(("image/png", output.into_inner()))
+    } else {
+        JpegEncoder::new_with_quality(&mut output, JPEG_QUALITY)
+            .encode_image(&image.to_rgb8())
+            .map_err(|e| e.to_string())?;
+        Ok(("image/jpeg", output.into_inner()))
+    }
+}
+
+/// Brings one data URL within the limits. Returns `None` when the image
+/// can be sent as it is.
+fn process(url: &str, limits: &ImageLimits) -> Result<Option<String>, ImageError> {
+    let (media_type, bytes) = decode_data_url(url).map_err(|e| e.to_string())?;
+
+    // Checked ahead of the guess, which knows AVIF without decoding it.
+    if is_heif(&bytes) || HEIF_MEDIA_TYPES.contains(&media_type.to_ascii_lowercase().as_str()) {
+        return Err(ImageError::Unsupported(
+            "HEIC, HEIF and AVIF images are not supported; convert the image to JPEG or PNG",
+        ));
+    }
+    let format = image::guess_format(&bytes)
+        .map_err(|_| "image data is not a recognized image format".to_string())?;
+    let (width, height) = ImageReader::with_format(Cursor::new(&bytes), format)
+        .into_dimensions()
+        .map_err(|e| format!("invalid image: {}", e))?;
+
+    let actual_media_type = format.to_mime_type();
+    if limits.formats.contains(&format)
+        && width.max(height) <= limits.max_dimension
+        && bytes.len() <= limits.max_bytes
+    {
+        if media_type == actual_media_type {
+            return Ok(None);
+        }
+        debug!(
+            "Correcting image media type from {} to {}",
+            media_type, actual_media_type
+        );
+        return Ok(Some(encode_data_url(actual_media_type, &bytes)));
+    }
+
+    let mut image = image::load_from_memory_with_format(&bytes, format)
+        .map_err(|e| format!("invalid image: {}", e))?;
+    let mut max_dimension = limits.max_dimension.min(width.max(height));
+
+    for _ in 0..MAX_SHRINK_ATTEMPTS {
+        if image.width().max(image.height()) > max_dimension {
+            image = image.resize(max_dimension, max_dimension, FilterType::Lanczos3);
+        }
+        let (media_type, output) = encode(&image)?;
+        if output.len() <= limits.max_bytes {
+            debug!(
+                "Transcoded {}x{} {} image ({} bytes) to {}x{} {} ({} bytes)",
+                width,
+                height,
+                actual_media_type,
+                bytes.len(),
+                image.width(),
+                image.height(),
+                media_type,
+                output.len()
+            );
+            return Ok(Some(encode_data_url(media_type, &output)));
+        }
+        max_dimension = max_dimension * 3 / 4;
+    }
+
+    Err(format!(
+        "image could not be reduced below {} bytes",
+        limits.max_bytes
+    )
+    .into())
+}
+
+/// Validates the inline images of a request and transcodes or downscales
+/// those the backend would reject or bill at full resolution. Remote image
+/// URLs are left alone. This decodes images, so it belongs on a blocking
+/// thread.
+pub fn preprocess_images(
+    request: &mut ChatCompletionsRequest,
+    limits: &ImageLimits,
+) -> Result<(), ProviderError> {
+    for (i, message) in request.messages.iter_mut().enumerate() {
+        let Some(Contents::Array(parts)) = &mut message.contents else {
+            continue;
+        };
+        for (j, part) in parts.iter_mut().enumerate() {
+            let Content::ImageUrl { image_url, .. } = part else {
+                continue;
+            };
+            if !image_url.url.starts_with("data:") {
+                continue;
+            }
+
+            match process(&image_url.url, limits) {
+                Ok(Some(url)) => image_url.url = url,
+                Ok(None) => {}
+                Err(ImageError::Unsupported(e)) => {
+                    let param = format!("messages[{}].content[{}]", i, j);
+                    return Err(ProviderError::invalid_request(
+                        format!("{}: {}", param, e),
+                        Some(&param),
+                    )
+                    .code("unsupported_image_format"));
+                }
+                Err(ImageError::Invalid(e)) => {
+                    let param = format!("messages[{}].content[{}]", i, j);
+                    return Err(ProviderError::invalid_request(
+                        format!("{}: {}", param, e),
+                        Some(&param),
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs [`preprocess_images`] on a blocking thread when the request has
+/// images at all.
+pub async fn preprocess_images_blocking(
+    request: ChatCompletionsRequest,
+    limits: &ImageLimits,
+) -> Result<ChatCompletionsRequest, ProviderError> {
+    if !has_images(&request) {
+        return Ok(request);
+    }
+
+    let limits = limits.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut request = request;
+        preprocess_images(&mut request, &limits).map(|()| request)
+    })
+    .await
+    .map_err(|e| {
+        ProviderError::new(
+            ErrorKind::Internal,
+            format!("Image preprocessing failed: {}", e),
+        )
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn private(addr: &str) -> bool {
+        is_private(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn refuses_reserved_ipv4_ranges() {
+        for addr in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "255.255.255.255",
+        ] {
+            assert!(private(addr), "{}", addr);
+        }
+        for addr in ["8.8.8.8", "100.128.0.1", "192.0.1.1", "198.20.0.1"] {
+            assert!(!private(addr), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn refuses_ipv4_embedded_in_ipv6() {
+        for addr in [
+            "::1",
+            "::",
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::",
+            "2002:a00:1::1",
+            "2002:a9fe:a9fe::",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(private(addr), "{}", addr);
+        }
+        for addr in [
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+            "64:ff9b::8.8.8.8",
+            "2002:808:808::",
+        ] {
+            assert!(!private(addr), "{}", addr);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use crate::{
+    StreamEvent,
+    bedrock::check_logit_bias,
+    create_stream_event,
+    error::{ProviderError, from_bedrock_error},
+    providers::ChatCompletionsProvider,
+};
+use async_stream::stream;
+use async_trait::async_trait;
+use aws_sdk_bedrockruntime::{Client, primitives::Blob, types::ResponseStream};
+use bytes::BytesMut;
+use chrono::offset::Utc;
+use futures::{StreamExt, stream::BoxStream};
+use request::{ChatCompletionsRequest, Role};
+use response::{ChatCompletionsResponse, ChoiceBuilder, Delta, Usage, UsageBuilder};
+use serde_json::{Value, json};
+use std::sync::Arc;
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+/// Bedrock text models that Converse does not serve, called through
+/// `InvokeModel` with their native request bodies instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InvokeFamily {
+    TitanText,
+    CohereCommand,
+    Jurassic,
+}
+
+impl InvokeFamily {
+    /// Also matches inference profiles, which prefix the id with a region.
+    fn of(model: &str) -> Option<Self> {
+        let model = model.to_lowercase();
+        if model.contains("amazon.titan-text") {
+            Some(Self::TitanText)
+        } else if model.contains("cohere.command-text")
+            || model.contains("cohere.command-light-text")
+        {
+            Some(Self::CohereCommand)
+        } else if model.contains("ai21.j2") {
+            Some(Self::Jurassic)
+        } else {
+            None
+        }
+    }
+
+    fn body(self, prompt: &str, request: &ChatCompletionsRequest) -> Value {
+        let stop = request.stop.clone().unwrap_or_default();
+        let mut body = match self {
+            Self::TitanText => json!({
+                "inputText": prompt,
+                "textGenerationConfig": { "stopSequences": stop },
+            }),
+            Self::CohereCommand => json!({ "prompt": prompt, "stop_sequences": stop }),
+            Self::Jurassic => json!({ "prompt": prompt, "stopSequences": stop }),
+        };
+        let config = match self {
+            Self::TitanText => &mut body["textGenerationConfig"],
+            Self::CohereCommand | Self::Jurassic => &mut body,
+        };
+        let (max_tokens, top_p) = match self {
+            Self::TitanText => ("maxTokenCount", "topP"),
+            Self::CohereCommand => ("max_tokens", "p"),
+            Self::Jurassic => ("maxTokens", "topP"),
+        };
+        if let Some(value) = request.max_tokens {
+            config[max_tokens] = json!(value);
+        }
+        if let Some(value) = request.temperature {
+            config["temperature"] = json!(value);
+        }
+        if let Some(value) = request.top_p {
+            config[top_p] = json!(value);
+        }
+        if self == Self::CohereCommand {
+            body["stream"] = json!(true);
+        }
+        body
+    }
+
+    /// The text and finish reason of one streamed chunk.
+    fn parse_chunk(self, chunk: &Value) -> (Option<String>, Option<&'static str>) {
+        match self {
+            Self::TitanText => (
+                chunk["outputText"].as_str().map(str::to_string),
+                chunk["completionReason"].as_str().map(finish_reason),
+            ),
+            Self::CohereCommand => {
+                let generation = chunk
+                    .get("generations")
+                    .and_then(|generations| generations.get(0))
+                    .unwrap_or(chunk);
+                (
+                    generation["text"].as_str().map(str::to_string),
+                    generation["finish_reason"].as_str().map(finish_reason),
+                )
+            }
+            Self::Jurassic => {
+                let completion = &chunk["completions"][0];
+                (
+                    completion["data"]["text"].as_str().map(str::to_string),
+                    completion["finishReason"]["reason"]
+                        .as_str()
+                        .map(finish_reason),
+                )
+            }
+        }
+    }
+}
+
+pub fn is_invoke_model(model: &str) -> bool {
+    InvokeFamily::of(model).is_some()
+}
+
+fn finish_reason(reason: &str) -> &'static str {
+    match reason.to_lowercase().as_str() {
+        "length" | "max_tokens" => "length",
+        "content_filtered" | "error_toxic" => "content_filter",
+        _ => "stop",
+    }
+}
+
+/// These models take one prompt, so the conversation is rendered as a
+/// transcript ending with the assistant's turn.
+fn render_prompt(request: &ChatCompletionsRequest) -> String {
+    let mut prompt = String::new();
+    for message in &request.messages {
+        let text = message
+            .contents
+            .as_ref()
+            .map(|contents| contents.to_text())
+            .unwrap_or_default();
+        let speaker = match message.role {
+            Role::Developer | Role::System => {
+                prompt.push_str(&text);
+                prompt.push_str("\n\n");
+                continue;
+            }
+            Role::Assistant => "Bot",
+            Role::Tool | Role::User => "User",
+        };
+        prompt.push_str(&format!("{}: {}\n", speaker, text));
+    }
+    prompt.push_str("Bot:");
+    prompt
+}
+
+/// Bedrock adds the token counts to the last chunk of every model.
+fn invocation_usage(chunk: &Value) -> Option<(i32, i32)> {
+    let metrics = chunk.get("amazon-bedrock-invocationMetrics")?;
+    Some((
+        metrics["inputTokenCount"].as_i64()? as i32,
+        metrics["outputTokenCount"].as_i64()? as i32,
+    ))
+}
+
+pub struct InvokeModelChatCompletionsProvider {
+    client: Client,
+}
+
+impl InvokeModelChatCompletionsProvider {
+    pub fn new(client: &Client) -> Self {
+        Self {
+            client: client.clone(),
+        }
+    }
+
+    /// The chunks of `InvokeModelWithResponseStream`, or the whole response
+    /// of `InvokeModel` as one chunk for models that do not stream.
+    async fn invoke(
+        &self,
+        family: InvokeFamily,
+        model: &str,
+        body: Vec<u8>,
+    ) -> anyhow::Result<BoxStream<'static, anyhow::Result<Value>>> {
+        if family == InvokeFamily::Jurassic {
+            let output = self
+                .client
+                .invoke_model()
+                .model_id(model)
+                .content_type("application/json")
+                .accept("application/json")
+                .body(Blob::new(body))
+                .send()
+                .await
+                .map_err(|e| from_bedrock_error(e, model))?;
+            let mut chunk: Value = serde_json::from_slice(output.body().as_ref())?;
+            let prompt_tokens = chunk["prompt"]["tokens"].as_array().map_or(0, Vec::len);
+            let completion_tokens = chunk["completions"][0]["data"]["tokens"]
+                .as_array()
+                .map_or(0, Vec::len);
+            chunk["amazon-bedrock-invocationMetrics"] = json!({
+                "inputTokenCount": prompt_tokens,
+                "outputTokenCount": completion_tokens,
+            });
+            return Ok(futures::stream::once(async { Ok(chunk) }).boxed());
+        }
+
+        let mut output = self
+            .client
+            .invoke_model_with_response_stream()
+            .model_id(model)
+            .content_type("application/json")
+            .accept("application/json")
+            .body(Blob::new(body))
+            .send()
+            .await
+            .map_err(|e| from_bedrock_error(e, model))?;
+        let model = model.to_string();
+        Ok(stream! {
+            loop {
+                match output.body.recv().await {
+                    Ok(Some(ResponseStream::Chunk(part))) => {
+                        let Some(bytes) = part.bytes() else {
+                            continue;
+                        };
+                        yield serde_json::from_slice(bytes.as_ref()).map_err(anyhow::Error::from);
+                    }
+                    Ok(Some(_)) => continue,
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Error receiving from InvokeModel stream: {}", e);
+                        yield Err(from_bedrock_error(e, &model).into());
+                        break;
+                    }
+                }
+            }
+        }
+        .boxed())
+    }
+}
+
+#[async_trait]
+impl ChatCompletionsProvider for InvokeModelChatCompletionsProvider {
+    async fn chat_completions_stream<F>(
+        self,
+        request: ChatCompletionsRequest,
+        usage_callback: F,
+    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<StreamEvent>>>
+    where
+        F: Fn(&Usage) + Send + Sync + 'static,
+    {
+        let family = InvokeFamily::of(&request.model)
+            .ok_or_else(|| ProviderError::model_not_found(&request.model))?;
+        check_logit_bias(&request)?;
+        if request
+            .tools
+            .as_ref()
+            .is_some_and(|tools| !tools.is_empty())
+        {
+            return Err(ProviderError::invalid_request(
+                format!("Model {} does not support tools", request.model),
+                Some("tools"),
+            )
+            .into());
+        }
+
+        let prompt = render_prompt(&request);
+        let body = serde_json::to_vec(&family.body(&prompt, &request))?;
+        info!(
+            "Sending InvokeModel request to Bedrock for model: {}",
+            request.model
+        );
+        let mut chunks = self.invoke(family, &request.model, body).await?;
+
+        let id: Arc<str> = Uuid::new_v4().to_string().into();
+        let created = Utc::now().timestamp();
+        let model = request.model;
+        let stream = stream! {
+            let mut buffer = BytesMut::new();
+            let mut usage = (0, 0);
+            let mut started = false;
+            while let Some(chunk) = chunks.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        yield Err(e);
+                        break;
+                    }
+                };
+                if let Some(counts) = invocation_usage(&chunk) {
+                    usage = counts;
+                }
+                let (text, finish) = family.parse_chunk(&chunk);
+
+                let mut response = ChatCompletionsResponse::builder()
+                    .id(Some(id.clone()))
+                    .created(Some(created))
+                    .model(Some(model.clone()))
+                    .object(Some("chat.completion.chunk".to_string()));
+                if !started {
+                    started = true;
+                    response = response.choice(
+                        ChoiceBuilder::default()
+                            .delta(Some(Delta::Role { role: "assistant".to_string() }))
+                            .build(),
+                    );
+                }
+                if let Some(text) = text.filter(|text| !text.is_empty()) {
+                    response = response.choice(
+                        ChoiceBuilder::default()
+                            .delta(Some(Delta::Content { content: text }))
+                            .build(),
+                    );
+                }
+                if let Some(finish) = finish {
+                    response = response.choice(
+                        ChoiceBuilder::default()
+                            .finish_reason(Some(finish.to_string()))
+                            .build(),
+                    );
+                }
+
+                let response = response.build();
+                if response.choices.is_empty() {
+                    continue;
+                }
+                match create_stream_event(&response, &mut buffer) {
+                    Ok(event) => yield Ok(event),
+                    Err(e) => {
+                        error!("Failed to create stream event: {}", e);
+                        yield Err(e);
+                    }
+                }
+            }
+
+            let (prompt_tokens, completion_tokens) = usage;
+            debug!(
+                "InvokeModel usage: prompt_tokens={}, completion_tokens={}",
+                prompt_tokens, completion_tokens
+            );
+            let usage = UsageBuilder::default()
+                .prompt_tokens(prompt_tokens)
+                .completion_tokens(completion_tokens)
+                .total_tokens(prompt_tokens + completion_tokens)
+                .build();
+            usage_callback(&usage);
+            let response = ChatCompletionsResponse::builder()
+                .id(Some(id))
+                .created(Some(created))
+                .model(Some(model))
+                .object(Some("chat.completion.chunk".to_string()))
+                .usage(Some(usage))
+                .build();
+            match create_stream_event(&response, &mut buffer) {
+                Ok(event) => yield Ok(event),
+                Err(e) => {
+                    error!("Failed to create stream event: {}", e);
+                    yield Err(e);
+                }
+            }
+
+            info!("InvokeModel stream completed, sending DONE message");
+            yield Ok(StreamEvent::Done);
+        };
+
+        Ok(stream.boxed())
+    }
+}
@@ -0,0 +1,240 @@
+use std::{net::IpAddr, str::FromStr};
+
+/// An address range in CIDR notation, or a single address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are their IPv4 address, as
+/// dual-stack listeners report IPv4 peers like that.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        addr => addr,
+    }
+}
+
+fn bits(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(v4) => u32::from(v4) as u128,
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+impl IpNet {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = canonical(addr);
+        let width = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if self.addr.is_ipv4() != addr.is_ipv4() {
+            return false;
+        }
+        if self.prefix == 0 {
+            return true;
+        }
+        let shift = width - self.prefix as u32;
+        bits(self.addr) >> shift == bits(addr) >> shift
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .trim()
+            .parse::<IpAddr>()
+            .map(canonical)
+            .map_err(|_| anyhow::anyhow!("Invalid IP address in {}", s))?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.trim().parse::<u8>() {
+                Ok(prefix) if prefix <= width => prefix,
+                _ => anyhow::bail!("Invalid prefix length in {}", s),
+            },
+            None => width,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Which client addresses may call the proxy. A denied address is turned
+/// away even when an allow range covers it; with an allowlist, addresses
+/// outside it are turned away too.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+    /// Proxies whose `X-Forwarded-For` names the client.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn is_enabled(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty()
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(addr))
+    }
+
+    /// The client behind `peer`. When `peer` is a trusted proxy, the
+    /// `X-Forwarded-For` entries are read from the right, skipping other
+    /// trusted proxies, because only those were appended by proxies we
+    /// trust; anything to their left is whatever the client sent.
+    pub fn client_addr(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let peer = canonical(peer);
+        let Some(forwarded_for) = forwarded_for.filter(|_| self.is_trusted(peer)) else {
+            return peer;
+        };
+        let mut client = peer;
+        for hop in forwarded_for.rsplit(',') {
+            let Ok(addr) = hop.trim().parse::<IpAddr>().map(canonical) else {
+                break;
+            };
+            client = addr;
+            if !self.is_trusted(addr) {
+                break;
+            }
+        }
+        client
+    }
+
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn behind(proxies: &[&str]) -> IpFilter {
+        IpFilter {
+            trusted_proxies: proxies.iter().map(|proxy| net(proxy)).collect(),
+            ..IpFilter::default()
+        }
+    }
+
+    #[test]
+    fn cidr_prefix_edges() {
+        assert!(net("10.0.0.0/8").contains(ip("10.255.255.255")));
+        assert!(!net("10.0.0.0/8").contains(ip("11.0.0.0")));
+        assert!(net("192.168.1.6/31").contains(ip("192.168.1.7")));
+        assert!(!net("192.168.1.6/31").contains(ip("192.168.1.8")));
+        assert!(net("203.0.113.9").contains(ip("203.0.113.9")));
+        assert!(!net("203.0.113.9/32").contains(ip("203.0.113.8")));
+        assert!(net("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(!net("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(net("::/0").contains(ip("2001:db8::1")));
+        assert!(net("2001:db8::/64").contains(ip("2001:db8::ffff:1")));
+        assert!(!net("2001:db8::/64").contains(ip("2001:db8:0:1::1")));
+        assert!(!net("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn mapped_addresses_match_ipv4_ranges() {
+        assert!(net("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(net("::ffff:10.0.0.1").contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for s in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "example.com",
+            "",
+        ] {
+            assert!(s.parse::<IpNet>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_an_untrusted_peer() {
+        let filter = behind(&["10.0.0.0/8"]);
+        assert_eq!(
+            filter.client_addr(ip("198.51.100.7"), Some("1.2.3.4")),
+            ip("198.51.100.7")
+        );
+        assert_eq!(
+            behind(&[]).client_addr(ip("10.0.0.1"), Some("1.2.3.4")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn reads_forwarded_for_from_the_right() {
+        let filter = behind(&["10.0.0.0/8"]);
+        // The client prepends a spoofed hop; the trusted proxy appends the
+        // address it saw.
+        assert_eq!(
+            filter.client_addr(ip("10.0.0.1"), Some("127.0.0.1, 203.0.113.5")),
+            ip("203.0.113.5")
+        );
+        assert_eq!(
+            filter.client_addr(ip("10.0.0.1"), Some("203.0.113.5, 10.0.0.2, 10.0.0.3")),
+            ip("203.0.113.5")
+        );
+    }
+
+    #[test]
+    fn stops_at_an_unparseable_hop() {
+        let filter = behind(&["10.0.0.0/8"]);
+        assert_eq!(
+            filter.client_addr(ip("10.0.0.1"), Some("1.2.3.4, unknown, 10.0.0.2")),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            filter.client_addr(ip("10.0.0.1"), Some("garbage")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn canonicalizes_mapped_peers_and_hops() {
+        let filter = behind(&["10.0.0.0/8"]);
+        assert_eq!(
+            filter.client_addr(ip("::ffff:10.0.0.1"), Some("::ffff:203.0.113.5")),
+            ip("203.0.113.5")
+        );
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = IpFilter {
+            allow: vec![net("10.0.0.0/8")],
+            deny: vec![net("10.0.0.13")],
+            ..IpFilter::default()
+        };
+        assert!(filter.is_enabled());
+        assert!(filter.permits(ip("10.0.0.12")));
+        assert!(!filter.permits(ip("10.0.0.13")));
+        assert!(!filter.permits(ip("192.0.2.1")));
+        assert!(IpFilter::default().permits(ip("192.0.2.1")));
+    }
+}
@@ -0,0 +1,498 @@
+use crate::error::{ErrorKind, ProviderError};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::Utc;
+use ring::signature::{
+    self, ECDSA_P256_SHA256_FIXED, ECDSA_P384_SHA384_FIXED, RSA_PKCS1_2048_8192_SHA256,
+    RSA_PKCS1_2048_8192_SHA384, RSA_PKCS1_2048_8192_SHA512, RsaPublicKeyComponents,
+    UnparsedPublicKey,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    /// Tokens must carry this `iss`. Its OpenID configuration names the
+    /// JWKS unless `jwks_url` is set.
+    pub issuer: String,
+    pub jwks_url: Option<String>,
+    /// Tokens must list this in `aud` when set.
+    pub audience: Option<String>,
+    /// The claim naming the tenant whose policies apply.
+    pub tenant_claim: String,
+    /// Allowed clock skew for `exp` and `nbf`.
+    pub leeway: Duration,
+    /// How long fetched keys are used before they are fetched again.
+    pub jwks_refresh: Duration,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            jwks_url: None,
+            audience: None,
+            tenant_claim: "sub".to_string(),
+            leeway: Duration::from_secs(60),
+            jwks_refresh: Duration::from_secs(3600),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct OpenIdConfiguration {
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// The verified claims of a token.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub tenant: Option<String>,
+    pub claims: Map<String, Value>,
+}
+
+struct KeySet {
+    keys: Vec<Jwk>,
+    fetched: Instant,
+}
+
+/// Verifies bearer JWTs signed by an OIDC issuer's keys (RS256, RS384,
+/// RS512, ES256 or ES384). Keys are fetched again when a token names an
+/// unknown `kid`, at most once a minute, so rotations are picked up.
+pub struct JwtVerifier {
+    config: JwtConfig,
+    http: reqwest::Client,
+    keys: Mutex<Option<KeySet>>,
+}
+
+fn unauthorized(message: impl Into<String>) -> ProviderError {
+    ProviderError::new(ErrorKind::Authentication, message).code("invalid_token")
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, ProviderError> {
+    URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| unauthorized("Malformed token"))
+}
+
+/// Whether a bearer token is shaped like a JWT rather than an API key.
+pub fn is_jwt(token: &str) -> bool {
+    token.starts_with("eyJ") && token.split('.').count() == 3
+}
+
+impl JwtVerifier {
+    pub fn new(config: JwtConfig, http: &reqwest::Client) -> Self {
+        info!("Accepting JWTs issued by {}", config.issuer);
+        Self {
+            config,
+            http: http.clone(),
+            keys: Mutex::new(None),
+        }
+    }
+
+    async fn fetch_keys(&self) -> anyhow::Result<Vec<Jwk>> {
+        let jwks_url = match &self.config.jwks_url {
+            Some(url) => url.clone(),
+            None => {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let configuration: OpenIdConfiguration = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                configuration.jwks_uri
+            }
+        };
+        let jwks: Jwks = self
+            .http
+            .get(&jwks_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        debug!("Fetched {} signing keys from {}", jwks.keys.len(), jwks_url);
+        Ok(jwks.keys)
+    }
+
+    async fn key(&self, kid: Option<&str>) -> Result<Jwk, ProviderError> {
+        let find = |keys: &[Jwk]| {
+            keys.iter()
+                .find(|key| kid.is_none() || key.kid.as_deref() == kid)
+                .cloned()
+        };
+        let mut keys = self.keys.lock().await;
+        if let Some(set) = keys.as_ref() {
+            let fresh = set.fetched.elapsed() < self.config.jwks_refresh;
+            let recent = set.fetched.elapsed() < Duration::from_secs(60);
+            match find(&set.keys) {
+                Some(key) if fresh => return Ok(key),
+                None if recent => return Err(unauthorized("Token signed by an unknown key")),
+                _ => {}
+            }
+        }
+        match self.fetch_keys().await {
+            Ok(fetched) => {
+                *keys = Some(KeySet {
+                    keys: fetched,
+                    fetched: Instant::now(),
+                });
+            }
+            Err(e) => {
+                warn!("Failed to fetch JWKS for {}: {}", self.config.issuer, e);
+                if keys.is_none() {
+                    return Err(ProviderError::new(
+                        ErrorKind::Unavailable,
+                        "The token issuer's keys are unavailable",
+                    ));
+                }
+            }
+        }
+        keys.as_ref()
+            .and_then(|set| find(&set.keys))
+            .ok_or_else(|| unauthorized("Token signed by an unknown key"))
+    }
+
+    pub async fn verify(&self, token: &str) -> Result<Identity, ProviderError> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(unauthorized("Malformed token"));
+        };
+        let header: Header = serde_json::from_slice(&decode(header)?)
+            .map_err(|_| unauthorized("Malformed token header"))?;
+        let key = self.key(header.kid.as_deref()).await?;
+        let message = &token[..token.len() - signature.len() - 1];
+        verify_signature(&header.alg, &key, message.as_bytes(), &decode(signature)?)?;
+
+        let claims: Map<String, Value> = serde_json::from_slice(&decode(payload)?)
+            .map_err(|_| unauthorized("Malformed token claims"))?;
+        self.check_claims(&claims)?;
+
+        let subject = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .ok_or_else(|| unauthorized("Token has no subject"))?
+            .to_string();
+        let tenant = claims
+            .get(&self.config.tenant_claim)
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        Ok(Identity {
+            subject,
+            tenant,
+            claims,
+        })
+    }
+
+    fn check_claims(&self, claims: &Map<String, Value>) -> Result<(), ProviderError> {
+        if claims.get("iss").and_then(Value::as_str) != Some(self.config.issuer.as_str()) {
+            return Err(unauthorized("Token is from another issuer"));
+        }
+        if let Some(audience) = &self.config.audience {
+            let listed = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud == audience.as_str()),
+                _ => false,
+            };
+            if !listed {
+                return Err(unauthorized("Token is for another audience"));
+            }
+        }
+        let now = Utc::now().timestamp();
+        let leeway = self.config.leeway.as_secs() as i64;
+        match claims.get("exp").and_then(Value::as_i64) {
+            Some(exp) if exp + leeway > now => {}
+            Some(_) => return Err(unauthorized("Token has expired")),
+            None => return Err(unauthorized("Token has no expiry")),
+        }
+        if claims
+            .get("nbf")
+            .and_then(Value::as_i64)
+            .is_some_and(|nbf| nbf - leeway > now)
+        {
+            return Err(unauthorized("Token is not valid yet"));
+        }
+        Ok(())
+    }
+}
+
+fn verify_signature(
+    alg: &str,
+    key: &Jwk,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), ProviderError> {
+    let component = |value: &Option<String>| {
+        value
+            .as_deref()
+            .ok_or_else(|| unauthorized("Incomplete signing key"))
+            .and_then(decode)
+    };
+    let verified = match (alg, key.kty.as_str()) {
+        ("RS256" | "RS384" | "RS512", "RSA") => {
+            let params = match alg {
+                "RS256" => &RSA_PKCS1_2048_8192_SHA256,
+                "RS384" => &RSA_PKCS1_2048_8192_SHA384,
+                _ => &RSA_PKCS1_2048_8192_SHA512,
+            };
+            RsaPublicKeyComponents {
+                n: component(&key.n)?,
+                e: component(&key.e)?,
+            }
+            .verify(params, message, signature)
+        }
+        ("ES256" | "ES384", "EC") => {
+            let algorithm: &dyn signature::VerificationAlgorithm = match (alg, key.crv.as_deref()) {
+                ("ES256", Some("P-256")) => &ECDSA_P256_SHA256_FIXED,
+                ("ES384", Some("P-384")) => &ECDSA_P384_SHA384_FIXED,
+                _ => return Err(unauthorized("Signing key does not match the algorithm")),
+            };
+            let mut point = vec![0x04];
+            point.extend(component(&key.x)?);
+            point.extend(component(&key.y)?);
+            UnparsedPublicKey::new(algorithm, point).verify(message, signature)
+        }
+        _ => return Err(unauthorized(format!("Unsupported token algorithm {}", alg))),
+    };
+    verified.map_err(|_| unauthorized("Invalid token signature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair},
+    };
+    use serde_json::json;
+
+    const ISSUER: &str = "https://issuer.example.com";
+
+    fn verifier(audience: Option<&str>) -> JwtVerifier {
+        JwtVerifier::new(
+            JwtConfig {
+                issuer: ISSUER.to_string(),
+                audience: audience.map(str::to_string),
+                ..JwtConfig::default()
+            },
+            &reqwest::Client::new(),
+        )
+    }
+
+    fn claims(value: Value) -> Map<String, Value> {
+        let Value::Object(mut claims) = value else {
+            unreachable!()
+        };
+        claims.entry("iss").or_insert(json!(ISSUER));
+        claims
+            .entry("exp")
+            .or_insert(json!(Utc::now().timestamp() + 300));
+        claims
+    }
+
+    fn message(result: Result<impl std::fmt::Debug, ProviderError>) -> String {
+        result.unwrap_err().message
+    }
+
+    #[test]
+    fn accepts_current_claims() {
+        assert!(verifier(None).check_claims(&claims(json!({}))).is_ok());
+    }
+
+    #[test]
+    fn rejects_expired_tokens_past_the_leeway() {
+        let verifier = verifier(None);
+        let now = Utc::now().timestamp();
+        let expired = claims(json!({ "exp": now - 61 }));
+        assert_eq!(
+            message(verifier.check_claims(&expired)),
+            "Token has expired"
+        );
+        let within_leeway = claims(json!({ "exp": now - 30 }));
+        assert!(verifier.check_claims(&within_leeway).is_ok());
+        let mut no_expiry = claims(json!({}));
+        no_expiry.remove("exp");
+        assert_eq!(
+            message(verifier.check_claims(&no_expiry)),
+            "Token has no expiry"
+        );
+    }
+
+    #[test]
+    fn rejects_tokens_not_valid_yet() {
+        let nbf = Utc::now().timestamp() + 120;
+        assert_eq!(
+            message(verifier(None).check_claims(&claims(json!({ "nbf": nbf })))),
+            "Token is not valid yet"
+        );
+    }
+
+    #[test]
+    fn rejects_other_issuers() {
+        let other = claims(json!({ "iss": "https://other.example.com" }));
+        assert_eq!(
+            message(verifier(None).check_claims(&other)),
+            "Token is from another issuer"
+        );
+    }
+
+    #[test]
+    fn checks_the_audience() {
+        let verifier = verifier(Some("llm-proxy"));
+        assert!(
+            verifier
+                .check_claims(&claims(json!({ "aud": "llm-proxy" })))
+                .is_ok()
+        );
+        assert!(
+            verifier
+                .check_claims(&claims(json!({ "aud": ["other", "llm-proxy"] })))
+                .is_ok()
+        );
+        for aud in [json!("other"), json!(["other"]), json!(null)] {
+            assert_eq!(
+                message(verifier.check_claims(&claims(json!({ "aud": aud })))),
+                "Token is for another audience"
+            );
+        }
+    }
+
+    struct Signer {
+        key_pair: EcdsaKeyPair,
+        rng: SystemRandom,
+    }
+
+    impl Signer {
+        fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+            let key_pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                    .unwrap();
+            Self { key_pair, rng }
+        }
+
+        fn jwk(&self) -> Jwk {
+            let point = &self.key_pair.public_key().as_ref()[1..];
+            Jwk {
+                kty: "EC".to_string(),
+                kid: Some("key-1".to_string()),
+                crv: Some("P-256".to_string()),
+                n: None,
+                e: None,
+                x: Some(URL_SAFE_NO_PAD.encode(&point[..32])),
+                y: Some(URL_SAFE_NO_PAD.encode(&point[32..])),
+            }
+        }
+
+        fn token(&self, alg: &str, claims: &Map<String, Value>) -> String {
+            let header = json!({ "alg": alg, "kid": "key-1", "typ": "JWT" });
+            let message = format!(
+                "{}.{}",
+                URL_SAFE_NO_PAD.encode(header.to_string()),
+                URL_SAFE_NO_PAD.encode(Value::Object(claims.clone()).to_string())
+            );
+            let signature = self.key_pair.sign(&self.rng, message.as_bytes()).unwrap();
+            format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature.as_ref()))
+        }
+    }
+
+    fn with_key(verifier: JwtVerifier, key: Jwk) -> JwtVerifier {
+        JwtVerifier {
+            keys: Mutex::new(Some(KeySet {
+                keys: vec![key],
+                fetched: Instant::now(),
+            })),
+            ..verifier
+        }
+    }
+
+    #[test]
+    fn pairs_algorithms_with_key_types() {
+        let signer = Signer::new();
+        let key = signer.jwk();
+        let signed = b"header.claims";
+        let signature = signer.key_pair.sign(&signer.rng, signed).unwrap();
+        let signature = signature.as_ref();
+        assert!(verify_signature("ES256", &key, signed, signature).is_ok());
+        assert_eq!(
+            message(verify_signature("ES384", &key, signed, signature)),
+            "Signing key does not match the algorithm"
+        );
+        for alg in ["RS256", "HS256", "none"] {
+            assert_eq!(
+                message(verify_signature(alg, &key, signed, signature)),
+                format!("Unsupported token algorithm {}", alg)
+            );
+        }
+        assert_eq!(
+            message(verify_signature("ES256", &key, b"header.other", signature)),
+            "Invalid token signature"
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_signed_tokens() {
+        let signer = Signer::new();
+        let verifier = with_key(verifier(None), signer.jwk());
+        let token = signer.token("ES256", &claims(json!({ "sub": "user-1" })));
+        assert!(is_jwt(&token));
+        let identity = verifier.verify(&token).await.unwrap();
+        assert_eq!(identity.subject, "user-1");
+        assert_eq!(identity.tenant.as_deref(), Some("user-1"));
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_and_mislabelled_tokens() {
+        let signer = Signer::new();
+        let verifier = with_key(verifier(None), signer.jwk());
+        let expired = claims(json!({ "sub": "user-1", "exp": Utc::now().timestamp() - 600 }));
+        assert_eq!(
+            message(verifier.verify(&signer.token("ES256", &expired)).await),
+            "Token has expired"
+        );
+        let current = claims(json!({ "sub": "user-1" }));
+        assert_eq!(
+            message(verifier.verify(&signer.token("RS256", &current)).await),
+            "Unsupported token algorithm RS256"
+        );
+        let mut tampered = signer.token("ES256", &current);
+        tampered.insert(tampered.rfind('.').unwrap(), 'x');
+        assert!(verifier.verify(&tampered).await.is_err());
+        assert_eq!(message(verifier.verify("a.b").await), "Malformed token");
+    }
+}
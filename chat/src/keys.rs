@@ -0,0 +1,155 @@
+use crate::budget::Quota;
+use request::{ChatCompletionsRequest, Message};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt};
+
+/// How urgently a key's traffic needs serving. Under contention lower
+/// priorities wait for a slot first and are the first to be moved to a
+/// cheaper fallback model.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Background,
+    Batch,
+    #[default]
+    Interactive,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Priority::Background => "background",
+            Priority::Batch => "batch",
+            Priority::Interactive => "interactive",
+        })
+    }
+}
+
+/// Request parameters applied when the client leaves them unset.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct KeyDefaults {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<i32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub stop: Option<Vec<String>>,
+}
+
+/// Server-side settings for one API key, so a platform owner can pin tone
+/// or guardrail instructions for an application whatever its client sends.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct KeyConfig {
+    /// Tenant name, shown instead of the key in stats.
+    pub name: Option<String>,
+    /// Always sent first, ahead of any system message from the client.
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// May route a request to another allowed Bedrock region with the
+    /// `x-aws-region` header.
+    #[serde(default)]
+    pub region_override: bool,
+    #[serde(default)]
+    pub defaults: KeyDefaults,
+    /// Requests are turned away once the key has spent this much, in USD.
+    pub budget: Option<f64>,
+    #[serde(default)]
+    pub quotas: Vec<Quota>,
+    /// The team whose limits the key shares.
+    pub team: Option<String>,
+}
+
+impl KeyConfig {
+    pub fn apply(&self, request: &mut ChatCompletionsRequest) {
+        let defaults = &self.defaults;
+        request.temperature = request.temperature.or(defaults.temperature);
+        request.top_p = request.top_p.or(defaults.top_p);
+        request.max_tokens = request.max_tokens.or(defaults.max_tokens);
+        request.frequency_penalty = request.frequency_penalty.or(defaults.frequency_penalty);
+        request.presence_penalty = request.presence_penalty.or(defaults.presence_penalty);
+        if request.stop.is_none() {
+            request.stop = defaults.stop.clone();
+        }
+
+        if let Some(system_prompt) = &self.system_prompt {
+            request
+                .messages
+                .insert(0, Message::system(system_prompt.clone()));
+        }
+    }
+}
+
+/// A group of keys. The team's budget and quotas cap all of its keys
+/// together, as do those of its parent teams, while the key limits apply to
+/// each key that sets none of its own.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TeamConfig {
+    pub parent: Option<String>,
+    pub budget: Option<f64>,
+    #[serde(default)]
+    pub quotas: Vec<Quota>,
+    pub key_budget: Option<f64>,
+    #[serde(default)]
+    pub key_quotas: Vec<Quota>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Teams {
+    pub teams: HashMap<String, TeamConfig>,
+}
+
+impl Teams {
+    pub fn is_empty(&self) -> bool {
+        self.teams.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.teams.len()
+    }
+
+    /// Fails on keys or teams naming an unknown team and on teams that are
+    /// their own ancestors.
+    pub fn check(&self, keys: &HashMap<String, KeyConfig>) -> anyhow::Result<()> {
+        for key in keys.values() {
+            if let Some(team) = key.team.as_deref()
+                && !self.teams.contains_key(team)
+            {
+                anyhow::bail!(
+                    "Key {} is in unknown team {:?}",
+                    key.name.as_deref().unwrap_or("without a name"),
+                    team
+                );
+            }
+        }
+        for (name, team) in &self.teams {
+            let mut ancestor = team.parent.as_deref();
+            let mut depth = 0;
+            while let Some(parent) = ancestor {
+                let Some(parent_team) = self.teams.get(parent) else {
+                    anyhow::bail!("Team {:?} has unknown parent {:?}", name, parent);
+                };
+                depth += 1;
+                if parent == name || depth > self.teams.len() {
+                    anyhow::bail!("Team {:?} is its own ancestor", name);
+                }
+                ancestor = parent_team.parent.as_deref();
+            }
+        }
+        Ok(())
+    }
+
+    /// The team and its ancestors, closest first.
+    pub fn chain(&self, team: Option<&str>) -> Vec<(&str, &TeamConfig)> {
+        let mut chain = Vec::new();
+        let mut next = team;
+        while let Some((name, team)) = next.and_then(|name| self.teams.get_key_value(name)) {
+            if chain.len() > self.teams.len() {
+                break;
+            }
+            chain.push((name.as_str(), team));
+            next = team.parent.as_deref();
+        }
+        chain
+    }
+}
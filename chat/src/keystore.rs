@@ -0,0 +1,213 @@
+use crate::keys::KeyConfig;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::Utc;
+use ring::{
+    digest::{SHA256, digest},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use tracing::{error, info};
+use uuid::Uuid;
+
+/// A key issued through the admin API. Only a digest of the secret is
+/// kept, so neither the store nor the API can give the key out again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IssuedKey {
+    pub id: String,
+    pub created: i64,
+    digest: String,
+    pub settings: Arc<KeyConfig>,
+}
+
+fn key_digest(api_key: &str) -> String {
+    digest(&SHA256, api_key.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The spend ledger of a key. It is derived from the secret, so it stays
+/// the same when the key is renamed and no two keys share one.
+pub fn key_ledger(api_key: &str) -> String {
+    ledger_of_digest(&key_digest(api_key))
+}
+
+fn ledger_of_digest(digest: &str) -> String {
+    format!("key:{}", &digest[..16])
+}
+
+impl IssuedKey {
+    pub fn ledger(&self) -> String {
+        ledger_of_digest(&self.digest)
+    }
+}
+
+/// Keys from the configuration, plus those issued through the admin API,
+/// which are persisted to a JSON file when one is configured. Revoking a
+/// key turns its next request away; requests already running finish.
+#[derive(Default)]
+pub struct KeyStore {
+    path: Option<PathBuf>,
+    configured: HashMap<String, Arc<KeyConfig>>,
+    /// Issued keys by the digest of their secret.
+    issued: RwLock<HashMap<String, Arc<IssuedKey>>>,
+    /// Serializes changes so the file always reflects the latest one.
+    changes: tokio::sync::Mutex<()>,
+}
+
+impl KeyStore {
+    pub async fn load(
+        path: Option<PathBuf>,
+        configured: HashMap<String, KeyConfig>,
+    ) -> anyhow::Result<Self> {
+        let issued = match &path {
+            Some(path) if tokio::fs::try_exists(path).await? => {
+                let keys: Vec<IssuedKey> = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+                info!("Loaded {} issued keys from {}", keys.len(), path.display());
+                keys.into_iter()
+                    .map(|key| (key.digest.clone(), Arc::new(key)))
+                    .collect()
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Self {
+            path,
+            configured: configured
+                .into_iter()
+                .map(|(api_key, config)| (api_key, Arc::new(config)))
+                .collect(),
+            issued: RwLock::new(issued),
+            changes: tokio::sync::Mutex::default(),
+        })
+    }
+
+    /// The settings of a key, configured or issued.
+    pub fn get(&self, api_key: &str) -> Option<Arc<KeyConfig>> {
+        if let Some(config) = self.configured.get(api_key) {
+            return Some(config.clone());
+        }
+        self.issued
+            .read()
+            .unwrap()
+            .get(&key_digest(api_key))
+            .map(|key| key.settings.clone())
+    }
+
+    /// The configured keys' settings, with their ledgers.
+    pub fn configured(&self) -> Vec<(String, Arc<KeyConfig>)> {
+        self.configured
+            .iter()
+            .map(|(api_key, config)| (key_ledger(api_key), config.clone()))
+            .collect()
+    }
+
+    pub fn issued(&self) -> Vec<Arc<IssuedKey>> {
+        let mut keys: Vec<_> = self.issued.read().unwrap().values().cloned().collect();
+        keys.sort_by_key(|key| key.created);
+        keys
+    }
+
+    pub fn find(&self, id: &str) -> Option<Arc<IssuedKey>> {
+        self.issued
+            .read()
+            .unwrap()
+            .values()
+            .find(|key| key.id == id)
+            .cloned()
+    }
+
+    /// Issues a key with these settings, returning its secret, which is
+    /// only ever shown this once. Unnamed keys are named by their id.
+    pub async fn issue(&self, mut settings: KeyConfig) -> anyhow::Result<(String, Arc<IssuedKey>)> {
+        let mut secret = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_| anyhow::anyhow!("Failed to generate a key"))?;
+        let api_key = format!("sk-{}", URL_SAFE_NO_PAD.encode(secret));
+        let id = format!("key_{}", Uuid::new_v4().simple());
+        settings.name.get_or_insert_with(|| id.clone());
+        let key = Arc::new(IssuedKey {
+            id,
+            created: Utc::now().timestamp(),
+            digest: key_digest(&api_key),
+            settings: Arc::new(settings),
+        });
+
+        // Each change is written to the file before it takes effect, so a
+        // failed write leaves both as they were.
+        let _change = self.changes.lock().await;
+        let mut issued = self.issued.read().unwrap().clone();
+        issued.insert(key.digest.clone(), key.clone());
+        self.persist(&issued).await?;
+        *self.issued.write().unwrap() = issued;
+        Ok((api_key, key))
+    }
+
+    /// Replaces the settings of an issued key, keeping its name unless the
+    /// new settings give another. The key's spend stays with it, whatever
+    /// the name.
+    pub async fn update(
+        &self,
+        id: &str,
+        mut settings: KeyConfig,
+    ) -> anyhow::Result<Option<Arc<IssuedKey>>> {
+        let _change = self.changes.lock().await;
+        let mut issued = self.issued.read().unwrap().clone();
+        let Some(existing) = issued.values_mut().find(|key| key.id == id) else {
+            return Ok(None);
+        };
+        if settings.name.is_none() {
+            settings.name = existing.settings.name.clone();
+        }
+        let key = Arc::new(IssuedKey {
+            settings: Arc::new(settings),
+            ..existing.as_ref().clone()
+        });
+        *existing = key.clone();
+        self.persist(&issued).await?;
+        *self.issued.write().unwrap() = issued;
+        Ok(Some(key))
+    }
+
+    /// Revokes an issued key, returning whether it existed.
+    pub async fn revoke(&self, id: &str) -> anyhow::Result<bool> {
+        let _change = self.changes.lock().await;
+        let mut issued = self.issued.read().unwrap().clone();
+        let len = issued.len();
+        issued.retain(|_, key| key.id != id);
+        if issued.len() == len {
+            return Ok(false);
+        }
+        self.persist(&issued).await?;
+        *self.issued.write().unwrap() = issued;
+        Ok(true)
+    }
+
+    async fn persist(&self, keys: &HashMap<String, Arc<IssuedKey>>) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let write = async {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            let tmp = path.with_extension("tmp");
+            tokio::fs::write(
+                &tmp,
+                serde_json::to_vec_pretty(&keys.values().map(Arc::as_ref).collect::<Vec<_>>())?,
+            )
+            .await?;
+            tokio::fs::rename(&tmp, path).await?;
+            anyhow::Ok(())
+        };
+        write.await.inspect_err(|e| {
+            error!("Failed to persist issued keys to {}: {}", path.display(), e);
+        })
+    }
+}
@@ -1,19 +1,87 @@
+pub mod admission;
+pub mod aliases;
+pub mod anthropic;
+pub mod archive;
+pub mod audit;
 pub mod bedrock;
+pub mod budget;
+pub mod buffer;
+pub mod cache;
+#[cfg(feature = "convert")]
+pub mod convert;
+pub mod embeddings;
+pub mod emulation;
+pub mod error;
+pub mod events;
+pub mod gemini;
+pub mod guardrails;
+pub mod image;
+pub mod invoke;
+pub mod ipfilter;
+pub mod jwt;
+pub mod keys;
+pub mod keystore;
+pub mod limits;
+pub mod mcp;
+pub mod memory;
+pub mod mock;
+pub mod models;
 pub mod openai;
+pub mod prefill;
+pub mod prompts;
 pub mod providers;
+pub mod registry;
+pub mod replay;
+pub mod routing;
+pub mod sagemaker;
+pub mod session;
+pub mod smoothing;
+pub mod sse;
+pub mod stats;
+pub mod stop;
+pub mod store;
+pub mod system_messages;
+pub mod tiers;
+pub mod tokens;
+pub mod tools;
+pub mod truncation;
+pub mod upstream;
+pub mod vertex;
+pub mod warmup;
+pub mod writer;
 
-use axum::response::sse::Event;
+use bytes::{BufMut, Bytes, BytesMut};
 use response::ChatCompletionsResponse;
 
 pub const DONE_MESSAGE: &str = "[DONE]";
 
+#[derive(Debug)]
+pub enum StreamEvent {
+    Chunk(Bytes),
+    Dropped(usize),
+    Done,
+}
+
+impl StreamEvent {
+    pub fn buffered_size(&self) -> usize {
+        match self {
+            StreamEvent::Chunk(data) => data.len(),
+            StreamEvent::Dropped(_) | StreamEvent::Done => 0,
+        }
+    }
+}
+
 pub trait ProcessChatCompletionsRequest<T> {
     fn process_chat_completions_request(&self, request: &request::ChatCompletionsRequest) -> T;
 }
 
-fn create_sse_event(response: &ChatCompletionsResponse) -> anyhow::Result<Event> {
-    match serde_json::to_string(response) {
-        Ok(data) => Ok(Event::default().data(data)),
-        Err(e) => anyhow::bail!("Failed to serialize response: {}", e),
+fn create_stream_event(
+    response: &ChatCompletionsResponse,
+    buffer: &mut BytesMut,
+) -> anyhow::Result<StreamEvent> {
+    if let Err(e) = serde_json::to_writer(buffer.writer(), response) {
+        buffer.clear();
+        anyhow::bail!("Failed to serialize response: {}", e);
     }
+    Ok(StreamEvent::Chunk(buffer.split().freeze()))
 }
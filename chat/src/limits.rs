@@ -0,0 +1,99 @@
+use crate::{
+    StreamEvent,
+    error::{ErrorKind, ProviderError},
+};
+use futures::{StreamExt, stream::BoxStream};
+use request::ChatCompletionsRequest;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::{Instant, timeout_at};
+use tracing::info;
+
+pub const MAX_TOKENS_CLAMPED_METRIC: &str = "llm_proxy_max_tokens_clamped_total";
+
+/// Operator caps for models matching a prefix, enforced whatever the client
+/// asks for.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelLimit {
+    pub models: Vec<String>,
+    /// Upper bound for `max_tokens`, also sent when the client sets none.
+    pub max_tokens: Option<i32>,
+    /// The whole response must be streamed within this many seconds.
+    pub stream_timeout_secs: Option<u64>,
+}
+
+impl ModelLimit {
+    pub fn stream_timeout(&self) -> Option<Duration> {
+        self.stream_timeout_secs
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ModelLimits {
+    pub rules: Vec<ModelLimit>,
+}
+
+impl ModelLimits {
+    fn rule_for(&self, model: &str) -> Option<&ModelLimit> {
+        self.rules
+            .iter()
+            .filter_map(|rule| {
+                rule.models
+                    .iter()
+                    .filter(|prefix| model.starts_with(prefix.as_str()))
+                    .map(|prefix| (prefix.len(), rule))
+                    .max_by_key(|(len, _)| *len)
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, rule)| rule)
+    }
+
+    /// Lowers `max_tokens` to the cap of the model's rule and returns the
+    /// rule, so the caller can apply its stream timeout.
+    pub fn clamp(&self, request: &mut ChatCompletionsRequest, model: &str) -> Option<&ModelLimit> {
+        let rule = self.rule_for(model)?;
+        if let Some(cap) = rule.max_tokens {
+            match request.max_tokens {
+                Some(max_tokens) if max_tokens > cap => {
+                    info!(
+                        "Clamping max_tokens from {} to {} for model {}",
+                        max_tokens, cap, model
+                    );
+                    metrics::counter!(MAX_TOKENS_CLAMPED_METRIC, "model" => model.to_string())
+                        .increment(1);
+                    request.max_tokens = Some(cap);
+                }
+                Some(_) => {}
+                None => request.max_tokens = Some(cap),
+            }
+        }
+        Some(rule)
+    }
+}
+
+/// Ends the stream with a timeout error once it runs past `timeout`.
+pub fn deadline<'a>(
+    mut stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    timeout: Duration,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+    async_stream::stream! {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match timeout_at(deadline, stream.next()).await {
+                Ok(Some(event)) => yield event,
+                Ok(None) => break,
+                Err(_) => {
+                    yield Err(ProviderError::new(
+                        ErrorKind::Timeout,
+                        format!("The response did not finish within {:?}.", timeout),
+                    )
+                    .into());
+                    break;
+                }
+            }
+        }
+    }
+    .boxed()
+}
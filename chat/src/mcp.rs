@@ -0,0 +1,506 @@
+use crate::sse::SseParser;
+use anyhow::{Context, anyhow, bail};
+use futures::StreamExt;
+use request::{FunctionDefinition, Tool};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdin, Command},
+    sync::{Mutex as AsyncMutex, oneshot},
+};
+use tracing::{debug, error, info, trace, warn};
+
+const PROTOCOL_VERSION: &str = "2025-06-18";
+const SESSION_HEADER: &str = "mcp-session-id";
+
+/// An MCP server, launched as a child process speaking JSON-RPC over stdio
+/// when `command` is set, or reached over streamable HTTP at `url`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct McpServerConfig {
+    pub name: String,
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct McpConfig {
+    pub servers: Vec<McpServerConfig>,
+    pub max_tool_rounds: usize,
+    pub call_timeout: Duration,
+}
+
+impl Default for McpConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            max_tool_rounds: 8,
+            call_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+type PendingResponses = Arc<Mutex<HashMap<u64, oneshot::Sender<anyhow::Result<Value>>>>>;
+
+enum Transport {
+    Stdio {
+        stdin: AsyncMutex<ChildStdin>,
+        pending: PendingResponses,
+        _child: Child,
+    },
+    Http {
+        client: reqwest::Client,
+        url: String,
+        headers: HashMap<String, String>,
+        session_id: Mutex<Option<String>>,
+    },
+}
+
+/// Forgets a stdio request when it is answered, fails or is given up on,
+/// such as by a timeout dropping it.
+struct PendingGuard<'a> {
+    pending: &'a PendingResponses,
+    id: u64,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// A minimal MCP client covering what the proxy needs: the initialize
+/// handshake, tool discovery and tool calls.
+pub struct McpClient {
+    name: String,
+    transport: Transport,
+    next_id: AtomicU64,
+}
+
+impl McpClient {
+    pub async fn connect(config: &McpServerConfig, http: &reqwest::Client) -> anyhow::Result<Self> {
+        let transport = match (&config.command, &config.url) {
+            (Some(command), None) => spawn_stdio(&config.name, command, config)?,
+            (None, Some(url)) => Transport::Http {
+                client: http.clone(),
+                url: url.clone(),
+                headers: config.headers.clone(),
+                session_id: Mutex::new(None),
+            },
+            _ => bail!(
+                "MCP server {} must set exactly one of `command` or `url`",
+                config.name
+            ),
+        };
+
+        let client = Self {
+            name: config.name.clone(),
+            transport,
+            next_id: AtomicU64::new(1),
+        };
+
+        let result = client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {
+                        "name": "llm-proxy",
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                }),
+            )
+            .await
+            .with_context(|| format!("Failed to initialize MCP server {}", config.name))?;
+        debug!(
+            "MCP server {} initialized with protocol version {}",
+            config.name, result["protocolVersion"]
+        );
+        client
+            .notify("notifications/initialized", json!({}))
+            .await?;
+
+        Ok(client)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn list_tools(&self) -> anyhow::Result<Vec<Tool>> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let result = self.request("tools/list", params).await?;
+
+            for tool in result["tools"].as_array().into_iter().flatten() {
+                let Some(name) = tool["name"].as_str() else {
+                    warn!("Skipping unnamed tool from MCP server {}", self.name);
+                    continue;
+                };
+                tools.push(Tool {
+                    r#type: "function".to_string(),
+                    function: FunctionDefinition {
+                        name: name.to_string(),
+                        description: tool["description"].as_str().map(str::to_string),
+                        parameters: Some(tool["inputSchema"].clone()),
+                        strict: None,
+                    },
+                });
+            }
+
+            cursor = result["nextCursor"].as_str().map(str::to_string);
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        Ok(tools)
+    }
+
+    /// Calls a tool and flattens its content into text. The flag is set when
+    /// the server reported the call as failed.
+    pub async fn call_tool(&self, name: &str, arguments: Value) -> anyhow::Result<(String, bool)> {
+        let result = self
+            .request(
+                "tools/call",
+                json!({ "name": name, "arguments": arguments }),
+            )
+            .await?;
+
+        let text: Vec<String> = result["content"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|content| match content["text"].as_str() {
+                Some(text) if content["type"] == "text" => text.to_string(),
+                _ => content.to_string(),
+            })
+            .collect();
+        let is_error = result["isError"].as_bool().unwrap_or(false);
+
+        Ok((text.join("\n"), is_error))
+    }
+
+    async fn request(&self, method: &str, params: Value) -> anyhow::Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        trace!("Sending MCP request {} to {}", method, self.name);
+
+        match &self.transport {
+            Transport::Stdio { stdin, pending, .. } => {
+                let (sender, receiver) = oneshot::channel();
+                pending.lock().unwrap().insert(id, sender);
+                let _pending = PendingGuard { pending, id };
+                write_line(stdin, &message).await?;
+                receiver
+                    .await
+                    .map_err(|_| anyhow!("MCP server {} exited", self.name))?
+            }
+            Transport::Http { .. } => {
+                let response = self.post(&message).await?;
+                let content_type = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+
+                if content_type.starts_with("text/event-stream") {
+                    let mut parser = SseParser::new();
+                    let mut body = response.bytes_stream();
+                    let mut finished = false;
+                    while !finished {
+                        let events = match body.next().await {
+                            Some(chunk) => parser.feed(&chunk?),
+                            None => {
+                                finished = true;
+                                parser.finish().into_iter().collect()
+                            }
+                        };
+                        for event in events {
+                            let Ok(message) = serde_json::from_str::<Value>(&event.data) else {
+                                continue;
+                            };
+                            if message["id"] == id {
+                                return into_result(message);
+                            }
+                        }
+                    }
+                    bail!(
+                        "MCP server {} closed the stream without a response",
+                        self.name
+                    )
+                } else {
+                    into_result(response.json().await?)
+                }
+            }
+        }
+    }
+
+    async fn notify(&self, method: &str, params: Value) -> anyhow::Result<()> {
+        let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        match &self.transport {
+            Transport::Stdio { stdin, .. } => write_line(stdin, &message).await,
+            Transport::Http { .. } => self.post(&message).await.map(|_| ()),
+        }
+    }
+
+    async fn post(&self, message: &Value) -> anyhow::Result<reqwest::Response> {
+        let Transport::Http {
+            client,
+            url,
+            headers,
+            session_id,
+        } = &self.transport
+        else {
+            unreachable!("post is only used by the HTTP transport");
+        };
+
+        let mut builder = client
+            .post(url)
+            .header(
+                reqwest::header::ACCEPT,
+                "application/json, text/event-stream",
+            )
+            .json(message);
+        for (name, value) in headers {
+            builder = builder.header(name, value);
+        }
+        let current_session = session_id.lock().unwrap().clone();
+        if let Some(current_session) = current_session {
+            builder = builder.header(SESSION_HEADER, current_session);
+        }
+
+        let response = builder.send().await?.error_for_status()?;
+        if let Some(new_session) = response
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+        {
+            *session_id.lock().unwrap() = Some(new_session.to_string());
+        }
+
+        Ok(response)
+    }
+}
+
+fn spawn_stdio(name: &str, command: &str, config: &McpServerConfig) -> anyhow::Result<Transport> {
+    let mut child = Command::new(command)
+        .args(&config.args)
+        .envs(&config.env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to launch MCP server {}", name))?;
+
+    let stdin = child.stdin.take().context("MCP server stdin unavailable")?;
+    let stdout = child
+        .stdout
+        .take()
+        .context("MCP server stdout unavailable")?;
+    let pending: PendingResponses = Arc::default();
+
+    let name = name.to_string();
+    let responses = pending.clone();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stdout).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                        warn!("Ignoring malformed output from MCP server {}", name);
+                        continue;
+                    };
+                    let Some(id) = message["id"]
+                        .as_u64()
+                        .filter(|_| message["method"].is_null())
+                    else {
+                        trace!("Ignoring MCP message from {}: {}", name, message["method"]);
+                        continue;
+                    };
+                    if let Some(sender) = responses.lock().unwrap().remove(&id) {
+                        let _ = sender.send(into_result(message));
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Failed to read from MCP server {}: {}", name, e);
+                    break;
+                }
+            }
+        }
+        warn!("MCP server {} closed its output", name);
+        responses.lock().unwrap().clear();
+    });
+
+    Ok(Transport::Stdio {
+        stdin: AsyncMutex::new(stdin),
+        pending,
+        _child: child,
+    })
+}
+
+async fn write_line(stdin: &AsyncMutex<ChildStdin>, message: &Value) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    let mut stdin = stdin.lock().await;
+    stdin.write_all(&line).await?;
+    stdin.flush().await?;
+    Ok(())
+}
+
+fn into_result(mut message: Value) -> anyhow::Result<Value> {
+    if !message["error"].is_null() {
+        bail!(
+            "MCP error {}: {}",
+            message["error"]["code"],
+            message["error"]["message"].as_str().unwrap_or_default()
+        );
+    }
+    Ok(message["result"].take())
+}
+
+/// The tools of every configured MCP server, keyed by tool name, which the
+/// proxy advertises to the model and executes on its behalf.
+pub struct McpRegistry {
+    tools: HashMap<String, (Arc<McpClient>, Tool)>,
+    pub max_tool_rounds: usize,
+    pub call_timeout: Duration,
+}
+
+impl Default for McpRegistry {
+    fn default() -> Self {
+        let config = McpConfig::default();
+        Self {
+            tools: HashMap::new(),
+            max_tool_rounds: config.max_tool_rounds,
+            call_timeout: config.call_timeout,
+        }
+    }
+}
+
+impl McpRegistry {
+    /// Connects to every server in the configuration. A server that fails to
+    /// start is logged and left out rather than stopping the proxy.
+    pub async fn connect(config: &McpConfig, http: &reqwest::Client) -> Self {
+        let mut tools: HashMap<String, (Arc<McpClient>, Tool)> = HashMap::new();
+
+        for server in &config.servers {
+            let connect =
+                tokio::time::timeout(config.call_timeout, McpClient::connect(server, http));
+            let client = match connect.await {
+                Ok(Ok(client)) => Arc::new(client),
+                Ok(Err(e)) => {
+                    error!("Failed to connect to MCP server {}: {:#}", server.name, e);
+                    continue;
+                }
+                Err(_) => {
+                    error!("Timed out connecting to MCP server {}", server.name);
+                    continue;
+                }
+            };
+            let server_tools =
+                match tokio::time::timeout(config.call_timeout, client.list_tools()).await {
+                    Ok(Ok(server_tools)) => server_tools,
+                    Ok(Err(e)) => {
+                        error!(
+                            "Failed to list tools of MCP server {}: {:#}",
+                            server.name, e
+                        );
+                        continue;
+                    }
+                    Err(_) => {
+                        error!("Timed out listing tools of MCP server {}", server.name);
+                        continue;
+                    }
+                };
+
+            info!(
+                "Connected to MCP server {} with {} tools",
+                server.name,
+                server_tools.len()
+            );
+            for tool in server_tools {
+                let name = tool.function.name.clone();
+                if let Some((existing, _)) = tools.get(&name) {
+                    warn!(
+                        "Tool {} from MCP server {} is already provided by {}, skipping",
+                        name,
+                        server.name,
+                        McpClient::name(existing)
+                    );
+                    continue;
+                }
+                tools.insert(name, (client.clone(), tool));
+            }
+        }
+
+        Self {
+            tools,
+            max_tool_rounds: config.max_tool_rounds,
+            call_timeout: config.call_timeout,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tools.contains_key(name)
+    }
+
+    pub fn tools(&self) -> impl Iterator<Item = &Tool> {
+        self.tools.values().map(|(_, tool)| tool)
+    }
+
+    /// Executes a tool call. Failures are returned as error text, so the
+    /// model can see what went wrong and carry on.
+    pub async fn call(&self, name: &str, arguments: Value) -> (String, bool) {
+        let Some((client, _)) = self.tools.get(name) else {
+            return (format!("Unknown tool: {}", name), true);
+        };
+
+        info!("Calling tool {} on MCP server {}", name, client.name());
+        match tokio::time::timeout(self.call_timeout, client.call_tool(name, arguments)).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => {
+                error!("Tool {} failed: {:#}", name, e);
+                (format!("Tool call failed: {:#}", e), true)
+            }
+            Err(_) => {
+                error!("Tool {} timed out", name);
+                (
+                    format!("Tool call timed out after {:?}", self.call_timeout),
+                    true,
+                )
+            }
+        }
+    }
+}
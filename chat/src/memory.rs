@@ -0,0 +1,76 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+pub const DEFAULT_MAX_STREAM_BYTES: usize = 16 * 1024 * 1024;
+
+pub const STREAM_BUFFERED_BYTES_METRIC: &str = "llm_proxy_stream_buffered_bytes";
+pub const STREAM_MEMORY_LIMIT_EXCEEDED_METRIC: &str =
+    "llm_proxy_stream_memory_limit_exceeded_total";
+
+static TOTAL_BUFFERED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+struct Inner {
+    used: AtomicUsize,
+    limit: usize,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let used = self.used.load(Ordering::Relaxed);
+        if used > 0 {
+            let total = TOTAL_BUFFERED_BYTES.fetch_sub(used, Ordering::Relaxed) - used;
+            metrics::gauge!(STREAM_BUFFERED_BYTES_METRIC).set(total as f64);
+        }
+    }
+}
+
+/// Accounts for the bytes one stream holds in proxy buffers, shared between
+/// the producing and consuming halves of the stream.
+#[derive(Clone)]
+pub struct StreamMemory {
+    inner: Arc<Inner>,
+}
+
+impl StreamMemory {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                used: AtomicUsize::new(0),
+                limit,
+            }),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    pub fn reserve(&self, bytes: usize) -> anyhow::Result<()> {
+        let used = self.inner.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if used > self.inner.limit {
+            self.inner.used.fetch_sub(bytes, Ordering::Relaxed);
+            metrics::counter!(STREAM_MEMORY_LIMIT_EXCEEDED_METRIC).increment(1);
+            anyhow::bail!(
+                "Stream exceeded memory limit of {} bytes ({} bytes buffered)",
+                self.inner.limit,
+                used
+            );
+        }
+
+        let total = TOTAL_BUFFERED_BYTES.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        metrics::gauge!(STREAM_BUFFERED_BYTES_METRIC).set(total as f64);
+        Ok(())
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.inner.used.fetch_sub(bytes, Ordering::Relaxed);
+        let total = TOTAL_BUFFERED_BYTES.fetch_sub(bytes, Ordering::Relaxed) - bytes;
+        metrics::gauge!(STREAM_BUFFERED_BYTES_METRIC).set(total as f64);
+    }
+}
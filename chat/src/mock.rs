@@ -0,0 +1,125 @@
+use crate::{StreamEvent, create_stream_event, providers::ChatCompletionsProvider};
+use async_trait::async_trait;
+use bytes::BytesMut;
+use chrono::offset::Utc;
+use futures::stream::{BoxStream, StreamExt};
+use request::ChatCompletionsRequest;
+use response::{ChatCompletionsResponse, ChoiceBuilder, Delta, Usage, UsageBuilder};
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+pub const MOCK_MODEL_PREFIX: &str = "mock";
+
+#[derive(Clone, Copy, Debug)]
+pub struct MockConfig {
+    pub chunks: usize,
+    pub chunk_delay: Duration,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            chunks: 64,
+            chunk_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Streams a fixed number of synthetic tokens without calling any upstream,
+/// for load-testing the proxy's own streaming path.
+pub struct MockChatCompletionsProvider {
+    config: MockConfig,
+}
+
+impl MockChatCompletionsProvider {
+    pub fn new(config: MockConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl ChatCompletionsProvider for MockChatCompletionsProvider {
+    async fn chat_completions_stream<F>(
+        self,
+        request: ChatCompletionsRequest,
+        usage_callback: F,
+    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<StreamEvent>>>
+    where
+        F: Fn(&Usage) + Send + Sync + 'static,
+    {
+        debug!("Starting mock chat completion for model: {}", request.model);
+
+        let id: Arc<str> = Uuid::new_v4().to_string().into();
+        let created = Utc::now().timestamp();
+        let model = request.model;
+        let config = self.config;
+
+        let stream = async_stream::stream! {
+            let mut buffer = BytesMut::new();
+            let mut deltas = vec![Delta::Role {
+                role: "assistant".to_string(),
+            }];
+            deltas.extend((0..config.chunks).map(|i| Delta::Content {
+                content: format!("token{} ", i),
+            }));
+
+            for (i, delta) in deltas.into_iter().enumerate() {
+                if i > 0 && !config.chunk_delay.is_zero() {
+                    tokio::time::sleep(config.chunk_delay).await;
+                }
+
+                let response = ChatCompletionsResponse::builder()
+                    .id(Some(id.clone()))
+                    .created(Some(created))
+                    .model(Some(model.clone()))
+                    .choice(ChoiceBuilder::default().delta(Some(delta)).build())
+                    .build();
+                match create_stream_event(&response, &mut buffer) {
+                    Ok(event) => yield Ok(event),
+                    Err(e) => {
+                        error!("Failed to create stream event: {}", e);
+                        yield Err(e);
+                    }
+                }
+            }
+
+            let completion_tokens = config.chunks as i32;
+            let usage = UsageBuilder::default()
+                .completion_tokens(completion_tokens)
+                .prompt_tokens(0)
+                .total_tokens(completion_tokens)
+                .build();
+            usage_callback(&usage);
+
+            let responses = [
+                ChatCompletionsResponse::builder()
+                    .choice(
+                        ChoiceBuilder::default()
+                            .finish_reason(Some("stop".to_string()))
+                            .build(),
+                    ),
+                ChatCompletionsResponse::builder().usage(Some(usage)),
+            ];
+            for builder in responses {
+                let response = builder
+                    .id(Some(id.clone()))
+                    .created(Some(created))
+                    .model(Some(model.clone()))
+                    .build();
+                match create_stream_event(&response, &mut buffer) {
+                    Ok(event) => yield Ok(event),
+                    Err(e) => {
+                        error!("Failed to create stream event: {}", e);
+                        yield Err(e);
+                    }
+                }
+            }
+
+            info!("Mock stream completed, sending DONE message");
+            yield Ok(StreamEvent::Done);
+        };
+
+        Ok(stream.boxed())
+    }
+}
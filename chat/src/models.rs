@@ -0,0 +1,317 @@
+use crate::{
+    error::ProviderError,
+    image::{has_images, has_videos},
+    session::ModelPrice,
+    tokens::estimate_prompt_tokens,
+};
+use request::ChatCompletionsRequest;
+use serde::{Deserialize, Serialize};
+
+/// The model table shipped with the proxy, in the same format as the
+/// `models_file` that overrides it.
+pub const BUNDLED_MODELS: &str = include_str!("models.toml");
+
+/// How far, in percent, the local prompt estimate may run over the real
+/// count. Only prompts over the context window by more than this are
+/// refused; the upstream, which counts exactly, judges the rest.
+const PROMPT_ESTIMATE_MARGIN_PERCENT: i64 = 25;
+
+/// Cross-region inference profiles prefix the model id with a geography.
+const REGION_PREFIXES: [&str; 5] = ["us.", "eu.", "apac.", "us-gov.", "global."];
+
+/// What the proxy knows about a model family, matched by model id prefix.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owned_by: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    #[serde(default)]
+    pub vision: bool,
+    #[serde(default)]
+    pub video: bool,
+    #[serde(default)]
+    pub tools: bool,
+    #[serde(default)]
+    pub json_mode: bool,
+    /// Tokenizer family used for local token estimates, e.g. `o200k_base`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokenizer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_per_million: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_per_million: Option<f64>,
+}
+
+impl ModelInfo {
+    pub fn owner(&self) -> &str {
+        match &self.owned_by {
+            Some(owned_by) => owned_by,
+            None if self.id.starts_with("gpt-") => "openai",
+            None => "aws-bedrock",
+        }
+    }
+
+    /// Rejects requests the model cannot serve, with an error that names the
+    /// offending parameter instead of whatever the upstream would report.
+    /// Tools are accepted for models without native support when they are
+    /// emulated through the prompt. Prompts are measured against the
+    /// context window with the local estimate, before any upstream sees
+    /// them, and refused only when clearly too long for it.
+    pub fn check(
+        &self,
+        request: &ChatCompletionsRequest,
+        tools_emulated: bool,
+    ) -> Result<(), ProviderError> {
+        if !self.vision && has_images(request) {
+            return Err(ProviderError::invalid_request(
+                format!("Model {} does not support image inputs", request.model),
+                Some("messages"),
+            )
+            .code("image_input_not_supported"));
+        }
+        if !self.video && has_videos(request) {
+            return Err(ProviderError::invalid_request(
+                format!("Model {} does not support video inputs", request.model),
+                Some("messages"),
+            )
+            .code("video_input_not_supported"));
+        }
+        if !self.tools
+            && !tools_emulated
+            && request
+                .tools
+                .as_ref()
+                .is_some_and(|tools| !tools.is_empty())
+        {
+            return Err(ProviderError::invalid_request(
+                format!("Model {} does not support tools", request.model),
+                Some("tools"),
+            )
+            .code("tools_not_supported"));
+        }
+        if let (Some(max_tokens), Some(max_output_tokens)) =
+            (request.max_tokens, self.max_output_tokens)
+            && i64::from(max_tokens) > i64::from(max_output_tokens)
+        {
+            return Err(ProviderError::invalid_request(
+                format!(
+                    "max_tokens is too large: {}. Model {} supports at most {} completion tokens",
+                    max_tokens, request.model, max_output_tokens
+                ),
+                Some("max_tokens"),
+            )
+            .code("max_tokens_too_large"));
+        }
+        if let Some(context_window) = self.context_window {
+            let prompt_tokens = estimate_prompt_tokens(request, self.tokenizer.as_deref());
+            let max_tokens = request.max_tokens.unwrap_or_default().max(0);
+            let requested = i64::from(prompt_tokens) + i64::from(max_tokens);
+            let least_prompt_tokens =
+                i64::from(prompt_tokens) * (100 - PROMPT_ESTIMATE_MARGIN_PERCENT) / 100;
+            if least_prompt_tokens + i64::from(max_tokens) > i64::from(context_window) {
+                let message = if max_tokens > 0 {
+                    format!(
+                        "This model's maximum context length is {} tokens. However, you requested about {} tokens ({} in the messages, {} in the completion). Please reduce the length of the messages or completion.",
+                        context_window, requested, prompt_tokens, max_tokens
+                    )
+                } else {
+                    format!(
+                        "This model's maximum context length is {} tokens. However, your messages resulted in about {} tokens. Please reduce the length of the messages.",
+                        context_window, prompt_tokens
+                    )
+                };
+                return Err(ProviderError::invalid_request(message, Some("messages"))
+                    .code("context_length_exceeded"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pricing(&self) -> Option<ModelPricing> {
+        Some(ModelPricing {
+            prompt_per_million: self.prompt_per_million?,
+            completion_per_million: self.completion_per_million?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelList {
+    pub object: &'static str,
+    pub data: Vec<ModelObject>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelObject {
+    pub id: String,
+    pub object: &'static str,
+    pub created: i64,
+    pub owned_by: String,
+    pub llm_proxy: ModelMetadata,
+}
+
+/// Vendor extension attached to every entry of `/v1/models`, so clients can
+/// pick a model by capability instead of hard-coding model ids.
+#[derive(Debug, Serialize)]
+pub struct ModelMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    pub capabilities: ModelCapabilities,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokenizer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pricing: Option<ModelPricing>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ModelCapabilities {
+    pub vision: bool,
+    pub video: bool,
+    pub tools: bool,
+    pub json_mode: bool,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ModelPricing {
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+}
+
+impl From<&ModelInfo> for ModelObject {
+    fn from(info: &ModelInfo) -> Self {
+        Self {
+            id: info.id.clone(),
+            object: "model",
+            created: 0,
+            owned_by: info.owner().to_string(),
+            llm_proxy: ModelMetadata {
+                context_window: info.context_window,
+                max_output_tokens: info.max_output_tokens,
+                capabilities: ModelCapabilities {
+                    vision: info.vision,
+                    video: info.video,
+                    tools: info.tools,
+                    json_mode: info.json_mode,
+                },
+                tokenizer: info.tokenizer.clone(),
+                pricing: info.pricing(),
+            },
+        }
+    }
+}
+
+/// A Bedrock model as reported by `ListFoundationModels`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FoundationModel {
+    pub model_id: String,
+    #[serde(default)]
+    pub input_modalities: Vec<String>,
+    #[serde(default)]
+    pub output_modalities: Vec<String>,
+    pub response_streaming_supported: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ModelCatalog {
+    models: Vec<ModelInfo>,
+    /// Models found upstream that are only listed, never used to check
+    /// requests, so discovering a model does not change what it accepts.
+    discovered: Vec<ModelInfo>,
+}
+
+impl ModelCatalog {
+    pub fn new(models: Vec<ModelInfo>) -> Self {
+        Self {
+            models,
+            discovered: Vec::new(),
+        }
+    }
+
+    /// Lists the Bedrock models that stream text and are not in the table,
+    /// with the details of the table entry covering them if there is one.
+    /// Returns how many were added.
+    pub fn add_foundation_models(&mut self, models: Vec<FoundationModel>) -> usize {
+        let before = self.discovered.len();
+        for model in models {
+            let streams_text = model.output_modalities.iter().any(|m| m == "TEXT")
+                && model.response_streaming_supported != Some(false);
+            let listed = self
+                .models
+                .iter()
+                .chain(&self.discovered)
+                .any(|info| info.id.eq_ignore_ascii_case(&model.model_id));
+            if !streams_text || listed {
+                continue;
+            }
+
+            let mut info = self.get(&model.model_id).cloned().unwrap_or_default();
+            info.id = model.model_id;
+            info.vision |= model.input_modalities.iter().any(|m| m == "IMAGE");
+            info.video |= model.input_modalities.iter().any(|m| m == "VIDEO");
+            self.discovered.push(info);
+        }
+        self.discovered.len() - before
+    }
+
+    /// Replaces the entries with the same id as an override and appends the
+    /// rest, so an override file only needs the models it changes.
+    pub fn merge(&mut self, overrides: Vec<ModelInfo>) {
+        for info in overrides {
+            match self.models.iter_mut().find(|model| model.id == info.id) {
+                Some(model) => *model = info,
+                None => self.models.push(info),
+            }
+        }
+    }
+
+    pub fn models(&self) -> &[ModelInfo] {
+        &self.models
+    }
+
+    /// The entry with the longest id that prefixes `model`, so an entry for a
+    /// model family also covers its dated versions and inference profiles.
+    pub fn get(&self, model: &str) -> Option<&ModelInfo> {
+        let model = model.to_lowercase();
+        let model = REGION_PREFIXES
+            .iter()
+            .find_map(|prefix| model.strip_prefix(prefix))
+            .unwrap_or(&model);
+        self.models
+            .iter()
+            .filter(|info| model.starts_with(&info.id.to_lowercase()))
+            .max_by_key(|info| info.id.len())
+    }
+
+    pub fn prices(&self) -> Vec<ModelPrice> {
+        self.models
+            .iter()
+            .filter_map(|info| {
+                let pricing = info.pricing()?;
+                Some(ModelPrice {
+                    model: info.id.clone(),
+                    prompt_per_million: pricing.prompt_per_million,
+                    completion_per_million: pricing.completion_per_million,
+                })
+            })
+            .collect()
+    }
+
+    pub fn list(&self) -> ModelList {
+        ModelList {
+            object: "list",
+            data: self
+                .models
+                .iter()
+                .chain(&self.discovered)
+                .map(ModelObject::from)
+                .collect(),
+        }
+    }
+}
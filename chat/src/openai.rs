@@ -1,36 +1,89 @@
-use crate::{DONE_MESSAGE, create_sse_event, providers::ChatCompletionsProvider};
+use crate::{
+    DONE_MESSAGE, StreamEvent, create_stream_event,
+    error::{from_openai_response, from_reqwest_error},
+    image::{ImageLimits, preprocess_images_blocking},
+    memory::DEFAULT_MAX_STREAM_BYTES,
+    providers::ChatCompletionsProvider,
+    sse::SseParser,
+    tokens::UsageEstimator,
+};
 use async_stream::stream;
 use async_trait::async_trait;
-use axum::response::sse::Event;
-use futures::StreamExt;
+use bytes::BytesMut;
 use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
 use request::{ChatCompletionsRequest, StreamOptions};
 use reqwest;
-use reqwest_streams::JsonStreamResponse as _;
 use response::{ChatCompletionsResponse, Usage};
 use tracing::{debug, error, info};
 
 pub const OPENAI_API_CHAT_COMPLETIONS_URL: &str = "https://api.openai.com/v1/chat/completions";
+pub const OPENROUTER_CHAT_COMPLETIONS_URL: &str = "https://openrouter.ai/api/v1/chat/completions";
+
+/// OpenAI rejects requests with more stop sequences; the proxy enforces the
+/// rest.
+pub const MAX_OPENAI_STOP_SEQUENCES: usize = 4;
 
 pub struct OpenAIChatCompletionsProvider {
+    client: reqwest::Client,
+    url: String,
     openai_api_key: String,
+    max_buffered_bytes: usize,
+    image_limits: ImageLimits,
+    tokenizer: Option<String>,
+    openrouter: bool,
 }
 
 impl OpenAIChatCompletionsProvider {
-    pub fn new(openai_api_key: &str) -> Self {
+    pub fn new(client: &reqwest::Client, openai_api_key: &str) -> Self {
         Self {
+            client: client.clone(),
+            url: OPENAI_API_CHAT_COMPLETIONS_URL.to_string(),
             openai_api_key: openai_api_key.to_string(),
+            max_buffered_bytes: DEFAULT_MAX_STREAM_BYTES,
+            image_limits: ImageLimits::openai(),
+            tokenizer: None,
+            openrouter: false,
         }
     }
+
+    /// Sends requests to OpenRouter, forwarding its routing fields.
+    pub fn openrouter(client: &reqwest::Client, api_key: &str) -> Self {
+        let mut provider = Self::new(client, api_key).url(OPENROUTER_CHAT_COMPLETIONS_URL);
+        provider.openrouter = true;
+        provider
+    }
+
+    /// Sends requests to an OpenAI-compatible endpoint instead of OpenAI.
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    pub fn max_buffered_bytes(mut self, max_buffered_bytes: usize) -> Self {
+        self.max_buffered_bytes = max_buffered_bytes;
+        self
+    }
+
+    pub fn image_limits(mut self, image_limits: ImageLimits) -> Self {
+        self.image_limits = image_limits;
+        self
+    }
+
+    /// Tokenizer hint for estimating usage when the upstream reports none.
+    pub fn tokenizer(mut self, tokenizer: Option<String>) -> Self {
+        self.tokenizer = tokenizer;
+        self
+    }
 }
 
 #[async_trait]
 impl ChatCompletionsProvider for OpenAIChatCompletionsProvider {
     async fn chat_completions_stream<F>(
         self,
-        mut request: ChatCompletionsRequest,
+        request: ChatCompletionsRequest,
         usage_callback: F,
-    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<Event>>>
+    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<StreamEvent>>>
     where
         F: Fn(&Usage) + Send + Sync + 'static,
     {
@@ -39,47 +92,128 @@ impl ChatCompletionsProvider for OpenAIChatCompletionsProvider {
             request.model
         );
 
+        let mut request = preprocess_images_blocking(request, &self.image_limits).await?;
+        // Non-streaming requests are collected by the proxy.
+        request.stream = Some(true);
         request.stream_options = Some(StreamOptions {
             include_usage: true,
         });
+        if let Some(stop) = &mut request.stop {
+            stop.truncate(MAX_OPENAI_STOP_SEQUENCES);
+        }
+        // Only the Messages API reads thinking blocks back.
+        for message in &mut request.messages {
+            message.thinking_blocks = None;
+        }
+        if !self.openrouter {
+            request.provider = None;
+            request.route = None;
+            request.transforms = None;
+        }
 
-        let client = reqwest::Client::new();
-        let response = client
-            .post(OPENAI_API_CHAT_COMPLETIONS_URL)
-            .header("Authorization", format!("Bearer {}", self.openai_api_key))
-            .header("Content-Type", "application/json")
+        let mut builder = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json");
+        if !self.openai_api_key.is_empty() {
+            builder = builder.header("Authorization", format!("Bearer {}", self.openai_api_key));
+        }
+        let response = builder
             .json(&request)
             .send()
-            .await?;
+            .await
+            .map_err(from_reqwest_error)?;
 
         let status = response.status();
         debug!("OpenAI API response status: {}", status);
 
         if !status.is_success() {
-            let error_text = response.text().await?;
+            let error_text = response.text().await.map_err(from_reqwest_error)?;
             error!("OpenAI API error: {} - {}", status, error_text);
-            anyhow::bail!("OpenAI API error: {} - {}", status, error_text);
+            return Err(from_openai_response(status.as_u16(), &error_text, &request.model).into());
         }
 
         info!("Successfully connected to OpenAI API, starting stream processing");
 
-        let stream = stream! {
-            let mut stream = response
-                .json_array_stream::<ChatCompletionsResponse>(1024 * 1024);
+        let estimator = UsageEstimator::new(&request, self.tokenizer);
+        Ok(chunk_stream(
+            response.bytes_stream(),
+            self.max_buffered_bytes,
+            estimator,
+            usage_callback,
+        ))
+    }
+}
+
+/// Streams the completion chunks of an OpenAI-compatible SSE body, reporting
+/// the upstream's usage or, if it sends none, an estimate.
+pub(crate) fn chunk_stream<'a, S, B, E, F>(
+    body: S,
+    max_buffered_bytes: usize,
+    mut estimator: UsageEstimator,
+    usage_callback: F,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>>
+where
+    S: Stream<Item = Result<B, E>> + Send + 'a,
+    B: AsRef<[u8]> + Send,
+    E: std::fmt::Display + Send,
+    F: Fn(&Usage) + Send + Sync + 'a,
+{
+    stream! {
+        let mut body = std::pin::pin!(body);
+        let mut parser = SseParser::new();
+        let mut buffer = BytesMut::new();
+        let mut usage_seen = false;
+        let mut last_id = None;
+        let mut last_model = None;
+
+        let mut finished = false;
+        'outer: while !finished {
+            let sse_events = match body.next().await {
+                Some(Ok(chunk)) => parser.feed(chunk.as_ref()),
+                Some(Err(e)) => {
+                    error!("Error receiving from OpenAI stream: {}", e);
+                    yield Err(anyhow::anyhow!("Stream receive error: {}", e));
+                    break;
+                }
+                // The last event may end without a blank line.
+                None => {
+                    finished = true;
+                    parser.finish().into_iter().collect()
+                }
+            };
+            if parser.buffered_len() > max_buffered_bytes {
+                error!("OpenAI stream event exceeded {} bytes", max_buffered_bytes);
+                yield Err(anyhow::anyhow!(
+                    "Upstream event exceeded memory limit of {} bytes",
+                    max_buffered_bytes
+                ));
+                break;
+            }
+
+            for sse_event in sse_events {
+                if sse_event.data == DONE_MESSAGE {
+                    debug!("Received DONE message from OpenAI");
+                    break 'outer;
+                }
 
-            while let Some(item) = stream.next().await {
-                match item {
+                match serde_json::from_str::<ChatCompletionsResponse>(&sse_event.data) {
                     Ok(response) => {
                         if let Some(usage) = &response.usage {
                             debug!("Received usage data: prompt_tokens={}, completion_tokens={}, total_tokens={}",
                                   usage.prompt_tokens, usage.completion_tokens, usage.total_tokens);
                             usage_callback(usage);
+                            usage_seen = true;
+                        } else {
+                            estimator.observe(&response);
                         }
+                        last_id = response.id.clone().or(last_id);
+                        last_model = response.model.clone().or(last_model);
 
-                        match create_sse_event(&response) {
+                        match create_stream_event(&response, &mut buffer) {
                             Ok(event) => yield Ok(event),
                             Err(e) => {
-                                error!("Failed to create SSE event: {}", e);
+                                error!("Failed to create stream event: {}", e);
                                 yield Err(e);
                             }
                         }
@@ -91,10 +225,30 @@ impl ChatCompletionsProvider for OpenAIChatCompletionsProvider {
                     }
                 }
             }
-            info!("OpenAI stream completed, sending DONE message");
-            yield Ok(Event::default().data(DONE_MESSAGE));
-        };
+        }
+        if !usage_seen {
+            let usage = estimator.usage();
+            debug!(
+                "Upstream reported no usage, estimated prompt_tokens={}, completion_tokens={}",
+                usage.prompt_tokens, usage.completion_tokens
+            );
+            usage_callback(&usage);
+            let response = ChatCompletionsResponse::builder()
+                .id(last_id)
+                .model(last_model)
+                .usage(Some(usage))
+                .build();
+            match create_stream_event(&response, &mut buffer) {
+                Ok(event) => yield Ok(event),
+                Err(e) => {
+                    error!("Failed to create stream event: {}", e);
+                    yield Err(e);
+                }
+            }
+        }
 
-        Ok(stream.boxed())
+        info!("OpenAI stream completed, sending DONE message");
+        yield Ok(StreamEvent::Done);
     }
+    .boxed()
 }
@@ -0,0 +1,87 @@
+use response::{ChatCompletionsResponse, Delta};
+use tracing::debug;
+
+/// Keeps a continuation from repeating the assistant prefill it follows.
+///
+/// Converse continues a trailing assistant message where it ends, but
+/// some models start the answer over. Text that could be the prefill again
+/// is held back until it either departs from it, and is sent, or covers
+/// all of it, which is dropped.
+pub struct PrefillFilter {
+    prefill: String,
+    held: String,
+    done: bool,
+}
+
+impl PrefillFilter {
+    pub fn new(prefill: &str) -> Option<Self> {
+        let prefill = prefill.trim();
+        if prefill.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            prefill: prefill.to_string(),
+            held: String::new(),
+            done: false,
+        })
+    }
+
+    pub fn push(&mut self, text: &str) -> String {
+        if self.done {
+            return text.to_string();
+        }
+
+        self.held.push_str(text);
+        let held = self.held.trim_start();
+        if let Some(rest) = held.strip_prefix(self.prefill.as_str()) {
+            debug!("Dropping a repeat of the assistant prefill");
+            let rest = rest.to_string();
+            self.held.clear();
+            self.done = true;
+            return rest;
+        }
+        if self.prefill.starts_with(held) {
+            return String::new();
+        }
+        self.done = true;
+        std::mem::take(&mut self.held)
+    }
+
+    pub fn flush(&mut self) -> String {
+        self.done = true;
+        std::mem::take(&mut self.held)
+    }
+
+    /// Applies the filter to one outgoing chunk. Returns `false` when the
+    /// chunk has nothing left to send.
+    pub fn filter_response(&mut self, response: &mut ChatCompletionsResponse) -> bool {
+        if self.done {
+            return true;
+        }
+
+        for choice in &mut response.choices {
+            if let Some(Delta::Content { content }) = &mut choice.delta {
+                *content = self.push(content);
+            }
+
+            if choice.finish_reason.is_some() {
+                let held = self.flush();
+                if !held.is_empty() {
+                    match &mut choice.delta {
+                        Some(Delta::Content { content }) => content.insert_str(0, &held),
+                        _ => choice.delta = Some(Delta::Content { content: held }),
+                    }
+                }
+            }
+        }
+
+        response.choices.retain(|choice| {
+            choice.finish_reason.is_some()
+                || !matches!(&choice.delta, Some(Delta::Content { content }) if content.is_empty())
+        });
+        !response.choices.is_empty()
+            || response.usage.is_some()
+            || response.guardrail_trace.is_some()
+    }
+}
@@ -0,0 +1,228 @@
+use crate::error::{ErrorKind, ProviderError};
+use chrono::Utc;
+use request::{ChatCompletionsRequest, Contents, Message, Role};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Variables every template can use without configuring them.
+pub const BUILTIN_VARIABLES: [&str; 4] = ["date", "datetime", "model", "tenant"];
+
+/// Splits a template into literal text and `{{ name }}` placeholders. An
+/// unterminated `{{` is kept as text.
+fn parts(template: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    let mut rest = template;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        match rest.find("{{").and_then(|start| {
+            let end = rest[start..].find("}}")? + start;
+            Some((start, end))
+        }) {
+            Some((start, end)) => {
+                let part = (&rest[..start], Some(rest[start + 2..end].trim()));
+                rest = &rest[end + 2..];
+                Some(part)
+            }
+            None => {
+                let part = (rest, None);
+                rest = "";
+                Some(part)
+            }
+        }
+    })
+}
+
+/// The names of the variables a template uses.
+pub fn variables(template: &str) -> impl Iterator<Item = &str> {
+    parts(template).filter_map(|(_, name)| name)
+}
+
+/// Substitutes `{{ name }}` placeholders, failing with the name of the first
+/// variable that has no value.
+pub fn render<'a>(
+    template: &str,
+    lookup: impl Fn(&str) -> Option<&'a str>,
+) -> Result<String, String> {
+    let mut output = String::with_capacity(template.len());
+    for (text, name) in parts(template) {
+        output.push_str(text);
+        if let Some(name) = name {
+            output.push_str(lookup(name).ok_or_else(|| name.to_string())?);
+        }
+    }
+    Ok(output)
+}
+
+/// Values for the built-in variables of one request.
+pub fn builtin_values(model: &str, tenant: Option<&str>) -> HashMap<&'static str, String> {
+    let now = Utc::now();
+    HashMap::from([
+        ("date", now.format("%Y-%m-%d").to_string()),
+        ("datetime", now.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        ("model", model.to_string()),
+        ("tenant", tenant.unwrap_or_default().to_string()),
+    ])
+}
+
+/// A system prompt injected into requests for models matching a prefix.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SystemPromptRule {
+    pub models: Vec<String>,
+    pub template: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SystemPrompts {
+    pub rules: Vec<SystemPromptRule>,
+    /// Custom values available to every template next to the built-in ones.
+    pub variables: HashMap<String, String>,
+}
+
+impl SystemPrompts {
+    /// Fails on a template that names a variable nobody provides, so a typo
+    /// shows up at startup instead of in every request.
+    pub fn check(&self) -> anyhow::Result<()> {
+        for rule in &self.rules {
+            if let Some(name) = variables(&rule.template).find(|name| {
+                !BUILTIN_VARIABLES.contains(name) && !self.variables.contains_key(*name)
+            }) {
+                anyhow::bail!(
+                    "System prompt for {:?} uses unknown variable {:?}",
+                    rule.models,
+                    name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn rule_for(&self, model: &str) -> Option<&SystemPromptRule> {
+        self.rules
+            .iter()
+            .filter_map(|rule| {
+                rule.models
+                    .iter()
+                    .filter(|prefix| model.starts_with(prefix.as_str()))
+                    .map(|prefix| (prefix.len(), rule))
+                    .max_by_key(|(len, _)| *len)
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, rule)| rule)
+    }
+
+    /// Renders the prompt of the rule matching the request's model and sends
+    /// it ahead of the client's messages.
+    pub fn apply(&self, request: &mut ChatCompletionsRequest, tenant: Option<&str>) {
+        let model = request.model.to_lowercase();
+        let Some(rule) = self.rule_for(&model) else {
+            return;
+        };
+
+        let builtins = builtin_values(&request.model, tenant);
+        let lookup = |name: &str| {
+            builtins
+                .get(name)
+                .or_else(|| self.variables.get(name))
+                .map(String::as_str)
+        };
+        match render(&rule.template, lookup) {
+            Ok(prompt) => request.messages.insert(0, Message::system(prompt)),
+            Err(name) => warn!("Skipping system prompt with unknown variable {}", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PromptMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+/// A named prompt kept on the server. Clients reference it with a `prompt`
+/// object and the proxy renders it into the messages of the request.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PromptTemplate {
+    /// Used when the request does not name a model.
+    #[serde(default)]
+    pub model: Option<String>,
+    pub messages: Vec<PromptMessage>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PromptTemplates {
+    pub templates: HashMap<String, PromptTemplate>,
+}
+
+impl PromptTemplates {
+    /// Renders the template the request references in front of the messages
+    /// the request brings itself. Request variables take precedence over
+    /// the built-in ones.
+    pub fn apply(
+        &self,
+        request: &mut ChatCompletionsRequest,
+        tenant: Option<&str>,
+    ) -> Result<(), ProviderError> {
+        let Some(prompt) = request.prompt.take() else {
+            return Ok(());
+        };
+        let template = self.templates.get(&prompt.id).ok_or_else(|| {
+            ProviderError::new(
+                ErrorKind::NotFound,
+                format!("No prompt template named {}", prompt.id),
+            )
+            .param("prompt.id")
+            .code("prompt_not_found")
+        })?;
+
+        if request.model.is_empty()
+            && let Some(model) = &template.model
+        {
+            request.model = model.clone();
+        }
+
+        let variables: HashMap<&str, String> = prompt
+            .variables
+            .iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    serde_json::Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                (name.as_str(), value)
+            })
+            .collect();
+        let builtins = builtin_values(&request.model, tenant);
+        let lookup = |name: &str| {
+            variables
+                .get(name)
+                .or_else(|| builtins.get(name))
+                .map(String::as_str)
+        };
+
+        let mut messages = Vec::with_capacity(template.messages.len() + request.messages.len());
+        for message in &template.messages {
+            let content = render(&message.content, lookup).map_err(|name| {
+                ProviderError::invalid_request(
+                    format!(
+                        "Prompt template {} requires the variable {}",
+                        prompt.id, name
+                    ),
+                    Some("prompt.variables"),
+                )
+            })?;
+            messages.push(Message {
+                contents: Some(Contents::String(content)),
+                name: None,
+                role: message.role,
+                tool_call_id: None,
+                tool_calls: None,
+                thinking_blocks: None,
+            });
+        }
+        messages.append(&mut request.messages);
+        request.messages = messages;
+        Ok(())
+    }
+}
@@ -1,18 +1,51 @@
 use crate::{
-    DONE_MESSAGE, ProcessChatCompletionsRequest,
-    bedrock::{BedrockChatCompletion, process_chat_completions_request_to_bedrock_chat_completion},
-    create_sse_event,
+    ProcessChatCompletionsRequest, StreamEvent,
+    bedrock::{
+        BedrockChatCompletion, apply_extra_body, apply_prompt_caching, apply_reasoning_effort,
+        check_logit_bias, penalty_fields, prefill,
+        process_chat_completions_request_to_bedrock_chat_completion,
+    },
+    create_stream_event,
+    emulation::{EmulationFormat, ToolCallParser, emulate_tools},
+    error::{ErrorKind, ProviderError, from_bedrock_error},
+    guardrails::{guardrail_stream_config, guardrail_trace_json},
+    image::{ImageFetcher, ImageLimits, preprocess_images_blocking},
+    prefill::PrefillFilter,
+    stop::StopSequenceFilter,
+    store::CompletionAccumulator,
+    tiers::ServiceTier,
+    tokens::UsageEstimator,
+    tools::ServerTools,
 };
 use async_trait::async_trait;
-use aws_config::BehaviorVersion;
-use aws_sdk_bedrockruntime::Client;
-use axum::response::sse::Event;
+use aws_sdk_bedrockruntime::{
+    Client,
+    primitives::event_stream::EventReceiver,
+    types::{
+        ContentBlock, ContentBlockDelta, ContentBlockDeltaEvent, ContentBlockStart,
+        ContentBlockStartEvent, ContentBlockStopEvent, ConversationRole, ConverseStreamOutput,
+        Message, StopReason, ToolResultBlock, ToolResultContentBlock, ToolResultStatus,
+        ToolUseBlock, error::ConverseStreamOutputError,
+    },
+};
+use bytes::BytesMut;
 use chrono::offset::Utc;
-use futures::stream::{BoxStream, StreamExt};
-use request::ChatCompletionsRequest;
-use response::{Usage, converse_stream_output_to_chat_completions_response_builder};
-use std::sync::Arc;
-use tracing::{debug, error, info, trace};
+use futures::{
+    future::join_all,
+    stream::{BoxStream, StreamExt},
+};
+use request::{ChatCompletionsRequest, Guardrail, document::json_to_document, schema::StrictTools};
+use response::{
+    ChatCompletionsResponse, Delta, ToolCallTracker, Usage,
+    converse_stream_output_to_chat_completions_response_builder,
+};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, btree_map::Entry},
+    mem,
+    sync::Arc,
+};
+use tracing::{debug, error, info, trace, warn};
 use uuid::Uuid;
 
 #[async_trait]
@@ -21,35 +54,628 @@ pub trait ChatCompletionsProvider {
         self,
         request: ChatCompletionsRequest,
         usage_callback: F,
-    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<Event>>>
+    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<StreamEvent>>>
     where
         F: Fn(&Usage) + Send + Sync + 'static;
 }
 
-pub struct BedrockChatCompletionsProvider {}
+/// Reads a completion stream to the end and folds it into a single
+/// `chat.completion` object, for clients that did not ask for streaming.
+pub async fn collect_completion(
+    mut stream: BoxStream<'_, anyhow::Result<StreamEvent>>,
+    model: &str,
+) -> anyhow::Result<Value> {
+    let mut accumulator = CompletionAccumulator::default();
+    while let Some(event) = stream.next().await {
+        match event? {
+            StreamEvent::Chunk(data) => accumulator.push(serde_json::from_slice(&data)?),
+            StreamEvent::Dropped(count) => {
+                warn!("{} chunks were dropped from a collected completion", count)
+            }
+            StreamEvent::Done => break,
+        }
+    }
+    Ok(accumulator.into_completion(model))
+}
+
+pub struct BedrockChatCompletionsProvider {
+    client: Client,
+    server_tools: Option<ServerTools>,
+    tool_emulation: Option<EmulationFormat>,
+    image_limits: ImageLimits,
+    image_fetcher: Option<ImageFetcher>,
+    strict_parameters: bool,
+    reasoning_content: bool,
+    guardrail: Option<Guardrail>,
+    cache_system_prompt_tokens: Option<i32>,
+    service_tier: Option<ServiceTier>,
+}
 
 impl BedrockChatCompletionsProvider {
-    pub async fn new() -> Self {
-        Self {}
+    pub fn new(client: &Client) -> Self {
+        Self {
+            client: client.clone(),
+            server_tools: None,
+            tool_emulation: None,
+            image_limits: ImageLimits::bedrock(),
+            image_fetcher: None,
+            strict_parameters: false,
+            reasoning_content: true,
+            guardrail: None,
+            cache_system_prompt_tokens: None,
+            service_tier: None,
+        }
+    }
+
+    /// Advertises the server-side tools to the model and runs the calls it
+    /// makes to them in the proxy, looping until a final answer.
+    pub fn server_tools(mut self, server_tools: ServerTools) -> Self {
+        if !server_tools.is_empty() {
+            self.server_tools = Some(server_tools);
+        }
+        self
+    }
+
+    /// Describes the request's tools in a prompt and parses the calls back
+    /// out of the text, for models without native tool use.
+    pub fn tool_emulation(mut self, format: Option<EmulationFormat>) -> Self {
+        self.tool_emulation = format;
+        self
+    }
+
+    pub fn image_limits(mut self, image_limits: ImageLimits) -> Self {
+        self.image_limits = image_limits;
+        self
+    }
+
+    /// Inlines remote image URLs, which Bedrock does not fetch itself.
+    /// Without a fetcher, requests with them are rejected.
+    pub fn image_fetcher(mut self, image_fetcher: Option<ImageFetcher>) -> Self {
+        self.image_fetcher = image_fetcher;
+        self
+    }
+
+    /// Rejects parameters the model cannot honor instead of dropping them
+    /// with a warning.
+    pub fn strict_parameters(mut self, strict_parameters: bool) -> Self {
+        self.strict_parameters = strict_parameters;
+        self
     }
+
+    /// Streams the thinking of reasoning models as `reasoning_content`
+    /// deltas; without it the thinking is dropped.
+    pub fn reasoning_content(mut self, reasoning_content: bool) -> Self {
+        self.reasoning_content = reasoning_content;
+        self
+    }
+
+    /// The guardrail the config sets for the model, which takes the place
+    /// of any the request names.
+    pub fn guardrail(mut self, guardrail: Option<Guardrail>) -> Self {
+        self.guardrail = guardrail;
+        self
+    }
+
+    /// Caches system prompts of at least this many estimated tokens on
+    /// models with prompt caching, unless the request marks its own.
+    pub fn cache_system_prompt_tokens(mut self, tokens: Option<i32>) -> Self {
+        self.cache_system_prompt_tokens = tokens;
+        self
+    }
+
+    /// The tier the config resolved for the request, reported in the
+    /// response. Its model id, if any, serves the request in place of the
+    /// model's own.
+    pub fn service_tier(mut self, service_tier: Option<ServiceTier>) -> Self {
+        self.service_tier = service_tier;
+        self
+    }
+
+    async fn prepare(
+        &self,
+        mut request: ChatCompletionsRequest,
+    ) -> anyhow::Result<PreparedRequest> {
+        if let Some(image_fetcher) = &self.image_fetcher {
+            image_fetcher.inline_images(&mut request).await?;
+        }
+        let mut request = preprocess_images_blocking(request, &self.image_limits).await?;
+
+        let tools_disabled = request
+            .tool_choice
+            .as_ref()
+            .is_some_and(|tool_choice| tool_choice.disables_tools());
+        let has_tools = request
+            .tools
+            .as_ref()
+            .is_some_and(|tools| !tools.is_empty());
+        let emulation = self.tool_emulation.filter(|_| has_tools && !tools_disabled);
+        let server_tools = self
+            .server_tools
+            .clone()
+            .filter(|_| !tools_disabled && self.tool_emulation.is_none());
+        if let Some(server_tools) = &server_tools {
+            merge_server_tools(&mut request, server_tools);
+        }
+        if let Some(format) = emulation {
+            emulate_tools(&mut request, format);
+        }
+
+        let completion = self.process_chat_completions_request(&request)?;
+        info!(
+            "Processed request to Bedrock format with {} messages",
+            completion.messages.len()
+        );
+        Ok(PreparedRequest {
+            request,
+            completion,
+            emulation,
+            server_tools,
+        })
+    }
+
+    /// The Converse request the proxy would send for `request`, with images
+    /// preprocessed and server-side or emulated tools applied, for
+    /// debugging the mapping without invoking the model.
+    pub async fn converse_request(
+        &self,
+        request: ChatCompletionsRequest,
+    ) -> anyhow::Result<BedrockChatCompletion> {
+        Ok(self.prepare(request).await?.completion)
+    }
+}
+
+/// A request converted for Converse, with the tool handling it needs.
+struct PreparedRequest {
+    request: ChatCompletionsRequest,
+    completion: BedrockChatCompletion,
+    emulation: Option<EmulationFormat>,
+    server_tools: Option<ServerTools>,
 }
 
-impl ProcessChatCompletionsRequest<BedrockChatCompletion> for BedrockChatCompletionsProvider {
+impl ProcessChatCompletionsRequest<Result<BedrockChatCompletion, ProviderError>>
+    for BedrockChatCompletionsProvider
+{
     fn process_chat_completions_request(
         &self,
         request: &ChatCompletionsRequest,
-    ) -> BedrockChatCompletion {
-        process_chat_completions_request_to_bedrock_chat_completion(request)
+    ) -> Result<BedrockChatCompletion, ProviderError> {
+        check_logit_bias(request)?;
+        let mut completion = process_chat_completions_request_to_bedrock_chat_completion(request)?;
+        completion.additional_model_request_fields =
+            penalty_fields(request, self.strict_parameters)?;
+        apply_reasoning_effort(&mut completion, request, self.strict_parameters)?;
+        apply_extra_body(&mut completion, request);
+        apply_prompt_caching(&mut completion, self.cache_system_prompt_tokens);
+        if let Some(guardrail) = &self.guardrail {
+            completion.guardrail = Some(guardrail.clone());
+        }
+        // Last, as the model checks above go by the model's own id.
+        if let Some(model_id) = self
+            .service_tier
+            .as_ref()
+            .and_then(|tier| tier.model_id.as_ref())
+        {
+            debug!("Serving the request with {}", model_id);
+            completion.model_id = model_id.clone();
+        }
+        Ok(completion)
+    }
+}
+
+type ConverseEventReceiver = EventReceiver<ConverseStreamOutput, ConverseStreamOutputError>;
+
+async fn send_converse_stream(
+    client: &Client,
+    completion: &BedrockChatCompletion,
+    messages: Vec<Message>,
+    model: &str,
+) -> Result<ConverseEventReceiver, ProviderError> {
+    Ok(client
+        .converse_stream()
+        .model_id(&completion.model_id)
+        .set_system(Some(completion.system_content_blocks.clone()))
+        .set_messages(Some(messages))
+        .set_tool_config(completion.tool_config.clone())
+        .set_inference_config(completion.inference_config.clone())
+        .set_additional_model_request_fields(completion.additional_model_request_fields.clone())
+        .set_request_metadata(completion.request_metadata.clone())
+        .set_guardrail_config(
+            completion
+                .guardrail
+                .as_ref()
+                .and_then(guardrail_stream_config),
+        )
+        .send()
+        .await
+        .map_err(|e| from_bedrock_error(e, model))?
+        .stream)
+}
+
+fn merge_server_tools(request: &mut ChatCompletionsRequest, server_tools: &ServerTools) {
+    let tools = request.tools.get_or_insert_default();
+    for tool in server_tools.tools() {
+        if tools.iter().any(|t| t.function.name == tool.function.name) {
+            debug!(
+                "Client tool {} shadows the server-side tool of the same name",
+                tool.function.name
+            );
+            continue;
+        }
+        tools.push(tool.clone());
+    }
+}
+
+enum TurnBlock {
+    Text(String),
+    ToolUse {
+        id: String,
+        name: String,
+        input: String,
+    },
+}
+
+/// The assistant message of one model turn, reassembled from the stream so
+/// it can be replayed when the proxy answers the model's tool calls itself.
+#[derive(Default)]
+struct AgentTurn {
+    blocks: BTreeMap<i32, TurnBlock>,
+}
+
+impl AgentTurn {
+    fn observe(&mut self, output: &ConverseStreamOutput) {
+        match output {
+            ConverseStreamOutput::ContentBlockStart(event) => {
+                if let Some(ContentBlockStart::ToolUse(start)) = &event.start {
+                    self.blocks.insert(
+                        event.content_block_index,
+                        TurnBlock::ToolUse {
+                            id: start.tool_use_id.clone(),
+                            name: start.name.clone(),
+                            input: String::new(),
+                        },
+                    );
+                }
+            }
+            ConverseStreamOutput::ContentBlockDelta(event) => {
+                let block = self.blocks.entry(event.content_block_index);
+                match (&event.delta, block) {
+                    (Some(ContentBlockDelta::Text(text)), block) => {
+                        if let TurnBlock::Text(content) =
+                            block.or_insert_with(|| TurnBlock::Text(String::new()))
+                        {
+                            content.push_str(text);
+                        }
+                    }
+                    (Some(ContentBlockDelta::ToolUse(delta)), Entry::Occupied(mut block)) => {
+                        if let TurnBlock::ToolUse { input, .. } = block.get_mut() {
+                            input.push_str(&delta.input);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the block is a call of a server-side tool, which the client
+    /// should not see.
+    fn is_server_call(&self, index: i32, server_tools: &ServerTools) -> bool {
+        matches!(
+            self.blocks.get(&index),
+            Some(TurnBlock::ToolUse { name, .. }) if server_tools.contains(name)
+        )
+    }
+
+    fn calls_only(&self, server_tools: &ServerTools) -> bool {
+        let mut names = self.blocks.values().filter_map(|block| match block {
+            TurnBlock::ToolUse { name, .. } => Some(name),
+            TurnBlock::Text(_) => None,
+        });
+        names.clone().next().is_some() && names.all(|name| server_tools.contains(name))
+    }
+
+    /// Runs the turn's tool calls and returns the assistant message followed
+    /// by the user message carrying the results.
+    async fn execute(&mut self, server_tools: &ServerTools) -> anyhow::Result<[Message; 2]> {
+        let mut content = Vec::new();
+        let mut calls = Vec::new();
+
+        for block in mem::take(&mut self.blocks).into_values() {
+            match block {
+                TurnBlock::Text(text) if !text.trim().is_empty() => {
+                    content.push(ContentBlock::Text(text))
+                }
+                TurnBlock::Text(_) => {}
+                TurnBlock::ToolUse { id, name, input } => {
+                    let arguments = if input.trim().is_empty() {
+                        serde_json::json!({})
+                    } else {
+                        serde_json::from_str(&input).unwrap_or_else(|e| {
+                            warn!("Tool {} was called with invalid arguments: {}", name, e);
+                            serde_json::json!({})
+                        })
+                    };
+                    content.push(ContentBlock::ToolUse(
+                        ToolUseBlock::builder()
+                            .tool_use_id(&id)
+                            .name(&name)
+                            .input(json_to_document(&arguments))
+                            .build()?,
+                    ));
+                    calls.push((id, name, arguments));
+                }
+            }
+        }
+
+        let outputs = join_all(
+            calls
+                .iter()
+                .map(|(_, name, arguments)| server_tools.call(name, arguments.clone())),
+        )
+        .await;
+
+        let mut results = Vec::new();
+        for ((id, _, _), (output, is_error)) in calls.into_iter().zip(outputs) {
+            results.push(ContentBlock::ToolResult(
+                ToolResultBlock::builder()
+                    .tool_use_id(id)
+                    .content(ToolResultContentBlock::Text(output))
+                    .status(if is_error {
+                        ToolResultStatus::Error
+                    } else {
+                        ToolResultStatus::Success
+                    })
+                    .build()?,
+            ));
+        }
+
+        Ok([
+            Message::builder()
+                .role(ConversationRole::Assistant)
+                .set_content(Some(content))
+                .build()?,
+            Message::builder()
+                .role(ConversationRole::User)
+                .set_content(Some(results))
+                .build()?,
+        ])
     }
 }
 
+/// How the calls a model makes are handled, for each choice.
+#[derive(Clone)]
+struct ToolHandling {
+    emulation: Option<EmulationFormat>,
+    server_tools: Option<ServerTools>,
+    strict_tools: Option<StrictTools>,
+}
+
+/// Checks the arguments of the response's calls to strict tools, which
+/// Bedrock does not hold the model to.
+fn check_tool_arguments(
+    response: &ChatCompletionsResponse,
+    strict_tools: &StrictTools,
+) -> Result<(), String> {
+    for choice in &response.choices {
+        if let Some(Delta::ToolCalls { tool_calls }) = &choice.delta {
+            for call in tool_calls {
+                strict_tools
+                    .check(&call.function.name, &call.function.arguments)
+                    .map_err(|e| {
+                        format!(
+                            "the model called {} with arguments that do not match its schema: {}",
+                            call.function.name, e
+                        )
+                    })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Streams one choice of a completion from its first ConverseStream
+/// response, running server-side tool rounds and filtering each chunk.
+/// Chunks carry choice index 0 and no id; usage comes in a chunk of its own.
+/// A stop sequence matched here ends the choice without reading the rest of
+/// the completion, and its usage is then estimated.
+fn choice_stream(
+    client: Client,
+    completion: Arc<BedrockChatCompletion>,
+    mut messages: Vec<Message>,
+    mut stream: ConverseEventReceiver,
+    model: String,
+    tools: ToolHandling,
+    mut estimator: UsageEstimator,
+) -> BoxStream<'static, anyhow::Result<ChatCompletionsResponse>> {
+    let ToolHandling {
+        emulation,
+        server_tools,
+        strict_tools,
+    } = tools;
+    let mut prefill_filter = prefill(&messages).as_deref().and_then(PrefillFilter::new);
+    let mut stop_filter = StopSequenceFilter::new(completion.client_stop_sequences.clone());
+    let mut tool_call_parser = emulation.map(ToolCallParser::new);
+    let stream = async_stream::stream! {
+        trace!("Starting to process stream");
+        let mut turn = AgentTurn::default();
+        // Strict tools are checked on whole calls.
+        let mut tool_calls = match strict_tools {
+            Some(_) => ToolCallTracker::buffered(),
+            None => ToolCallTracker::default(),
+        };
+        let mut rounds = 0;
+        let mut prior_usage = (0, 0, 0, 0, 0);
+        let mut stopped_early = false;
+        'turns: loop {
+            let mut run_tools = false;
+            loop {
+                match stream.recv().await {
+                    Ok(Some(mut output)) => {
+                        trace!("Received output from Bedrock stream");
+                        if let Some(server_tools) = &server_tools {
+                            turn.observe(&output);
+                            match &mut output {
+                                ConverseStreamOutput::MessageStart(_) if rounds > 0 => continue,
+                                ConverseStreamOutput::ContentBlockStart(ContentBlockStartEvent { content_block_index, .. })
+                                | ConverseStreamOutput::ContentBlockDelta(ContentBlockDeltaEvent { content_block_index, .. })
+                                | ConverseStreamOutput::ContentBlockStop(ContentBlockStopEvent { content_block_index, .. })
+                                    if turn.is_server_call(*content_block_index, server_tools) =>
+                                {
+                                    continue
+                                }
+                                ConverseStreamOutput::MessageStop(event)
+                                    if event.stop_reason == StopReason::ToolUse
+                                        && turn.calls_only(server_tools) =>
+                                {
+                                    if rounds < server_tools.max_tool_rounds() {
+                                        run_tools = true;
+                                        continue;
+                                    }
+                                    warn!("Reached the limit of {} tool rounds", server_tools.max_tool_rounds());
+                                }
+                                ConverseStreamOutput::Metadata(event) => {
+                                    if let Some(usage) = &mut event.usage {
+                                        if run_tools {
+                                            prior_usage.0 += usage.input_tokens;
+                                            prior_usage.1 += usage.output_tokens;
+                                            prior_usage.2 += usage.total_tokens;
+                                            prior_usage.3 += usage.cache_read_input_tokens.unwrap_or_default();
+                                            prior_usage.4 += usage.cache_write_input_tokens.unwrap_or_default();
+                                        } else {
+                                            usage.input_tokens += prior_usage.0;
+                                            usage.output_tokens += prior_usage.1;
+                                            usage.total_tokens += prior_usage.2;
+                                            if prior_usage.3 != 0 {
+                                                *usage.cache_read_input_tokens.get_or_insert_default() += prior_usage.3;
+                                            }
+                                            if prior_usage.4 != 0 {
+                                                *usage.cache_write_input_tokens.get_or_insert_default() += prior_usage.4;
+                                            }
+                                        }
+                                    }
+                                    if run_tools {
+                                        continue;
+                                    }
+                                }
+                                _ => {}
+                            }
+                        }
+
+                        let guardrail_trace = match &output {
+                            ConverseStreamOutput::Metadata(event) => event
+                                .trace
+                                .as_ref()
+                                .and_then(|trace| trace.guardrail.as_ref())
+                                .map(guardrail_trace_json),
+                            _ => None,
+                        };
+                        let mut response = converse_stream_output_to_chat_completions_response_builder(output, &mut tool_calls, &|_| {})
+                            .guardrail_trace(guardrail_trace)
+                            .build();
+
+                        if let Some(prefill_filter) = prefill_filter.as_mut()
+                            && !prefill_filter.filter_response(&mut response)
+                        {
+                            continue;
+                        }
+
+                        if let Some(parser) = tool_call_parser.as_mut()
+                            && !parser.filter_response(&mut response)
+                        {
+                            continue;
+                        }
+
+                        if let Some(stop_filter) = stop_filter.as_mut() {
+                            let stopped = stop_filter.is_stopped();
+                            if !stop_filter.filter_response(&mut response) {
+                                continue;
+                            }
+                            stopped_early = !stopped && stop_filter.is_stopped();
+                        }
+
+                        if let Some(strict_tools) = &strict_tools
+                            && let Err(e) = check_tool_arguments(&response, strict_tools)
+                        {
+                            error!("Rejecting tool call: {}", e);
+                            yield Err(ProviderError::new(ErrorKind::Upstream, e).into());
+                            break 'turns;
+                        }
+
+                        estimator.observe(&response);
+                        yield Ok(response);
+                        if stopped_early {
+                            break 'turns;
+                        }
+                    }
+                    Ok(None) => {
+                        debug!("Stream completed");
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Error receiving from stream: {}", e);
+                        yield Err(from_bedrock_error(e, &model).into());
+                        break 'turns;
+                    }
+                }
+            }
+
+            let Some(server_tools) = server_tools.as_ref().filter(|_| run_tools) else {
+                break;
+            };
+            rounds += 1;
+            tool_calls.reset();
+            info!("Executing server-side tool calls, round {}", rounds);
+            match turn.execute(server_tools).await {
+                Ok([assistant, results]) => {
+                    // A prefill and the turn continuing it make one message.
+                    match messages.last_mut() {
+                        Some(last) if last.role == ConversationRole::Assistant => {
+                            last.content.extend(assistant.content)
+                        }
+                        _ => messages.push(assistant),
+                    }
+                    messages.push(results);
+                }
+                Err(e) => {
+                    error!("Failed to build tool round messages: {}", e);
+                    yield Err(ProviderError::new(ErrorKind::Internal, e.to_string()).into());
+                    break;
+                }
+            }
+            match send_converse_stream(&client, &completion, messages.clone(), &model).await {
+                Ok(next) => stream = next,
+                Err(e) => {
+                    error!("Failed to continue Bedrock conversation: {}", e);
+                    yield Err(e.into());
+                    break;
+                }
+            }
+        }
+        if !stopped_early {
+            return;
+        }
+
+        debug!("Stop sequence matched, dropping the Bedrock stream");
+        drop(stream);
+        let mut usage = estimator.usage();
+        usage.prompt_tokens += prior_usage.0;
+        usage.completion_tokens += prior_usage.1;
+        usage.total_tokens += prior_usage.2;
+        yield Ok(ChatCompletionsResponse::builder().usage(Some(usage)).build());
+    };
+    stream.boxed()
+}
+
 #[async_trait]
 impl ChatCompletionsProvider for BedrockChatCompletionsProvider {
+    /// With `n` above 1, sends one Converse request per choice and
+    /// interleaves their chunks, reporting the usage of all of them.
     async fn chat_completions_stream<F>(
         self,
         request: ChatCompletionsRequest,
         usage_callback: F,
-    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<Event>>>
+    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<StreamEvent>>>
     where
         F: Fn(&Usage) + Send + Sync + 'static,
     {
@@ -57,76 +683,130 @@ impl ChatCompletionsProvider for BedrockChatCompletionsProvider {
             "Processing chat completions request for model: {}",
             request.model
         );
-        let bedrock_chat_completion = self.process_chat_completions_request(&request);
-        info!(
-            "Processed request to Bedrock format with {} messages",
-            bedrock_chat_completion.messages.len()
-        );
-
-        debug!("Loading AWS config");
-        let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-        let client = Client::new(&config);
+        let PreparedRequest {
+            request,
+            completion: mut bedrock_chat_completion,
+            emulation,
+            server_tools,
+        } = self.prepare(request).await?;
 
         info!(
             "Sending request to Bedrock API for model: {}",
             bedrock_chat_completion.model_id
         );
-        let mut stream = client
-            .converse_stream()
-            .model_id(&bedrock_chat_completion.model_id)
-            .set_system(Some(bedrock_chat_completion.system_content_blocks))
-            .set_messages(Some(bedrock_chat_completion.messages))
-            .send()
-            .await?
-            .stream;
+        let tools = ToolHandling {
+            emulation,
+            server_tools,
+            strict_tools: StrictTools::from_request(&request),
+        };
+        let choices = request.n.unwrap_or(1).max(1);
+        let estimator = UsageEstimator::new(&request, None);
+        // Choices that fail before their Metadata are still billed.
+        let mut estimators = vec![estimator.clone(); choices as usize];
+        let messages = mem::take(&mut bedrock_chat_completion.messages);
+        let completion = Arc::new(bedrock_chat_completion);
+        let streams = join_all((0..choices).map(|_| {
+            send_converse_stream(&self.client, &completion, messages.clone(), &request.model)
+        }))
+        .await;
         info!("Successfully connected to Bedrock stream");
 
-        let id = Uuid::new_v4().to_string();
+        let mut choice_streams = Vec::new();
+        for (index, stream) in streams.into_iter().enumerate() {
+            let choice = choice_stream(
+                self.client.clone(),
+                completion.clone(),
+                messages.clone(),
+                stream?,
+                request.model.clone(),
+                tools.clone(),
+                estimator.clone(),
+            );
+            choice_streams.push(choice.map(move |response| (index as i32, response)));
+        }
+
+        let id: Arc<str> = Uuid::new_v4().to_string().into();
         let created = Utc::now().timestamp();
         debug!("Created response with id: {}", id);
 
-        let usage_callback = Arc::new(usage_callback);
-
+        let reasoning_content = self.reasoning_content;
+        let service_tier = self.service_tier.map(|tier| tier.tier);
+        let mut choice_streams = futures::stream::select_all(choice_streams);
         let stream = async_stream::stream! {
-            trace!("Starting to process stream");
-            loop {
-                match stream.recv().await {
-                    Ok(Some(output)) => {
-                        trace!("Received output from Bedrock stream");
-                        let usage_callback = usage_callback.clone();
-                        let builder = converse_stream_output_to_chat_completions_response_builder(&output, usage_callback);
-                        let response = builder
-                            .id(Some(id.clone()))
-                            .created(Some(created))
-                            .build();
-
-                        match create_sse_event(&response) {
-                            Ok(event) => {
-                                trace!("Created SSE event");
-                                yield Ok(event);
-                            },
-                            Err(e) => {
-                                error!("Failed to create SSE event: {}", e);
-                                yield Err(e);
-                            }
+            let mut buffer = BytesMut::new();
+            let mut usage: Option<Usage> = None;
+            let mut reported = vec![false; estimators.len()];
+            while let Some((index, response)) = choice_streams.next().await {
+                let mut response = match response {
+                    Ok(response) => response,
+                    Err(e) => {
+                        for (estimator, _) in estimators
+                            .iter()
+                            .zip(&reported)
+                            .filter(|(_, reported)| !**reported)
+                        {
+                            usage.get_or_insert_default().add(&estimator.usage());
+                        }
+                        if let Some(usage) = &usage {
+                            usage_callback(usage);
                         }
+                        yield Err(e);
+                        return;
                     }
-                    Ok(None) => {
-                        debug!("Stream completed");
-                        break;
+                };
+                if let Some(choice_usage) = response.usage.take() {
+                    usage.get_or_insert_default().add(&choice_usage);
+                    reported[index as usize] = true;
+                } else {
+                    estimators[index as usize].observe(&response);
+                }
+                if !reasoning_content {
+                    response.choices.retain(|choice| {
+                        choice.finish_reason.is_some()
+                            || !matches!(choice.delta, Some(Delta::Reasoning { .. }))
+                    });
+                }
+                if response.choices.is_empty() && response.guardrail_trace.is_none() {
+                    continue;
+                }
+                for choice in &mut response.choices {
+                    choice.index = index;
+                }
+                response.id = Some(id.clone());
+                response.created = Some(created);
+                response.service_tier = service_tier.clone();
+
+                match create_stream_event(&response, &mut buffer) {
+                    Ok(event) => {
+                        trace!("Created stream event");
+                        yield Ok(event);
+                    },
+                    Err(e) => {
+                        error!("Failed to create stream event: {}", e);
+                        yield Err(e);
                     }
+                }
+            }
+
+            if let Some(usage) = usage {
+                usage_callback(&usage);
+                let response = ChatCompletionsResponse::builder()
+                    .id(Some(id.clone()))
+                    .created(Some(created))
+                    .service_tier(service_tier.clone())
+                    .usage(Some(usage))
+                    .build();
+                match create_stream_event(&response, &mut buffer) {
+                    Ok(event) => yield Ok(event),
                     Err(e) => {
-                        error!("Error receiving from stream: {}", e);
-                        yield Err(anyhow::anyhow!(
-                            "Stream receive error: {}",
-                            e
-                        ));
+                        error!("Failed to create stream event: {}", e);
+                        yield Err(e);
                     }
                 }
             }
 
             info!("Stream finished, sending DONE message");
-            yield Ok(Event::default().data(DONE_MESSAGE));
+            yield Ok(StreamEvent::Done);
         };
 
         Ok(stream.boxed())
@@ -0,0 +1,252 @@
+use crate::error::ProviderError;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use tracing::{error, info};
+
+/// The prefix of the environment variables runtime providers may take their
+/// API key from, so the admin API can't send any other secret of the
+/// process to a host of its choosing.
+pub const PROVIDER_KEY_ENV_PREFIX: &str = "LLM_PROXY_PROVIDER_";
+
+/// An OpenAI-compatible upstream added at runtime or defined in the
+/// configuration. Runtime providers reference the API key by the name of an
+/// environment variable so that secrets never pass through the admin API or
+/// land in the store; configured ones may also set it inline.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DynamicProvider {
+    #[serde(default)]
+    pub name: String,
+    pub base_url: String,
+    #[serde(default, skip_serializing)]
+    pub api_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+    /// Model id prefixes routed to this provider.
+    pub models: Vec<String>,
+}
+
+impl DynamicProvider {
+    pub fn chat_completions_url(&self) -> String {
+        format!("{}/chat/completions", self.base_url.trim_end_matches('/'))
+    }
+
+    pub fn api_key(&self) -> anyhow::Result<Option<String>> {
+        if let Some(api_key) = &self.api_key {
+            return Ok(Some(api_key.clone()));
+        }
+        match &self.api_key_env {
+            Some(var) => std::env::var(var).map(Some).map_err(|_| {
+                anyhow::anyhow!(
+                    "Provider {} references environment variable {}, which is not set",
+                    self.name,
+                    var
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ProviderError> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ProviderError::invalid_request(
+                "Provider names may only contain letters, digits, - and _",
+                Some("name"),
+            ));
+        }
+        match reqwest::Url::parse(&self.base_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => {
+                return Err(ProviderError::invalid_request(
+                    format!("{} is not a valid http(s) URL", self.base_url),
+                    Some("base_url"),
+                ));
+            }
+        }
+        if self.models.is_empty() || self.models.iter().any(|model| model.is_empty()) {
+            return Err(ProviderError::invalid_request(
+                "models must list at least one non-empty model prefix",
+                Some("models"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Runtime providers may only read their key from the variables set
+    /// aside for them.
+    fn validate_key_env(&self) -> Result<(), ProviderError> {
+        match &self.api_key_env {
+            Some(var)
+                if var.len() <= PROVIDER_KEY_ENV_PREFIX.len()
+                    || !var.starts_with(PROVIDER_KEY_ENV_PREFIX) =>
+            {
+                Err(ProviderError::invalid_request(
+                    format!(
+                        "api_key_env must name a variable starting with {}",
+                        PROVIDER_KEY_ENV_PREFIX
+                    ),
+                    Some("api_key_env"),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Providers from the configuration, plus those managed through the admin
+/// API, which are persisted to a JSON file when one is configured. Requests
+/// route with a snapshot of the provider, so changing or removing it leaves
+/// streams already in flight alone.
+#[derive(Default)]
+pub struct ProviderRegistry {
+    path: Option<PathBuf>,
+    configured: Vec<Arc<DynamicProvider>>,
+    providers: RwLock<Vec<Arc<DynamicProvider>>>,
+    /// Serializes changes so the file always reflects the latest one.
+    changes: tokio::sync::Mutex<()>,
+}
+
+impl ProviderRegistry {
+    pub async fn load(
+        path: Option<PathBuf>,
+        configured: Vec<DynamicProvider>,
+    ) -> anyhow::Result<Self> {
+        for provider in &configured {
+            provider
+                .validate()
+                .map_err(|e| anyhow::anyhow!("Invalid provider {}: {}", provider.name, e))?;
+        }
+        let providers = match &path {
+            Some(path) if tokio::fs::try_exists(path).await? => {
+                let providers: Vec<DynamicProvider> =
+                    serde_json::from_slice(&tokio::fs::read(path).await?)?;
+                for provider in &providers {
+                    provider.validate_key_env().map_err(|e| {
+                        anyhow::anyhow!(
+                            "Invalid provider {} in {}: {}",
+                            provider.name,
+                            path.display(),
+                            e
+                        )
+                    })?;
+                }
+                info!(
+                    "Loaded {} dynamic providers from {}",
+                    providers.len(),
+                    path.display()
+                );
+                providers.into_iter().map(Arc::new).collect()
+            }
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            path,
+            configured: configured.into_iter().map(Arc::new).collect(),
+            providers: RwLock::new(providers),
+            changes: tokio::sync::Mutex::default(),
+        })
+    }
+
+    pub fn list(&self) -> Vec<Arc<DynamicProvider>> {
+        let mut providers = self.configured.clone();
+        providers.extend(self.providers.read().unwrap().iter().cloned());
+        providers
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<DynamicProvider>> {
+        self.list()
+            .into_iter()
+            .find(|provider| provider.name == name)
+    }
+
+    /// The provider with the longest model prefix matching `model`.
+    pub fn route(&self, model: &str) -> Option<Arc<DynamicProvider>> {
+        self.list()
+            .iter()
+            .filter_map(|provider| {
+                provider
+                    .models
+                    .iter()
+                    .filter(|prefix| model.starts_with(prefix.as_str()))
+                    .map(|prefix| (prefix.len(), provider))
+                    .max_by_key(|(len, _)| *len)
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, provider)| provider.clone())
+    }
+
+    /// Adds the provider, or replaces the one with the same name.
+    pub async fn upsert(&self, provider: DynamicProvider) -> anyhow::Result<()> {
+        provider.validate()?;
+        provider.validate_key_env()?;
+        if provider.api_key.is_some() {
+            return Err(ProviderError::invalid_request(
+                "Pass the API key through api_key_env",
+                Some("api_key"),
+            )
+            .into());
+        }
+        if self.configured.iter().any(|p| p.name == provider.name) {
+            return Err(ProviderError::invalid_request(
+                format!("Provider {} is defined in the configuration", provider.name),
+                Some("name"),
+            )
+            .into());
+        }
+        // The file is written before the change takes effect, so a failed
+        // write leaves both as they were.
+        let _change = self.changes.lock().await;
+        let mut providers = self.providers.read().unwrap().clone();
+        let provider = Arc::new(provider);
+        match providers.iter_mut().find(|p| p.name == provider.name) {
+            Some(existing) => *existing = provider,
+            None => providers.push(provider),
+        }
+        self.persist(&providers).await?;
+        *self.providers.write().unwrap() = providers;
+        Ok(())
+    }
+
+    /// Removes the provider, returning whether it existed.
+    pub async fn remove(&self, name: &str) -> anyhow::Result<bool> {
+        let _change = self.changes.lock().await;
+        let mut providers = self.providers.read().unwrap().clone();
+        let len = providers.len();
+        providers.retain(|provider| provider.name != name);
+        if providers.len() == len {
+            return Ok(false);
+        }
+        self.persist(&providers).await?;
+        *self.providers.write().unwrap() = providers;
+        Ok(true)
+    }
+
+    async fn persist(&self, providers: &[Arc<DynamicProvider>]) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let write = async {
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            let tmp = path.with_extension("tmp");
+            tokio::fs::write(
+                &tmp,
+                serde_json::to_vec_pretty(&providers.iter().map(Arc::as_ref).collect::<Vec<_>>())?,
+            )
+            .await?;
+            tokio::fs::rename(&tmp, path).await?;
+            anyhow::Ok(())
+        };
+        write.await.inspect_err(|e| {
+            error!("Failed to persist providers to {}: {}", path.display(), e);
+        })
+    }
+}
@@ -0,0 +1,342 @@
+use crate::{StreamEvent, store::CompletionAccumulator};
+use chrono::Utc;
+use futures::stream::{BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+pub(crate) const REDACTED: &str = "[REDACTED]";
+
+#[derive(Clone, Debug)]
+pub struct ReplayConfig {
+    pub enabled: bool,
+    pub capacity: usize,
+    /// Keeps the captures across restarts.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            capacity: 100,
+            file: None,
+        }
+    }
+}
+
+/// One request as the proxy sent it upstream, after templates, system
+/// prompts and key defaults, with the completion it got back.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Capture {
+    pub id: String,
+    pub captured_at: i64,
+    pub model: String,
+    pub caller: String,
+    pub request: Value,
+    pub response: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CaptureSummary<'a> {
+    pub id: &'a str,
+    pub captured_at: i64,
+    pub model: &'a str,
+    pub caller: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'a str>,
+}
+
+impl Capture {
+    pub fn summary(&self) -> CaptureSummary<'_> {
+        CaptureSummary {
+            id: &self.id,
+            captured_at: self.captured_at,
+            model: &self.model,
+            caller: &self.caller,
+            error: self.error.as_deref(),
+        }
+    }
+}
+
+/// Whether a word looks like a credential or an email address.
+fn sensitive(word: &str) -> bool {
+    let word = word.trim_matches(|c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_');
+    let aws_key = (word.starts_with("AKIA") || word.starts_with("ASIA"))
+        && word.len() == 20
+        && word
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+    let api_key = (word.starts_with("sk-") || word.starts_with("sk_")) && word.len() >= 20;
+    let email = word
+        .split_once('@')
+        .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.'));
+    aws_key || api_key || email
+}
+
+/// The text with each sensitive word, and each word after `Bearer`,
+/// replaced, or `None` when there is nothing to redact.
+fn redact_text(text: &str) -> Option<String> {
+    if !text
+        .split_whitespace()
+        .any(|word| sensitive(word) || word.eq_ignore_ascii_case("bearer"))
+    {
+        return None;
+    }
+    let mut output = String::with_capacity(text.len());
+    let mut redacted = false;
+    let mut after_bearer = false;
+    for part in text.split_inclusive(char::is_whitespace) {
+        let word = part.trim_end();
+        if word.is_empty() {
+            output.push_str(part);
+            continue;
+        }
+        if after_bearer || sensitive(word) {
+            output.push_str(REDACTED);
+            output.push_str(&part[word.len()..]);
+            redacted = true;
+        } else {
+            output.push_str(part);
+        }
+        after_bearer = word.eq_ignore_ascii_case("bearer");
+    }
+    redacted.then_some(output)
+}
+
+fn redact_value(value: &mut Value, users: bool) {
+    match value {
+        Value::String(text) => {
+            if let Some(redacted) = redact_text(text) {
+                *text = redacted;
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact_value(item, users)),
+        Value::Object(fields) => {
+            if users && fields.contains_key("user") {
+                fields.insert("user".to_string(), Value::from(REDACTED));
+            }
+            fields
+                .values_mut()
+                .for_each(|field| redact_value(field, users));
+        }
+        _ => {}
+    }
+}
+
+/// Masks API keys, AWS access keys, bearer tokens and email addresses in
+/// every string of a payload.
+pub fn mask_secrets(value: &mut Value) {
+    redact_value(value, false);
+}
+
+/// Masks secrets like [`mask_secrets`] in a captured payload, and drops
+/// the end-user id.
+pub fn redact(value: &mut Value) {
+    redact_value(value, true);
+}
+
+/// A field where two completions disagree, by JSON pointer.
+#[derive(Debug, Serialize)]
+pub struct Difference {
+    pub path: String,
+    pub original: Value,
+    pub replayed: Value,
+}
+
+fn diff_into(path: String, original: &Value, replayed: &Value, out: &mut Vec<Difference>) {
+    match (original, replayed) {
+        (Value::Object(a), Value::Object(b)) => {
+            for key in a.keys().chain(b.keys().filter(|key| !a.contains_key(*key))) {
+                diff_into(
+                    format!("{}/{}", path, key),
+                    a.get(key).unwrap_or(&Value::Null),
+                    b.get(key).unwrap_or(&Value::Null),
+                    out,
+                );
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            for index in 0..a.len().max(b.len()) {
+                diff_into(
+                    format!("{}/{}", path, index),
+                    a.get(index).unwrap_or(&Value::Null),
+                    b.get(index).unwrap_or(&Value::Null),
+                    out,
+                );
+            }
+        }
+        (a, b) if a != b => out.push(Difference {
+            path,
+            original: a.clone(),
+            replayed: b.clone(),
+        }),
+        _ => {}
+    }
+}
+
+/// Compares the choices of two completions. Ids, timestamps and usage
+/// always differ between runs and are left out.
+pub fn diff(original: &Value, replayed: &Value) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    diff_into(
+        "/choices".to_string(),
+        &original["choices"],
+        &replayed["choices"],
+        &mut differences,
+    );
+    differences
+}
+
+/// The last requests and responses, for debugging conversions. Captures
+/// are redacted before they are kept.
+pub struct ReplayBuffer {
+    config: ReplayConfig,
+    captures: Mutex<VecDeque<Arc<Capture>>>,
+    /// Serializes writes so the file always reflects the latest capture.
+    writes: tokio::sync::Mutex<()>,
+}
+
+impl ReplayBuffer {
+    pub async fn load(config: ReplayConfig) -> anyhow::Result<Self> {
+        let mut captures = VecDeque::new();
+        if let Some(file) = &config.file
+            && tokio::fs::try_exists(file).await?
+        {
+            let loaded: Vec<Capture> = serde_json::from_slice(&tokio::fs::read(file).await?)?;
+            info!(
+                "Loaded {} replay captures from {}",
+                loaded.len(),
+                file.display()
+            );
+            captures.extend(loaded.into_iter().map(Arc::new));
+            while captures.len() > config.capacity {
+                captures.pop_front();
+            }
+        }
+        Ok(Self {
+            config,
+            captures: Mutex::new(captures),
+            writes: tokio::sync::Mutex::default(),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled && self.config.capacity > 0
+    }
+
+    pub fn list(&self) -> Vec<Arc<Capture>> {
+        self.captures.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Capture>> {
+        self.captures
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|capture| capture.id == id)
+            .cloned()
+    }
+
+    pub async fn insert(&self, mut capture: Capture) {
+        redact(&mut capture.request);
+        if let Some(response) = &mut capture.response {
+            redact(response);
+        }
+
+        let _write = self.writes.lock().await;
+        let snapshot = {
+            let mut captures = self.captures.lock().unwrap();
+            captures.push_back(Arc::new(capture));
+            while captures.len() > self.config.capacity {
+                captures.pop_front();
+            }
+            captures.clone()
+        };
+
+        let Some(file) = &self.config.file else {
+            return;
+        };
+        let write = async {
+            if let Some(dir) = file.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            let tmp = file.with_extension("tmp");
+            let captures: Vec<&Capture> = snapshot.iter().map(Arc::as_ref).collect();
+            tokio::fs::write(&tmp, serde_json::to_vec(&captures)?).await?;
+            tokio::fs::rename(&tmp, file).await?;
+            anyhow::Ok(())
+        };
+        if let Err(e) = write.await {
+            error!(
+                "Failed to persist replay captures to {}: {}",
+                file.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Passes a completion stream through unchanged and captures the request
+/// with its accumulated response, or the error it failed with.
+pub fn capture<'a>(
+    mut stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    replay: Arc<ReplayBuffer>,
+    request: Value,
+    model: String,
+    caller: String,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+    async_stream::stream! {
+        let mut accumulator = CompletionAccumulator::default();
+        let mut error = None;
+        let mut last = None;
+
+        while let Some(item) = stream.next().await {
+            match &item {
+                Ok(StreamEvent::Chunk(data)) => match serde_json::from_slice(data) {
+                    Ok(response) => accumulator.push(response),
+                    Err(e) => warn!("Failed to parse chunk for the replay buffer: {}", e),
+                },
+                Err(e) => error = Some(e.to_string()),
+                _ => {}
+            }
+            if matches!(item, Ok(StreamEvent::Done) | Err(_)) {
+                last = Some(item);
+                break;
+            }
+            yield item;
+        }
+
+        let id = match accumulator.id() {
+            Some(id) => id.to_string(),
+            None => Uuid::new_v4().to_string(),
+        };
+        replay
+            .insert(Capture {
+                id,
+                captured_at: Utc::now().timestamp(),
+                response: error.is_none().then(|| accumulator.into_completion(&model)),
+                model,
+                caller,
+                request,
+                error,
+            })
+            .await;
+
+        if let Some(item) = last {
+            yield item;
+        }
+        while let Some(item) = stream.next().await {
+            yield item;
+        }
+    }
+    .boxed()
+}
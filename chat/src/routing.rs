@@ -0,0 +1,89 @@
+use serde::Deserialize;
+use std::{convert::Infallible, str::FromStr};
+
+/// The upstream serving a model.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Upstream {
+    OpenAI,
+    Anthropic,
+    Gemini,
+    Vertex,
+    OpenRouter,
+    SageMaker,
+    Mock,
+    /// Converse, or `InvokeModel` for the models Converse does not serve.
+    Bedrock,
+    /// An OpenAI-compatible provider from the configuration or admin API.
+    Provider(String),
+}
+
+impl FromStr for Upstream {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "openai" => Self::OpenAI,
+            "anthropic" => Self::Anthropic,
+            "gemini" => Self::Gemini,
+            "vertex" => Self::Vertex,
+            "openrouter" => Self::OpenRouter,
+            "sagemaker" => Self::SageMaker,
+            "mock" => Self::Mock,
+            "bedrock" => Self::Bedrock,
+            _ => Self::Provider(s.to_string()),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Route {
+    /// A model name, where `*` matches any characters and `?` one.
+    pub model: String,
+    pub provider: String,
+}
+
+/// Routes from the configuration, tried in order before the routing by
+/// model name prefix.
+#[derive(Clone, Debug, Default)]
+pub struct RoutingTable {
+    routes: Vec<(String, Upstream)>,
+}
+
+impl RoutingTable {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Self {
+            routes: routes
+                .into_iter()
+                .map(|route| {
+                    let Ok(upstream) = route.provider.parse();
+                    (route.model.to_lowercase(), upstream)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn upstream_for(&self, model: &str) -> Option<&Upstream> {
+        let model = model.to_lowercase();
+        self.routes
+            .iter()
+            .find(|(pattern, _)| matches(pattern.as_bytes(), model.as_bytes()))
+            .map(|(_, upstream)| upstream)
+    }
+}
+
+fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| matches(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && matches(rest, &name[1..]),
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{
+    StreamEvent,
+    error::{ProviderError, from_openai_response, from_reqwest_error},
+    image::{ImageLimits, preprocess_images_blocking},
+    memory::DEFAULT_MAX_STREAM_BYTES,
+    openai::chunk_stream,
+    providers::ChatCompletionsProvider,
+    tokens::UsageEstimator,
+    upstream::UpstreamClients,
+};
+use async_stream::stream;
+use async_trait::async_trait;
+use aws_smithy_eventstream::frame::{DecodedFrame, MessageFrameDecoder};
+use aws_smithy_types::event_stream::{HeaderValue, Message};
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt, stream::BoxStream};
+use request::{ChatCompletionsRequest, StreamOptions};
+use response::Usage;
+use serde::Deserialize;
+use tracing::{debug, error, info};
+
+/// A SageMaker real-time endpoint serving one model. The container must
+/// speak the OpenAI chat completions API, as the vLLM, TGI and LMI ones do.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SageMakerEndpoint {
+    /// The model id requests name.
+    pub model: String,
+    /// The endpoint name.
+    pub endpoint: String,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    /// Defaults to the region of the default AWS account.
+    pub region: Option<String>,
+}
+
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+fn header<'a>(message: &'a Message, name: &str) -> Option<&'a str> {
+    message
+        .headers()
+        .iter()
+        .find(|header| header.name().as_str() == name)
+        .and_then(|header| match header.value() {
+            HeaderValue::String(value) => Some(value.as_str()),
+            _ => None,
+        })
+}
+
+/// The payload parts of an `InvokeEndpointWithResponseStream` body.
+fn payload_parts<S>(body: S) -> impl Stream<Item = anyhow::Result<Bytes>> + Send
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Send,
+{
+    stream! {
+        let mut body = std::pin::pin!(body);
+        let mut decoder = MessageFrameDecoder::new();
+        let mut buffer = BytesMut::new();
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(chunk) => buffer.extend_from_slice(&chunk),
+                Err(e) => {
+                    yield Err(e.into());
+                    return;
+                }
+            }
+            loop {
+                let message = match decoder.decode_frame(&mut buffer) {
+                    Ok(DecodedFrame::Complete(message)) => message,
+                    Ok(DecodedFrame::Incomplete) => break,
+                    Err(e) => {
+                        yield Err(anyhow::anyhow!("Invalid SageMaker event stream: {}", e));
+                        return;
+                    }
+                };
+                match header(&message, ":message-type") {
+                    Some("event") => {
+                        if header(&message, ":event-type") == Some("PayloadPart") {
+                            yield Ok(message.payload().clone());
+                        }
+                    }
+                    _ => {
+                        yield Err(anyhow::anyhow!(
+                            "SageMaker stream error {}: {}",
+                            header(&message, ":exception-type")
+                                .or(header(&message, ":error-code"))
+                                .unwrap_or("unknown"),
+                            String::from_utf8_lossy(message.payload())
+                        ));
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct SageMakerChatCompletionsProvider {
+    clients: UpstreamClients,
+    endpoint: SageMakerEndpoint,
+    max_buffered_bytes: usize,
+    image_limits: ImageLimits,
+    tokenizer: Option<String>,
+}
+
+impl SageMakerChatCompletionsProvider {
+    pub fn new(clients: &UpstreamClients, endpoint: &SageMakerEndpoint) -> Self {
+        Self {
+            clients: clients.clone(),
+            endpoint: endpoint.clone(),
+            max_buffered_bytes: DEFAULT_MAX_STREAM_BYTES,
+            image_limits: ImageLimits::openai(),
+            tokenizer: None,
+        }
+    }
+
+    pub fn max_buffered_bytes(mut self, max_buffered_bytes: usize) -> Self {
+        self.max_buffered_bytes = max_buffered_bytes;
+        self
+    }
+
+    pub fn image_limits(mut self, image_limits: ImageLimits) -> Self {
+        self.image_limits = image_limits;
+        self
+    }
+
+    /// Tokenizer hint for estimating usage when the endpoint reports none.
+    pub fn tokenizer(mut self, tokenizer: Option<String>) -> Self {
+        self.tokenizer = tokenizer;
+        self
+    }
+}
+
+#[async_trait]
+impl ChatCompletionsProvider for SageMakerChatCompletionsProvider {
+    async fn chat_completions_stream<F>(
+        self,
+        request: ChatCompletionsRequest,
+        usage_callback: F,
+    ) -> anyhow::Result<BoxStream<'async_trait, anyhow::Result<StreamEvent>>>
+    where
+        F: Fn(&Usage) + Send + Sync + 'static,
+    {
+        debug!(
+            "Starting SageMaker request to endpoint {} for model {}",
+            self.endpoint.endpoint, request.model
+        );
+
+        let mut request = preprocess_images_blocking(request, &self.image_limits).await?;
+        request.stream = Some(true);
+        request.stream_options = Some(StreamOptions {
+            include_usage: true,
+        });
+        // Serving containers know none of the OpenAI-only fields.
+        request.metadata = None;
+        request.provider = None;
+        request.route = None;
+        request.service_tier = None;
+        request.store = None;
+        request.transforms = None;
+
+        let region = match &self.endpoint.region {
+            Some(region) => region.clone(),
+            None => self.clients.region()?,
+        };
+        let url = format!(
+            "https://runtime.sagemaker.{}.amazonaws.com/endpoints/{}/invocations-response-stream",
+            region, self.endpoint.endpoint
+        );
+        let response = self
+            .clients
+            .signed(
+                "sagemaker",
+                &region,
+                reqwest::Method::POST,
+                &url,
+                &[
+                    ("content-type", &self.endpoint.content_type),
+                    ("x-amzn-sagemaker-accept", "application/json"),
+                ],
+                serde_json::to_vec(&request)?,
+            )
+            .await?
+            .send()
+            .await
+            .map_err(from_reqwest_error)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.map_err(from_reqwest_error)?;
+            error!("SageMaker error: {} - {}", status, error_text);
+            if status.as_u16() == 404 {
+                return Err(ProviderError::model_not_found(&request.model).into());
+            }
+            return Err(from_openai_response(status.as_u16(), &error_text, &request.model).into());
+        }
+
+        info!(
+            "Connected to SageMaker endpoint {}, starting stream processing",
+            self.endpoint.endpoint
+        );
+
+        let estimator = UsageEstimator::new(&request, self.tokenizer);
+        Ok(chunk_stream(
+            payload_parts(response.bytes_stream()),
+            self.max_buffered_bytes,
+            estimator,
+            usage_callback,
+        ))
+    }
+}
@@ -0,0 +1,163 @@
+use crate::error::{ErrorKind, ProviderError};
+use response::Usage;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+pub const SESSION_HEADER: &str = "x-session-id";
+pub const SESSION_LIMIT_EXCEEDED_METRIC: &str = "llm_proxy_session_limit_exceeded_total";
+
+/// Price of a model family in USD per million tokens, matched by model id
+/// prefix.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModelPrice {
+    pub model: String,
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionLimits {
+    pub max_turns: Option<u64>,
+    pub max_tokens: Option<u64>,
+    pub max_cost: Option<f64>,
+    pub idle_timeout: Duration,
+    pub pricing: Vec<ModelPrice>,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            max_turns: None,
+            max_tokens: None,
+            max_cost: None,
+            idle_timeout: Duration::from_secs(3600),
+            pricing: Vec::new(),
+        }
+    }
+}
+
+impl SessionLimits {
+    pub fn cost(&self, model: &str, usage: &Usage) -> f64 {
+        self.pricing
+            .iter()
+            .filter(|price| model.starts_with(price.model.as_str()))
+            .max_by_key(|price| price.model.len())
+            .map(|price| {
+                (usage.prompt_tokens.max(0) as f64 * price.prompt_per_million
+                    + usage.completion_tokens.max(0) as f64 * price.completion_per_million)
+                    / 1_000_000.0
+            })
+            .unwrap_or(0.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SessionUsage {
+    pub turns: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+    last_seen: Instant,
+}
+
+impl SessionUsage {
+    fn new() -> Self {
+        Self {
+            turns: 0,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            cost: 0.0,
+            last_seen: Instant::now(),
+        }
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+/// Usage per client session, keyed by the session id header, so a runaway
+/// agent loop can be cut off once it crosses the configured limits.
+/// Sessions idle for longer than the timeout are forgotten.
+pub struct SessionTracker {
+    limits: SessionLimits,
+    sessions: Mutex<HashMap<String, SessionUsage>>,
+}
+
+impl SessionTracker {
+    pub fn new(limits: SessionLimits) -> Self {
+        Self {
+            limits,
+            sessions: Mutex::default(),
+        }
+    }
+
+    fn exceeded(&self, usage: &SessionUsage) -> Option<String> {
+        if let Some(max_turns) = self.limits.max_turns
+            && usage.turns >= max_turns
+        {
+            return Some(format!("the limit of {} turns", max_turns));
+        }
+        if let Some(max_tokens) = self.limits.max_tokens
+            && usage.total_tokens() >= max_tokens
+        {
+            return Some(format!("the limit of {} tokens", max_tokens));
+        }
+        if let Some(max_cost) = self.limits.max_cost
+            && usage.cost >= max_cost
+        {
+            return Some(format!("the cost limit of ${:.2}", max_cost));
+        }
+        None
+    }
+
+    /// Starts a turn in the session, or fails if the session has already
+    /// used up one of its limits.
+    pub fn begin_turn(&self, session_id: &str) -> Result<(), ProviderError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let idle_timeout = self.limits.idle_timeout;
+        sessions.retain(|_, usage| usage.last_seen.elapsed() < idle_timeout);
+
+        let usage = sessions
+            .entry(session_id.to_string())
+            .or_insert_with(SessionUsage::new);
+        usage.last_seen = Instant::now();
+
+        if let Some(limit) = self.exceeded(usage) {
+            warn!("Session {} has reached {}", session_id, limit);
+            metrics::counter!(SESSION_LIMIT_EXCEEDED_METRIC).increment(1);
+            return Err(ProviderError::new(
+                ErrorKind::RateLimited,
+                format!("Session {} has reached {}.", session_id, limit),
+            )
+            .code("session_limit_exceeded"));
+        }
+
+        usage.turns += 1;
+        Ok(())
+    }
+
+    pub fn record_usage(&self, session_id: &str, model: &str, usage: &Usage) {
+        let cost = self.limits.cost(model, usage);
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .entry(session_id.to_string())
+            .or_insert_with(SessionUsage::new);
+        session.prompt_tokens += usage.prompt_tokens.max(0) as u64;
+        session.completion_tokens += usage.completion_tokens.max(0) as u64;
+        session.cost += cost;
+        session.last_seen = Instant::now();
+        debug!(
+            "Session {}: {} turns, {} tokens, ${:.4}",
+            session_id,
+            session.turns,
+            session.total_tokens(),
+            session.cost
+        );
+    }
+}
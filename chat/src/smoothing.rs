@@ -0,0 +1,110 @@
+use crate::StreamEvent;
+use bytes::Bytes;
+use futures::{StreamExt, stream::BoxStream};
+use serde_json::Value;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SmoothingConfig {
+    pub enabled: bool,
+    /// Deltas with more characters than this are split.
+    pub min_chars: usize,
+    pub words_per_chunk: usize,
+    /// Pause between the pieces of one split delta.
+    pub interval: Duration,
+}
+
+impl Default for SmoothingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_chars: 64,
+            words_per_chunk: 2,
+            interval: Duration::from_millis(15),
+        }
+    }
+}
+
+/// Splits text into pieces of `words` words, keeping the whitespace after
+/// each word so the pieces join back to the original.
+fn split_words(text: &str, words: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut count = 0;
+    let mut in_word = false;
+    for (index, c) in text.char_indices() {
+        if c.is_whitespace() {
+            in_word = false;
+            continue;
+        }
+        if !in_word {
+            in_word = true;
+            if count == words {
+                pieces.push(&text[start..index]);
+                start = index;
+                count = 0;
+            }
+            count += 1;
+        }
+    }
+    pieces.push(&text[start..]);
+    pieces
+}
+
+/// The pieces a chunk is sent as: the text delta of its single choice split
+/// into words, with the finish reason and usage kept for the last piece.
+fn split_chunk(data: &Bytes, config: &SmoothingConfig) -> Option<Vec<Bytes>> {
+    let chunk: Value = serde_json::from_slice(data).ok()?;
+    let choices = chunk["choices"].as_array().filter(|c| c.len() == 1)?;
+    let content = choices[0]["delta"]["content"]
+        .as_str()
+        .filter(|content| content.chars().count() > config.min_chars)?;
+    let pieces = split_words(content, config.words_per_chunk.max(1));
+    if pieces.len() < 2 {
+        return None;
+    }
+
+    let last = pieces.len() - 1;
+    pieces
+        .into_iter()
+        .enumerate()
+        .map(|(index, piece)| {
+            let mut chunk = chunk.clone();
+            chunk["choices"][0]["delta"]["content"] = Value::from(piece);
+            if index < last {
+                chunk["choices"][0]["finish_reason"] = Value::Null;
+                if let Some(fields) = chunk.as_object_mut() {
+                    fields.remove("usage");
+                }
+            }
+            serde_json::to_vec(&chunk).ok().map(Bytes::from)
+        })
+        .collect()
+}
+
+/// Re-emits large text deltas as a few words at a time at a steady pace, for
+/// backends that send whole sentences at once. Other events pass unchanged.
+pub fn smooth<'a>(
+    mut stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    config: SmoothingConfig,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+    async_stream::stream! {
+        while let Some(item) = stream.next().await {
+            let pieces = match &item {
+                Ok(StreamEvent::Chunk(data)) => split_chunk(data, &config),
+                _ => None,
+            };
+            let Some(pieces) = pieces else {
+                yield item;
+                continue;
+            };
+            for (index, piece) in pieces.into_iter().enumerate() {
+                if index > 0 {
+                    tokio::time::sleep(config.interval).await;
+                }
+                yield Ok(StreamEvent::Chunk(piece));
+            }
+        }
+    }
+    .boxed()
+}
@@ -0,0 +1,211 @@
+use std::mem;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+    pub id: Option<String>,
+    pub retry: Option<u64>,
+}
+
+/// Incremental parser for `text/event-stream` bodies.
+///
+/// Bytes are buffered until a complete line is available, so UTF-8 sequences
+/// split across chunk boundaries are decoded only once they are whole.
+#[derive(Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+    pending_cr: bool,
+    event: Option<String>,
+    data: Vec<String>,
+    id: Option<String>,
+    retry: Option<u64>,
+}
+
+impl SseParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len() + self.data.iter().map(String::len).sum::<usize>()
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+
+        for &byte in chunk {
+            if self.pending_cr {
+                self.pending_cr = false;
+                if byte == b'\n' {
+                    continue;
+                }
+            }
+
+            match byte {
+                b'\r' => {
+                    self.pending_cr = true;
+                    self.process_line(&mut events);
+                }
+                b'\n' => self.process_line(&mut events),
+                _ => self.buffer.push(byte),
+            }
+        }
+
+        events
+    }
+
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        let mut events = Vec::new();
+        if !self.buffer.is_empty() {
+            self.process_line(&mut events);
+        }
+        self.process_line(&mut events);
+        events.pop()
+    }
+
+    fn process_line(&mut self, events: &mut Vec<SseEvent>) {
+        let line = mem::take(&mut self.buffer);
+
+        if line.is_empty() {
+            if let Some(event) = self.dispatch() {
+                events.push(event);
+            }
+            return;
+        }
+
+        if line[0] == b':' {
+            return;
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line.as_ref(), ""),
+        };
+
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => self.data.push(value.to_string()),
+            "id" if !value.contains('\0') => self.id = Some(value.to_string()),
+            "retry" => {
+                if let Ok(retry) = value.parse() {
+                    self.retry = Some(retry);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        if self.data.is_empty() {
+            self.event = None;
+            return None;
+        }
+
+        Some(SseEvent {
+            event: self.event.take(),
+            data: mem::take(&mut self.data).join("\n"),
+            id: self.id.clone(),
+            retry: self.retry,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(events: &[SseEvent]) -> Vec<&str> {
+        events.iter().map(|event| event.data.as_str()).collect()
+    }
+
+    #[test]
+    fn parses_events_split_across_chunks_with_crlf() {
+        let mut parser = SseParser::new();
+        let mut events = Vec::new();
+        // The CR and LF of one line end land in different chunks.
+        for chunk in [
+            "da",
+            "ta: {\"a\":",
+            "1}\r",
+            "\n\r",
+            "\ndata: two\r\n",
+            "\r\n",
+        ] {
+            events.extend(parser.feed(chunk.as_bytes()));
+        }
+        assert_eq!(data(&events), ["{\"a\":1}", "two"]);
+        assert_eq!(parser.buffered_len(), 0);
+    }
+
+    #[test]
+    fn accepts_lf_cr_and_crlf_line_ends() {
+        let mut parser = SseParser::new();
+        let events = parser.feed(b"data: a\n\ndata: b\r\rdata: c\r\n\r\n");
+        assert_eq!(data(&events), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn joins_data_lines_and_keeps_fields() {
+        let mut parser = SseParser::new();
+        let events = parser.feed(
+            b": keep-alive\nevent: message_delta\nid: 7\nretry: 500\ndata: one\ndata:two\n\n",
+        );
+        assert_eq!(
+            events,
+            [SseEvent {
+                event: Some("message_delta".to_string()),
+                data: "one\ntwo".to_string(),
+                id: Some("7".to_string()),
+                retry: Some(500),
+            }]
+        );
+    }
+
+    #[test]
+    fn keeps_the_last_id_and_resets_the_event_name() {
+        let mut parser = SseParser::new();
+        let events = parser.feed(b"event: ping\nid: 1\ndata: a\n\ndata: b\n\n");
+        assert_eq!(events[1].event, None);
+        assert_eq!(events[1].id.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn skips_events_without_data() {
+        let mut parser = SseParser::new();
+        let events = parser.feed(b"event: ping\n\ndata: a\n\n");
+        assert_eq!(data(&events), ["a"]);
+        assert_eq!(events[0].event, None);
+    }
+
+    #[test]
+    fn decodes_utf8_split_across_chunks() {
+        let text = "data: héllo\n\n".as_bytes();
+        let split = text.iter().position(|&byte| byte == 0xc3).unwrap() + 1;
+        let mut parser = SseParser::new();
+        assert!(parser.feed(&text[..split]).is_empty());
+        assert_eq!(data(&parser.feed(&text[split..])), ["héllo"]);
+    }
+
+    #[test]
+    fn finish_flushes_an_unterminated_event() {
+        let mut parser = SseParser::new();
+        assert_eq!(parser.feed(b"data: first\n\ndata: last").len(), 1);
+        assert_eq!(
+            parser.finish().map(|event| event.data).as_deref(),
+            Some("last")
+        );
+        assert_eq!(parser.finish(), None);
+    }
+
+    #[test]
+    fn finish_flushes_an_event_missing_its_blank_line() {
+        let mut parser = SseParser::new();
+        assert!(parser.feed(b"data: last\r\n").is_empty());
+        assert_eq!(
+            parser.finish().map(|event| event.data).as_deref(),
+            Some("last")
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use crate::StreamEvent;
+use futures::{StreamExt, stream::BoxStream};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{Instrument, Span, debug, error};
+
+pub const REQUESTS_METRIC: &str = "llm_proxy_requests_total";
+pub const REQUEST_ERRORS_METRIC: &str = "llm_proxy_request_errors_total";
+pub const REQUEST_DURATION_METRIC: &str = "llm_proxy_request_duration_seconds";
+pub const TIME_TO_FIRST_TOKEN_METRIC: &str = "llm_proxy_time_to_first_token_seconds";
+pub const INTER_CHUNK_LATENCY_METRIC: &str = "llm_proxy_inter_chunk_latency_seconds";
+pub const STREAM_CHUNKS_METRIC: &str = "llm_proxy_stream_chunks";
+
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(60);
+const MAX_RECENT_ERRORS: usize = 50;
+
+#[derive(Default)]
+struct ModelCounters {
+    requests: u64,
+    errors: u64,
+    completed: u64,
+    latency: Duration,
+    first_tokens: u64,
+    time_to_first_token: Duration,
+}
+
+#[derive(Default)]
+struct KeySpend {
+    requests: u64,
+    tokens: u64,
+    cost: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RecentError {
+    pub timestamp: u64,
+    pub model: String,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelStats {
+    pub model: String,
+    pub requests: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    pub avg_latency_ms: Option<f64>,
+    pub avg_ttft_ms: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyStats {
+    pub key: String,
+    pub requests: u64,
+    pub tokens: u64,
+    pub cost: f64,
+}
+
+/// Everything the admin dashboard shows, as of one moment.
+#[derive(Debug, Serialize)]
+pub struct StatsSnapshot {
+    pub uptime_secs: u64,
+    pub requests_last_minute: usize,
+    pub models: Vec<ModelStats>,
+    pub spend: Vec<KeyStats>,
+    pub recent_errors: Vec<RecentError>,
+}
+
+#[derive(Default)]
+struct Counters {
+    recent_requests: VecDeque<Instant>,
+    models: BTreeMap<String, ModelCounters>,
+    spend: BTreeMap<String, KeySpend>,
+    recent_errors: VecDeque<RecentError>,
+}
+
+fn average_ms(total: Duration, count: u64) -> Option<f64> {
+    (count > 0).then(|| total.as_secs_f64() * 1000.0 / count as f64)
+}
+
+/// In-memory request statistics since startup, kept for the admin dashboard.
+pub struct RequestStats {
+    started: Instant,
+    counters: Mutex<Counters>,
+}
+
+impl Default for RequestStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            counters: Mutex::default(),
+        }
+    }
+}
+
+impl RequestStats {
+    pub fn record_request(&self, model: &str) {
+        metrics::counter!(REQUESTS_METRIC, "model" => model.to_string()).increment(1);
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        counters.recent_requests.push_back(now);
+        while counters
+            .recent_requests
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > THROUGHPUT_WINDOW)
+        {
+            counters.recent_requests.pop_front();
+        }
+        counters
+            .models
+            .entry(model.to_string())
+            .or_default()
+            .requests += 1;
+    }
+
+    pub fn record_error(&self, model: &str, message: impl Into<String>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        metrics::counter!(REQUEST_ERRORS_METRIC, "model" => model.to_string()).increment(1);
+        let mut counters = self.counters.lock().unwrap();
+        counters.models.entry(model.to_string()).or_default().errors += 1;
+        if counters.recent_errors.len() == MAX_RECENT_ERRORS {
+            counters.recent_errors.pop_front();
+        }
+        counters.recent_errors.push_back(RecentError {
+            timestamp,
+            model: model.to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn record_first_token(&self, model: &str, elapsed: Duration) {
+        metrics::histogram!(TIME_TO_FIRST_TOKEN_METRIC, "model" => model.to_string())
+            .record(elapsed);
+        let mut counters = self.counters.lock().unwrap();
+        let model = counters.models.entry(model.to_string()).or_default();
+        model.first_tokens += 1;
+        model.time_to_first_token += elapsed;
+    }
+
+    fn record_completion(&self, model: &str, elapsed: Duration) {
+        metrics::histogram!(REQUEST_DURATION_METRIC, "model" => model.to_string()).record(elapsed);
+        let mut counters = self.counters.lock().unwrap();
+        let model = counters.models.entry(model.to_string()).or_default();
+        model.completed += 1;
+        model.latency += elapsed;
+    }
+
+    pub fn record_spend(&self, key: &str, tokens: u64, cost: f64) {
+        let mut counters = self.counters.lock().unwrap();
+        let spend = counters.spend.entry(key.to_string()).or_default();
+        spend.requests += 1;
+        spend.tokens += tokens;
+        spend.cost += cost;
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let counters = self.counters.lock().unwrap();
+        let now = Instant::now();
+        StatsSnapshot {
+            uptime_secs: self.started.elapsed().as_secs(),
+            requests_last_minute: counters
+                .recent_requests
+                .iter()
+                .filter(|at| now.duration_since(**at) <= THROUGHPUT_WINDOW)
+                .count(),
+            models: counters
+                .models
+                .iter()
+                .map(|(model, counter)| ModelStats {
+                    model: model.clone(),
+                    requests: counter.requests,
+                    errors: counter.errors,
+                    error_rate: if counter.requests > 0 {
+                        counter.errors as f64 / counter.requests as f64
+                    } else {
+                        0.0
+                    },
+                    avg_latency_ms: average_ms(counter.latency, counter.completed),
+                    avg_ttft_ms: average_ms(counter.time_to_first_token, counter.first_tokens),
+                })
+                .collect(),
+            spend: counters
+                .spend
+                .iter()
+                .map(|(key, spend)| KeyStats {
+                    key: key.clone(),
+                    requests: spend.requests,
+                    tokens: spend.tokens,
+                    cost: spend.cost,
+                })
+                .collect(),
+            recent_errors: counters.recent_errors.iter().rev().cloned().collect(),
+        }
+    }
+}
+
+/// How one completion stream has gone so far.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StreamTiming {
+    pub time_to_first_token: Option<Duration>,
+    pub chunks: u64,
+    /// The longest gap between two chunks, and all of them added up.
+    pub max_inter_chunk: Duration,
+    pub total_inter_chunk: Duration,
+}
+
+impl StreamTiming {
+    pub fn mean_inter_chunk(&self) -> Option<Duration> {
+        (self.chunks > 1).then(|| self.total_inter_chunk / (self.chunks - 1) as u32)
+    }
+}
+
+/// Passes a completion stream through unchanged while timing the first
+/// chunk, the gaps between chunks and the whole stream, and counting a
+/// failure midway as an error. The timing so far is kept in `timing`, for
+/// the usage record.
+pub fn measure<'a>(
+    mut stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    stats: Arc<RequestStats>,
+    model: String,
+    started: Instant,
+    timing: Arc<Mutex<StreamTiming>>,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+    async_stream::stream! {
+        let mut last_chunk: Option<Instant> = None;
+
+        while let Some(item) = stream.next().await {
+            match &item {
+                Ok(StreamEvent::Chunk(_)) => {
+                    let now = Instant::now();
+                    let mut timing = timing.lock().unwrap();
+                    timing.chunks += 1;
+                    match last_chunk {
+                        None => {
+                            let elapsed = now - started;
+                            timing.time_to_first_token = Some(elapsed);
+                            stats.record_first_token(&model, elapsed);
+                        }
+                        Some(last) => {
+                            let gap = now - last;
+                            timing.max_inter_chunk = timing.max_inter_chunk.max(gap);
+                            timing.total_inter_chunk += gap;
+                            metrics::histogram!(INTER_CHUNK_LATENCY_METRIC, "model" => model.clone())
+                                .record(gap);
+                        }
+                    }
+                    last_chunk = Some(now);
+                }
+                Ok(StreamEvent::Done) => {
+                    stats.record_completion(&model, started.elapsed());
+                    let chunks = timing.lock().unwrap().chunks;
+                    metrics::histogram!(STREAM_CHUNKS_METRIC, "model" => model.clone())
+                        .record(chunks as f64);
+                }
+                Err(e) => stats.record_error(&model, e.to_string()),
+                _ => {}
+            }
+            yield item;
+        }
+    }
+    .boxed()
+}
+
+/// Passes a completion stream through unchanged, recording each chunk as a
+/// debug event of `span`, which is kept open until the stream ends.
+pub fn traced<'a>(
+    mut stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    span: Span,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+    async_stream::stream! {
+        let mut index = 0;
+        while let Some(item) = stream.next().await {
+            match &item {
+                Ok(StreamEvent::Chunk(data)) => {
+                    debug!(parent: &span, index, bytes = data.len(), "chunk");
+                    index += 1;
+                }
+                Ok(StreamEvent::Dropped(count)) => {
+                    debug!(parent: &span, count, "chunks dropped");
+                }
+                Ok(StreamEvent::Done) => debug!(parent: &span, chunks = index, "done"),
+                Err(e) => error!(parent: &span, "{}", e),
+            }
+            yield item;
+        }
+    }
+    .boxed()
+}
+
+/// Polls a stream inside `span`, so what happens while it is read, after
+/// the handler has returned, is still attributed to the request.
+pub fn instrumented<'a, T: Send + 'a>(
+    mut stream: BoxStream<'a, T>,
+    span: Span,
+) -> BoxStream<'a, T> {
+    async_stream::stream! {
+        while let Some(item) = stream.next().instrument(span.clone()).await {
+            yield item;
+        }
+    }
+    .boxed()
+}
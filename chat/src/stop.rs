@@ -0,0 +1,386 @@
+use crate::{StreamEvent, create_stream_event, tokens::UsageOnce};
+use bytes::BytesMut;
+use futures::{StreamExt, stream::BoxStream};
+use response::{ChatCompletionsResponse, Choice, Delta};
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Enforces stop sequences in the proxy by scanning streamed text.
+///
+/// Text that could be the beginning of a stop sequence is held back until
+/// the next delta decides it, so a sequence split across chunks is still
+/// caught and never partially emitted.
+#[derive(Clone)]
+pub struct StopSequenceFilter {
+    sequences: Vec<String>,
+    held: String,
+    stopped: bool,
+}
+
+impl StopSequenceFilter {
+    pub fn new(sequences: Vec<String>) -> Option<Self> {
+        let sequences: Vec<String> = sequences.into_iter().filter(|s| !s.is_empty()).collect();
+        if sequences.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            sequences,
+            held: String::new(),
+            stopped: false,
+        })
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn push(&mut self, text: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+
+        self.held.push_str(text);
+
+        let first_match = self
+            .sequences
+            .iter()
+            .filter_map(|sequence| self.held.find(sequence.as_str()))
+            .min();
+        if let Some(index) = first_match {
+            self.stopped = true;
+            self.held.truncate(index);
+            return std::mem::take(&mut self.held);
+        }
+
+        let hold = self
+            .sequences
+            .iter()
+            .map(|sequence| partial_match_len(&self.held, sequence))
+            .max()
+            .unwrap_or(0);
+        let emit = self.held.len() - hold;
+        let rest = self.held.split_off(emit);
+        std::mem::replace(&mut self.held, rest)
+    }
+
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+
+    /// Applies the filter to one outgoing chunk. Returns `false` when the
+    /// chunk has nothing left to send.
+    pub fn filter_response(&mut self, response: &mut ChatCompletionsResponse) -> bool {
+        response
+            .choices
+            .retain_mut(|choice| self.filter_choice(choice));
+        !response.choices.is_empty()
+            || response.usage.is_some()
+            || response.guardrail_trace.is_some()
+    }
+
+    /// Applies the filter to one choice of a chunk. Returns `false` when the
+    /// choice has nothing left to send.
+    fn filter_choice(&mut self, choice: &mut Choice) -> bool {
+        if self.stopped {
+            return false;
+        }
+
+        if let Some(Delta::Content { content }) = &mut choice.delta {
+            *content = self.push(content);
+            if self.stopped {
+                choice.finish_reason = Some("stop".to_string());
+                return true;
+            }
+        }
+
+        if choice.finish_reason.is_some() {
+            let held = self.flush();
+            if !held.is_empty() {
+                match &mut choice.delta {
+                    Some(Delta::Content { content }) => content.push_str(&held),
+                    _ => choice.delta = Some(Delta::Content { content: held }),
+                }
+            }
+            return true;
+        }
+
+        !matches!(&choice.delta, Some(Delta::Content { content }) if content.is_empty())
+    }
+}
+
+/// Enforces stop sequences on the chunks of an upstream that takes fewer
+/// than a request asks for, or does not honor them. Each choice is filtered
+/// on its own and ends with a `stop` finish reason at its first match. Once
+/// all `choices` have ended, with at least one stopped here, the upstream is
+/// dropped rather than read to its end, and the usage it would have sent is
+/// estimated from what was passed on, as [`UsageOnce`] observes it.
+pub fn enforce_stop_sequences<'a>(
+    mut stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    sequences: Vec<String>,
+    choices: usize,
+    usage: UsageOnce,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+    let Some(filter) = StopSequenceFilter::new(sequences) else {
+        return stream;
+    };
+    async_stream::stream! {
+        let mut filters: HashMap<i32, StopSequenceFilter> = HashMap::new();
+        let mut ended: HashMap<i32, bool> = HashMap::new();
+        let mut buffer = BytesMut::new();
+        let mut last = None;
+        let mut stopped_early = false;
+        while let Some(item) = stream.next().await {
+            let response = match &item {
+                Ok(StreamEvent::Chunk(data)) => {
+                    serde_json::from_slice::<ChatCompletionsResponse>(data).ok()
+                }
+                _ => None,
+            };
+            let Some(mut response) = response else {
+                yield item;
+                continue;
+            };
+
+            response.choices.retain_mut(|choice| {
+                let filter = filters
+                    .entry(choice.index)
+                    .or_insert_with(|| filter.clone());
+                let keep = filter.filter_choice(choice);
+                if choice.finish_reason.is_some() {
+                    ended.insert(choice.index, filter.is_stopped());
+                }
+                keep
+            });
+            if response.choices.is_empty()
+                && response.usage.is_none()
+                && response.guardrail_trace.is_none()
+            {
+                continue;
+            }
+            last = Some((response.id.clone(), response.created, response.model.clone()));
+            yield create_stream_event(&response, &mut buffer);
+
+            if ended.len() >= choices && ended.values().any(|stopped| *stopped) {
+                stopped_early = true;
+                break;
+            }
+        }
+        let Some((id, created, model)) = last.filter(|_| stopped_early) else {
+            return;
+        };
+
+        debug!("Every choice has stopped, dropping the upstream stream");
+        drop(stream);
+        let estimated = usage.estimate();
+        usage.report(&estimated);
+        let response = ChatCompletionsResponse::builder()
+            .id(id)
+            .created(created)
+            .model(model)
+            .usage(Some(estimated))
+            .build();
+        yield create_stream_event(&response, &mut buffer);
+        yield Ok(StreamEvent::Done);
+    }
+    .boxed()
+}
+
+/// Length of the longest suffix of `text` that is a proper prefix of
+/// `sequence`.
+fn partial_match_len(text: &str, sequence: &str) -> usize {
+    (1..sequence.len().min(text.len() + 1))
+        .rev()
+        .filter(|&len| sequence.is_char_boundary(len) && text.is_char_boundary(text.len() - len))
+        .find(|&len| text.ends_with(&sequence[..len]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokens::UsageEstimator;
+    use bytes::Bytes;
+    use request::ChatCompletionsRequest;
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    fn filter(sequences: &[&str]) -> StopSequenceFilter {
+        StopSequenceFilter::new(sequences.iter().map(|s| s.to_string()).collect()).unwrap()
+    }
+
+    #[test]
+    fn catches_a_sequence_split_across_deltas() {
+        let mut filter = filter(&["STOP"]);
+        assert_eq!(filter.push("Hello ST"), "Hello ");
+        assert!(!filter.is_stopped());
+        assert_eq!(filter.push("OP and more"), "");
+        assert!(filter.is_stopped());
+        assert_eq!(filter.push("ignored"), "");
+    }
+
+    #[test]
+    fn releases_held_text_that_does_not_match() {
+        let mut filter = filter(&["abc"]);
+        assert_eq!(filter.push("xab"), "x");
+        assert_eq!(filter.push("x"), "abx");
+        assert_eq!(filter.push("ab"), "");
+        assert_eq!(filter.flush(), "ab");
+    }
+
+    #[test]
+    fn stops_at_the_earliest_of_several_sequences() {
+        let mut filter = filter(&["world", "lo w"]);
+        assert_eq!(filter.push("hello world"), "hel");
+        assert!(filter.is_stopped());
+    }
+
+    #[test]
+    fn ignores_empty_sequences() {
+        assert!(StopSequenceFilter::new(vec![String::new()]).is_none());
+        let mut filter = filter(&["", "."]);
+        assert_eq!(filter.push("a.b"), "a");
+    }
+
+    #[test]
+    fn partial_matches_respect_char_boundaries() {
+        assert_eq!(partial_match_len("café", "é!"), "é".len());
+        assert_eq!(partial_match_len("caf\u{e9}", "\u{e9}"), 0);
+        assert_eq!(partial_match_len("日本", "本語"), "本".len());
+        assert_eq!(partial_match_len("abc", "xyz"), 0);
+        assert_eq!(partial_match_len("", "abc"), 0);
+        // A whole match is not a partial one.
+        assert_eq!(partial_match_len("abc", "bc"), 0);
+    }
+
+    #[test]
+    fn flushes_held_text_with_the_finish_reason() {
+        let mut filter = filter(&["END"]);
+        let mut response: ChatCompletionsResponse = serde_json::from_value(json!({
+            "choices": [{ "index": 0, "delta": { "content": "almost EN" } }],
+        }))
+        .unwrap();
+        assert!(filter.filter_response(&mut response));
+        let mut last: ChatCompletionsResponse = serde_json::from_value(json!({
+            "choices": [{ "index": 0, "delta": {}, "finish_reason": "length" }],
+        }))
+        .unwrap();
+        assert!(filter.filter_response(&mut last));
+        assert_eq!(
+            serde_json::to_value(&last).unwrap()["choices"][0]["delta"]["content"],
+            "EN"
+        );
+    }
+
+    fn chunk(
+        index: i32,
+        content: &str,
+        finish_reason: Option<&str>,
+    ) -> anyhow::Result<StreamEvent> {
+        let chunk = json!({
+            "id": "chatcmpl-1",
+            "created": 1,
+            "model": "m",
+            "choices": [{
+                "index": index,
+                "delta": { "content": content },
+                "finish_reason": finish_reason,
+            }],
+        });
+        Ok(StreamEvent::Chunk(Bytes::from(
+            serde_json::to_vec(&chunk).unwrap(),
+        )))
+    }
+
+    async fn run(
+        upstream: Vec<anyhow::Result<StreamEvent>>,
+        choices: usize,
+    ) -> (Vec<Value>, bool, Vec<i32>) {
+        let request: ChatCompletionsRequest =
+            serde_json::from_value(json!({ "model": "m", "messages": [] })).unwrap();
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let usage = {
+            let reported = reported.clone();
+            UsageOnce::new(
+                move |usage| reported.lock().unwrap().push(usage.completion_tokens),
+                UsageEstimator::new(&request, None),
+            )
+        };
+        let stream = enforce_stop_sequences(
+            futures::stream::iter(upstream).boxed(),
+            vec!["STOP".to_string()],
+            choices,
+            usage.clone(),
+        );
+        let events: Vec<_> = usage.observe(stream).collect().await;
+        let mut chunks = Vec::new();
+        let mut done = false;
+        for event in events {
+            match event.unwrap() {
+                StreamEvent::Chunk(data) => chunks.push(serde_json::from_slice(&data).unwrap()),
+                StreamEvent::Done => done = true,
+                StreamEvent::Dropped(_) => {}
+            }
+        }
+        let reported = reported.lock().unwrap().clone();
+        (chunks, done, reported)
+    }
+
+    #[tokio::test]
+    async fn drops_the_upstream_once_every_choice_has_ended() {
+        let (chunks, done, reported) = run(
+            vec![
+                chunk(0, "one ST", None),
+                chunk(1, "two", Some("stop")),
+                chunk(0, "OP three", None),
+                chunk(0, "never sent", None),
+            ],
+            2,
+        )
+        .await;
+        let contents: Vec<&Value> = chunks
+            .iter()
+            .filter_map(|chunk| chunk["choices"].get(0))
+            .map(|choice| &choice["delta"]["content"])
+            .collect();
+        assert_eq!(contents, [&json!("one "), &json!("two"), &json!("")]);
+        assert_eq!(chunks[2]["choices"][0]["finish_reason"], "stop");
+        let usage = &chunks.last().unwrap()["usage"];
+        assert_eq!(usage["estimated"], true);
+        assert!(done);
+        assert_eq!(
+            reported,
+            [usage["completion_tokens"].as_i64().unwrap() as i32]
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_on_while_a_choice_is_still_running() {
+        let (chunks, done, reported) = run(
+            vec![
+                chunk(0, "STOP", None),
+                chunk(1, "still going", None),
+                chunk(1, "", Some("stop")),
+            ],
+            2,
+        )
+        .await;
+        assert_eq!(chunks[1]["choices"][0]["delta"]["content"], "still going");
+        assert!(done);
+        assert_eq!(reported.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn streams_without_a_match_pass_through() {
+        let (chunks, done, reported) = run(
+            vec![chunk(0, "no match", Some("stop")), Ok(StreamEvent::Done)],
+            1,
+        )
+        .await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "no match");
+        assert!(done);
+        // The upstream sent no usage, so the estimate is reported on drop.
+        assert_eq!(reported.len(), 1);
+    }
+}
@@ -0,0 +1,415 @@
+use crate::StreamEvent;
+use futures::stream::{BoxStream, StreamExt};
+use response::{ChatCompletionsResponse, Delta, ToolCall, Usage};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{debug, error, info, warn};
+
+/// Header that asks for a completion to be stored, for clients that cannot
+/// set `store` in the body.
+pub const STORE_HEADER: &str = "x-proxy-store";
+
+/// How often files past their retention are looked for in the store's
+/// directory.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Debug)]
+pub struct ConversationStoreConfig {
+    pub enabled: bool,
+    pub max_entries: usize,
+    pub dir: Option<PathBuf>,
+    /// Files in `dir` older than this are deleted. Unset keeps them.
+    pub retention: Option<Duration>,
+}
+
+impl Default for ConversationStoreConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_entries: 1000,
+            dir: None,
+            retention: Some(Duration::from_secs(30 * 24 * 3600)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct AccumulatedChoice {
+    content: String,
+    reasoning_content: String,
+    thinking_blocks: Vec<Value>,
+    refusal: String,
+    tool_calls: Vec<ToolCall>,
+    finish_reason: Option<String>,
+}
+
+impl AccumulatedChoice {
+    /// Adds a call, or the next chunk of the arguments of the call with
+    /// its index.
+    fn push_tool_call(&mut self, call: ToolCall) {
+        let started = self
+            .tool_calls
+            .iter_mut()
+            .find(|started| call.index.is_some() && started.index == call.index);
+        match started {
+            Some(started) if call.id.is_empty() => started
+                .function
+                .arguments
+                .push_str(&call.function.arguments),
+            _ => self.tool_calls.push(call),
+        }
+    }
+}
+
+/// Folds the chunks of a streamed completion back into a single
+/// `chat.completion` object.
+#[derive(Default)]
+pub struct CompletionAccumulator {
+    id: Option<Arc<str>>,
+    created: Option<i64>,
+    model: Option<String>,
+    service_tier: Option<String>,
+    choices: BTreeMap<i32, AccumulatedChoice>,
+    usage: Option<Usage>,
+    guardrail_trace: Option<Value>,
+}
+
+impl CompletionAccumulator {
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn push(&mut self, response: ChatCompletionsResponse) {
+        self.id = self.id.take().or(response.id);
+        self.created = self.created.or(response.created);
+        self.model = self.model.take().or(response.model);
+        self.service_tier = self.service_tier.take().or(response.service_tier);
+        if response.usage.is_some() {
+            self.usage = response.usage;
+        }
+        if response.guardrail_trace.is_some() {
+            self.guardrail_trace = response.guardrail_trace;
+        }
+
+        for choice in response.choices {
+            let accumulated = self.choices.entry(choice.index).or_default();
+            match choice.delta {
+                Some(Delta::Content { content }) => accumulated.content.push_str(&content),
+                Some(Delta::Reasoning { reasoning_content }) => {
+                    accumulated.reasoning_content.push_str(&reasoning_content)
+                }
+                Some(Delta::ThinkingBlocks { thinking_blocks }) => {
+                    accumulated.thinking_blocks.extend(thinking_blocks)
+                }
+                Some(Delta::Refusal { refusal }) => accumulated.refusal.push_str(&refusal),
+                Some(Delta::ToolCalls { tool_calls }) => {
+                    for call in tool_calls {
+                        accumulated.push_tool_call(call);
+                    }
+                }
+                _ => {}
+            }
+            if choice.finish_reason.is_some() {
+                accumulated.finish_reason = choice.finish_reason;
+            }
+        }
+    }
+
+    pub fn into_completion(self, model: &str) -> Value {
+        let choices: Vec<Value> = self
+            .choices
+            .into_iter()
+            .map(|(index, choice)| {
+                let mut message = json!({ "role": "assistant", "content": choice.content });
+                // Refusals come in place of content, which OpenAI then nulls.
+                if choice.content.is_empty() && !choice.refusal.is_empty() {
+                    message["content"] = Value::Null;
+                }
+                if !choice.reasoning_content.is_empty() {
+                    message["reasoning_content"] = json!(choice.reasoning_content);
+                }
+                if !choice.thinking_blocks.is_empty() {
+                    message["thinking_blocks"] = json!(choice.thinking_blocks);
+                }
+                if !choice.refusal.is_empty() {
+                    message["refusal"] = json!(choice.refusal);
+                }
+                if !choice.tool_calls.is_empty() {
+                    // Indexes only matter to streaming clients.
+                    let mut tool_calls = choice.tool_calls;
+                    tool_calls.sort_by_key(|call| call.index);
+                    let tool_calls: Vec<ToolCall> = tool_calls
+                        .into_iter()
+                        .map(|call| ToolCall {
+                            index: None,
+                            ..call
+                        })
+                        .collect();
+                    message["tool_calls"] = json!(tool_calls);
+                }
+                json!({
+                    "index": index,
+                    "message": message,
+                    "finish_reason": choice.finish_reason,
+                })
+            })
+            .collect();
+
+        let mut completion = json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model.as_deref().unwrap_or(model),
+            "choices": choices,
+            "usage": self.usage,
+        });
+        if let Some(service_tier) = self.service_tier {
+            completion["service_tier"] = json!(service_tier);
+        }
+        if let Some(guardrail_trace) = self.guardrail_trace {
+            completion["guardrail_trace"] = guardrail_trace;
+        }
+        completion
+    }
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// A stored completion with the caller that made it, who alone may read it
+/// back.
+#[derive(Deserialize, Serialize)]
+struct Stored {
+    owner: String,
+    completion: Arc<Value>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_id: HashMap<String, Arc<Stored>>,
+    order: VecDeque<String>,
+}
+
+/// Completions kept for later retrieval by id, by the caller that made
+/// them. The most recent `max_entries` live in memory; with `dir` set they
+/// are also written there as JSON files, read back once evicted and deleted
+/// once past `retention`.
+pub struct ConversationStore {
+    config: ConversationStoreConfig,
+    entries: Mutex<Entries>,
+    last_sweep: Mutex<Option<Instant>>,
+}
+
+impl ConversationStore {
+    pub fn new(config: ConversationStoreConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::default(),
+            last_sweep: Mutex::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub async fn insert(&self, id: String, owner: String, record: Value) {
+        if !valid_id(&id) {
+            warn!("Not storing completion with invalid id {:?}", id);
+            return;
+        }
+        // An id another caller stored under stays theirs.
+        if let Some(existing) = self.find(&id).await
+            && existing.owner != owner
+        {
+            warn!(
+                "Not storing completion {}, its id belongs to another caller",
+                id
+            );
+            return;
+        }
+        let record = Arc::new(Stored {
+            owner,
+            completion: Arc::new(record),
+        });
+
+        if let Some(dir) = &self.config.dir {
+            let path = dir.join(format!("{}.json", id));
+            let write = async {
+                tokio::fs::create_dir_all(dir).await?;
+                tokio::fs::write(&path, serde_json::to_vec(&*record)?).await?;
+                anyhow::Ok(())
+            };
+            if let Err(e) = write.await {
+                error!(
+                    "Failed to write stored completion {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+            self.sweep(dir).await;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.by_id.insert(id.clone(), record).is_none() {
+            entries.order.push_back(id);
+        }
+        while entries.order.len() > self.config.max_entries {
+            if let Some(evicted) = entries.order.pop_front() {
+                entries.by_id.remove(&evicted);
+            }
+        }
+        debug!("Stored completion, {} in memory", entries.order.len());
+    }
+
+    /// The completion stored under `id`, if `owner` made it. Others are
+    /// told there is none, so ids can't be probed.
+    pub async fn get(&self, id: &str, owner: &str) -> Option<Arc<Value>> {
+        let record = self.find(id).await?;
+        if record.owner != owner {
+            debug!("Refusing stored completion {} to another caller", id);
+            return None;
+        }
+        Some(record.completion.clone())
+    }
+
+    async fn find(&self, id: &str) -> Option<Arc<Stored>> {
+        if !valid_id(id) {
+            return None;
+        }
+        if let Some(record) = self.entries.lock().unwrap().by_id.get(id) {
+            return Some(record.clone());
+        }
+
+        let path = self.config.dir.as_ref()?.join(format!("{}.json", id));
+        let modified = tokio::fs::metadata(&path).await.ok()?.modified().ok()?;
+        if self.expired(modified) {
+            return None;
+        }
+        let data = tokio::fs::read(&path).await.ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(record) => Some(Arc::new(record)),
+            Err(e) => {
+                error!("Failed to read stored completion {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+impl ConversationStore {
+    fn expired(&self, modified: SystemTime) -> bool {
+        self.config
+            .retention
+            .is_some_and(|retention| modified.elapsed().is_ok_and(|elapsed| elapsed > retention))
+    }
+
+    /// Deletes the files past their retention, at most once per
+    /// [`SWEEP_INTERVAL`].
+    async fn sweep(&self, dir: &Path) {
+        if self.config.retention.is_none() {
+            return;
+        }
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            if last_sweep.is_some_and(|last| last.elapsed() < SWEEP_INTERVAL) {
+                return;
+            }
+            *last_sweep = Some(Instant::now());
+        }
+
+        let mut files = match tokio::fs::read_dir(dir).await {
+            Ok(files) => files,
+            Err(e) => {
+                error!(
+                    "Failed to list stored completions in {}: {}",
+                    dir.display(),
+                    e
+                );
+                return;
+            }
+        };
+        let mut deleted = 0;
+        while let Ok(Some(file)) = files.next_entry().await {
+            let path = file.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Ok(modified) = file
+                .metadata()
+                .await
+                .and_then(|metadata| metadata.modified())
+            else {
+                continue;
+            };
+            if !self.expired(modified) {
+                continue;
+            }
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => deleted += 1,
+                Err(e) => warn!(
+                    "Failed to delete stored completion {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        if deleted > 0 {
+            info!(
+                "Deleted {} stored completions past their retention",
+                deleted
+            );
+        }
+    }
+}
+
+/// Passes a completion stream through unchanged while accumulating it, and
+/// stores the conversation with its final response once the stream is done.
+/// A stream that fails midway is not stored.
+pub fn record<'a>(
+    mut stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    store: Arc<ConversationStore>,
+    owner: String,
+    messages: Value,
+    model: String,
+) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+    async_stream::stream! {
+        let mut accumulator = CompletionAccumulator::default();
+        let mut failed = false;
+
+        while let Some(item) = stream.next().await {
+            match &item {
+                Ok(StreamEvent::Chunk(data)) => match serde_json::from_slice(data) {
+                    Ok(response) => accumulator.push(response),
+                    Err(e) => warn!("Failed to parse chunk for the conversation store: {}", e),
+                },
+                Ok(StreamEvent::Done) if !failed => {
+                    match accumulator.id().map(str::to_string) {
+                        Some(id) => {
+                            let mut completion = accumulator.into_completion(&model);
+                            completion["messages"] = messages;
+                            store.insert(id, owner, completion).await;
+                        }
+                        None => warn!("Not storing completion without an id"),
+                    }
+                    yield item;
+                    break;
+                }
+                Err(_) => failed = true,
+                _ => {}
+            }
+            yield item;
+        }
+    }
+    .boxed()
+}
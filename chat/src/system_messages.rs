@@ -0,0 +1,183 @@
+use crate::error::ProviderError;
+use request::{ChatCompletionsRequest, Content, Contents, Message, Role};
+use serde::Deserialize;
+use tracing::debug;
+
+/// What becomes of a system or developer message that follows the start
+/// of the conversation.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SystemMessageStrategy {
+    /// Moved up to the system messages the conversation opens with.
+    #[default]
+    Hoist,
+    /// Turned into user text, prefixed, in the user message that follows,
+    /// or one of its own where none does.
+    Merge,
+    /// The request is rejected.
+    Reject,
+}
+
+fn default_prefix() -> String {
+    "System: ".to_string()
+}
+
+/// A strategy for the models matching any of the prefixes.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SystemMessageRule {
+    pub models: Vec<String>,
+    #[serde(default)]
+    pub strategy: SystemMessageStrategy,
+    /// Put before the text of a merged message.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+/// The handling of mid-conversation system messages the config sets per
+/// model. Requests to models no rule covers pass unchanged, leaving each
+/// upstream to treat them its own way.
+#[derive(Clone, Debug, Default)]
+pub struct SystemMessages {
+    pub rules: Vec<SystemMessageRule>,
+}
+
+impl SystemMessages {
+    /// The first rule matching the model wins.
+    pub fn for_model(&self, model: &str) -> Option<&SystemMessageRule> {
+        self.rules.iter().find(|rule| {
+            rule.models
+                .iter()
+                .any(|prefix| model.starts_with(prefix.as_str()))
+        })
+    }
+
+    /// Applies the model's strategy to the system and developer messages
+    /// after the first message of another role.
+    pub fn apply(
+        &self,
+        request: &mut ChatCompletionsRequest,
+        model: &str,
+    ) -> Result<(), ProviderError> {
+        let Some(rule) = self.for_model(model) else {
+            return Ok(());
+        };
+        let Some(start) = request
+            .messages
+            .iter()
+            .position(|message| !is_system(message))
+        else {
+            return Ok(());
+        };
+        let Some(first) = request.messages[start..]
+            .iter()
+            .position(is_system)
+            .map(|i| start + i)
+        else {
+            return Ok(());
+        };
+
+        match rule.strategy {
+            SystemMessageStrategy::Reject => {
+                let param = format!("messages[{}]", first);
+                Err(ProviderError::invalid_request(
+                    format!(
+                        "{}: system messages must come before the conversation for model {}",
+                        param, request.model
+                    ),
+                    Some(&param),
+                ))
+            }
+            SystemMessageStrategy::Hoist => {
+                debug!("Hoisting mid-conversation system messages");
+                let (mut system, rest): (Vec<Message>, Vec<Message>) =
+                    request.messages.drain(start..).partition(is_system);
+                request.messages.append(&mut system);
+                request.messages.extend(rest);
+                Ok(())
+            }
+            SystemMessageStrategy::Merge => {
+                debug!("Merging mid-conversation system messages into user messages");
+                let tail: Vec<Message> = request.messages.drain(start..).collect();
+                let mut merged: Vec<Message> = Vec::with_capacity(tail.len());
+                let mut pending: Vec<Content> = Vec::new();
+                for mut message in tail {
+                    if is_system(&message) {
+                        pending.push(text(format!("{}{}", rule.prefix, message_text(&message))));
+                        continue;
+                    }
+                    // Tool results must follow the calls they answer.
+                    if pending.is_empty() || matches!(message.role, Role::Tool) {
+                        merged.push(message);
+                        continue;
+                    }
+                    if matches!(message.role, Role::User) {
+                        let mut parts = std::mem::take(&mut pending);
+                        parts.extend(into_parts(message.contents.take()));
+                        message.contents = Some(Contents::Array(parts));
+                        merged.push(message);
+                        continue;
+                    }
+                    merged.push(user(std::mem::take(&mut pending)));
+                    merged.push(message);
+                }
+                if !pending.is_empty() {
+                    match merged.last_mut() {
+                        Some(last) if matches!(last.role, Role::User) => {
+                            let mut parts = into_parts(last.contents.take());
+                            parts.append(&mut pending);
+                            last.contents = Some(Contents::Array(parts));
+                        }
+                        _ => merged.push(user(pending)),
+                    }
+                }
+                request.messages.extend(merged);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn is_system(message: &Message) -> bool {
+    matches!(message.role, Role::System | Role::Developer)
+}
+
+fn text(text: String) -> Content {
+    Content::Text {
+        text,
+        cache_control: None,
+    }
+}
+
+fn message_text(message: &Message) -> String {
+    match &message.contents {
+        Some(Contents::String(text)) => text.clone(),
+        Some(Contents::Array(parts)) => parts
+            .iter()
+            .filter_map(|part| match part {
+                Content::Text { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => String::new(),
+    }
+}
+
+fn into_parts(contents: Option<Contents>) -> Vec<Content> {
+    match contents {
+        Some(Contents::Array(parts)) => parts,
+        Some(Contents::String(text)) => vec![self::text(text)],
+        None => Vec::new(),
+    }
+}
+
+fn user(parts: Vec<Content>) -> Message {
+    Message {
+        contents: Some(Contents::Array(parts)),
+        name: None,
+        role: Role::User,
+        tool_call_id: None,
+        tool_calls: None,
+        thinking_blocks: None,
+    }
+}
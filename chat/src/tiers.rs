@@ -0,0 +1,55 @@
+use serde::Deserialize;
+
+/// The tier on-demand Bedrock model ids serve.
+pub const DEFAULT_TIER: &str = "default";
+
+/// A Bedrock model id, such as a provisioned throughput ARN, that serves a
+/// service tier for the models matching any of the prefixes.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ServiceTierRule {
+    pub models: Vec<String>,
+    pub tier: String,
+    pub model_id: String,
+}
+
+/// The tier a request runs on, with the model id serving it when that is
+/// not the model's own.
+#[derive(Clone, Debug)]
+pub struct ServiceTier {
+    pub tier: String,
+    pub model_id: Option<String>,
+}
+
+/// The Bedrock service tiers the config sets per model.
+#[derive(Clone, Debug, Default)]
+pub struct ServiceTiers {
+    pub rules: Vec<ServiceTierRule>,
+}
+
+impl ServiceTiers {
+    /// The tier for a request to `model` asking for `requested`; requests
+    /// without one ask for `default`, and `auto` takes the first tier a
+    /// rule sets for the model. Tiers no rule sets are served on demand,
+    /// as `default`. `None` when the request names no tier and no rule
+    /// covers its default.
+    pub fn resolve(&self, model: &str, requested: Option<&str>) -> Option<ServiceTier> {
+        let tier = requested.unwrap_or(DEFAULT_TIER);
+        let rule = self.rules.iter().find(|rule| {
+            (tier == "auto" || rule.tier == tier)
+                && rule
+                    .models
+                    .iter()
+                    .any(|prefix| model.starts_with(prefix.as_str()))
+        });
+        match rule {
+            Some(rule) => Some(ServiceTier {
+                tier: rule.tier.clone(),
+                model_id: Some(rule.model_id.clone()),
+            }),
+            None => requested.map(|_| ServiceTier {
+                tier: DEFAULT_TIER.to_string(),
+                model_id: None,
+            }),
+        }
+    }
+}
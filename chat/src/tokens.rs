@@ -0,0 +1,204 @@
+use crate::StreamEvent;
+use futures::{StreamExt, stream::BoxStream};
+use request::{ChatCompletionsRequest, Content, Contents, Message};
+use response::{ChatCompletionsResponse, Delta, Usage, UsageBuilder};
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use tracing::debug;
+
+/// Tokens added per message for the role and separators of chat formats.
+pub(crate) const TOKENS_PER_MESSAGE: i32 = 4;
+/// Tokens that prime the assistant reply.
+const TOKENS_PER_REPLY: i32 = 3;
+/// What one image costs at high detail on OpenAI-style vision models.
+const TOKENS_PER_IMAGE: i32 = 765;
+/// A rough figure for a short clip, which Nova samples at a frame per
+/// second.
+const TOKENS_PER_VIDEO: i32 = 10_000;
+
+/// Average characters per token for a tokenizer family from the model
+/// table. Without a hint, English text averages about four.
+fn chars_per_token(tokenizer: Option<&str>) -> f64 {
+    match tokenizer {
+        Some("claude") => 3.5,
+        Some("o200k_base") => 4.2,
+        _ => 4.0,
+    }
+}
+
+/// A rough token count for text, good enough for accounting and rate
+/// limits when the upstream reports nothing.
+pub fn estimate_tokens(text: &str, tokenizer: Option<&str>) -> i32 {
+    tokens_for_chars(text.chars().count(), tokenizer)
+}
+
+fn tokens_for_chars(chars: usize, tokenizer: Option<&str>) -> i32 {
+    if chars == 0 {
+        return 0;
+    }
+    (chars as f64 / chars_per_token(tokenizer)).ceil() as i32
+}
+
+/// A rough token count for one message, with the overhead of its role.
+pub fn estimate_message_tokens(message: &Message, tokenizer: Option<&str>) -> i32 {
+    let content = match &message.contents {
+        Some(Contents::String(text)) => estimate_tokens(text, tokenizer),
+        Some(Contents::Array(parts)) => parts
+            .iter()
+            .map(|part| match part {
+                Content::Text { text, .. } => estimate_tokens(text, tokenizer),
+                Content::ImageUrl { .. } => TOKENS_PER_IMAGE,
+                // As much as text of the decoded size would be.
+                Content::File { file, .. } => file.file_data.as_ref().map_or(0, |data| {
+                    (data.len() as f64 * 0.75 / chars_per_token(tokenizer)).ceil() as i32
+                }),
+                Content::Video { .. } => TOKENS_PER_VIDEO,
+            })
+            .sum(),
+        None => 0,
+    };
+    let tool_calls: i32 = message
+        .tool_calls
+        .iter()
+        .flatten()
+        .map(|call| {
+            estimate_tokens(&call.function.name, tokenizer)
+                + estimate_tokens(&call.function.arguments, tokenizer)
+        })
+        .sum();
+    TOKENS_PER_MESSAGE + content + tool_calls
+}
+
+pub fn estimate_prompt_tokens(request: &ChatCompletionsRequest, tokenizer: Option<&str>) -> i32 {
+    let messages: i32 = request
+        .messages
+        .iter()
+        .map(|message| estimate_message_tokens(message, tokenizer))
+        .sum();
+    let tools: i32 = request
+        .tools
+        .iter()
+        .flatten()
+        .map(|tool| estimate_tokens(&serde_json::to_string(tool).unwrap_or_default(), tokenizer))
+        .sum();
+    messages + tools + TOKENS_PER_REPLY
+}
+
+/// Counts the completion text of a stream as it passes, for streams that end
+/// without a usage chunk. Only the characters are counted; the text itself
+/// is not kept.
+#[derive(Clone)]
+pub struct UsageEstimator {
+    tokenizer: Option<String>,
+    prompt_tokens: i32,
+    completion_chars: usize,
+}
+
+impl UsageEstimator {
+    pub fn new(request: &ChatCompletionsRequest, tokenizer: Option<String>) -> Self {
+        Self {
+            prompt_tokens: estimate_prompt_tokens(request, tokenizer.as_deref()),
+            tokenizer,
+            completion_chars: 0,
+        }
+    }
+
+    pub fn observe(&mut self, response: &ChatCompletionsResponse) {
+        let chars = |text: &str| text.chars().count();
+        for choice in &response.choices {
+            self.completion_chars += match &choice.delta {
+                Some(Delta::Content { content })
+                | Some(Delta::Reasoning {
+                    reasoning_content: content,
+                })
+                | Some(Delta::Refusal { refusal: content }) => chars(content),
+                Some(Delta::ToolCalls { tool_calls }) => tool_calls
+                    .iter()
+                    .map(|call| chars(&call.function.name) + chars(&call.function.arguments))
+                    .sum(),
+                Some(Delta::FunctionCall { function_call }) => {
+                    chars(&function_call.name) + chars(&function_call.arguments)
+                }
+                _ => 0,
+            };
+        }
+    }
+
+    pub fn usage(&self) -> Usage {
+        let completion_tokens = tokens_for_chars(self.completion_chars, self.tokenizer.as_deref());
+        UsageBuilder::default()
+            .prompt_tokens(self.prompt_tokens)
+            .completion_tokens(completion_tokens)
+            .total_tokens(self.prompt_tokens + completion_tokens)
+            .estimated(true)
+            .build()
+    }
+}
+
+/// A usage callback shared by an upstream and the proxy, for streams that
+/// may end before the upstream reports its usage: the proxy stopping it,
+/// a deadline, or the client going away. Only the first report is passed
+/// on; a stream that ends with none reports an estimate of what it sent.
+#[derive(Clone)]
+pub struct UsageOnce {
+    reported: Arc<AtomicBool>,
+    callback: Arc<dyn Fn(&Usage) + Send + Sync>,
+    estimator: Arc<Mutex<UsageEstimator>>,
+}
+
+impl UsageOnce {
+    pub fn new(
+        callback: impl Fn(&Usage) + Send + Sync + 'static,
+        estimator: UsageEstimator,
+    ) -> Self {
+        Self {
+            reported: Arc::default(),
+            callback: Arc::new(callback),
+            estimator: Arc::new(Mutex::new(estimator)),
+        }
+    }
+
+    pub fn report(&self, usage: &Usage) {
+        if !self.reported.swap(true, Ordering::AcqRel) {
+            (self.callback)(usage);
+        }
+    }
+
+    /// The usage of what the stream has sent so far.
+    pub fn estimate(&self) -> Usage {
+        self.estimator.lock().unwrap().usage()
+    }
+
+    /// Counts the completion of each chunk passed on, and reports an
+    /// estimate when the stream is dropped or ends without a usage report.
+    pub fn observe<'a>(
+        self,
+        mut stream: BoxStream<'a, anyhow::Result<StreamEvent>>,
+    ) -> BoxStream<'a, anyhow::Result<StreamEvent>> {
+        async_stream::stream! {
+            let usage = ReportOnDrop(self);
+            while let Some(item) = stream.next().await {
+                if let Ok(StreamEvent::Chunk(data)) = &item
+                    && let Ok(response) = serde_json::from_slice::<ChatCompletionsResponse>(data)
+                {
+                    usage.0.estimator.lock().unwrap().observe(&response);
+                }
+                yield item;
+            }
+        }
+        .boxed()
+    }
+}
+
+struct ReportOnDrop(UsageOnce);
+
+impl Drop for ReportOnDrop {
+    fn drop(&mut self) {
+        if !self.0.reported.load(Ordering::Acquire) {
+            debug!("The stream ended before its usage was reported, recording an estimate");
+            self.0.report(&self.0.estimate());
+        }
+    }
+}
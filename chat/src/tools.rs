@@ -0,0 +1,330 @@
+use crate::mcp::McpRegistry;
+use anyhow::{Context, bail};
+use async_trait::async_trait;
+use futures::StreamExt;
+use request::{FunctionDefinition, Tool};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+pub const WEB_SEARCH_TOOL: &str = "web_search";
+pub const FETCH_URL_TOOL: &str = "fetch_url";
+
+#[derive(Clone, Debug)]
+pub struct WebSearchConfig {
+    pub url: String,
+    pub query_param: String,
+    pub api_key_header: Option<String>,
+    pub api_key: Option<String>,
+    pub max_bytes: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct FetchUrlConfig {
+    pub allowlist: Vec<String>,
+    pub max_bytes: usize,
+}
+
+/// Built-in tools the proxy runs itself. `enabled` applies to every caller
+/// unless their API key has its own list in `keys`.
+#[derive(Clone, Debug, Default)]
+pub struct BuiltinToolsConfig {
+    pub web_search: Option<WebSearchConfig>,
+    pub fetch_url: Option<FetchUrlConfig>,
+    pub enabled: Vec<String>,
+    pub keys: HashMap<String, Vec<String>>,
+    pub timeout: Option<Duration>,
+}
+
+#[async_trait]
+trait BuiltinTool: Send + Sync {
+    fn definition(&self) -> Tool;
+
+    async fn call(&self, arguments: Value) -> anyhow::Result<String>;
+}
+
+fn function_tool(name: &str, description: &str, parameters: Value) -> Tool {
+    Tool {
+        r#type: "function".to_string(),
+        function: FunctionDefinition {
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            parameters: Some(parameters),
+            strict: None,
+        },
+    }
+}
+
+fn string_argument<'a>(arguments: &'a Value, name: &str) -> anyhow::Result<&'a str> {
+    arguments[name]
+        .as_str()
+        .with_context(|| format!("Missing string argument `{}`", name))
+}
+
+/// The first `max_bytes` of a response body. The rest is never read, so a
+/// large or endless body costs no more than the limit.
+async fn read_text(response: reqwest::Response, max_bytes: usize) -> anyhow::Result<String> {
+    let status = response.status();
+    if !status.is_success() {
+        bail!("Request failed with status {}", status);
+    }
+
+    let mut body = Vec::new();
+    let mut chunks = response.bytes_stream();
+    while body.len() < max_bytes
+        && let Some(chunk) = chunks.next().await
+    {
+        let chunk = chunk?;
+        let end = chunk.len().min(max_bytes - body.len());
+        body.extend_from_slice(&chunk[..end]);
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+struct WebSearch {
+    client: reqwest::Client,
+    config: WebSearchConfig,
+}
+
+#[async_trait]
+impl BuiltinTool for WebSearch {
+    fn definition(&self) -> Tool {
+        function_tool(
+            WEB_SEARCH_TOOL,
+            "Search the web and return the raw results.",
+            json!({
+                "type": "object",
+                "properties": { "query": { "type": "string", "description": "The search query" } },
+                "required": ["query"],
+            }),
+        )
+    }
+
+    async fn call(&self, arguments: Value) -> anyhow::Result<String> {
+        let query = string_argument(&arguments, "query")?;
+        let mut builder = self
+            .client
+            .get(&self.config.url)
+            .query(&[(self.config.query_param.as_str(), query)]);
+        if let (Some(header), Some(api_key)) = (&self.config.api_key_header, &self.config.api_key) {
+            builder = builder.header(header, api_key);
+        }
+        read_text(builder.send().await?, self.config.max_bytes).await
+    }
+}
+
+fn host_allowed(allowlist: &[String], url: &reqwest::Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    matches!(url.scheme(), "http" | "https")
+        && allowlist.iter().any(|allowed| {
+            host == allowed
+                || host
+                    .strip_suffix(allowed.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+}
+
+struct FetchUrl {
+    client: reqwest::Client,
+    config: FetchUrlConfig,
+}
+
+impl FetchUrl {
+    fn new(config: FetchUrlConfig, timeout: Option<Duration>) -> anyhow::Result<Self> {
+        // Redirects are followed only while they stay on allowed hosts.
+        let allowlist = config.allowlist.clone();
+        let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::custom(
+            move |attempt| {
+                if attempt.previous().len() < 10 && host_allowed(&allowlist, attempt.url()) {
+                    attempt.follow()
+                } else {
+                    attempt.stop()
+                }
+            },
+        ));
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            config,
+        })
+    }
+}
+
+#[async_trait]
+impl BuiltinTool for FetchUrl {
+    fn definition(&self) -> Tool {
+        function_tool(
+            FETCH_URL_TOOL,
+            &format!(
+                "Fetch a web page and return its contents. Only these hosts are allowed: {}.",
+                self.config.allowlist.join(", ")
+            ),
+            json!({
+                "type": "object",
+                "properties": { "url": { "type": "string", "description": "The http or https URL to fetch" } },
+                "required": ["url"],
+            }),
+        )
+    }
+
+    async fn call(&self, arguments: Value) -> anyhow::Result<String> {
+        let url: reqwest::Url = string_argument(&arguments, "url")?.parse()?;
+        if !host_allowed(&self.config.allowlist, &url) {
+            bail!("Fetching {} is not allowed", url);
+        }
+        read_text(self.client.get(url).send().await?, self.config.max_bytes).await
+    }
+}
+
+/// The configured built-in tools, shared by all requests.
+#[derive(Default)]
+pub struct BuiltinTools {
+    tools: HashMap<&'static str, Arc<dyn BuiltinTool>>,
+    enabled: Vec<String>,
+    keys: HashMap<String, Vec<String>>,
+}
+
+impl BuiltinTools {
+    pub fn new(config: &BuiltinToolsConfig, http: &reqwest::Client) -> anyhow::Result<Self> {
+        let mut tools: HashMap<&'static str, Arc<dyn BuiltinTool>> = HashMap::new();
+
+        if let Some(web_search) = &config.web_search {
+            let client = match config.timeout {
+                Some(timeout) => reqwest::Client::builder().timeout(timeout).build()?,
+                None => http.clone(),
+            };
+            tools.insert(
+                WEB_SEARCH_TOOL,
+                Arc::new(WebSearch {
+                    client,
+                    config: web_search.clone(),
+                }),
+            );
+        }
+        if let Some(fetch_url) = &config.fetch_url {
+            tools.insert(
+                FETCH_URL_TOOL,
+                Arc::new(FetchUrl::new(fetch_url.clone(), config.timeout)?),
+            );
+        }
+
+        for name in config.enabled.iter().chain(config.keys.values().flatten()) {
+            if !tools.contains_key(name.as_str()) {
+                warn!("Built-in tool {} is enabled but not configured", name);
+            }
+        }
+
+        Ok(Self {
+            tools,
+            enabled: config.enabled.clone(),
+            keys: config.keys.clone(),
+        })
+    }
+
+    fn for_key(&self, api_key: Option<&str>) -> Vec<Arc<dyn BuiltinTool>> {
+        let enabled = api_key
+            .and_then(|api_key| self.keys.get(api_key))
+            .unwrap_or(&self.enabled);
+        enabled
+            .iter()
+            .filter_map(|name| self.tools.get(name.as_str()).cloned())
+            .collect()
+    }
+}
+
+/// Shortened form of an API key for logs and the admin dashboard.
+pub fn key_label(api_key: Option<&str>) -> String {
+    match api_key {
+        Some(api_key) if api_key.len() > 8 => {
+            format!("{}...", api_key.chars().take(6).collect::<String>())
+        }
+        Some(_) => "***".to_string(),
+        None => "anonymous".to_string(),
+    }
+}
+
+/// The tools a request may have run in the proxy: the MCP servers' tools
+/// and the built-in tools enabled for the caller's key.
+#[derive(Clone)]
+pub struct ServerTools {
+    mcp: Arc<McpRegistry>,
+    builtin: Vec<(Tool, Arc<dyn BuiltinTool>)>,
+    caller: String,
+}
+
+impl ServerTools {
+    pub fn new(mcp: &Arc<McpRegistry>, builtin: &BuiltinTools, api_key: Option<&str>) -> Self {
+        Self {
+            mcp: mcp.clone(),
+            builtin: builtin
+                .for_key(api_key)
+                .into_iter()
+                .map(|tool| (tool.definition(), tool))
+                .collect(),
+            caller: key_label(api_key),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mcp.is_empty() && self.builtin.is_empty()
+    }
+
+    pub fn max_tool_rounds(&self) -> usize {
+        self.mcp.max_tool_rounds
+    }
+
+    fn builtin(&self, name: &str) -> Option<&Arc<dyn BuiltinTool>> {
+        self.builtin
+            .iter()
+            .find(|(tool, _)| tool.function.name == name)
+            .map(|(_, tool)| tool)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.builtin(name).is_some() || self.mcp.contains(name)
+    }
+
+    pub fn tools(&self) -> impl Iterator<Item = &Tool> {
+        self.builtin
+            .iter()
+            .map(|(tool, _)| tool)
+            .chain(self.mcp.tools())
+    }
+
+    /// Executes a tool call and records it in the audit log. Failures are
+    /// returned as error text for the model.
+    pub async fn call(&self, name: &str, arguments: Value) -> (String, bool) {
+        let started = Instant::now();
+        let arguments_json = arguments.to_string();
+
+        let (output, is_error) = match self.builtin(name) {
+            Some(tool) => match tool.call(arguments).await {
+                Ok(output) => (output, false),
+                Err(e) => (format!("Tool call failed: {:#}", e), true),
+            },
+            None => self.mcp.call(name, arguments).await,
+        };
+
+        info!(
+            target: "audit",
+            tool = name,
+            caller = %self.caller,
+            arguments = %arguments_json,
+            duration_ms = started.elapsed().as_millis() as u64,
+            output_bytes = output.len(),
+            is_error,
+            "Server-side tool invocation"
+        );
+
+        (output, is_error)
+    }
+}
@@ -0,0 +1,145 @@
+use crate::tokens::{
+    TOKENS_PER_MESSAGE, estimate_message_tokens, estimate_prompt_tokens, estimate_tokens,
+};
+use request::{ChatCompletionsRequest, Contents, Message, Role};
+use std::ops::Range;
+use tracing::info;
+
+/// Models, matched by id prefix, whose oldest messages are dropped rather
+/// than the request refused when the prompt would not fit the context
+/// window.
+#[derive(Clone, Debug, Default)]
+pub struct TruncationConfig {
+    pub models: Vec<String>,
+}
+
+impl TruncationConfig {
+    pub fn applies_to(&self, model: &str) -> bool {
+        self.models
+            .iter()
+            .any(|prefix| model.starts_with(prefix.as_str()))
+    }
+}
+
+fn is_system(message: &Message) -> bool {
+    matches!(message.role, Role::System | Role::Developer)
+}
+
+/// The runs of messages that are dropped together: an assistant message
+/// with the results of the tool calls it makes, or any other message on
+/// its own. System and developer messages are always kept.
+fn turns(messages: &[Message]) -> Vec<Range<usize>> {
+    let mut turns = Vec::new();
+    let mut i = 0;
+    while i < messages.len() {
+        if is_system(&messages[i]) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        if messages[start]
+            .tool_calls
+            .as_ref()
+            .is_some_and(|calls| !calls.is_empty())
+        {
+            while i < messages.len() && matches!(messages[i].role, Role::Tool) {
+                i += 1;
+            }
+        }
+        turns.push(start..i);
+    }
+    turns
+}
+
+/// Cuts the start of a text message so it is estimated at about `tokens`.
+fn trim_start(message: &mut Message, tokens: i32, tokenizer: Option<&str>) {
+    let Some(Contents::String(text)) = &mut message.contents else {
+        return;
+    };
+    let chars = text.chars().count();
+    let content = estimate_tokens(text, tokenizer).max(1);
+    let mut keep = chars * tokens.max(0) as usize / content as usize;
+    let suffix = |keep: usize| text.chars().skip(chars - keep).collect::<String>();
+    let mut trimmed = suffix(keep);
+    while keep > 0 && estimate_tokens(&trimmed, tokenizer) > tokens {
+        keep -= 1;
+        trimmed = suffix(keep);
+    }
+    *text = trimmed;
+}
+
+/// Drops the oldest turns of a conversation until the prompt fits the
+/// context window with room for `max_tokens`, trimming the start of the
+/// oldest user message instead when that is enough. The latest turn and
+/// all system messages are kept, and tool calls keep their results, so the
+/// prompt may still not fit. Returns the number of messages dropped.
+pub fn truncate_to_window(
+    request: &mut ChatCompletionsRequest,
+    context_window: u32,
+    tokenizer: Option<&str>,
+) -> usize {
+    let budget =
+        i64::from(context_window) - i64::from(request.max_tokens.unwrap_or_default().max(0));
+    let mut excess = i64::from(estimate_prompt_tokens(request, tokenizer)) - budget;
+    if excess <= 0 {
+        return 0;
+    }
+
+    let turns = turns(&request.messages);
+    let Some((_, older)) = turns.split_last() else {
+        return 0;
+    };
+    let mut dropped = vec![false; request.messages.len()];
+    for turn in older {
+        let message = &request.messages[turn.start];
+        let tokens: i64 = request.messages[turn.clone()]
+            .iter()
+            .map(|message| i64::from(estimate_message_tokens(message, tokenizer)))
+            .sum();
+        let content = tokens - i64::from(TOKENS_PER_MESSAGE);
+        if turn.len() == 1
+            && matches!(message.role, Role::User)
+            && matches!(message.contents, Some(Contents::String(_)))
+            && excess < content
+        {
+            info!("Trimming the oldest user message to fit the context window");
+            trim_start(
+                &mut request.messages[turn.start],
+                (content - excess) as i32,
+                tokenizer,
+            );
+            break;
+        }
+        dropped[turn.clone()].fill(true);
+        excess -= tokens;
+        if excess <= 0 {
+            break;
+        }
+    }
+    // Conversations open with a user turn.
+    let any_dropped = dropped.contains(&true);
+    for turn in older.iter().filter(|_| any_dropped) {
+        if dropped[turn.start] {
+            continue;
+        }
+        if !matches!(request.messages[turn.start].role, Role::Assistant) {
+            break;
+        }
+        dropped[turn.clone()].fill(true);
+    }
+
+    let count = dropped.iter().filter(|dropped| **dropped).count();
+    if count > 0 {
+        info!(
+            "Dropped the {} oldest messages to fit the context window of {} tokens",
+            count, context_window
+        );
+    }
+    let mut index = 0;
+    request.messages.retain(|_| {
+        index += 1;
+        !dropped[index - 1]
+    });
+    count
+}
@@ -0,0 +1,315 @@
+use crate::{
+    error::{ErrorKind, ProviderError},
+    models::FoundationModel,
+};
+use aws_config::{BehaviorVersion, ConfigLoader, SdkConfig, timeout::TimeoutConfig};
+use aws_sdk_bedrockruntime::config::{ProvideCredentials, Region};
+use aws_sigv4::{
+    http_request::{
+        PercentEncodingMode, SignableBody, SignableRequest, SigningSettings,
+        UriPathNormalizationMode, sign,
+    },
+    sign::v4,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::OnceCell;
+use tracing::{debug, info};
+
+#[derive(Clone, Debug)]
+pub struct UpstreamHttpConfig {
+    pub http2_prior_knowledge: bool,
+    pub http2_adaptive_window: bool,
+    pub http2_keep_alive_interval: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    pub tcp_nodelay: bool,
+    pub pool_idle_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: usize,
+    pub connect_timeout: Option<Duration>,
+    pub dns_cache: bool,
+}
+
+impl Default for UpstreamHttpConfig {
+    fn default() -> Self {
+        Self {
+            http2_prior_knowledge: false,
+            http2_adaptive_window: true,
+            http2_keep_alive_interval: Some(Duration::from_secs(30)),
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            tcp_nodelay: true,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            pool_max_idle_per_host: usize::MAX,
+            connect_timeout: Some(Duration::from_secs(10)),
+            dns_cache: true,
+        }
+    }
+}
+
+/// Lets privileged keys send one request to a specific Bedrock region.
+pub const REGION_HEADER: &str = "x-aws-region";
+
+/// An AWS account, by shared config profile, that serves the Bedrock models
+/// matching its prefixes instead of the default credentials.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AwsAccount {
+    pub name: String,
+    pub profile: Option<String>,
+    pub region: Option<String>,
+    pub models: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BedrockClientsConfig {
+    /// Regions the region header may pick. They use the default account.
+    pub override_regions: Vec<String>,
+    pub accounts: Vec<AwsAccount>,
+    /// Adds the models `ListFoundationModels` reports to `/v1/models`.
+    pub list_models: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FoundationModels {
+    model_summaries: Vec<FoundationModel>,
+}
+
+/// The client of an account, created on first use.
+struct AccountClient {
+    account: AwsAccount,
+    client: OnceCell<aws_sdk_bedrockruntime::Client>,
+}
+
+/// Clients shared by every request so upstream connections are pooled and
+/// reused instead of being set up again for each completion.
+#[derive(Clone)]
+pub struct UpstreamClients {
+    pub http: reqwest::Client,
+    pub bedrock: aws_sdk_bedrockruntime::Client,
+    pub aws: SdkConfig,
+    /// Clients for the regions requests may pick with the region header.
+    regional: Arc<HashMap<String, aws_sdk_bedrockruntime::Client>>,
+    accounts: Arc<Vec<AccountClient>>,
+    upstream: UpstreamHttpConfig,
+}
+
+impl UpstreamClients {
+    pub async fn new(
+        config: &UpstreamHttpConfig,
+        bedrock: &BedrockClientsConfig,
+    ) -> anyhow::Result<Self> {
+        let aws = load_aws_config(config).await;
+        let regional = bedrock
+            .override_regions
+            .iter()
+            .map(|region| {
+                let config = aws_sdk_bedrockruntime::config::Builder::from(&aws)
+                    .region(Region::new(region.clone()))
+                    .build();
+                (
+                    region.clone(),
+                    aws_sdk_bedrockruntime::Client::from_conf(config),
+                )
+            })
+            .collect();
+        Ok(Self {
+            http: build_http_client(config)?,
+            bedrock: aws_sdk_bedrockruntime::Client::new(&aws),
+            aws,
+            regional: Arc::new(regional),
+            accounts: Arc::new(
+                bedrock
+                    .accounts
+                    .iter()
+                    .map(|account| AccountClient {
+                        account: account.clone(),
+                        client: OnceCell::new(),
+                    })
+                    .collect(),
+            ),
+            upstream: config.clone(),
+        })
+    }
+
+    fn account_for(&self, model: &str) -> Option<&AccountClient> {
+        self.accounts
+            .iter()
+            .filter_map(|account| {
+                account
+                    .account
+                    .models
+                    .iter()
+                    .filter(|prefix| model.starts_with(prefix.as_str()))
+                    .map(|prefix| (prefix.len(), account))
+                    .max_by_key(|(len, _)| *len)
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, account)| account)
+    }
+
+    /// The models of the default account and region, from the Bedrock
+    /// control plane. Its SDK is not a dependency, so the request is signed
+    /// here.
+    pub async fn list_foundation_models(&self) -> anyhow::Result<Vec<FoundationModel>> {
+        let region = self.region()?;
+        let url = format!("https://bedrock.{}.amazonaws.com/foundation-models", region);
+        let models: FoundationModels = self
+            .signed(
+                "bedrock",
+                &region,
+                reqwest::Method::GET,
+                &url,
+                &[],
+                Vec::new(),
+            )
+            .await?
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(models.model_summaries)
+    }
+
+    /// The region of the default account.
+    pub fn region(&self) -> anyhow::Result<String> {
+        self.aws
+            .region()
+            .map(|region| region.to_string())
+            .ok_or_else(|| anyhow::anyhow!("No AWS region configured"))
+    }
+
+    /// A request to an AWS service without an SDK dependency, signed with
+    /// the default account's credentials.
+    pub async fn signed(
+        &self,
+        service: &str,
+        region: &str,
+        method: reqwest::Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> anyhow::Result<reqwest::RequestBuilder> {
+        let credentials = self
+            .aws
+            .credentials_provider()
+            .ok_or_else(|| anyhow::anyhow!("No AWS credentials provider configured"))?
+            .provide_credentials()
+            .await?;
+        let identity = credentials.into();
+        let mut settings = SigningSettings::default();
+        // S3 signs the object key as it is sent, encoded once and with any
+        // `.` or `//` kept.
+        if service == "s3" {
+            settings.percent_encoding_mode = PercentEncodingMode::Single;
+            settings.uri_path_normalization_mode = UriPathNormalizationMode::Disabled;
+        }
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(region)
+            .name(service)
+            .time(SystemTime::now())
+            .settings(settings)
+            .build()?
+            .into();
+
+        let signable = SignableRequest::new(
+            method.as_str(),
+            url,
+            headers.iter().copied(),
+            SignableBody::Bytes(&body),
+        )?;
+        let (instructions, _) = sign(signable, &params)?.into_parts();
+        let mut request = self.http.request(method, url);
+        for (name, value) in headers.iter().copied().chain(instructions.headers()) {
+            request = request.header(name, value);
+        }
+        Ok(request.body(body))
+    }
+
+    /// The Bedrock client for the region a request asked for, if the key
+    /// may override it and the region is allowed. Otherwise the client of
+    /// the account serving the model, or the default one.
+    pub async fn bedrock_for(
+        &self,
+        model: &str,
+        region: Option<&str>,
+        may_override: bool,
+    ) -> Result<&aws_sdk_bedrockruntime::Client, ProviderError> {
+        let Some(region) = region else {
+            return Ok(match self.account_for(model) {
+                Some(account) => {
+                    account
+                        .client
+                        .get_or_init(|| async {
+                            info!("Loading AWS config for account {}", account.account.name);
+                            let mut loader = aws_config_loader(&self.upstream);
+                            if let Some(profile) = &account.account.profile {
+                                loader = loader.profile_name(profile);
+                            }
+                            if let Some(region) = &account.account.region {
+                                loader = loader.region(Region::new(region.clone()));
+                            }
+                            aws_sdk_bedrockruntime::Client::new(&loader.load().await)
+                        })
+                        .await
+                }
+                None => &self.bedrock,
+            });
+        };
+        if !may_override {
+            return Err(ProviderError::new(
+                ErrorKind::PermissionDenied,
+                format!("This key may not set the {} header", REGION_HEADER),
+            ));
+        }
+        debug!("Using Bedrock region {} for this request", region);
+        self.regional.get(region).ok_or_else(|| {
+            ProviderError::invalid_request(
+                format!("Region {} is not allowed", region),
+                Some(REGION_HEADER),
+            )
+        })
+    }
+}
+
+pub fn build_http_client(config: &UpstreamHttpConfig) -> anyhow::Result<reqwest::Client> {
+    debug!("Building upstream HTTP client with {:?}", config);
+
+    let mut builder = reqwest::Client::builder()
+        .http2_adaptive_window(config.http2_adaptive_window)
+        .http2_keep_alive_interval(config.http2_keep_alive_interval)
+        .http2_keep_alive_while_idle(config.http2_keep_alive_interval.is_some())
+        .tcp_keepalive(config.tcp_keepalive)
+        .tcp_nodelay(config.tcp_nodelay)
+        .pool_idle_timeout(config.pool_idle_timeout)
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .hickory_dns(config.dns_cache);
+
+    if config.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(connect_timeout) = config.connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+
+    Ok(builder.build()?)
+}
+
+fn aws_config_loader(config: &UpstreamHttpConfig) -> ConfigLoader {
+    let mut timeout_config = TimeoutConfig::builder();
+    timeout_config.set_connect_timeout(config.connect_timeout);
+    aws_config::defaults(BehaviorVersion::latest()).timeout_config(timeout_config.build())
+}
+
+pub async fn load_aws_config(config: &UpstreamHttpConfig) -> SdkConfig {
+    debug!("Loading AWS config");
+    aws_config_loader(config).load().await
+}
+
+pub async fn build_bedrock_client(config: &UpstreamHttpConfig) -> aws_sdk_bedrockruntime::Client {
+    aws_sdk_bedrockruntime::Client::new(&load_aws_config(config).await)
+}
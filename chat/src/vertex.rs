@@ -0,0 +1,241 @@
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use chrono::Utc;
+use ring::{
+    rand::SystemRandom,
+    signature::{RSA_PKCS1_SHA256, RsaKeyPair},
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const JWT_BEARER_GRANT: &str = "urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer";
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+/// Tokens are renewed this long before they expire.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+pub struct VertexConfig {
+    pub project: String,
+    /// A region such as `us-central1`, or `global`.
+    pub region: String,
+    /// Replaces the regional endpoint, e.g. for Private Service Connect.
+    pub endpoint: Option<String>,
+    /// A service account key. Without one the key named by
+    /// `GOOGLE_APPLICATION_CREDENTIALS` is used, and without that the
+    /// metadata server's identity (workload identity on GKE).
+    pub credentials_file: Option<PathBuf>,
+    /// Model id prefixes served through Vertex AI.
+    pub models: Vec<String>,
+}
+
+impl Default for VertexConfig {
+    fn default() -> Self {
+        Self {
+            project: String::new(),
+            region: "us-central1".to_string(),
+            endpoint: None,
+            credentials_file: None,
+            models: vec!["gemini-".to_string()],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    #[serde(rename = "type")]
+    key_type: String,
+    client_email: String,
+    private_key: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
+
+enum TokenSource {
+    ServiceAccount {
+        client_email: String,
+        token_uri: String,
+        key: Box<RsaKeyPair>,
+    },
+    Metadata,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Vertex AI endpoints and the OAuth access tokens to call them, renewed
+/// shortly before they expire.
+pub struct VertexClient {
+    config: VertexConfig,
+    http: reqwest::Client,
+    source: TokenSource,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl VertexClient {
+    pub async fn new(config: VertexConfig, http: &reqwest::Client) -> anyhow::Result<Self> {
+        if config.project.is_empty() {
+            anyhow::bail!("vertex.project must be set");
+        }
+        let credentials_file = config.credentials_file.clone().or_else(|| {
+            std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS")
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+        });
+        let source = match credentials_file {
+            Some(path) => {
+                let key: ServiceAccountKey =
+                    serde_json::from_slice(&tokio::fs::read(&path).await?)?;
+                if key.key_type != "service_account" {
+                    anyhow::bail!(
+                        "Unsupported Google credentials type {} in {}",
+                        key.key_type,
+                        path.display()
+                    );
+                }
+                info!(
+                    "Vertex AI uses service account {} from {}",
+                    key.client_email,
+                    path.display()
+                );
+                TokenSource::ServiceAccount {
+                    client_email: key.client_email,
+                    token_uri: key.token_uri,
+                    key: Box::new(
+                        RsaKeyPair::from_pkcs8(&pem_to_der(&key.private_key)?)
+                            .map_err(|e| anyhow::anyhow!("Invalid service account key: {}", e))?,
+                    ),
+                }
+            }
+            None => {
+                info!("Vertex AI uses the metadata server's service account");
+                TokenSource::Metadata
+            }
+        };
+
+        Ok(Self {
+            config,
+            http: http.clone(),
+            source,
+            token: Mutex::new(None),
+        })
+    }
+
+    pub fn serves(&self, model: &str) -> bool {
+        self.config
+            .models
+            .iter()
+            .any(|prefix| model.starts_with(prefix.as_str()))
+    }
+
+    /// The URL publisher model ids are appended to.
+    pub fn models_url(&self) -> String {
+        let endpoint = match &self.config.endpoint {
+            Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+            None if self.config.region == "global" => {
+                "https://aiplatform.googleapis.com".to_string()
+            }
+            None => format!("https://{}-aiplatform.googleapis.com", self.config.region),
+        };
+        format!(
+            "{}/v1/projects/{}/locations/{}/publishers/google/models",
+            endpoint, self.config.project, self.config.region
+        )
+    }
+
+    pub async fn token(&self) -> anyhow::Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some((access_token, expires)) = token.as_ref()
+            && Instant::now() + TOKEN_REFRESH_MARGIN < *expires
+        {
+            return Ok(access_token.clone());
+        }
+
+        let response = match &self.source {
+            TokenSource::ServiceAccount {
+                client_email,
+                token_uri,
+                key,
+            } => {
+                let assertion = signed_jwt(client_email, token_uri, key)?;
+                self.http
+                    .post(token_uri)
+                    .header("Content-Type", "application/x-www-form-urlencoded")
+                    .body(format!(
+                        "grant_type={}&assertion={}",
+                        JWT_BEARER_GRANT, assertion
+                    ))
+                    .send()
+                    .await?
+            }
+            TokenSource::Metadata => {
+                self.http
+                    .get(METADATA_TOKEN_URL)
+                    .header("Metadata-Flavor", "Google")
+                    .send()
+                    .await?
+            }
+        };
+        let response: TokenResponse = response.error_for_status()?.json().await?;
+        debug!(
+            "Fetched a Vertex AI access token valid for {} s",
+            response.expires_in
+        );
+
+        let expires = Instant::now() + Duration::from_secs(response.expires_in);
+        *token = Some((response.access_token.clone(), expires));
+        Ok(response.access_token)
+    }
+}
+
+fn pem_to_der(pem: &str) -> anyhow::Result<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    Ok(STANDARD.decode(body.trim())?)
+}
+
+/// A self-signed JWT asserting the service account, exchanged for an
+/// access token at the token URI.
+fn signed_jwt(client_email: &str, token_uri: &str, key: &RsaKeyPair) -> anyhow::Result<String> {
+    let now = Utc::now().timestamp();
+    let header = URL_SAFE_NO_PAD.encode(json!({ "alg": "RS256", "typ": "JWT" }).to_string());
+    let claims = URL_SAFE_NO_PAD.encode(
+        json!({
+            "iss": client_email,
+            "scope": CLOUD_PLATFORM_SCOPE,
+            "aud": token_uri,
+            "iat": now,
+            "exp": now + 3600,
+        })
+        .to_string(),
+    );
+    let message = format!("{}.{}", header, claims);
+
+    let mut signature = vec![0; key.public().modulus_len()];
+    key.sign(
+        &RSA_PKCS1_SHA256,
+        &SystemRandom::new(),
+        message.as_bytes(),
+        &mut signature,
+    )
+    .map_err(|_| anyhow::anyhow!("Failed to sign the service account assertion"))?;
+    Ok(format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature)))
+}
@@ -0,0 +1,205 @@
+use crate::{
+    error::from_bedrock_error, openai::OPENAI_API_CHAT_COMPLETIONS_URL, registry::DynamicProvider,
+    upstream::UpstreamClients,
+};
+use aws_config::SdkConfig;
+use aws_sdk_bedrockruntime::{
+    config::ProvideCredentials,
+    types::{ContentBlock, ConversationRole, InferenceConfiguration, Message},
+};
+use serde::Serialize;
+use std::{
+    future::Future,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
+use tracing::{info, warn};
+
+const OPENAI_API_MODELS_URL: &str = "https://api.openai.com/v1/models";
+const CANARY_PROMPT: &str = "ping";
+
+#[derive(Clone, Debug, Default)]
+pub struct WarmupConfig {
+    pub enabled: bool,
+    /// Model for a one-token Bedrock completion. Without one only the
+    /// credentials are resolved, as Bedrock has no cheaper call.
+    pub bedrock_canary_model: Option<String>,
+    /// Model for a one-token OpenAI completion.
+    pub openai_canary_model: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WarmupCheck {
+    pub name: String,
+    pub ok: bool,
+    pub elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WarmupReport {
+    pub ready: bool,
+    pub checks: Vec<WarmupCheck>,
+}
+
+/// Warms up credentials and upstream connections before traffic arrives and
+/// keeps the results for the readiness endpoint. Failed checks are reported
+/// but do not hold readiness back, since the upstream may recover by the
+/// time a request needs it.
+#[derive(Default)]
+pub struct Warmup {
+    report: RwLock<WarmupReport>,
+}
+
+impl Warmup {
+    /// A warmup that has nothing to do and is ready right away.
+    pub fn ready() -> Self {
+        Self {
+            report: RwLock::new(WarmupReport {
+                ready: true,
+                checks: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn report(&self) -> WarmupReport {
+        self.report.read().unwrap().clone()
+    }
+
+    fn record(&self, check: WarmupCheck) {
+        self.report.write().unwrap().checks.push(check);
+    }
+
+    async fn check<F>(&self, name: String, task: F)
+    where
+        F: Future<Output = anyhow::Result<()>>,
+    {
+        let started = Instant::now();
+        let result = task.await;
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(()) => info!("Warmup {} succeeded in {} ms", name, elapsed_ms),
+            Err(e) => warn!("Warmup {} failed after {} ms: {}", name, elapsed_ms, e),
+        }
+        self.record(WarmupCheck {
+            name,
+            ok: result.is_ok(),
+            elapsed_ms,
+            error: result.err().map(|e| e.to_string()),
+        });
+    }
+
+    pub async fn run(
+        self: Arc<Self>,
+        config: WarmupConfig,
+        clients: UpstreamClients,
+        openai_api_key: Option<String>,
+        providers: Vec<Arc<DynamicProvider>>,
+    ) {
+        info!("Warming up upstream connections");
+
+        self.check(
+            "bedrock_credentials".to_string(),
+            bedrock_credentials(&clients.aws),
+        )
+        .await;
+        if let Some(model) = &config.bedrock_canary_model {
+            self.check(
+                format!("bedrock_canary:{}", model),
+                bedrock_canary(&clients.bedrock, model),
+            )
+            .await;
+        }
+
+        if let Some(api_key) = openai_api_key.filter(|key| !key.is_empty()) {
+            self.check(
+                "openai_connection".to_string(),
+                connect(
+                    &clients.http,
+                    OPENAI_API_MODELS_URL.to_string(),
+                    Some(&api_key),
+                ),
+            )
+            .await;
+            if let Some(model) = &config.openai_canary_model {
+                self.check(
+                    format!("openai_canary:{}", model),
+                    openai_canary(&clients.http, &api_key, model),
+                )
+                .await;
+            }
+        }
+
+        for provider in providers {
+            let url = format!("{}/models", provider.base_url.trim_end_matches('/'));
+            let task = async {
+                let api_key = provider.api_key()?;
+                connect(&clients.http, url, api_key.as_deref()).await
+            };
+            self.check(format!("provider_connection:{}", provider.name), task)
+                .await;
+        }
+
+        self.report.write().unwrap().ready = true;
+        info!("Warmup finished");
+    }
+}
+
+async fn bedrock_credentials(aws: &SdkConfig) -> anyhow::Result<()> {
+    let provider = aws
+        .credentials_provider()
+        .ok_or_else(|| anyhow::anyhow!("No AWS credentials provider configured"))?;
+    provider.provide_credentials().await?;
+    Ok(())
+}
+
+async fn bedrock_canary(
+    client: &aws_sdk_bedrockruntime::Client,
+    model: &str,
+) -> anyhow::Result<()> {
+    client
+        .converse()
+        .model_id(model)
+        .messages(
+            Message::builder()
+                .role(ConversationRole::User)
+                .content(ContentBlock::Text(CANARY_PROMPT.to_string()))
+                .build()?,
+        )
+        .inference_config(InferenceConfiguration::builder().max_tokens(1).build())
+        .send()
+        .await
+        .map_err(|e| from_bedrock_error(e, model))?;
+    Ok(())
+}
+
+/// Opens a pooled connection. Any HTTP answer counts, the point is the TLS
+/// handshake and the DNS lookup.
+async fn connect(
+    client: &reqwest::Client,
+    url: String,
+    api_key: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut request = client.get(url);
+    if let Some(api_key) = api_key.filter(|key| !key.is_empty()) {
+        request = request.bearer_auth(api_key);
+    }
+    request.send().await?;
+    Ok(())
+}
+
+async fn openai_canary(client: &reqwest::Client, api_key: &str, model: &str) -> anyhow::Result<()> {
+    client
+        .post(OPENAI_API_CHAT_COMPLETIONS_URL)
+        .bearer_auth(api_key)
+        .json(&serde_json::json!({
+            "model": model,
+            "messages": [{"role": "user", "content": CANARY_PROMPT}],
+            "max_tokens": 1,
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
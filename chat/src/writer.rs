@@ -0,0 +1,226 @@
+use crate::{DONE_MESSAGE, StreamEvent, error::ProviderError, memory::StreamMemory};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::stream::{BoxStream, StreamExt};
+use std::{str::FromStr, time::Duration};
+use tokio::time::{Instant, timeout_at};
+use tracing::error;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlushStrategy {
+    #[default]
+    Immediate,
+    EveryEvents(usize),
+    Interval(Duration),
+}
+
+impl FromStr for FlushStrategy {
+    type Err = anyhow::Error;
+
+    /// Parses `immediate`, `events:<n>` or `interval:<ms>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "immediate" => Ok(FlushStrategy::Immediate),
+            Some(("events", n)) => Ok(FlushStrategy::EveryEvents(n.parse()?)),
+            Some(("interval", ms)) => {
+                Ok(FlushStrategy::Interval(Duration::from_millis(ms.parse()?)))
+            }
+            _ => anyhow::bail!("Unknown flush strategy: {}", s),
+        }
+    }
+}
+
+/// How the chunk stream is framed on the wire.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StreamFormat {
+    /// `text/event-stream` with `data:` lines, as OpenAI streams.
+    #[default]
+    Sse,
+    /// One JSON object per line. The end of the body marks the end of the
+    /// stream, so there is no `[DONE]` line.
+    Ndjson,
+}
+
+impl StreamFormat {
+    pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+    /// Picks NDJSON when the `Accept` header asks for it, SSE otherwise.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        let ndjson = accept.is_some_and(|accept| {
+            accept.split(',').any(|range| {
+                let media_type = range.split(';').next().unwrap_or_default().trim();
+                media_type.eq_ignore_ascii_case(Self::NDJSON_CONTENT_TYPE)
+                    || media_type.eq_ignore_ascii_case("application/jsonl")
+            })
+        });
+        if ndjson {
+            StreamFormat::Ndjson
+        } else {
+            StreamFormat::Sse
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            StreamFormat::Sse => "text/event-stream",
+            StreamFormat::Ndjson => Self::NDJSON_CONTENT_TYPE,
+        }
+    }
+
+    /// SSE events carry `id` when given; NDJSON has nowhere to put it.
+    pub fn encode_event(&self, event: &StreamEvent, id: Option<&str>, buffer: &mut BytesMut) {
+        match self {
+            StreamFormat::Sse => encode_sse_event(event, id, buffer),
+            StreamFormat::Ndjson => encode_ndjson_event(event, buffer),
+        }
+    }
+
+    pub fn encode_error(&self, error: &anyhow::Error, buffer: &mut BytesMut) {
+        match self {
+            StreamFormat::Sse => encode_sse_error(error, buffer),
+            StreamFormat::Ndjson => encode_ndjson_error(error, buffer),
+        }
+    }
+}
+
+pub fn encode_ndjson_event(event: &StreamEvent, buffer: &mut BytesMut) {
+    match event {
+        StreamEvent::Chunk(data) => buffer.put_slice(data),
+        StreamEvent::Dropped(dropped) => {
+            buffer.put_slice(format!("{{\"dropped_events\":{}}}", dropped).as_bytes());
+        }
+        StreamEvent::Done => return,
+    }
+    buffer.put_u8(b'\n');
+}
+
+pub fn encode_sse_event(event: &StreamEvent, id: Option<&str>, buffer: &mut BytesMut) {
+    if let Some(id) = id {
+        buffer.put_slice(b"id: ");
+        buffer.put_slice(id.as_bytes());
+        buffer.put_u8(b'\n');
+    }
+    match event {
+        StreamEvent::Chunk(data) => {
+            buffer.put_slice(b"data: ");
+            buffer.put_slice(data);
+        }
+        StreamEvent::Dropped(dropped) => {
+            buffer.put_slice(b"event: dropped\ndata: ");
+            buffer.put_slice(format!("{{\"dropped_events\":{}}}", dropped).as_bytes());
+        }
+        StreamEvent::Done => {
+            buffer.put_slice(b"data: ");
+            buffer.put_slice(DONE_MESSAGE.as_bytes());
+        }
+    }
+    buffer.put_slice(b"\n\n");
+}
+
+fn error_payload(error: &anyhow::Error) -> serde_json::Value {
+    let (error_type, code) = match error.downcast_ref::<ProviderError>() {
+        Some(e) => (e.kind.error_type(), e.code.as_deref()),
+        None => ("server_error", None),
+    };
+    serde_json::json!({
+        "error": {
+            "message": error.to_string(),
+            "type": error_type,
+            "code": code,
+        }
+    })
+}
+
+pub fn encode_sse_error(error: &anyhow::Error, buffer: &mut BytesMut) {
+    buffer.put_slice(b"data: ");
+    buffer.put_slice(error_payload(error).to_string().as_bytes());
+    buffer.put_slice(b"\n\n");
+}
+
+pub fn encode_ndjson_error(error: &anyhow::Error, buffer: &mut BytesMut) {
+    buffer.put_slice(error_payload(error).to_string().as_bytes());
+    buffer.put_u8(b'\n');
+}
+
+/// Encodes events in `format` and groups them into body chunks according
+/// to `strategy`, trading write latency for fewer syscalls.
+///
+/// Chunk memory reserved upstream is released once the chunk is handed to
+/// the socket. An error ends the body with an OpenAI-style error event.
+/// With a request id, SSE events are numbered under it (`<id>-<n>`), so
+/// client logs can be matched with the proxy's.
+pub fn stream_body(
+    mut stream: BoxStream<'static, anyhow::Result<StreamEvent>>,
+    format: StreamFormat,
+    strategy: FlushStrategy,
+    memory: StreamMemory,
+    request_id: Option<String>,
+) -> BoxStream<'static, anyhow::Result<Bytes>> {
+    let body = async_stream::stream! {
+        let mut buffer = BytesMut::new();
+        let mut sequence = 0;
+        let mut buffered_events = 0;
+        let mut buffered_bytes = 0;
+        let mut deadline: Option<Instant> = None;
+
+        loop {
+            let item = match deadline {
+                Some(deadline) if buffered_events > 0 => match timeout_at(deadline, stream.next()).await {
+                    Ok(item) => item,
+                    Err(_) => {
+                        buffered_events = 0;
+                        yield Ok(buffer.split().freeze());
+                        memory.release(std::mem::take(&mut buffered_bytes));
+                        continue;
+                    }
+                },
+                _ => stream.next().await,
+            };
+
+            let Some(item) = item else {
+                break;
+            };
+
+            let event = match item {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("Stream failed: {}", e);
+                    format.encode_error(&e, &mut buffer);
+                    yield Ok(buffer.split().freeze());
+                    return;
+                }
+            };
+
+            let done = matches!(event, StreamEvent::Done);
+            let id = request_id.as_ref().map(|request_id| format!("{}-{}", request_id, sequence));
+            sequence += 1;
+            format.encode_event(&event, id.as_deref(), &mut buffer);
+            buffered_events += 1;
+            buffered_bytes += event.buffered_size();
+
+            let flush = done
+                || match strategy {
+                    FlushStrategy::Immediate => true,
+                    FlushStrategy::EveryEvents(n) => buffered_events >= n,
+                    FlushStrategy::Interval(interval) => {
+                        if buffered_events == 1 {
+                            deadline = Some(Instant::now() + interval);
+                        }
+                        false
+                    }
+                };
+
+            if flush {
+                buffered_events = 0;
+                yield Ok(buffer.split().freeze());
+                memory.release(std::mem::take(&mut buffered_bytes));
+            }
+        }
+
+        if !buffer.is_empty() {
+            yield Ok(buffer.split().freeze());
+            memory.release(buffered_bytes);
+        }
+    };
+
+    body.boxed()
+}
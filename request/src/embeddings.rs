@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmbeddingsRequest {
+    /// Only Titan models take a dimension count.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+    /// `float` (the default) or `base64`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding_format: Option<EncodingFormat>,
+    pub input: EmbeddingsInput,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodingFormat {
+    #[default]
+    Float,
+    Base64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingsInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+impl EmbeddingsInput {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            EmbeddingsInput::Single(input) => vec![input],
+            EmbeddingsInput::Batch(inputs) => inputs,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            EmbeddingsInput::Single(_) => 1,
+            EmbeddingsInput::Batch(inputs) => inputs.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
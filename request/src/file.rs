@@ -0,0 +1,117 @@
+use crate::{InputFile, image::decode_field_data_url};
+use aws_sdk_bedrockruntime::{
+    error::BuildError,
+    primitives::Blob,
+    types::{DocumentBlock, DocumentFormat, DocumentSource},
+};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// The document types Bedrock reads, by extension and media type.
+const DOCUMENT_TYPES: &[(&str, &str, DocumentFormat)] = &[
+    ("pdf", "application/pdf", DocumentFormat::Pdf),
+    ("csv", "text/csv", DocumentFormat::Csv),
+    ("doc", "application/msword", DocumentFormat::Doc),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        DocumentFormat::Docx,
+    ),
+    ("xls", "application/vnd.ms-excel", DocumentFormat::Xls),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        DocumentFormat::Xlsx,
+    ),
+    ("html", "text/html", DocumentFormat::Html),
+    ("htm", "text/html", DocumentFormat::Html),
+    ("txt", "text/plain", DocumentFormat::Txt),
+    ("md", "text/markdown", DocumentFormat::Md),
+];
+
+/// The media type of a file part: the one of its data URL when that is a
+/// document type, else the one its filename's extension stands for, as
+/// clients often send `application/octet-stream`.
+pub fn file_media_type(file: &InputFile) -> Option<String> {
+    let media_type = file
+        .file_data
+        .as_deref()?
+        .strip_prefix("data:")?
+        .split_once(";base64,")?
+        .0
+        .to_ascii_lowercase();
+    if DOCUMENT_TYPES
+        .iter()
+        .any(|(_, known, _)| *known == media_type)
+    {
+        return Some(media_type);
+    }
+    let extension = file
+        .filename
+        .as_deref()
+        .and_then(|filename| filename.rsplit_once('.'))
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    DOCUMENT_TYPES
+        .iter()
+        .find(|(known, _, _)| Some(*known) == extension.as_deref())
+        .map(|(_, media_type, _)| media_type.to_string())
+        .or(Some(media_type))
+}
+
+/// A document name Bedrock accepts: letters, digits, single spaces,
+/// hyphens, parentheses and square brackets. Files without a usable name
+/// are named after a hash of their bytes, since names must differ within
+/// a request.
+fn document_name(filename: Option<&str>, bytes: &[u8]) -> String {
+    let stem = filename
+        .map(|filename| match filename.rsplit_once('.') {
+            Some((stem, _)) if !stem.is_empty() => stem,
+            _ => filename,
+        })
+        .unwrap_or_default();
+    let mut name = String::new();
+    for c in stem.chars() {
+        let c = match c {
+            c if c.is_alphanumeric() || "-()[]".contains(c) => c,
+            c if c.is_whitespace() => ' ',
+            _ => '-',
+        };
+        if !(c == ' ' && (name.is_empty() || name.ends_with(' '))) {
+            name.push(c);
+        }
+    }
+    let name = name.trim_end();
+    if !name.is_empty() {
+        return name.to_string();
+    }
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("document-{:08x}", hasher.finish() as u32)
+}
+
+pub fn file_to_block(file: &InputFile) -> Result<DocumentBlock, BuildError> {
+    if file.file_data.is_none() && file.file_id.is_some() {
+        return Err(BuildError::invalid_field(
+            "file",
+            "uploaded file ids are not supported; send the file as file_data",
+        ));
+    }
+    let data = file
+        .file_data
+        .as_deref()
+        .ok_or_else(|| BuildError::invalid_field("file", "file_data is required"))?;
+    let (_, bytes) = decode_field_data_url("file", data)?;
+    let media_type = file_media_type(file).unwrap_or_default();
+    let format = DOCUMENT_TYPES
+        .iter()
+        .find(|(_, known, _)| *known == media_type)
+        .map(|(_, _, format)| format.clone())
+        .ok_or_else(|| {
+            BuildError::invalid_field("file", format!("unsupported document type {}", media_type))
+        })?;
+
+    DocumentBlock::builder()
+        .format(format)
+        .name(document_name(file.filename.as_deref(), &bytes))
+        .source(DocumentSource::Bytes(Blob::new(bytes)))
+        .build()
+}
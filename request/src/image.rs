@@ -0,0 +1,57 @@
+use aws_sdk_bedrockruntime::{
+    error::BuildError,
+    primitives::Blob,
+    types::{ImageBlock, ImageFormat, ImageSource},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+/// Splits a `data:<mime>;base64,<data>` URL into its media type and bytes.
+pub fn decode_data_url(url: &str) -> Result<(String, Vec<u8>), BuildError> {
+    decode_field_data_url("image_url", url)
+}
+
+/// As [`decode_data_url`], with errors naming `field`.
+pub(crate) fn decode_field_data_url(
+    field: &'static str,
+    url: &str,
+) -> Result<(String, Vec<u8>), BuildError> {
+    let rest = url
+        .strip_prefix("data:")
+        .ok_or_else(|| BuildError::invalid_field(field, "only base64 data URLs are supported"))?;
+    let (media_type, data) = rest
+        .split_once(";base64,")
+        .ok_or_else(|| BuildError::invalid_field(field, "data URL must be base64 encoded"))?;
+    let bytes = STANDARD
+        .decode(data.trim())
+        .map_err(|e| BuildError::invalid_field(field, format!("invalid base64: {}", e)))?;
+    Ok((media_type.to_ascii_lowercase(), bytes))
+}
+
+pub fn encode_data_url(media_type: &str, bytes: &[u8]) -> String {
+    format!("data:{};base64,{}", media_type, STANDARD.encode(bytes))
+}
+
+pub fn media_type_to_format(media_type: &str) -> Option<ImageFormat> {
+    match media_type {
+        "image/png" => Some(ImageFormat::Png),
+        "image/jpeg" | "image/jpg" => Some(ImageFormat::Jpeg),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::Webp),
+        _ => None,
+    }
+}
+
+pub fn image_url_to_block(url: &str) -> Result<ImageBlock, BuildError> {
+    let (media_type, bytes) = decode_data_url(url)?;
+    let format = media_type_to_format(&media_type).ok_or_else(|| {
+        BuildError::invalid_field(
+            "image_url",
+            format!("unsupported image type {}", media_type),
+        )
+    })?;
+
+    ImageBlock::builder()
+        .format(format)
+        .source(ImageSource::Bytes(Blob::new(bytes)))
+        .build()
+}
@@ -1,4 +1,19 @@
-use aws_sdk_bedrockruntime::types::{ContentBlock, ConversationRole, SystemContentBlock};
+pub mod document;
+pub mod embeddings;
+pub mod file;
+pub mod image;
+pub mod schema;
+pub mod validate;
+pub mod video;
+
+use aws_sdk_bedrockruntime::{
+    error::BuildError,
+    types::{
+        AnyToolChoice, AutoToolChoice, CachePointBlock, CachePointType, ContentBlock,
+        ConversationRole, SpecificToolChoice, SystemContentBlock, ToolInputSchema, ToolResultBlock,
+        ToolResultContentBlock, ToolSpecification, ToolUseBlock,
+    },
+};
 use serde::{
     Deserialize, Serialize,
     de::{self, SeqAccess, Visitor},
@@ -7,33 +22,131 @@ use std::{collections::HashMap, fmt};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ChatCompletionsRequest {
+    /// Provider-specific fields, such as `top_k`, merged into Bedrock's
+    /// additional model request fields. Never sent to OpenAI, which rejects
+    /// unknown fields, but kept in replay and audit captures.
+    #[serde(default, skip_serializing)]
+    pub extra_body: Option<serde_json::Map<String, serde_json::Value>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub frequency_penalty: Option<f32>,
+    /// A Bedrock guardrail for models the config sets none for. Kept out of
+    /// the OpenAI body but in replay and audit captures.
+    #[serde(default, skip_serializing)]
+    pub guardrail: Option<Guardrail>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub logit_bias: Option<HashMap<String, String>>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub logit_bias: Option<HashMap<String, f32>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub messages: Vec<Message>,
+    /// Key-value pairs recorded with the request's usage and audit records.
+    /// Forwarded to OpenAI, and to Bedrock as request metadata.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<i32>,
+    #[serde(default)]
     pub model: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub n: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub presence_penalty: Option<f32>,
+    /// OpenRouter provider preferences. Like `route` and `transforms`, only
+    /// forwarded to OpenRouter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<serde_json::Value>,
+    /// A server-side prompt template, rendered into messages by the proxy
+    /// and never sent upstream.
+    #[serde(default, skip_serializing)]
+    pub prompt: Option<PromptReference>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// Like OpenAI's `auto`, `default`, `flex` or `priority`. Forwarded to
+    /// OpenAI; on Bedrock, the config maps tiers to model ids such as
+    /// provisioned throughput ARNs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stop: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub store: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stream_options: Option<StreamOptions>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub transforms: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
 }
 
+/// A Bedrock guardrail, by identifier and version. With `trace` set, its
+/// assessment is returned in the response as `guardrail_trace`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Guardrail {
+    pub id: String,
+    pub version: String,
+    #[serde(default)]
+    pub trace: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Minimal,
+    Low,
+    Medium,
+    High,
+}
+
+/// Tokens left for the answer when `max_tokens` is not set and thinking
+/// takes part of the output.
+const THINKING_ANSWER_TOKENS: i32 = 4096;
+
+/// The smallest thinking budget Claude models take.
+pub const MIN_THINKING_BUDGET: i32 = 1024;
+
+impl ReasoningEffort {
+    fn thinking_budget(self) -> i32 {
+        match self {
+            ReasoningEffort::Minimal => MIN_THINKING_BUDGET,
+            ReasoningEffort::Low => 2048,
+            ReasoningEffort::Medium => 8192,
+            ReasoningEffort::High => 16384,
+        }
+    }
+
+    /// The extended thinking budget that stands for the effort on Claude
+    /// models, and the `max_tokens` to send with it, which must be larger.
+    /// The budget shrinks to fit a client's `max_tokens`; `None` when that
+    /// leaves less than the smallest budget.
+    pub fn thinking_tokens(self, max_tokens: Option<i32>) -> Option<(i32, i32)> {
+        let budget = self.thinking_budget();
+        match max_tokens {
+            Some(max_tokens) => {
+                let budget = budget.min(max_tokens - 1);
+                (budget >= MIN_THINKING_BUDGET).then_some((budget, max_tokens))
+            }
+            None => Some((budget, budget + THINKING_ANSWER_TOKENS)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PromptReference {
+    pub id: String,
+    #[serde(default)]
+    pub variables: HashMap<String, serde_json::Value>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct StreamOptions {
     pub include_usage: bool,
@@ -41,19 +154,102 @@ pub struct StreamOptions {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Message {
-    #[serde(rename = "content")]
-    pub contents: Contents,
+    #[serde(rename = "content", default, skip_serializing_if = "Option::is_none")]
+    pub contents: Option<Contents>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub role: Role,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The thinking blocks of an assistant turn as they were streamed, with
+    /// the signatures Anthropic checks when they are sent back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking_blocks: Option<Vec<serde_json::Value>>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Message {
+    pub fn system(text: impl Into<String>) -> Self {
+        Self {
+            contents: Some(Contents::String(text.into())),
+            name: None,
+            role: Role::System,
+            tool_call_id: None,
+            tool_calls: None,
+            thinking_blocks: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     Assistant,
+    /// What OpenAI's newer models call the system role. Backends without
+    /// one take it as a system message.
+    Developer,
     System,
+    Tool,
     User,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub r#type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Tool {
+    pub r#type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Named(NamedToolChoice),
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoiceMode {
+    Auto,
+    None,
+    Required,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NamedToolChoice {
+    pub r#type: String,
+    pub function: NamedFunction,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NamedFunction {
+    pub name: String,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Contents {
@@ -65,7 +261,96 @@ pub enum Contents {
 #[serde(tag = "type")]
 pub enum Content {
     #[serde(rename = "text")]
-    Text { text: String },
+    Text {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    #[serde(rename = "image_url")]
+    ImageUrl {
+        image_url: ImageUrl,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    #[serde(rename = "file")]
+    File {
+        file: InputFile,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    #[serde(rename = "video")]
+    Video {
+        video: VideoInput,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+}
+
+impl Content {
+    /// The cache breakpoint set after this part, if any.
+    pub fn cache_control(&self) -> Option<&CacheControl> {
+        match self {
+            Content::Text { cache_control, .. }
+            | Content::ImageUrl { cache_control, .. }
+            | Content::File { cache_control, .. }
+            | Content::Video { cache_control, .. } => cache_control.as_ref(),
+        }
+    }
+}
+
+/// An Anthropic-style marker asking to cache the prompt up to and including
+/// the part it is set on. Only the `ephemeral` type exists.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CacheControl {
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+/// The most cache breakpoints a request may set, on Bedrock and Anthropic
+/// alike.
+pub const MAX_CACHE_POINTS: usize = 4;
+
+/// A Bedrock breakpoint caching the prompt up to it.
+pub fn cache_point() -> Option<CachePointBlock> {
+    CachePointBlock::builder()
+        .r#type(CachePointType::Default)
+        .build()
+        .ok()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// A document sent inline, as OpenAI's `file` content parts carry it.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InputFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// A base64 data URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_data: Option<String>,
+    /// A file uploaded to OpenAI, which only OpenAI can read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+}
+
+/// A video for models that watch them, like Amazon Nova.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VideoInput {
+    /// A base64 data URL, or an `s3://` URI that Bedrock reads itself.
+    pub url: String,
+    /// Like `mp4` or `mov`; by default taken from the media type or the
+    /// extension of the URI.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// The account that owns the bucket of an S3 video, when that is not
+    /// the proxy's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bucket_owner: Option<String>,
 }
 
 impl<'de> Visitor<'de> for Contents {
@@ -92,51 +377,254 @@ impl<'de> Visitor<'de> for Contents {
     }
 }
 
-impl From<&Contents> for Vec<ContentBlock> {
-    fn from(contents: &Contents) -> Self {
-        match contents {
+impl Contents {
+    /// The text of all parts, joined by newlines.
+    pub fn to_text(&self) -> String {
+        match self {
             Contents::Array(arr) => arr
                 .iter()
-                .map(|c| match c {
-                    Content::Text { text } => ContentBlock::Text(text.clone()),
+                .filter_map(|c| match c {
+                    Content::Text { text, .. } => Some(text.as_str()),
+                    Content::ImageUrl { .. } | Content::File { .. } | Content::Video { .. } => None,
                 })
-                .collect(),
-            Contents::String(s) => vec![ContentBlock::Text(s.clone())],
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Contents::String(s) => s.clone(),
+        }
+    }
+}
+
+impl TryFrom<&Contents> for Vec<ContentBlock> {
+    type Error = BuildError;
+
+    fn try_from(contents: &Contents) -> Result<Self, Self::Error> {
+        match contents {
+            Contents::Array(arr) => {
+                let mut blocks = Vec::with_capacity(arr.len());
+                for c in arr {
+                    blocks.push(match c {
+                        Content::Text { text, .. } => ContentBlock::Text(text.clone()),
+                        Content::ImageUrl { image_url, .. } => {
+                            ContentBlock::Image(image::image_url_to_block(&image_url.url)?)
+                        }
+                        Content::File { file, .. } => {
+                            ContentBlock::Document(file::file_to_block(file)?)
+                        }
+                        Content::Video { video, .. } => {
+                            ContentBlock::Video(video::video_to_block(video)?)
+                        }
+                    });
+                    if c.cache_control().is_some() {
+                        blocks.extend(cache_point().map(ContentBlock::CachePoint));
+                    }
+                }
+                Ok(blocks)
+            }
+            Contents::String(s) => Ok(vec![ContentBlock::Text(s.clone())]),
         }
     }
 }
 
 impl From<&Contents> for Vec<SystemContentBlock> {
     fn from(contents: &Contents) -> Self {
+        match contents {
+            Contents::Array(arr) => {
+                let mut blocks = Vec::new();
+                for c in arr {
+                    if let Content::Text { text, .. } = c {
+                        blocks.push(SystemContentBlock::Text(text.clone()));
+                    }
+                    if c.cache_control().is_some() {
+                        blocks.extend(cache_point().map(SystemContentBlock::CachePoint));
+                    }
+                }
+                blocks
+            }
+            Contents::String(s) => vec![SystemContentBlock::Text(s.clone())],
+        }
+    }
+}
+
+/// Tool results may carry images, documents and videos as well as text,
+/// like the screenshots of computer use agents.
+impl TryFrom<&Contents> for Vec<ToolResultContentBlock> {
+    type Error = BuildError;
+
+    fn try_from(contents: &Contents) -> Result<Self, Self::Error> {
         match contents {
             Contents::Array(arr) => arr
                 .iter()
                 .map(|c| match c {
-                    Content::Text { text } => SystemContentBlock::Text(text.clone()),
+                    Content::Text { text, .. } => Ok(ToolResultContentBlock::Text(text.clone())),
+                    Content::ImageUrl { image_url, .. } => Ok(ToolResultContentBlock::Image(
+                        image::image_url_to_block(&image_url.url)?,
+                    )),
+                    Content::File { file, .. } => {
+                        Ok(ToolResultContentBlock::Document(file::file_to_block(file)?))
+                    }
+                    Content::Video { video, .. } => {
+                        Ok(ToolResultContentBlock::Video(video::video_to_block(video)?))
+                    }
                 })
                 .collect(),
-            Contents::String(s) => vec![SystemContentBlock::Text(s.clone())],
+            Contents::String(s) => Ok(vec![ToolResultContentBlock::Text(s.clone())]),
         }
     }
 }
 
-impl From<&Role> for ConversationRole {
-    fn from(role: &Role) -> Self {
+impl TryFrom<&ToolCall> for ToolUseBlock {
+    type Error = BuildError;
+
+    fn try_from(tool_call: &ToolCall) -> Result<Self, Self::Error> {
+        let arguments = if tool_call.function.arguments.trim().is_empty() {
+            serde_json::Value::Object(Default::default())
+        } else {
+            serde_json::from_str(&tool_call.function.arguments)
+                .map_err(|e| BuildError::invalid_field("arguments", e.to_string()))?
+        };
+
+        ToolUseBlock::builder()
+            .tool_use_id(&tool_call.id)
+            .name(&tool_call.function.name)
+            .input(document::json_to_document(&arguments))
+            .build()
+    }
+}
+
+impl TryFrom<&Message> for ToolResultBlock {
+    type Error = BuildError;
+
+    fn try_from(message: &Message) -> Result<Self, Self::Error> {
+        let tool_use_id = message.tool_call_id.as_ref().ok_or_else(|| {
+            BuildError::missing_field("tool_call_id", "tool messages require a tool_call_id")
+        })?;
+
+        ToolResultBlock::builder()
+            .tool_use_id(tool_use_id)
+            .set_content(Some(
+                message
+                    .contents
+                    .as_ref()
+                    .map(TryInto::try_into)
+                    .transpose()?
+                    .unwrap_or_default(),
+            ))
+            .build()
+    }
+}
+
+impl TryFrom<&Tool> for aws_sdk_bedrockruntime::types::Tool {
+    type Error = BuildError;
+
+    fn try_from(tool: &Tool) -> Result<Self, Self::Error> {
+        let parameters = tool
+            .function
+            .parameters
+            .clone()
+            .unwrap_or_else(|| serde_json::json!({ "type": "object", "properties": {} }));
+
+        let specification = ToolSpecification::builder()
+            .name(&tool.function.name)
+            .set_description(tool.function.description.clone())
+            .input_schema(ToolInputSchema::Json(document::json_to_document(
+                &parameters,
+            )))
+            .build()?;
+
+        Ok(aws_sdk_bedrockruntime::types::Tool::ToolSpec(specification))
+    }
+}
+
+impl ToolChoice {
+    pub fn disables_tools(&self) -> bool {
+        matches!(self, ToolChoice::Mode(ToolChoiceMode::None))
+    }
+}
+
+impl TryFrom<&ToolChoice> for aws_sdk_bedrockruntime::types::ToolChoice {
+    type Error = BuildError;
+
+    fn try_from(tool_choice: &ToolChoice) -> Result<Self, Self::Error> {
+        use aws_sdk_bedrockruntime::types::ToolChoice as BedrockToolChoice;
+
+        match tool_choice {
+            ToolChoice::Mode(ToolChoiceMode::Auto) => {
+                Ok(BedrockToolChoice::Auto(AutoToolChoice::builder().build()))
+            }
+            ToolChoice::Mode(ToolChoiceMode::Required) => {
+                Ok(BedrockToolChoice::Any(AnyToolChoice::builder().build()))
+            }
+            ToolChoice::Mode(ToolChoiceMode::None) => Err(BuildError::invalid_field(
+                "tool_choice",
+                "\"none\" has no Bedrock equivalent; omit the tool configuration instead",
+            )),
+            ToolChoice::Named(named) => Ok(BedrockToolChoice::Tool(
+                SpecificToolChoice::builder()
+                    .name(&named.function.name)
+                    .build()?,
+            )),
+        }
+    }
+}
+
+impl TryFrom<&Role> for ConversationRole {
+    type Error = BuildError;
+
+    /// System and developer messages have no conversation role on Bedrock;
+    /// they are always hoisted into the top-level system content blocks
+    /// instead.
+    fn try_from(role: &Role) -> Result<Self, Self::Error> {
         match role {
-            Role::Assistant => ConversationRole::Assistant,
-            Role::User => ConversationRole::User,
-            Role::System => unreachable!(),
+            Role::Assistant => Ok(ConversationRole::Assistant),
+            Role::Tool | Role::User => Ok(ConversationRole::User),
+            Role::Developer | Role::System => Err(BuildError::invalid_field(
+                "role",
+                "system messages must be converted to system content blocks",
+            )),
         }
     }
 }
 
 impl TryFrom<&Message> for aws_sdk_bedrockruntime::types::Message {
-    type Error = aws_sdk_bedrockruntime::error::BuildError;
+    type Error = BuildError;
 
     fn try_from(message: &Message) -> Result<Self, Self::Error> {
+        let mut content: Vec<ContentBlock> = match message.role {
+            Role::Tool => {
+                let mut content = vec![ContentBlock::ToolResult(message.try_into()?)];
+                if let Some(Contents::Array(parts)) = &message.contents
+                    && parts.iter().any(|part| part.cache_control().is_some())
+                {
+                    content.extend(cache_point().map(ContentBlock::CachePoint));
+                }
+                content
+            }
+            _ => message
+                .contents
+                .as_ref()
+                .map(TryInto::try_into)
+                .transpose()?
+                .unwrap_or_default(),
+        };
+
+        for tool_call in message.tool_calls.iter().flatten() {
+            content.push(ContentBlock::ToolUse(tool_call.try_into()?));
+        }
+
+        // Bedrock rejects blank text blocks, which clients commonly send as
+        // the content of assistant turns that only made tool calls.
+        content
+            .retain(|block| !matches!(block, ContentBlock::Text(text) if text.trim().is_empty()));
+        if content.is_empty() {
+            return Err(BuildError::invalid_field(
+                "content",
+                "message must contain non-empty text or tool calls",
+            ));
+        }
+
         aws_sdk_bedrockruntime::types::Message::builder()
-            .set_role(Some((&message.role).into()))
-            .set_content(Some((&message.contents).into()))
+            .set_role(Some((&message.role).try_into()?))
+            .set_content(Some(content))
             .build()
     }
 }
@@ -0,0 +1,283 @@
+use crate::ChatCompletionsRequest;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Checks that a strict tool's parameters follow the rules OpenAI's strict
+/// mode sets: the root is an object, and every object lists all of its
+/// properties as required and allows no others.
+pub fn check_strict_schema(schema: &Value) -> Result<(), String> {
+    if schema.get("type").and_then(Value::as_str) != Some("object") {
+        return Err("the parameters of a strict tool must be an object schema".to_string());
+    }
+    check_strict_node(schema, "parameters")
+}
+
+fn check_strict_node(schema: &Value, path: &str) -> Result<(), String> {
+    let Some(fields) = schema.as_object() else {
+        return Ok(());
+    };
+    if let Some(properties) = fields.get("properties").and_then(Value::as_object) {
+        if fields.get("additionalProperties") != Some(&Value::Bool(false)) {
+            return Err(format!(
+                "{} must set additionalProperties to false in strict mode",
+                path
+            ));
+        }
+        let required: Vec<&str> = fields
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        for (name, property) in properties {
+            if !required.contains(&name.as_str()) {
+                return Err(format!(
+                    "{}.properties.{} must be required in strict mode",
+                    path, name
+                ));
+            }
+            check_strict_node(property, &format!("{}.properties.{}", path, name))?;
+        }
+    }
+    if let Some(items) = fields.get("items") {
+        check_strict_node(items, &format!("{}.items", path))?;
+    }
+    for keyword in ["anyOf", "$defs", "definitions"] {
+        match fields.get(keyword) {
+            Some(Value::Array(schemas)) => {
+                for (i, schema) in schemas.iter().enumerate() {
+                    check_strict_node(schema, &format!("{}.{}[{}]", path, keyword, i))?;
+                }
+            }
+            Some(Value::Object(schemas)) => {
+                for (name, schema) in schemas {
+                    check_strict_node(schema, &format!("{}.{}.{}", path, keyword, name))?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks a value against a JSON schema. Covers the keywords strict tool
+/// schemas use: `type`, `enum`, `const`, `properties`, `required`,
+/// `additionalProperties`, `items`, `anyOf`, local `$ref`s and the numeric,
+/// length and item count bounds. `pattern` and `format` are not checked.
+pub fn validate_json(schema: &Value, value: &Value) -> Result<(), String> {
+    Validator { root: schema }.check(schema, value, "$", 0)
+}
+
+/// Deeper `$ref` chains than this are taken to be cycles.
+const MAX_DEPTH: usize = 64;
+
+struct Validator<'a> {
+    root: &'a Value,
+}
+
+impl Validator<'_> {
+    fn resolve(&self, reference: &str) -> Option<&Value> {
+        let pointer = reference.strip_prefix('#')?;
+        self.root.pointer(pointer)
+    }
+
+    fn check(&self, schema: &Value, value: &Value, path: &str, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("{}: the schema nests too deeply", path));
+        }
+        let fields = match schema {
+            Value::Bool(true) => return Ok(()),
+            Value::Bool(false) => return Err(format!("{}: no value is allowed", path)),
+            Value::Object(fields) => fields,
+            _ => return Ok(()),
+        };
+
+        if let Some(reference) = fields.get("$ref").and_then(Value::as_str) {
+            let target = self
+                .resolve(reference)
+                .ok_or_else(|| format!("{}: unresolvable $ref {}", path, reference))?;
+            return self.check(target, value, path, depth + 1);
+        }
+
+        if let Some(types) = fields.get("type") {
+            let types: Vec<&str> = match types {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+                return Err(format!(
+                    "{}: expected {}, got {}",
+                    path,
+                    types.join(" or "),
+                    type_name(value)
+                ));
+            }
+        }
+
+        if let Some(allowed) = fields.get("enum").and_then(Value::as_array)
+            && !allowed.contains(value)
+        {
+            return Err(format!(
+                "{}: {} is not one of the allowed values",
+                path, value
+            ));
+        }
+        if let Some(expected) = fields.get("const")
+            && expected != value
+        {
+            return Err(format!("{}: expected {}", path, expected));
+        }
+
+        if let Some(schemas) = fields.get("anyOf").and_then(Value::as_array)
+            && !schemas
+                .iter()
+                .any(|schema| self.check(schema, value, path, depth + 1).is_ok())
+        {
+            return Err(format!("{}: matches none of the anyOf schemas", path));
+        }
+
+        match value {
+            Value::Object(object) => self.check_object(fields, object, path, depth)?,
+            Value::Array(items) => {
+                check_bound(fields, "minItems", items.len(), path, |len, min| len >= min)?;
+                check_bound(fields, "maxItems", items.len(), path, |len, max| len <= max)?;
+                if let Some(schema) = fields.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        self.check(schema, item, &format!("{}[{}]", path, i), depth + 1)?;
+                    }
+                }
+            }
+            Value::String(string) => {
+                let len = string.chars().count();
+                check_bound(fields, "minLength", len, path, |len, min| len >= min)?;
+                check_bound(fields, "maxLength", len, path, |len, max| len <= max)?;
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or_default();
+                if let Some(minimum) = fields.get("minimum").and_then(Value::as_f64)
+                    && number < minimum
+                {
+                    return Err(format!(
+                        "{}: {} is below the minimum of {}",
+                        path, number, minimum
+                    ));
+                }
+                if let Some(maximum) = fields.get("maximum").and_then(Value::as_f64)
+                    && number > maximum
+                {
+                    return Err(format!(
+                        "{}: {} is above the maximum of {}",
+                        path, number, maximum
+                    ));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn check_object(
+        &self,
+        fields: &Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &str,
+        depth: usize,
+    ) -> Result<(), String> {
+        for name in fields
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(name) {
+                return Err(format!("{}: missing required property {}", path, name));
+            }
+        }
+
+        let properties = fields.get("properties").and_then(Value::as_object);
+        for (name, property) in object {
+            let path = format!("{}.{}", path, name);
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(schema) => self.check(schema, property, &path, depth + 1)?,
+                None => match fields.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        return Err(format!("{}: unexpected property", path));
+                    }
+                    Some(schema @ Value::Object(_)) => {
+                        self.check(schema, property, &path, depth + 1)?
+                    }
+                    _ => {}
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_bound(
+    fields: &Map<String, Value>,
+    keyword: &str,
+    actual: usize,
+    path: &str,
+    within: fn(usize, usize) -> bool,
+) -> Result<(), String> {
+    match fields.get(keyword).and_then(Value::as_u64) {
+        Some(bound) if !within(actual, bound as usize) => Err(format!(
+            "{}: expected a {} of {}, got {}",
+            path, keyword, bound, actual
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_i64().is_some() || value.as_u64().is_some(),
+        "number" => value.is_number(),
+        _ => type_name(value) == name,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// The parameter schemas of a request's strict tools, by tool name, for
+/// checking the arguments of the calls a model makes to them.
+#[derive(Clone, Debug)]
+pub struct StrictTools(HashMap<String, Value>);
+
+impl StrictTools {
+    /// `None` when the request has no strict tools.
+    pub fn from_request(request: &ChatCompletionsRequest) -> Option<Self> {
+        let schemas: HashMap<String, Value> = request
+            .tools
+            .iter()
+            .flatten()
+            .filter(|tool| tool.function.strict == Some(true))
+            .filter_map(|tool| {
+                let schema = tool.function.parameters.clone()?;
+                Some((tool.function.name.clone(), schema))
+            })
+            .collect();
+        (!schemas.is_empty()).then_some(Self(schemas))
+    }
+
+    /// Checks the arguments of a call; calls to other tools pass.
+    pub fn check(&self, name: &str, arguments: &str) -> Result<(), String> {
+        let Some(schema) = self.0.get(name) else {
+            return Ok(());
+        };
+        let arguments: Value = serde_json::from_str(arguments)
+            .map_err(|e| format!("the arguments are not valid JSON: {}", e))?;
+        validate_json(schema, &arguments)
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{
+    ChatCompletionsRequest, Content, Contents, MAX_CACHE_POINTS, Role,
+    embeddings::EmbeddingsRequest, schema::check_strict_schema,
+};
+use std::fmt;
+
+#[derive(Debug)]
+pub struct ValidationError {
+    pub message: String,
+    pub param: String,
+}
+
+impl ValidationError {
+    fn new(param: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            param: param.into(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.param, self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// OpenAI's limits on `metadata`.
+const MAX_METADATA_PAIRS: usize = 16;
+const MAX_METADATA_KEY_CHARS: usize = 64;
+const MAX_METADATA_VALUE_CHARS: usize = 512;
+
+/// OpenAI's limit on the inputs of one embeddings request.
+pub const MAX_EMBEDDINGS_INPUTS: usize = 2048;
+
+/// Bedrock answers each choice with a request of its own, all at once.
+pub const MAX_CHOICES: i32 = 16;
+
+fn check_range(param: &str, value: Option<f32>, min: f32, max: f32) -> Result<(), ValidationError> {
+    match value {
+        Some(value) if !(min..=max).contains(&value) => Err(ValidationError::new(
+            param,
+            format!(
+                "{} is not in the valid range; expected a value between {} and {}",
+                value, min, max
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl ChatCompletionsRequest {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.model.trim().is_empty() {
+            return Err(ValidationError::new("model", "model must not be empty"));
+        }
+
+        if self.messages.is_empty() {
+            return Err(ValidationError::new(
+                "messages",
+                "messages must contain at least one message",
+            ));
+        }
+
+        for (i, message) in self.messages.iter().enumerate() {
+            match message.role {
+                Role::Tool if message.tool_call_id.is_none() => {
+                    return Err(ValidationError::new(
+                        format!("messages[{}].tool_call_id", i),
+                        "tool messages must have a tool_call_id",
+                    ));
+                }
+                Role::Assistant => {}
+                _ if message.tool_calls.is_some() => {
+                    return Err(ValidationError::new(
+                        format!("messages[{}].tool_calls", i),
+                        "only assistant messages may contain tool_calls",
+                    ));
+                }
+                _ => {}
+            }
+            if !matches!(message.role, Role::User | Role::Tool)
+                && let Some(Contents::Array(parts)) = &message.contents
+                && let Some(j) = parts
+                    .iter()
+                    .position(|part| matches!(part, Content::File { .. } | Content::Video { .. }))
+            {
+                return Err(ValidationError::new(
+                    format!("messages[{}].content[{}]", i, j),
+                    "only user and tool messages may contain files and videos",
+                ));
+            }
+        }
+
+        let mut cache_points = 0;
+        for (i, message) in self.messages.iter().enumerate() {
+            let Some(Contents::Array(parts)) = &message.contents else {
+                continue;
+            };
+            for (j, part) in parts.iter().enumerate() {
+                let Some(cache_control) = part.cache_control() else {
+                    continue;
+                };
+                if cache_control.kind != "ephemeral" {
+                    return Err(ValidationError::new(
+                        format!("messages[{}].content[{}].cache_control.type", i, j),
+                        "cache_control.type must be \"ephemeral\"",
+                    ));
+                }
+                cache_points += 1;
+                if cache_points > MAX_CACHE_POINTS {
+                    return Err(ValidationError::new(
+                        format!("messages[{}].content[{}].cache_control", i, j),
+                        format!(
+                            "a request may set at most {} cache_control markers",
+                            MAX_CACHE_POINTS
+                        ),
+                    ));
+                }
+            }
+        }
+
+        for (i, tool) in self.tools.iter().flatten().enumerate() {
+            if tool.function.strict == Some(true)
+                && let Some(parameters) = &tool.function.parameters
+            {
+                check_strict_schema(parameters).map_err(|message| {
+                    ValidationError::new(format!("tools[{}].function.parameters", i), message)
+                })?;
+            }
+        }
+
+        for (token, bias) in self.logit_bias.iter().flatten() {
+            if token.parse::<u32>().is_err() {
+                return Err(ValidationError::new(
+                    "logit_bias",
+                    format!("{:?} is not a token id", token),
+                ));
+            }
+            check_range(&format!("logit_bias.{}", token), Some(*bias), -100.0, 100.0)?;
+        }
+
+        if let Some(metadata) = &self.metadata {
+            if metadata.len() > MAX_METADATA_PAIRS {
+                return Err(ValidationError::new(
+                    "metadata",
+                    format!("metadata may have at most {} keys", MAX_METADATA_PAIRS),
+                ));
+            }
+            for (key, value) in metadata {
+                if key.chars().count() > MAX_METADATA_KEY_CHARS {
+                    return Err(ValidationError::new(
+                        "metadata",
+                        format!(
+                            "metadata keys may be at most {} characters long",
+                            MAX_METADATA_KEY_CHARS
+                        ),
+                    ));
+                }
+                if value.chars().count() > MAX_METADATA_VALUE_CHARS {
+                    return Err(ValidationError::new(
+                        format!("metadata.{}", key),
+                        format!(
+                            "metadata values may be at most {} characters long",
+                            MAX_METADATA_VALUE_CHARS
+                        ),
+                    ));
+                }
+            }
+        }
+
+        check_range("temperature", self.temperature, 0.0, 2.0)?;
+        check_range("top_p", self.top_p, 0.0, 1.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+
+        if self.max_tokens.is_some_and(|max_tokens| max_tokens < 1) {
+            return Err(ValidationError::new(
+                "max_tokens",
+                "max_tokens must be at least 1",
+            ));
+        }
+        if self.n.is_some_and(|n| n < 1) {
+            return Err(ValidationError::new("n", "n must be at least 1"));
+        }
+        if self.n.is_some_and(|n| n > MAX_CHOICES) {
+            return Err(ValidationError::new(
+                "n",
+                format!("n must be at most {}", MAX_CHOICES),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl EmbeddingsRequest {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.model.trim().is_empty() {
+            return Err(ValidationError::new("model", "model must not be empty"));
+        }
+
+        if self.input.is_empty() {
+            return Err(ValidationError::new(
+                "input",
+                "input must contain at least one string",
+            ));
+        }
+        if self.input.len() > MAX_EMBEDDINGS_INPUTS {
+            return Err(ValidationError::new(
+                "input",
+                format!(
+                    "input must contain at most {} strings",
+                    MAX_EMBEDDINGS_INPUTS
+                ),
+            ));
+        }
+
+        if self.dimensions == Some(0) {
+            return Err(ValidationError::new(
+                "dimensions",
+                "dimensions must be greater than 0",
+            ));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use crate::{VideoInput, image::decode_field_data_url};
+use aws_sdk_bedrockruntime::{
+    error::BuildError,
+    primitives::Blob,
+    types::{S3Location, VideoBlock, VideoFormat, VideoSource},
+};
+
+/// The video types Bedrock reads, by extension and media type.
+const VIDEO_TYPES: &[(&str, &str, VideoFormat)] = &[
+    ("mp4", "video/mp4", VideoFormat::Mp4),
+    ("mov", "video/quicktime", VideoFormat::Mov),
+    ("mkv", "video/x-matroska", VideoFormat::Mkv),
+    ("webm", "video/webm", VideoFormat::Webm),
+    ("flv", "video/x-flv", VideoFormat::Flv),
+    ("mpeg", "video/mpeg", VideoFormat::Mpeg),
+    ("mpg", "video/mpeg", VideoFormat::Mpg),
+    ("wmv", "video/x-ms-wmv", VideoFormat::Wmv),
+    ("3gp", "video/3gpp", VideoFormat::ThreeGp),
+];
+
+fn unsupported(what: &str) -> BuildError {
+    BuildError::invalid_field("video", format!("unsupported video type {}", what))
+}
+
+/// The format named in the part, as an extension or Bedrock's own name.
+fn named_format(format: &str) -> Result<VideoFormat, BuildError> {
+    let format = format.to_ascii_lowercase();
+    VIDEO_TYPES
+        .iter()
+        .find(|(extension, _, known)| *extension == format || known.as_str() == format)
+        .map(|(_, _, known)| known.clone())
+        .ok_or_else(|| unsupported(&format))
+}
+
+pub fn video_to_block(video: &VideoInput) -> Result<VideoBlock, BuildError> {
+    let (format, source) = if video.url.starts_with("data:") {
+        let (media_type, bytes) = decode_field_data_url("video", &video.url)?;
+        let format = match &video.format {
+            Some(format) => named_format(format)?,
+            None => VIDEO_TYPES
+                .iter()
+                .find(|(_, known, _)| *known == media_type)
+                .map(|(_, _, format)| format.clone())
+                .ok_or_else(|| unsupported(&media_type))?,
+        };
+        (format, VideoSource::Bytes(Blob::new(bytes)))
+    } else if video.url.starts_with("s3://") {
+        let format = match &video.format {
+            Some(format) => named_format(format)?,
+            None => {
+                let extension = video
+                    .url
+                    .rsplit_once('.')
+                    .map(|(_, extension)| extension)
+                    .unwrap_or_default();
+                named_format(extension)?
+            }
+        };
+        let location = S3Location::builder()
+            .uri(&video.url)
+            .set_bucket_owner(video.bucket_owner.clone())
+            .build()?;
+        (format, VideoSource::S3Location(location))
+    } else {
+        return Err(BuildError::invalid_field(
+            "video",
+            "video url must be a base64 data URL or an s3:// URI",
+        ));
+    };
+
+    VideoBlock::builder().format(format).source(source).build()
+}
@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmbeddingsResponse {
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub object: String,
+    pub usage: EmbeddingsUsage,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Embedding {
+    pub embedding: EmbeddingVector,
+    pub index: usize,
+    pub object: String,
+}
+
+/// The vector as numbers, or with `encoding_format: base64` as the base64
+/// of its little-endian `f32` bytes.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct EmbeddingsUsage {
+    pub prompt_tokens: i32,
+    pub total_tokens: i32,
+}
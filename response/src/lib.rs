@@ -1,8 +1,12 @@
+pub mod embeddings;
+
 use aws_sdk_bedrockruntime::types::{
-    ContentBlockDelta, ConversationRole, ConverseStreamOutput, StopReason,
+    ContentBlockDelta, ContentBlockStart, ConversationRole, ConverseStreamOutput,
+    ReasoningContentBlockDelta, StopReason, TokenUsage,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
+use tracing::warn;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ChatCompletionsResponse {
@@ -10,13 +14,20 @@ pub struct ChatCompletionsResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub created: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub id: Option<String>,
+    pub id: Option<Arc<str>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub model: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub object: Option<String>,
+    /// The tier that served the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub usage: Option<Usage>,
+    /// What a Bedrock guardrail with tracing on made of the request and
+    /// the response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guardrail_trace: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -33,22 +44,52 @@ pub struct Choice {
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Delta {
-    Content { content: String },
-    Role { role: String },
-    ToolCalls { tool_calls: Vec<ToolCall> },
-    FunctionCall { function_call: FunctionCall },
+    Content {
+        content: String,
+    },
+    Role {
+        role: String,
+    },
+    ToolCalls {
+        tool_calls: Vec<ToolCall>,
+    },
+    /// The thinking of a reasoning model, streamed ahead of its answer.
+    Reasoning {
+        reasoning_content: String,
+    },
+    /// A finished thinking block with its signature, to be sent back in
+    /// the `thinking_blocks` of the assistant message.
+    ThinkingBlocks {
+        thinking_blocks: Vec<serde_json::Value>,
+    },
+    /// Why the model or a guardrail declined to answer, in place of content.
+    Refusal {
+        refusal: String,
+    },
+    FunctionCall {
+        function_call: FunctionCall,
+    },
     Empty {},
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ToolCall {
+    /// The position of the call among those of the message, which streaming
+    /// clients use to tell parallel calls apart.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<i32>,
+    /// Left out, with the type and name, of the chunks that continue the
+    /// arguments of a streamed call.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub r#type: String,
     pub function: FunctionCall,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct FunctionCall {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub name: String,
     pub arguments: String,
 }
@@ -58,6 +99,39 @@ pub struct Usage {
     pub completion_tokens: i32,
     pub prompt_tokens: i32,
     pub total_tokens: i32,
+    /// Set when the proxy estimated the counts because the upstream did not
+    /// report them.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+    /// Set when part of the prompt was read from or written to a prompt
+    /// cache. The prompt tokens include both.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PromptTokensDetails {
+    /// Tokens read from the cache.
+    pub cached_tokens: i32,
+    /// Tokens written to the cache, which upstreams bill above input tokens.
+    #[serde(default)]
+    pub cache_write_tokens: i32,
+}
+
+impl Usage {
+    /// Adds the counts of another response, as for the choices of one
+    /// request.
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.estimated |= other.estimated;
+        if let Some(other) = &other.prompt_tokens_details {
+            let details = self.prompt_tokens_details.get_or_insert_default();
+            details.cached_tokens += other.cached_tokens;
+            details.cache_write_tokens += other.cache_write_tokens;
+        }
+    }
 }
 
 impl ChatCompletionsResponse {
@@ -70,10 +144,12 @@ impl ChatCompletionsResponse {
 pub struct ChatCompletionsResponseBuilder {
     choices: Vec<Choice>,
     created: Option<i64>,
-    id: Option<String>,
+    id: Option<Arc<str>>,
     model: Option<String>,
     object: Option<String>,
+    service_tier: Option<String>,
     usage: Option<Usage>,
+    guardrail_trace: Option<serde_json::Value>,
 }
 
 impl ChatCompletionsResponseBuilder {
@@ -87,7 +163,7 @@ impl ChatCompletionsResponseBuilder {
         self
     }
 
-    pub fn id(mut self, id: Option<String>) -> Self {
+    pub fn id(mut self, id: Option<Arc<str>>) -> Self {
         self.id = id;
         self
     }
@@ -102,11 +178,21 @@ impl ChatCompletionsResponseBuilder {
         self
     }
 
+    pub fn service_tier(mut self, service_tier: Option<String>) -> Self {
+        self.service_tier = service_tier;
+        self
+    }
+
     pub fn usage(mut self, usage: Option<Usage>) -> Self {
         self.usage = usage;
         self
     }
 
+    pub fn guardrail_trace(mut self, guardrail_trace: Option<serde_json::Value>) -> Self {
+        self.guardrail_trace = guardrail_trace;
+        self
+    }
+
     pub fn build(self) -> ChatCompletionsResponse {
         ChatCompletionsResponse {
             choices: self.choices,
@@ -114,7 +200,9 @@ impl ChatCompletionsResponseBuilder {
             id: self.id,
             model: self.model,
             object: self.object,
+            service_tier: self.service_tier,
             usage: self.usage,
+            guardrail_trace: self.guardrail_trace,
         }
     }
 }
@@ -163,6 +251,9 @@ pub struct UsageBuilder {
     pub completion_tokens: i32,
     pub prompt_tokens: i32,
     pub total_tokens: i32,
+    pub estimated: bool,
+    pub cache_read_tokens: i32,
+    pub cache_write_tokens: i32,
 }
 
 impl UsageBuilder {
@@ -181,40 +272,211 @@ impl UsageBuilder {
         self
     }
 
+    pub fn estimated(mut self, estimated: bool) -> Self {
+        self.estimated = estimated;
+        self
+    }
+
+    pub fn cache_read_tokens(mut self, tokens: i32) -> Self {
+        self.cache_read_tokens = tokens;
+        self
+    }
+
+    pub fn cache_write_tokens(mut self, tokens: i32) -> Self {
+        self.cache_write_tokens = tokens;
+        self
+    }
+
     pub fn build(self) -> Usage {
+        let cached = self.cache_read_tokens != 0 || self.cache_write_tokens != 0;
         Usage {
             prompt_tokens: self.prompt_tokens,
             completion_tokens: self.completion_tokens,
             total_tokens: self.total_tokens,
+            estimated: self.estimated,
+            prompt_tokens_details: cached.then_some(PromptTokensDetails {
+                cached_tokens: self.cache_read_tokens,
+                cache_write_tokens: self.cache_write_tokens,
+            }),
+        }
+    }
+}
+
+/// Usage from Bedrock's counts, whose input tokens leave out the tokens
+/// read from and written to the prompt cache.
+pub fn bedrock_usage(usage: &TokenUsage) -> Usage {
+    let cache_read_tokens = usage.cache_read_input_tokens.unwrap_or_default();
+    let cache_write_tokens = usage.cache_write_input_tokens.unwrap_or_default();
+    let prompt_tokens = usage.input_tokens + cache_read_tokens + cache_write_tokens;
+    UsageBuilder::default()
+        .prompt_tokens(prompt_tokens)
+        .completion_tokens(usage.output_tokens)
+        .total_tokens(prompt_tokens + usage.output_tokens)
+        .cache_read_tokens(cache_read_tokens)
+        .cache_write_tokens(cache_write_tokens)
+        .build()
+}
+
+pub fn stop_reason_to_finish_reason(stop_reason: &StopReason) -> &'static str {
+    match stop_reason {
+        StopReason::EndTurn | StopReason::StopSequence => "stop",
+        StopReason::MaxTokens => "length",
+        StopReason::ToolUse => "tool_calls",
+        StopReason::ContentFiltered | StopReason::GuardrailIntervened => "content_filter",
+        other => {
+            warn!("Unknown Bedrock stop reason: {}", other.as_str());
+            "stop"
+        }
+    }
+}
+
+/// The tool calls of a ConverseStream response, by content block. A call is
+/// numbered when its block starts, so `tool_calls[].index` stays the same
+/// for each call however the model interleaves its blocks. Its id and name
+/// are sent then and its arguments as they stream, unless the tracker is
+/// buffered, which sends each call whole once its block stops, so the
+/// arguments can be checked against the tool's schema first.
+#[derive(Default)]
+pub struct ToolCallTracker {
+    pending: HashMap<i32, PendingToolCall>,
+    started: i32,
+    buffered: bool,
+}
+
+struct PendingToolCall {
+    index: i32,
+    /// The whole call, while a buffered tracker holds it back.
+    held: Option<ToolCall>,
+    streamed: bool,
+}
+
+/// A chunk of the arguments of a call already started.
+fn arguments_fragment(index: i32, arguments: String) -> ToolCall {
+    ToolCall {
+        index: Some(index),
+        id: String::new(),
+        r#type: String::new(),
+        function: FunctionCall {
+            name: String::new(),
+            arguments,
+        },
+    }
+}
+
+impl ToolCallTracker {
+    pub fn buffered() -> Self {
+        Self {
+            buffered: true,
+            ..Self::default()
+        }
+    }
+
+    fn start(&mut self, block: i32, id: String, name: String) -> Option<ToolCall> {
+        let index = self.started;
+        self.started += 1;
+        let call = ToolCall {
+            index: Some(index),
+            id,
+            r#type: "function".to_string(),
+            function: FunctionCall {
+                name,
+                arguments: String::new(),
+            },
+        };
+        let (held, sent) = match self.buffered {
+            true => (Some(call), None),
+            false => (None, Some(call)),
+        };
+        self.pending.insert(
+            block,
+            PendingToolCall {
+                index,
+                held,
+                streamed: false,
+            },
+        );
+        sent
+    }
+
+    fn delta(&mut self, block: i32, input: &str) -> Option<ToolCall> {
+        let pending = self.pending.get_mut(&block)?;
+        if let Some(call) = &mut pending.held {
+            call.function.arguments.push_str(input);
+            return None;
+        }
+        if input.is_empty() {
+            return None;
+        }
+        pending.streamed = true;
+        Some(arguments_fragment(pending.index, input.to_string()))
+    }
+
+    fn stop(&mut self, block: i32) -> Option<ToolCall> {
+        let pending = self.pending.remove(&block)?;
+        match pending.held {
+            Some(mut call) => {
+                if call.function.arguments.is_empty() {
+                    call.function.arguments = "{}".to_string();
+                }
+                Some(call)
+            }
+            None if !pending.streamed => Some(arguments_fragment(pending.index, "{}".to_string())),
+            None => None,
         }
     }
+
+    /// Forgets the calls of the message so far, for a new model turn.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.started = 0;
+    }
+}
+
+fn tool_call_choice(call: ToolCall) -> Choice {
+    ChoiceBuilder::default()
+        .delta(Some(Delta::ToolCalls {
+            tool_calls: vec![call],
+        }))
+        .build()
 }
 
 pub fn converse_stream_output_to_chat_completions_response_builder(
-    output: &ConverseStreamOutput,
-    usage_callback: Arc<dyn Fn(&Usage)>,
+    output: ConverseStreamOutput,
+    tool_calls: &mut ToolCallTracker,
+    usage_callback: &dyn Fn(&Usage),
 ) -> ChatCompletionsResponseBuilder {
     let mut builder = ChatCompletionsResponse::builder();
 
     match output {
+        ConverseStreamOutput::ContentBlockStart(event) => {
+            if let Some(ContentBlockStart::ToolUse(start)) = event.start
+                && let Some(call) =
+                    tool_calls.start(event.content_block_index, start.tool_use_id, start.name)
+            {
+                builder = builder.choice(tool_call_choice(call));
+            }
+        }
         ConverseStreamOutput::ContentBlockDelta(event) => {
-            let delta = event
-                .delta
-                .as_ref()
-                .and_then(|d| match d {
-                    ContentBlockDelta::Text(text) => Some(text.clone()),
-                    _ => None,
-                })
-                .map(|content| Delta::Content {
-                    content: content.clone(),
-                });
-
-            let choice = ChoiceBuilder::default()
-                .delta(delta)
-                .index(event.content_block_index)
-                .build();
-
-            builder = builder.choice(choice);
+            let delta = match event.delta {
+                Some(ContentBlockDelta::Text(content)) => Some(Delta::Content { content }),
+                Some(ContentBlockDelta::ReasoningContent(ReasoningContentBlockDelta::Text(
+                    reasoning_content,
+                ))) => Some(Delta::Reasoning { reasoning_content }),
+                Some(ContentBlockDelta::ToolUse(delta)) => {
+                    if let Some(call) = tool_calls.delta(event.content_block_index, &delta.input) {
+                        builder = builder.choice(tool_call_choice(call));
+                    }
+                    return builder;
+                }
+                _ => None,
+            };
+
+            builder = builder.choice(ChoiceBuilder::default().delta(delta).build());
+        }
+        ConverseStreamOutput::ContentBlockStop(event) => {
+            if let Some(call) = tool_calls.stop(event.content_block_index) {
+                builder = builder.choice(tool_call_choice(call));
+            }
         }
         ConverseStreamOutput::MessageStart(event) => {
             let choice = ChoiceBuilder::default()
@@ -230,21 +492,16 @@ pub fn converse_stream_output_to_chat_completions_response_builder(
         }
         ConverseStreamOutput::MessageStop(event) => {
             let choice = ChoiceBuilder::default()
-                .finish_reason(match event.stop_reason {
-                    StopReason::EndTurn => Some("stop".to_string()),
-                    _ => None,
-                })
+                .finish_reason(Some(
+                    stop_reason_to_finish_reason(&event.stop_reason).to_string(),
+                ))
                 .build();
 
             builder = builder.choice(choice);
         }
         ConverseStreamOutput::Metadata(event) => {
-            let usage = event.usage.as_ref().map(|u| {
-                let usage = UsageBuilder::default()
-                    .completion_tokens(u.output_tokens)
-                    .prompt_tokens(u.input_tokens)
-                    .total_tokens(u.total_tokens)
-                    .build();
+            let usage = event.usage.map(|u| {
+                let usage = bedrock_usage(&u);
 
                 usage_callback(&usage);
 
@@ -0,0 +1,266 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderName, Method, Version, header},
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde_json::json;
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::{error, info, warn};
+
+/// Lines waiting to be written; more are dropped.
+const QUEUE_CAPACITY: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AccessLogFormat {
+    /// The NCSA combined format, followed by the model and the duration in
+    /// milliseconds. The key stands in for the user.
+    #[default]
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => anyhow::bail!("Unknown access log format: {}", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum AccessLogOutput {
+    #[default]
+    Stdout,
+    Stderr,
+    /// Lines are appended to the file.
+    File(PathBuf),
+}
+
+impl FromStr for AccessLogOutput {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "stdout" | "-" => AccessLogOutput::Stdout,
+            "stderr" => AccessLogOutput::Stderr,
+            path => AccessLogOutput::File(PathBuf::from(path)),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    pub output: AccessLogOutput,
+}
+
+/// What a handler knows about a request that the access log can't tell
+/// from the request line: who sent it and for which model. Handlers find
+/// it in the request extensions.
+#[derive(Clone, Debug, Default)]
+pub struct AccessLabels(Arc<Mutex<Labels>>);
+
+#[derive(Clone, Debug, Default)]
+struct Labels {
+    key: Option<String>,
+    model: Option<String>,
+}
+
+impl AccessLabels {
+    pub fn set_key(&self, key: &str) {
+        self.0.lock().unwrap().key = Some(key.to_string());
+    }
+
+    pub fn set_model(&self, model: &str) {
+        self.0.lock().unwrap().model = Some(model.to_string());
+    }
+}
+
+/// One request, as it is logged once its response body has been sent.
+pub struct AccessEntry {
+    timestamp: DateTime<Utc>,
+    started: Instant,
+    client: IpAddr,
+    method: Method,
+    path: String,
+    version: Version,
+    request_id: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    labels: AccessLabels,
+    status: u16,
+    bytes: u64,
+}
+
+impl AccessEntry {
+    pub fn new(request: &Request, client: IpAddr, labels: AccessLabels) -> Self {
+        let header = |name: HeaderName| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            timestamp: Utc::now(),
+            started: Instant::now(),
+            client,
+            method: request.method().clone(),
+            path: request.uri().path().to_string(),
+            version: request.version(),
+            request_id: header(HeaderName::from_static("x-request-id")),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+            labels,
+            status: 0,
+            bytes: 0,
+        }
+    }
+
+    fn format(&self, format: AccessLogFormat) -> String {
+        let labels = self.labels.0.lock().unwrap().clone();
+        let duration_ms = self.started.elapsed().as_millis() as u64;
+        match format {
+            AccessLogFormat::Combined => {
+                let quoted = |value: &Option<String>| match value {
+                    Some(value) => format!("\"{}\"", value.replace('"', "\\\"")),
+                    None => "\"-\"".to_string(),
+                };
+                format!(
+                    "{} - {} [{}] \"{} {} {:?}\" {} {} {} {} {} {}",
+                    self.client,
+                    labels.key.as_deref().unwrap_or("-").replace(' ', "_"),
+                    self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+                    self.method,
+                    self.path,
+                    self.version,
+                    self.status,
+                    self.bytes,
+                    quoted(&self.referer),
+                    quoted(&self.user_agent),
+                    labels.model.as_deref().unwrap_or("-"),
+                    duration_ms
+                )
+            }
+            AccessLogFormat::Json => json!({
+                "timestamp": self.timestamp.to_rfc3339(),
+                "request_id": self.request_id,
+                "client": self.client.to_string(),
+                "method": self.method.as_str(),
+                "path": self.path,
+                "status": self.status,
+                "key": labels.key,
+                "model": labels.model,
+                "duration_ms": duration_ms,
+                "bytes": self.bytes,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+            })
+            .to_string(),
+        }
+    }
+}
+
+/// An entry whose body is still being sent. It is logged when the body
+/// ends or is dropped, as when the client disconnects mid-stream, so the
+/// duration and bytes cover the whole stream.
+struct PendingEntry {
+    log: Arc<AccessLog>,
+    entry: AccessEntry,
+}
+
+impl PendingEntry {
+    fn sent(&mut self, bytes: usize) {
+        self.entry.bytes += bytes as u64;
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        self.log.write(self.entry.format(self.log.config.format));
+    }
+}
+
+/// Writes one line per request, apart from the application logs. Lines
+/// are written in the background; nothing waits on them.
+pub struct AccessLog {
+    config: AccessLogConfig,
+    lines: mpsc::Sender<String>,
+}
+
+impl AccessLog {
+    pub async fn open(config: AccessLogConfig) -> anyhow::Result<Self> {
+        let mut writer: Box<dyn AsyncWrite + Send + Unpin> = match &config.output {
+            AccessLogOutput::Stdout => Box::new(tokio::io::stdout()),
+            AccessLogOutput::Stderr => Box::new(tokio::io::stderr()),
+            AccessLogOutput::File(path) => {
+                if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    tokio::fs::create_dir_all(dir).await?;
+                }
+                Box::new(
+                    tokio::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .await?,
+                )
+            }
+        };
+        info!("Writing access logs to {:?}", config.output);
+        let (lines, mut receiver) = mpsc::channel::<String>(QUEUE_CAPACITY);
+        tokio::spawn(async move {
+            while let Some(mut line) = receiver.recv().await {
+                line.push('\n');
+                let write = async {
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.flush().await
+                };
+                if let Err(e) = write.await {
+                    error!("Failed to write access log: {}", e);
+                }
+            }
+        });
+        Ok(Self { config, lines })
+    }
+
+    fn write(&self, line: String) {
+        if let Err(e) = self.lines.try_send(line) {
+            warn!("Dropping access log line: {}", e);
+        }
+    }
+
+    /// Counts the bytes of the response body as they are sent, and logs the
+    /// request once it has all been sent.
+    pub fn finish(self: &Arc<Self>, mut entry: AccessEntry, response: Response) -> Response {
+        let (parts, body) = response.into_parts();
+        entry.status = parts.status.as_u16();
+        let mut pending = PendingEntry {
+            log: self.clone(),
+            entry,
+        };
+        let body = body.into_data_stream().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                pending.sent(chunk.len());
+            }
+        });
+        Response::from_parts(parts, Body::from_stream(body))
+    }
+}
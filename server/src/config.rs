@@ -0,0 +1,1020 @@
+use chat::{
+    admission::AdmissionConfig,
+    aliases::ModelAliases,
+    archive::ArchiveConfig,
+    audit::{AuditConfig, RedactRule},
+    buffer::{OverflowPolicy, StreamBufferConfig},
+    cache::EmbeddingsCacheConfig,
+    emulation::{EmulationFormat, ToolEmulationConfig},
+    events::{EventDestination, EventSinkConfig},
+    guardrails::{GuardrailRule, Guardrails},
+    image::{ImageFetchConfig, ImageLimits},
+    ipfilter::{IpFilter, IpNet},
+    jwt::JwtConfig,
+    keys::{KeyConfig, TeamConfig, Teams},
+    limits::{ModelLimit, ModelLimits},
+    mcp::{McpConfig, McpServerConfig},
+    mock::MockConfig,
+    models::{BUNDLED_MODELS, ModelCatalog, ModelInfo},
+    prompts::{PromptTemplate, PromptTemplates, SystemPromptRule, SystemPrompts},
+    registry::DynamicProvider,
+    replay::ReplayConfig,
+    routing::{Route, RoutingTable},
+    sagemaker::SageMakerEndpoint,
+    session::{ModelPrice, SessionLimits},
+    smoothing::SmoothingConfig,
+    store::ConversationStoreConfig,
+    system_messages::{SystemMessageRule, SystemMessageStrategy, SystemMessages},
+    tiers::{ServiceTierRule, ServiceTiers},
+    tools::{BuiltinToolsConfig, FetchUrlConfig, WebSearchConfig},
+    truncation::TruncationConfig,
+    upstream::{AwsAccount, BedrockClientsConfig, UpstreamHttpConfig},
+    vertex::VertexConfig,
+    warmup::WarmupConfig,
+    writer::FlushStrategy,
+};
+use config::{Config, File, FileFormat};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+use tracing::{info, warn};
+
+use crate::{
+    access::{AccessLogConfig, AccessLogFormat, AccessLogOutput},
+    statsd::StatsdConfig,
+    telemetry::TelemetryConfig,
+};
+
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub openai_api_key: Option<String>,
+    pub anthropic_api_key: Option<String>,
+    pub gemini_api_key: Option<String>,
+    pub openrouter: Option<OpenRouterConfig>,
+    pub sagemaker: Vec<SageMakerEndpoint>,
+    pub routes: RoutingTable,
+    pub aliases: ModelAliases,
+    pub stream_buffer: StreamBufferConfig,
+    pub flush_strategy: FlushStrategy,
+    pub upstream: UpstreamHttpConfig,
+    pub mock: MockProviderConfig,
+    pub mcp: McpConfig,
+    pub tools: BuiltinToolsConfig,
+    pub tool_emulation: ToolEmulationConfig,
+    pub truncation: TruncationConfig,
+    pub guardrails: Guardrails,
+    pub service_tiers: ServiceTiers,
+    pub system_messages: SystemMessages,
+    pub store: ConversationStoreConfig,
+    pub embeddings_cache: EmbeddingsCacheConfig,
+    pub sessions: SessionLimits,
+    pub spend_file: Option<PathBuf>,
+    pub event_sinks: Vec<EventSinkConfig>,
+    pub telemetry: Option<TelemetryConfig>,
+    pub prometheus: bool,
+    pub statsd: Option<StatsdConfig>,
+    pub access_log: Option<AccessLogConfig>,
+    pub bedrock_images: ImageLimits,
+    pub openai_images: ImageLimits,
+    /// Unset when remote images are not fetched.
+    pub image_fetch: Option<ImageFetchConfig>,
+    pub models: ModelCatalog,
+    pub admin_token: Option<String>,
+    pub providers_store: Option<PathBuf>,
+    pub providers: Vec<DynamicProvider>,
+    pub keys: HashMap<String, KeyConfig>,
+    pub keys_store: Option<PathBuf>,
+    pub teams: Teams,
+    pub require_key: bool,
+    pub jwt: Option<JwtConfig>,
+    pub ip_filter: IpFilter,
+    pub system_prompts: SystemPrompts,
+    pub prompt_templates: PromptTemplates,
+    pub strict_parameters: bool,
+    pub reasoning_content: bool,
+    pub cache_system_prompt_tokens: Option<i32>,
+    pub admission: AdmissionConfig,
+    pub model_limits: ModelLimits,
+    pub warmup: WarmupConfig,
+    pub bedrock: BedrockClientsConfig,
+    pub replay: ReplayConfig,
+    pub audit: Option<AuditConfig>,
+    pub archive: Option<ArchiveConfig>,
+    pub smoothing: SmoothingConfig,
+    pub vertex: Option<VertexConfig>,
+}
+
+pub struct OpenRouterConfig {
+    pub api_key: String,
+    /// Model id prefixes routed to OpenRouter.
+    pub models: Vec<String>,
+}
+
+impl OpenRouterConfig {
+    pub fn serves(&self, model: &str) -> bool {
+        self.models
+            .iter()
+            .any(|prefix| model.starts_with(prefix.as_str()))
+    }
+
+    /// The OpenRouter id for a model routed with the `openrouter/` prefix.
+    /// OpenRouter's own models, such as `openrouter/auto`, keep it: ids are
+    /// `vendor/model`, so only a name with a further `/` is prefixed.
+    pub fn upstream_model(model: &str) -> Option<&str> {
+        let rest = model.get(..OPENROUTER_PREFIX.len())?;
+        if !rest.eq_ignore_ascii_case(OPENROUTER_PREFIX) {
+            return None;
+        }
+        Some(&model[OPENROUTER_PREFIX.len()..]).filter(|model| model.contains('/'))
+    }
+}
+
+/// The prefix the default configuration routes to OpenRouter by.
+pub const OPENROUTER_PREFIX: &str = "openrouter/";
+
+pub struct MockProviderConfig {
+    pub enabled: bool,
+    pub config: MockConfig,
+}
+
+fn get_duration_secs(settings: &Config, key: &str, default: Option<Duration>) -> Option<Duration> {
+    match settings.get::<u64>(key) {
+        Ok(0) => None,
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => default,
+    }
+}
+
+fn get_image_limits(settings: &Config, backend: &str, default: ImageLimits) -> ImageLimits {
+    ImageLimits {
+        max_bytes: settings
+            .get(&format!("images.{}.max_bytes", backend))
+            .unwrap_or(default.max_bytes),
+        max_dimension: settings
+            .get(&format!("images.{}.max_dimension", backend))
+            .unwrap_or(default.max_dimension),
+        ..default
+    }
+}
+
+fn get_event_sink(
+    settings: &Config,
+    section: &str,
+    destination: EventDestination,
+) -> EventSinkConfig {
+    let default = EventSinkConfig::new(destination);
+    EventSinkConfig {
+        batch_size: settings
+            .get(&format!("{}.batch_size", section))
+            .unwrap_or(default.batch_size),
+        flush_interval: settings
+            .get::<u64>(&format!("{}.flush_interval_ms", section))
+            .map(Duration::from_millis)
+            .unwrap_or(default.flush_interval),
+        max_retries: settings
+            .get(&format!("{}.max_retries", section))
+            .unwrap_or(default.max_retries),
+        queue_capacity: settings
+            .get(&format!("{}.queue_capacity", section))
+            .unwrap_or(default.queue_capacity),
+        ..default
+    }
+}
+
+pub async fn load_config() -> anyhow::Result<ServerConfig> {
+    let settings = Config::builder()
+        .add_source(File::with_name("config"))
+        .build()?;
+
+    let host: String = settings
+        .get("host")
+        .unwrap_or_else(|_| "127.0.0.1".to_string());
+    let port: u16 = settings.get("port").unwrap_or(3000);
+
+    let openai_api_key = settings.get::<String>("openai_api_key").ok();
+
+    if openai_api_key.is_some() {
+        info!("OpenAI API key found in configuration");
+    } else {
+        info!("No OpenAI API key found in configuration, OpenAI models will not be available");
+    }
+
+    let anthropic_api_key = settings
+        .get::<String>("anthropic_api_key")
+        .ok()
+        .filter(|key| !key.is_empty());
+    if anthropic_api_key.is_some() {
+        info!("Anthropic API key found in configuration, claude- models use the Anthropic API");
+    }
+
+    let gemini_api_key = settings
+        .get::<String>("gemini_api_key")
+        .ok()
+        .filter(|key| !key.is_empty());
+    if gemini_api_key.is_some() {
+        info!("Gemini API key found in configuration, gemini- models use the Gemini API");
+    }
+
+    let openrouter = settings
+        .get::<String>("openrouter.api_key")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(|api_key| OpenRouterConfig {
+            api_key,
+            models: settings
+                .get("openrouter.models")
+                .unwrap_or_else(|_| vec![OPENROUTER_PREFIX.to_string()]),
+        });
+    if let Some(openrouter) = &openrouter {
+        info!("OpenRouter serves models {:?}", openrouter.models);
+    }
+
+    let sagemaker = settings
+        .get::<Vec<SageMakerEndpoint>>("sagemaker.endpoints")
+        .unwrap_or_default();
+    if !sagemaker.is_empty() {
+        info!("{} SageMaker endpoints configured", sagemaker.len());
+    }
+
+    let routes = RoutingTable::new(settings.get::<Vec<Route>>("routes").unwrap_or_default());
+    if !routes.is_empty() {
+        info!("{} model routes configured", routes.len());
+    }
+
+    let aliases = ModelAliases::new(
+        settings
+            .get::<HashMap<String, String>>("aliases")
+            .unwrap_or_default(),
+    );
+    if !aliases.is_empty() {
+        info!("{} model aliases configured", aliases.len());
+    }
+
+    let default_stream_buffer = StreamBufferConfig::default();
+    let stream_buffer = StreamBufferConfig {
+        capacity: settings
+            .get("stream_buffer.capacity")
+            .unwrap_or(default_stream_buffer.capacity),
+        overflow_policy: match settings.get::<String>("stream_buffer.overflow_policy") {
+            Ok(policy) => policy.parse()?,
+            Err(_) => OverflowPolicy::default(),
+        },
+        max_stream_bytes: settings
+            .get("stream_buffer.max_stream_bytes")
+            .unwrap_or(default_stream_buffer.max_stream_bytes),
+    };
+
+    let flush_strategy = match settings.get::<String>("flush_strategy") {
+        Ok(strategy) => strategy.parse()?,
+        Err(_) => FlushStrategy::default(),
+    };
+
+    let default_upstream = UpstreamHttpConfig::default();
+    let upstream = UpstreamHttpConfig {
+        http2_prior_knowledge: settings
+            .get("upstream.http2_prior_knowledge")
+            .unwrap_or(default_upstream.http2_prior_knowledge),
+        http2_adaptive_window: settings
+            .get("upstream.http2_adaptive_window")
+            .unwrap_or(default_upstream.http2_adaptive_window),
+        http2_keep_alive_interval: get_duration_secs(
+            &settings,
+            "upstream.http2_keep_alive_interval_secs",
+            default_upstream.http2_keep_alive_interval,
+        ),
+        tcp_keepalive: get_duration_secs(
+            &settings,
+            "upstream.tcp_keepalive_secs",
+            default_upstream.tcp_keepalive,
+        ),
+        tcp_nodelay: settings
+            .get("upstream.tcp_nodelay")
+            .unwrap_or(default_upstream.tcp_nodelay),
+        pool_idle_timeout: get_duration_secs(
+            &settings,
+            "upstream.pool_idle_timeout_secs",
+            default_upstream.pool_idle_timeout,
+        ),
+        pool_max_idle_per_host: settings
+            .get("upstream.pool_max_idle_per_host")
+            .unwrap_or(default_upstream.pool_max_idle_per_host),
+        connect_timeout: get_duration_secs(
+            &settings,
+            "upstream.connect_timeout_secs",
+            default_upstream.connect_timeout,
+        ),
+        dns_cache: settings
+            .get("upstream.dns_cache")
+            .unwrap_or(default_upstream.dns_cache),
+    };
+
+    let bedrock = BedrockClientsConfig {
+        override_regions: settings.get("bedrock.override_regions").unwrap_or_default(),
+        accounts: settings
+            .get::<Vec<AwsAccount>>("bedrock.accounts")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|account| AwsAccount {
+                models: account
+                    .models
+                    .iter()
+                    .map(|model| model.to_lowercase())
+                    .collect(),
+                ..account
+            })
+            .collect(),
+        list_models: settings.get("bedrock.list_models").unwrap_or(false),
+    };
+    if !bedrock.override_regions.is_empty() {
+        info!(
+            "Bedrock region overrides allowed for {:?}",
+            bedrock.override_regions
+        );
+    }
+    for account in &bedrock.accounts {
+        info!(
+            "Bedrock models {:?} use AWS account {}",
+            account.models, account.name
+        );
+    }
+
+    let warmup = WarmupConfig {
+        enabled: settings.get("warmup.enabled").unwrap_or(false),
+        bedrock_canary_model: settings.get("warmup.bedrock_canary_model").ok(),
+        openai_canary_model: settings.get("warmup.openai_canary_model").ok(),
+    };
+
+    let default_mock = MockConfig::default();
+    let mock = MockProviderConfig {
+        enabled: settings.get("mock.enabled").unwrap_or(false),
+        config: MockConfig {
+            chunks: settings.get("mock.chunks").unwrap_or(default_mock.chunks),
+            chunk_delay: settings
+                .get::<u64>("mock.chunk_delay_ms")
+                .map(Duration::from_millis)
+                .unwrap_or(default_mock.chunk_delay),
+        },
+    };
+    if mock.enabled {
+        info!("Mock provider enabled for models prefixed with \"mock\"");
+    }
+
+    let default_mcp = McpConfig::default();
+    let mcp = McpConfig {
+        servers: settings
+            .get::<Vec<McpServerConfig>>("mcp.servers")
+            .unwrap_or_default(),
+        max_tool_rounds: settings
+            .get("mcp.max_tool_rounds")
+            .unwrap_or(default_mcp.max_tool_rounds),
+        call_timeout: settings
+            .get::<u64>("mcp.call_timeout_secs")
+            .map(Duration::from_secs)
+            .unwrap_or(default_mcp.call_timeout),
+    };
+    if !mcp.servers.is_empty() {
+        info!("{} MCP servers configured", mcp.servers.len());
+    }
+
+    const DEFAULT_TOOL_MAX_BYTES: usize = 64 * 1024;
+    let tools = BuiltinToolsConfig {
+        web_search: settings
+            .get::<String>("tools.web_search.url")
+            .ok()
+            .map(|url| WebSearchConfig {
+                url,
+                query_param: settings
+                    .get("tools.web_search.query_param")
+                    .unwrap_or_else(|_| "q".to_string()),
+                api_key_header: settings.get("tools.web_search.api_key_header").ok(),
+                api_key: settings.get("tools.web_search.api_key").ok(),
+                max_bytes: settings
+                    .get("tools.web_search.max_bytes")
+                    .unwrap_or(DEFAULT_TOOL_MAX_BYTES),
+            }),
+        fetch_url: settings
+            .get::<Vec<String>>("tools.fetch_url.allowlist")
+            .ok()
+            .map(|allowlist| FetchUrlConfig {
+                allowlist,
+                max_bytes: settings
+                    .get("tools.fetch_url.max_bytes")
+                    .unwrap_or(DEFAULT_TOOL_MAX_BYTES),
+            }),
+        enabled: settings.get("tools.enabled").unwrap_or_default(),
+        keys: settings
+            .get::<HashMap<String, Vec<String>>>("tools.keys")
+            .unwrap_or_default(),
+        timeout: get_duration_secs(
+            &settings,
+            "tools.timeout_secs",
+            Some(Duration::from_secs(30)),
+        ),
+    };
+
+    let truncation = TruncationConfig {
+        models: settings
+            .get::<Vec<String>>("truncation.models")
+            .unwrap_or_default()
+            .iter()
+            .map(|model| model.to_lowercase())
+            .collect(),
+    };
+    if !truncation.models.is_empty() {
+        info!(
+            "Truncating the oldest messages of prompts too long for models {:?}",
+            truncation.models
+        );
+    }
+
+    let tool_emulation = ToolEmulationConfig {
+        models: settings.get("tool_emulation.models").unwrap_or_default(),
+        format: match settings.get::<String>("tool_emulation.format") {
+            Ok(format) => format.parse()?,
+            Err(_) => EmulationFormat::default(),
+        },
+    };
+
+    let guardrails = Guardrails {
+        rules: settings
+            .get::<Vec<GuardrailRule>>("guardrails.rules")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|rule| GuardrailRule {
+                models: rule
+                    .models
+                    .iter()
+                    .map(|model| model.to_lowercase())
+                    .collect(),
+                ..rule
+            })
+            .collect(),
+    };
+    for rule in &guardrails.rules {
+        info!(
+            "Bedrock models {:?} use guardrail {} version {}",
+            rule.models, rule.guardrail.id, rule.guardrail.version
+        );
+    }
+
+    let service_tiers = ServiceTiers {
+        rules: settings
+            .get::<Vec<ServiceTierRule>>("service_tiers.rules")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|rule| ServiceTierRule {
+                models: rule
+                    .models
+                    .iter()
+                    .map(|model| model.to_lowercase())
+                    .collect(),
+                ..rule
+            })
+            .collect(),
+    };
+    for rule in &service_tiers.rules {
+        info!(
+            "Bedrock models {:?} use {} for the {} service tier",
+            rule.models, rule.model_id, rule.tier
+        );
+    }
+
+    let system_messages = SystemMessages {
+        rules: settings
+            .get::<Vec<SystemMessageRule>>("system_messages.rules")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|rule| SystemMessageRule {
+                models: rule
+                    .models
+                    .iter()
+                    .map(|model| model.to_lowercase())
+                    .collect(),
+                ..rule
+            })
+            .collect(),
+    };
+    for rule in &system_messages.rules {
+        info!(
+            "Models {:?} {} mid-conversation system messages",
+            rule.models,
+            match rule.strategy {
+                SystemMessageStrategy::Hoist => "hoist",
+                SystemMessageStrategy::Merge => "merge",
+                SystemMessageStrategy::Reject => "reject",
+            }
+        );
+    }
+
+    let default_store = ConversationStoreConfig::default();
+    let store = ConversationStoreConfig {
+        enabled: settings
+            .get("store.enabled")
+            .unwrap_or(default_store.enabled),
+        max_entries: settings
+            .get("store.max_entries")
+            .unwrap_or(default_store.max_entries),
+        dir: settings
+            .get::<String>("store.dir")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .map(Into::into),
+        retention: get_duration_secs(&settings, "store.retention_secs", default_store.retention),
+    };
+
+    let default_embeddings_cache = EmbeddingsCacheConfig::default();
+    let embeddings_cache = EmbeddingsCacheConfig {
+        enabled: settings
+            .get("embeddings_cache.enabled")
+            .unwrap_or(default_embeddings_cache.enabled),
+        ttl: settings
+            .get::<u64>("embeddings_cache.ttl_secs")
+            .map(Duration::from_secs)
+            .unwrap_or(default_embeddings_cache.ttl),
+        max_entries: settings
+            .get("embeddings_cache.max_entries")
+            .unwrap_or(default_embeddings_cache.max_entries),
+    };
+
+    let default_smoothing = SmoothingConfig::default();
+    let smoothing = SmoothingConfig {
+        enabled: settings
+            .get("smoothing.enabled")
+            .unwrap_or(default_smoothing.enabled),
+        min_chars: settings
+            .get("smoothing.min_chars")
+            .unwrap_or(default_smoothing.min_chars),
+        words_per_chunk: settings
+            .get("smoothing.words_per_chunk")
+            .unwrap_or(default_smoothing.words_per_chunk),
+        interval: settings
+            .get::<u64>("smoothing.interval_ms")
+            .map(Duration::from_millis)
+            .unwrap_or(default_smoothing.interval),
+    };
+    let vertex = if settings.get("vertex.enabled").unwrap_or(false) {
+        let default_vertex = VertexConfig::default();
+        let vertex = VertexConfig {
+            project: settings.get("vertex.project").unwrap_or_default(),
+            region: settings
+                .get("vertex.region")
+                .unwrap_or(default_vertex.region),
+            endpoint: settings
+                .get::<String>("vertex.endpoint")
+                .ok()
+                .filter(|endpoint| !endpoint.is_empty()),
+            credentials_file: settings
+                .get::<String>("vertex.credentials_file")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(Into::into),
+            models: settings
+                .get("vertex.models")
+                .unwrap_or(default_vertex.models),
+        };
+        info!(
+            "Vertex AI enabled in {} for models {:?}",
+            vertex.region, vertex.models
+        );
+        Some(vertex)
+    } else {
+        None
+    };
+
+    if store.enabled {
+        info!("Conversation store enabled for requests with store=true");
+    }
+
+    let mut models = ModelCatalog::new(
+        Config::builder()
+            .add_source(File::from_str(BUNDLED_MODELS, FileFormat::Toml))
+            .build()?
+            .get("models")?,
+    );
+    if let Ok(models_file) = settings.get::<String>("models_file") {
+        info!("Loading model overrides from {}", models_file);
+        models.merge(
+            Config::builder()
+                .add_source(File::with_name(&models_file))
+                .build()?
+                .get("models")?,
+        );
+    }
+    models.merge(settings.get::<Vec<ModelInfo>>("models").unwrap_or_default());
+
+    let default_replay = ReplayConfig::default();
+    let replay = ReplayConfig {
+        enabled: settings
+            .get("replay.enabled")
+            .unwrap_or(default_replay.enabled),
+        capacity: settings
+            .get("replay.capacity")
+            .unwrap_or(default_replay.capacity),
+        file: settings
+            .get::<String>("replay.file")
+            .ok()
+            .filter(|file| !file.is_empty())
+            .map(Into::into),
+    };
+    if replay.enabled {
+        info!(
+            "Keeping the last {} requests for replay at /admin/replay",
+            replay.capacity
+        );
+    }
+
+    let admin_token = settings
+        .get::<String>("admin.token")
+        .ok()
+        .filter(|token| !token.is_empty());
+    if admin_token.is_some() {
+        info!("Admin dashboard enabled at /admin");
+    }
+
+    let keys_store = settings
+        .get::<String>("admin.keys_store")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(Into::into);
+    let providers_store = settings
+        .get::<String>("providers.store")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(Into::into);
+    let providers = settings
+        .get::<Vec<DynamicProvider>>("providers.upstreams")
+        .unwrap_or_default();
+    if !providers.is_empty() {
+        info!("{} OpenAI-compatible providers configured", providers.len());
+    }
+
+    let keys = settings
+        .get::<HashMap<String, KeyConfig>>("keys")
+        .unwrap_or_default();
+    let jwt = settings
+        .get::<String>("auth.jwt.issuer")
+        .ok()
+        .filter(|issuer| !issuer.is_empty())
+        .map(|issuer| {
+            let default_jwt = JwtConfig::default();
+            JwtConfig {
+                issuer,
+                jwks_url: settings
+                    .get::<String>("auth.jwt.jwks_url")
+                    .ok()
+                    .filter(|url| !url.is_empty()),
+                audience: settings
+                    .get::<String>("auth.jwt.audience")
+                    .ok()
+                    .filter(|audience| !audience.is_empty()),
+                tenant_claim: settings
+                    .get("auth.jwt.tenant_claim")
+                    .unwrap_or(default_jwt.tenant_claim),
+                leeway: settings
+                    .get::<u64>("auth.jwt.leeway_secs")
+                    .map(Duration::from_secs)
+                    .unwrap_or(default_jwt.leeway),
+                jwks_refresh: settings
+                    .get::<u64>("auth.jwt.jwks_refresh_secs")
+                    .map(Duration::from_secs)
+                    .unwrap_or(default_jwt.jwks_refresh),
+            }
+        });
+
+    // Running open takes saying so: a config that set up no way to
+    // authenticate is refused rather than served to anyone.
+    if settings.get::<bool>("auth.require_key").is_ok() {
+        warn!("auth.require_key is no longer read, set auth.allow_anonymous to serve anyone");
+    }
+    let require_key = !settings.get("auth.allow_anonymous").unwrap_or(false);
+    if require_key {
+        if keys.is_empty() && keys_store.is_none() && admin_token.is_none() && jwt.is_none() {
+            anyhow::bail!(
+                "No keys, keys store, admin token or JWT issuer is configured, so no request \
+                 could authenticate; configure one or set auth.allow_anonymous = true"
+            );
+        }
+        info!("Requests must authenticate with one of the configured keys");
+    } else {
+        warn!("auth.allow_anonymous is set, anyone may call the proxy");
+    }
+
+    let ip_nets = |key: &str| -> anyhow::Result<Vec<IpNet>> {
+        settings
+            .get::<Vec<String>>(key)
+            .unwrap_or_default()
+            .iter()
+            .map(|net| net.parse())
+            .collect()
+    };
+    let ip_filter = IpFilter {
+        allow: ip_nets("ip_filter.allow")?,
+        deny: ip_nets("ip_filter.deny")?,
+        trusted_proxies: ip_nets("ip_filter.trusted_proxies")?,
+    };
+    if ip_filter.is_enabled() {
+        info!(
+            "IP filter allows {} and denies {} ranges",
+            ip_filter.allow.len(),
+            ip_filter.deny.len()
+        );
+    }
+    if !keys.is_empty() {
+        info!(
+            "{} API keys with server-side settings configured",
+            keys.len()
+        );
+    }
+    let teams = Teams {
+        teams: settings
+            .get::<HashMap<String, TeamConfig>>("teams")
+            .unwrap_or_default(),
+    };
+    teams.check(&keys)?;
+    if !teams.is_empty() {
+        info!("{} teams configured", teams.len());
+    }
+
+    let system_prompts = SystemPrompts {
+        rules: settings
+            .get::<Vec<SystemPromptRule>>("system_prompts")
+            .unwrap_or_default(),
+        variables: settings
+            .get::<HashMap<String, String>>("prompt_variables")
+            .unwrap_or_default(),
+    };
+    system_prompts.check()?;
+
+    let prompt_templates = PromptTemplates {
+        templates: settings
+            .get::<HashMap<String, PromptTemplate>>("prompt_templates")
+            .unwrap_or_default(),
+    };
+    if !prompt_templates.templates.is_empty() {
+        info!(
+            "{} prompt templates configured",
+            prompt_templates.templates.len()
+        );
+    }
+
+    let model_limits = ModelLimits {
+        rules: settings
+            .get::<Vec<ModelLimit>>("model_limits")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|rule| ModelLimit {
+                models: rule
+                    .models
+                    .iter()
+                    .map(|model| model.to_lowercase())
+                    .collect(),
+                ..rule
+            })
+            .collect(),
+    };
+
+    let strict_parameters = settings.get("strict_parameters").unwrap_or(false);
+    let reasoning_content = settings.get("reasoning_content").unwrap_or(true);
+    let cache_system_prompt_tokens: Option<i32> = settings.get("cache_system_prompt_tokens").ok();
+    if let Some(tokens) = cache_system_prompt_tokens {
+        info!(
+            "Caching Bedrock system prompts of at least {} tokens",
+            tokens
+        );
+    }
+
+    let default_admission = AdmissionConfig::default();
+    let admission = AdmissionConfig {
+        max_concurrent: settings
+            .get("admission.max_concurrent")
+            .unwrap_or(default_admission.max_concurrent),
+        queue_timeout: settings
+            .get::<u64>("admission.queue_timeout_secs")
+            .map(Duration::from_secs)
+            .unwrap_or(default_admission.queue_timeout),
+        fallback_models: settings
+            .get::<HashMap<String, String>>("admission.fallback_models")
+            .map(|models| {
+                models
+                    .into_iter()
+                    .map(|(model, fallback)| (model.to_lowercase(), fallback))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    if admission.max_concurrent > 0 {
+        info!(
+            "Admitting at most {} concurrent requests",
+            admission.max_concurrent
+        );
+    }
+
+    let sessions = SessionLimits {
+        max_turns: settings.get("sessions.max_turns").ok(),
+        max_tokens: settings.get("sessions.max_tokens").ok(),
+        max_cost: settings.get("sessions.max_cost").ok(),
+        idle_timeout: settings
+            .get::<u64>("sessions.idle_timeout_secs")
+            .map(Duration::from_secs)
+            .unwrap_or(SessionLimits::default().idle_timeout),
+        pricing: settings
+            .get::<Vec<ModelPrice>>("sessions.pricing")
+            .unwrap_or_else(|_| models.prices()),
+    };
+    let spend_file = settings
+        .get::<String>("budgets.file")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(Into::into);
+
+    let string = |key: &str| {
+        settings
+            .get::<String>(key)
+            .ok()
+            .filter(|value| !value.is_empty())
+    };
+    let mut event_sinks = Vec::new();
+    if let Some(url) = string("usage_webhook.url") {
+        event_sinks.push(get_event_sink(
+            &settings,
+            "usage_webhook",
+            EventDestination::Webhook {
+                url,
+                secret: string("usage_webhook.secret"),
+            },
+        ));
+    }
+    if let Some(stream) = string("usage_kinesis.stream") {
+        event_sinks.push(get_event_sink(
+            &settings,
+            "usage_kinesis",
+            EventDestination::Kinesis {
+                stream,
+                region: string("usage_kinesis.region"),
+            },
+        ));
+    }
+    if settings.get_table("usage_kafka").is_ok() {
+        warn!("usage_kafka is no longer read, the sink is configured as usage_kafka_rest");
+    }
+    if let (Some(rest_url), Some(topic)) = (
+        string("usage_kafka_rest.rest_url"),
+        string("usage_kafka_rest.topic"),
+    ) {
+        event_sinks.push(get_event_sink(
+            &settings,
+            "usage_kafka_rest",
+            EventDestination::KafkaRest { rest_url, topic },
+        ));
+    }
+
+    let archive = string("archive.bucket").map(|bucket| {
+        let default = ArchiveConfig::new(bucket);
+        ArchiveConfig {
+            prefix: string("archive.prefix").unwrap_or_default(),
+            region: string("archive.region"),
+            max_bytes: settings
+                .get("archive.max_bytes")
+                .unwrap_or(default.max_bytes),
+            max_age: settings
+                .get::<u64>("archive.max_age_secs")
+                .map(Duration::from_secs)
+                .unwrap_or(default.max_age),
+            audit: settings.get("archive.audit").unwrap_or(default.audit),
+            usage: settings.get("archive.usage").unwrap_or(default.usage),
+            ..default
+        }
+    });
+
+    let audit_file = string("audit.file");
+    let archive_audit = archive.as_ref().is_some_and(|archive| archive.audit);
+    let audit = (audit_file.is_some() || archive_audit).then(|| AuditConfig {
+        file: audit_file.map(PathBuf::from),
+        rules: settings
+            .get::<Vec<RedactRule>>("audit.redact")
+            .unwrap_or_default(),
+        mask_secrets: settings
+            .get("audit.mask_secrets")
+            .unwrap_or(AuditConfig::default().mask_secrets),
+        hash_salt: string("audit.hash_salt").unwrap_or_default(),
+    });
+
+    let telemetry = string("telemetry.otlp_endpoint").map(|otlp_endpoint| {
+        let default = TelemetryConfig::new(otlp_endpoint);
+        TelemetryConfig {
+            headers: settings
+                .get::<HashMap<String, String>>("telemetry.otlp_headers")
+                .unwrap_or_default(),
+            service_name: string("telemetry.service_name").unwrap_or(default.service_name.clone()),
+            ..default
+        }
+    });
+
+    let prometheus = settings.get("metrics.prometheus").unwrap_or(true);
+    let statsd = string("statsd.address").map(|address| {
+        let default = StatsdConfig::new(address);
+        StatsdConfig {
+            prefix: string("statsd.prefix"),
+            datadog: settings.get("statsd.datadog").unwrap_or(default.datadog),
+            tags: settings.get("statsd.tags").unwrap_or_default(),
+            flush_interval: settings
+                .get::<u64>("statsd.flush_interval_ms")
+                .map(Duration::from_millis)
+                .unwrap_or(default.flush_interval),
+            ..default
+        }
+    });
+
+    let access_log = if settings.get("access_log.enabled").unwrap_or(false) {
+        Some(AccessLogConfig {
+            format: match string("access_log.format") {
+                Some(format) => format.parse()?,
+                None => AccessLogFormat::default(),
+            },
+            output: match string("access_log.output") {
+                Some(output) => output.parse()?,
+                None => AccessLogOutput::default(),
+            },
+        })
+    } else {
+        None
+    };
+
+    let bedrock_images = get_image_limits(&settings, "bedrock", ImageLimits::bedrock());
+    let openai_images = get_image_limits(&settings, "openai", ImageLimits::openai());
+    let default_image_fetch = ImageFetchConfig::default();
+    let image_fetch = settings
+        .get("images.fetch.enabled")
+        .unwrap_or(true)
+        .then(|| ImageFetchConfig {
+            allowlist: settings.get("images.fetch.allowlist").unwrap_or_default(),
+            allow_private: settings
+                .get("images.fetch.allow_private")
+                .unwrap_or(default_image_fetch.allow_private),
+            max_bytes: settings
+                .get("images.fetch.max_bytes")
+                .unwrap_or(default_image_fetch.max_bytes),
+            timeout: settings
+                .get::<u64>("images.fetch.timeout_secs")
+                .map(Duration::from_secs)
+                .unwrap_or(default_image_fetch.timeout),
+        });
+
+    Ok(ServerConfig {
+        host,
+        port,
+        openai_api_key,
+        anthropic_api_key,
+        gemini_api_key,
+        openrouter,
+        sagemaker,
+        routes,
+        aliases,
+        stream_buffer,
+        flush_strategy,
+        upstream,
+        mock,
+        mcp,
+        tools,
+        tool_emulation,
+        truncation,
+        guardrails,
+        service_tiers,
+        system_messages,
+        store,
+        embeddings_cache,
+        sessions,
+        spend_file,
+        event_sinks,
+        telemetry,
+        prometheus,
+        statsd,
+        access_log,
+        bedrock_images,
+        openai_images,
+        image_fetch,
+        models,
+        admin_token,
+        providers_store,
+        providers,
+        keys,
+        keys_store,
+        teams,
+        require_key,
+        jwt,
+        ip_filter,
+        system_prompts,
+        prompt_templates,
+        strict_parameters,
+        reasoning_content,
+        cache_system_prompt_tokens,
+        admission,
+        model_limits,
+        warmup,
+        bedrock,
+        replay,
+        audit,
+        archive,
+        smoothing,
+        vertex,
+    })
+}
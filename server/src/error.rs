@@ -1,17 +1,33 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chat::error::{ErrorKind, ProviderError};
+use serde_json::json;
 
 pub struct AppError(anyhow::Error);
 
 impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        // Determine the appropriate status code based on the error
-        let status_code = if self.0.to_string().contains("Streaming is required") {
-            StatusCode::BAD_REQUEST
-        } else {
-            StatusCode::INTERNAL_SERVER_ERROR
+    fn into_response(self) -> Response {
+        let (kind, param, code) = match self.0.downcast_ref::<ProviderError>() {
+            Some(e) => (e.kind, e.param.as_deref(), e.code.as_deref()),
+            None => (ErrorKind::Internal, None, None),
         };
+        let status_code =
+            StatusCode::from_u16(kind.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let error_type = kind.error_type();
 
-        (status_code, format!("Error: {}", self.0)).into_response()
+        let body = json!({
+            "error": {
+                "message": self.0.to_string(),
+                "type": error_type,
+                "param": param,
+                "code": code,
+            }
+        });
+
+        (status_code, Json(body)).into_response()
     }
 }
 
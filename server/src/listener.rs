@@ -0,0 +1,51 @@
+use std::os::fd::{FromRawFd, RawFd};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+/// First descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the listener passed in by systemd (`LISTEN_PID`/`LISTEN_FDS`), so
+/// the socket can outlive restarts and bind privileged ports for us. The
+/// variables are unset so processes we start do not take the sockets for
+/// theirs.
+fn inherited() -> anyhow::Result<Option<TcpListener>> {
+    let Ok(fds) = std::env::var("LISTEN_FDS") else {
+        return Ok(None);
+    };
+    let pid = std::env::var("LISTEN_PID").ok();
+    // SAFETY: called at startup, before anything else reads or writes the
+    // environment.
+    unsafe {
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDNAMES");
+    }
+    if pid.as_deref() != Some(std::process::id().to_string().as_str()) {
+        warn!("Ignoring LISTEN_FDS meant for process {:?}", pid);
+        return Ok(None);
+    }
+    let count: RawFd = fds.parse()?;
+    if count < 1 {
+        return Ok(None);
+    }
+    if count > 1 {
+        warn!("Using the first of {} inherited sockets", count);
+    }
+
+    // SAFETY: systemd hands these descriptors to this process and nothing
+    // else owns them.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener.set_nonblocking(true)?;
+    Ok(Some(TcpListener::from_std(listener)?))
+}
+
+/// The inherited listener if there is one, else one bound to `host:port`.
+pub async fn bind(host: &str, port: u16) -> anyhow::Result<TcpListener> {
+    if let Some(listener) = inherited()? {
+        info!("Using inherited listener on {}", listener.local_addr()?);
+        return Ok(listener);
+    }
+    info!("Binding to {}:{}", host, port);
+    Ok(TcpListener::bind(format!("{}:{}", host, port)).await?)
+}
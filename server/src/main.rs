@@ -1,126 +1,1699 @@
 use axum::{
     Json, Router,
-    extract::State,
-    http::StatusCode,
-    response::{IntoResponse, sse::Sse},
-    routing::post,
+    body::Body,
+    extract::{ConnectInfo, Extension, Path, Request, State, rejection::JsonRejection},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post, put},
 };
 use chat::{
+    admission::{Admission, hold},
+    aliases::rename_model,
+    anthropic::AnthropicChatCompletionsProvider,
+    archive::{ArchiveKind, Archiver},
+    audit::{AuditLog, AuditRecord, audit},
+    budget::SpendLedger,
+    buffer::buffered,
+    cache::EmbeddingsCache,
+    embeddings::{BedrockEmbeddingsProvider, EmbeddingsProvider, is_embeddings_model},
+    error::{ErrorKind, ProviderError},
+    events::{EventSink, UsageEvent},
+    gemini::{GeminiAuth, GeminiChatCompletionsProvider},
+    image::ImageFetcher,
+    invoke::{InvokeModelChatCompletionsProvider, is_invoke_model},
+    jwt::{Identity, JwtVerifier, is_jwt},
+    keys::KeyConfig,
+    keystore::{IssuedKey, KeyStore, key_ledger},
+    limits::deadline,
+    mcp::McpRegistry,
+    memory::StreamMemory,
+    mock::{MOCK_MODEL_PREFIX, MockChatCompletionsProvider},
     openai::OpenAIChatCompletionsProvider,
-    providers::{BedrockChatCompletionsProvider, ChatCompletionsProvider},
+    providers::{BedrockChatCompletionsProvider, ChatCompletionsProvider, collect_completion},
+    registry::{DynamicProvider, ProviderRegistry},
+    replay::{ReplayBuffer, capture, diff},
+    routing::Upstream,
+    sagemaker::SageMakerChatCompletionsProvider,
+    session::{SESSION_HEADER, SessionTracker},
+    smoothing::smooth,
+    stats::{RequestStats, StreamTiming, instrumented, measure, traced},
+    stop::enforce_stop_sequences,
+    store::CompletionAccumulator,
+    store::{ConversationStore, STORE_HEADER, record},
+    tokens::{UsageEstimator, UsageOnce},
+    tools::{BuiltinTools, ServerTools, key_label},
+    truncation::truncate_to_window,
+    upstream::{REGION_HEADER, UpstreamClients},
+    vertex::VertexClient,
+    warmup::Warmup,
+    writer::{StreamFormat, stream_body},
 };
-use config::{Config, File};
-use request::{ChatCompletionsRequest, StreamOptions};
+use chrono::Utc;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics_util::layers::FanoutBuilder;
+use request::{ChatCompletionsRequest, StreamOptions, embeddings::EmbeddingsRequest};
 use response::Usage;
-use tracing::{debug, error, info};
+use std::{
+    mem,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use tracing::{Instrument, Span, debug, debug_span, error, info, info_span, warn};
+use tracing_subscriber::{
+    Layer, filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt,
+};
+use uuid::Uuid;
 
+mod access;
+mod config;
 mod error;
+mod listener;
+mod statsd;
+mod telemetry;
 
-use crate::error::AppError;
+use crate::{
+    access::{AccessEntry, AccessLabels, AccessLog},
+    config::{OpenRouterConfig, ServerConfig, load_config},
+    error::AppError,
+    statsd::StatsdRecorder,
+    telemetry::{OtlpExporter, OtlpLayer},
+};
 
 #[derive(Clone)]
 struct AppState {
-    openai_api_key: Option<String>,
+    config: Arc<ServerConfig>,
+    clients: UpstreamClients,
+    mcp: Arc<McpRegistry>,
+    builtin_tools: Arc<BuiltinTools>,
+    store: Arc<ConversationStore>,
+    sessions: Arc<SessionTracker>,
+    spend: Arc<SpendLedger>,
+    stats: Arc<RequestStats>,
+    admission: Arc<Admission>,
+    providers: Arc<ProviderRegistry>,
+    keys: Arc<KeyStore>,
+    warmup: Arc<Warmup>,
+    replay: Arc<ReplayBuffer>,
+    embeddings_cache: Arc<EmbeddingsCache>,
+    vertex: Option<Arc<VertexClient>>,
+    jwt: Option<Arc<JwtVerifier>>,
+    event_sinks: Arc<Vec<EventSink>>,
+    audit: Option<Arc<AuditLog>>,
+    archive: Option<Arc<Archiver>>,
+    access_log: Option<Arc<AccessLog>>,
+    image_fetcher: Option<ImageFetcher>,
+    /// Unset when Prometheus is disabled.
+    metrics: Option<PrometheusHandle>,
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Every `X-Forwarded-For` line in order, as one list. Proxies append a
+/// line of their own, so a client's line can come before it.
+fn forwarded_for(headers: &HeaderMap) -> Option<String> {
+    let lines: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    (!lines.is_empty()).then(|| lines.join(","))
+}
+
+/// Turns away clients outside `[ip_filter]` before anything reads the
+/// request.
+async fn filter_ip(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let filter = &state.config.ip_filter;
+    if filter.is_enabled() {
+        let forwarded_for = forwarded_for(request.headers());
+        let client = filter.client_addr(peer.ip(), forwarded_for.as_deref());
+        if !filter.permits(client) {
+            warn!("Refusing request from {}", client);
+            return Err(ProviderError::new(
+                ErrorKind::PermissionDenied,
+                "Requests from this address are not allowed",
+            )
+            .code("ip_not_allowed")
+            .into());
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+/// Logs each request once its response has been sent, with the caller and
+/// model its handler labelled it with.
+async fn log_access(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(log) = state.access_log.clone() else {
+        return next.run(request).await;
+    };
+    let forwarded_for = forwarded_for(request.headers());
+    let client = state
+        .config
+        .ip_filter
+        .client_addr(peer.ip(), forwarded_for.as_deref());
+    let labels = AccessLabels::default();
+    request.extensions_mut().insert(labels.clone());
+    let entry = AccessEntry::new(&request, client, labels);
+    log.finish(entry, next.run(request).await)
+}
+
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Whether a client's `X-Request-Id` is fit to be logged and echoed back.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// Gives each request an id, the client's `X-Request-Id` when it sends a
+/// usable one, else a new one. The id is set on the request for handlers,
+/// logged with everything the request does and returned in the response.
+async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("req_{}", Uuid::new_v4().simple()));
+    let value = HeaderValue::from_str(&id).expect("request ids are visible ASCII");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, value.clone());
+    let mut response = next
+        .run(request)
+        .instrument(info_span!("request", id = %id))
+        .await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    response
+}
+
+/// Spans each request for tracing, continuing the caller's trace when it
+/// sends a `traceparent` header. The span is at debug level, so it stays out
+/// of the logs.
+async fn trace_request(request: Request, next: Next) -> Response {
+    let span = debug_span!(
+        "http_request",
+        otel.kind = "server",
+        http.request.method = %request.method(),
+        url.path = request.uri().path(),
+        traceparent = request
+            .headers()
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok()),
+        http.response.status_code = tracing::field::Empty,
+    );
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    response
+}
+
+/// Who sent a request: the holder of a configured key, or the subject of a
+/// verified JWT.
+#[derive(Default)]
+struct Caller {
+    key_config: Option<Arc<KeyConfig>>,
+    identity: Option<Identity>,
+}
+
+/// Who may read back a stored completion: the JWT subject, or the holder of
+/// the bearer key, by its digest.
+fn store_owner(identity: Option<&Identity>, headers: &HeaderMap) -> String {
+    match (identity, bearer_token(headers)) {
+        (Some(identity), _) => format!("jwt:{}", identity.subject),
+        (None, Some(api_key)) => key_ledger(api_key),
+        (None, None) => "anonymous".to_string(),
+    }
+}
+
+/// The caller of a request. Bearer JWTs are verified when `[auth.jwt]` is
+/// configured; unless `auth.allow_anonymous` is set, requests with neither a
+/// valid JWT nor one of the configured keys are turned away.
+async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Caller, AppError> {
+    if let (Some(jwt), Some(token)) = (&state.jwt, bearer_token(headers).filter(|t| is_jwt(t))) {
+        let identity = jwt.verify(token).await?;
+        debug!("Authenticated {} with a JWT", identity.subject);
+        return Ok(Caller {
+            key_config: None,
+            identity: Some(identity),
+        });
+    }
+
+    let key_config = bearer_token(headers).and_then(|api_key| state.keys.get(api_key));
+    if state.config.require_key && key_config.is_none() {
+        let message = match bearer_token(headers) {
+            Some(_) => "Invalid API key",
+            None => "Missing API key, pass it as a bearer token",
+        };
+        return Err(AppError::from(
+            ProviderError::new(ErrorKind::Authentication, message).code("invalid_api_key"),
+        ));
+    }
+    Ok(Caller {
+        key_config,
+        identity: None,
+    })
 }
 
 async fn chat_completions(
     State(state): State<AppState>,
-    Json(mut payload): Json<ChatCompletionsRequest>,
+    access: Option<Extension<AccessLabels>>,
+    headers: HeaderMap,
+    payload: Result<Json<ChatCompletionsRequest>, JsonRejection>,
+) -> Result<Response, AppError> {
+    let Caller {
+        key_config,
+        identity,
+    } = authenticate(&state, &headers).await?;
+    let caller = match (key_config.as_deref(), &identity) {
+        (_, Some(identity)) => identity
+            .tenant
+            .as_ref()
+            .unwrap_or(&identity.subject)
+            .clone(),
+        (
+            Some(KeyConfig {
+                name: Some(name), ..
+            }),
+            None,
+        ) => name.clone(),
+        _ => key_label(bearer_token(&headers)),
+    };
+    if let Some(Extension(access)) = &access {
+        access.set_key(&caller);
+    }
+    let Json(payload) = payload.map_err(|e| ProviderError::invalid_request(e.body_text(), None))?;
+    if let Some(Extension(access)) = &access {
+        access.set_model(&payload.model);
+    }
+    complete(state, headers, payload, identity, false)
+        .instrument(info_span!("chat_completions", caller = %caller))
+        .await
+}
+
+async fn embeddings(
+    State(state): State<AppState>,
+    access: Option<Extension<AccessLabels>>,
+    headers: HeaderMap,
+    payload: Result<Json<EmbeddingsRequest>, JsonRejection>,
 ) -> Result<impl IntoResponse, AppError> {
+    let Caller { key_config, .. } = authenticate(&state, &headers).await?;
+    if let Some(Extension(access)) = &access {
+        access.set_key(
+            &key_config
+                .as_ref()
+                .and_then(|key_config| key_config.name.clone())
+                .unwrap_or_else(|| key_label(bearer_token(&headers))),
+        );
+    }
+    let Json(payload) = payload.map_err(|e| ProviderError::invalid_request(e.body_text(), None))?;
+    if let Some(Extension(access)) = &access {
+        access.set_model(&payload.model);
+    }
+    payload
+        .validate()
+        .map_err(|e| ProviderError::invalid_request(e.message, Some(&e.param)))?;
+
+    let model_name = payload.model.to_lowercase();
+    if !is_embeddings_model(&model_name) {
+        return Err(AppError::from(ProviderError::model_not_found(
+            &payload.model,
+        )));
+    }
+    let bedrock = state
+        .clients
+        .bedrock_for(
+            &model_name,
+            headers
+                .get(REGION_HEADER)
+                .and_then(|value| value.to_str().ok()),
+            key_config.is_some_and(|key_config| key_config.region_override),
+        )
+        .await?;
+
+    info!(
+        "Embedding {} inputs with model: {}",
+        payload.input.len(),
+        payload.model
+    );
+    state.stats.record_request(&model_name);
+    let response = BedrockEmbeddingsProvider::new(bedrock)
+        .cache(state.embeddings_cache.clone())
+        .embeddings(payload)
+        .await
+        .inspect_err(|e| state.stats.record_error(&model_name, e.to_string()))?;
+    Ok(Json(response))
+}
+
+/// The upstream named by a `provider/model` prefix, as LiteLLM clients send
+/// model names, with the model name it prefixes. Names that a configured
+/// route or provider serves as they are, like OpenRouter's `vendor/model`
+/// ids, are not split.
+fn prefixed_upstream(state: &AppState, model: &str) -> Option<(Upstream, String)> {
+    let model_name = model.to_lowercase();
+    if state.config.routes.upstream_for(&model_name).is_some()
+        || !matches!(default_upstream(state, &model_name), Upstream::Bedrock)
+    {
+        return None;
+    }
+    let (prefix, rest) = model.split_once('/')?;
+    if rest.is_empty() {
+        return None;
+    }
+    let Ok(upstream) = prefix.parse::<Upstream>();
+    if let Upstream::Provider(name) = &upstream {
+        state.providers.get(name)?;
+    }
+    Some((upstream, rest.to_string()))
+}
+
+/// The upstream for models the routing table does not cover, by the
+/// providers configured for them and the model name.
+fn default_upstream(state: &AppState, model_name: &str) -> Upstream {
+    if let Some(provider) = state.providers.route(model_name) {
+        Upstream::Provider(provider.name.clone())
+    } else if state
+        .config
+        .openrouter
+        .as_ref()
+        .is_some_and(|openrouter| openrouter.serves(model_name))
+    {
+        Upstream::OpenRouter
+    } else if state
+        .config
+        .sagemaker
+        .iter()
+        .any(|endpoint| endpoint.model.eq_ignore_ascii_case(model_name))
+    {
+        Upstream::SageMaker
+    } else if state.config.mock.enabled && model_name.starts_with(MOCK_MODEL_PREFIX) {
+        Upstream::Mock
+    } else if model_name.starts_with("gpt-") {
+        Upstream::OpenAI
+    } else if model_name.starts_with("claude-") {
+        Upstream::Anthropic
+    } else if state
+        .vertex
+        .as_ref()
+        .is_some_and(|vertex| vertex.serves(model_name))
+    {
+        Upstream::Vertex
+    } else if model_name.starts_with("gemini-") {
+        Upstream::Gemini
+    } else {
+        Upstream::Bedrock
+    }
+}
+
+/// Serves a completion. A `prepared` request already had its templates,
+/// system prompts and key defaults applied, as replayed captures have.
+/// Callers with a JWT get the policies of the tenant their token names.
+async fn complete(
+    state: AppState,
+    headers: HeaderMap,
+    mut payload: ChatCompletionsRequest,
+    identity: Option<Identity>,
+    prepared: bool,
+) -> Result<Response, AppError> {
+    let received = Instant::now();
+    let transform = debug_span!("transform_request", model = %payload.model).entered();
+    let api_key = bearer_token(&headers);
+    let key_config = api_key.and_then(|api_key| state.keys.get(api_key));
+    let key_config = key_config.as_deref();
+    let tenant = match &identity {
+        Some(identity) => identity.tenant.as_deref(),
+        None => key_config.and_then(|key_config| key_config.name.as_deref()),
+    };
+    if !prepared {
+        state.config.prompt_templates.apply(&mut payload, tenant)?;
+    }
+
+    payload
+        .validate()
+        .map_err(|e| ProviderError::invalid_request(e.message, Some(&e.param)))?;
+
     debug!(
         "Received chat completions request for model: {}",
         payload.model
     );
 
-    if payload.stream == Some(false) {
-        error!("Streaming is required but was disabled");
-        return Err(AppError::from(anyhow::anyhow!(
-            "Streaming is required but was disabled"
-        )));
+    if !prepared {
+        state.config.system_prompts.apply(&mut payload, tenant);
+        if let Some(key_config) = key_config {
+            key_config.apply(&mut payload);
+        }
+    }
+
+    let alias = match state.config.aliases.resolve(&payload.model) {
+        Some(model) => {
+            debug!("Model alias {} resolves to {}", payload.model, model);
+            Some(mem::replace(&mut payload.model, model.to_string()))
+        }
+        None => None,
+    };
+
+    let prefixed = prefixed_upstream(&state, &payload.model).map(|(upstream, model)| {
+        debug!("Model {} names provider {:?}", payload.model, upstream);
+        payload.model = model;
+        upstream
+    });
+
+    let priority = key_config
+        .map(|key_config| key_config.priority)
+        .unwrap_or_default();
+    if let Some(fallback) = state
+        .admission
+        .fallback(&payload.model.to_lowercase(), priority)
+    {
+        info!(
+            "Serving {} request for {} with {}",
+            priority, payload.model, fallback
+        );
+        payload.model = fallback.to_string();
     }
 
     let model_name = payload.model.to_lowercase();
 
+    let stream_timeout = state
+        .config
+        .model_limits
+        .clamp(&mut payload, &model_name)
+        .and_then(|limit| limit.stream_timeout());
+    state
+        .config
+        .system_messages
+        .apply(&mut payload, &model_name)?;
+
+    let tool_emulation = state.config.tool_emulation.format_for(&model_name);
+    let model_info = state.config.models.get(&model_name);
+    if let Some(info) = model_info {
+        if let Some(context_window) = info.context_window
+            && state.config.truncation.applies_to(&model_name)
+        {
+            truncate_to_window(&mut payload, context_window, info.tokenizer.as_deref());
+        }
+        info.check(&payload, tool_emulation.is_some())?;
+    }
+    let tokenizer = model_info.and_then(|info| info.tokenizer.clone());
+    drop(transform);
+
+    let bedrock = state
+        .clients
+        .bedrock_for(
+            &model_name,
+            headers
+                .get(REGION_HEADER)
+                .and_then(|value| value.to_str().ok()),
+            key_config.is_some_and(|key_config| key_config.region_override),
+        )
+        .await?;
+
+    let store_requested = payload.store == Some(true)
+        || headers
+            .get(STORE_HEADER)
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"true"));
+    let stored_messages = if store_requested && state.store.enabled() {
+        Some((
+            store_owner(identity.as_ref(), &headers),
+            serde_json::to_value(&payload.messages)?,
+            payload.model.clone(),
+        ))
+    } else {
+        if store_requested {
+            debug!("Ignoring store request, the conversation store is disabled");
+        }
+        None
+    };
+
+    // Upstreams always stream; a non-streaming response is collected here.
+    let streaming = payload.stream != Some(false);
+    let response_model = payload.model.clone();
     payload.stream_options = Some(StreamOptions {
         include_usage: true,
     });
+    let captured_request = if state.replay.enabled() || state.audit.is_some() {
+        Some(capture_request(&payload)?)
+    } else {
+        None
+    };
+    let request_id = headers
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let session_id = headers
+        .get(SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string);
+    if let Some(session_id) = &session_id {
+        state.sessions.begin_turn(session_id)?;
+    }
 
-    let usage_callback = |usage: &Usage| {
+    let config = state.config.clone();
+    let sessions = state.sessions.clone();
+    let stats = state.stats.clone();
+    let caller = match (tenant, &identity) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(identity)) => identity.subject.clone(),
+        (None, None) => key_label(api_key),
+    };
+    // Each kind of caller has ledgers of its own, so a key can't share one
+    // with a token subject or a team of the same name.
+    let ledger = match (&identity, api_key.filter(|_| key_config.is_some())) {
+        (Some(identity), _) => format!(
+            "jwt:{}",
+            identity.tenant.as_ref().unwrap_or(&identity.subject)
+        ),
+        (None, Some(api_key)) => key_ledger(api_key),
+        (None, None) => "anonymous".to_string(),
+    };
+    let teams: Vec<String> = key_config
+        .map(|key_config| state.config.teams.chain(key_config.team.as_deref()))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, team)| {
+            let ledger = format!("team:{}", name);
+            state.spend.check(
+                &ledger,
+                &format!("team {}", name),
+                team.budget,
+                &team.quotas,
+            )?;
+            Ok(ledger)
+        })
+        .collect::<Result<_, ProviderError>>()?;
+    if let Some(key_config) = key_config {
+        let team = key_config
+            .team
+            .as_deref()
+            .and_then(|team| state.config.teams.teams.get(team));
+        let quotas = match team {
+            Some(team) if key_config.quotas.is_empty() => &team.key_quotas,
+            _ => &key_config.quotas,
+        };
+        state.spend.check(
+            &ledger,
+            "this key",
+            key_config.budget.or(team.and_then(|team| team.key_budget)),
+            quotas,
+        )?;
+    }
+    state.spend.admit(&ledger);
+    for team in &teams {
+        state.spend.admit(team);
+    }
+    let spend = state.spend.clone();
+    let event_sinks = state.event_sinks.clone();
+    let archive = state
+        .archive
+        .clone()
+        .filter(|archive| archive.archives(ArchiveKind::Usage));
+    let captured_caller = caller.clone();
+    let usage_model = model_name.clone();
+    let metadata = payload.metadata.clone();
+    let usage_metadata = metadata.clone();
+    let timing = Arc::new(Mutex::new(StreamTiming::default()));
+    let stream_timing = timing.clone();
+    let usage_callback = move |usage: &Usage| {
+        let timing = *stream_timing.lock().unwrap();
+        let as_ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
         info!(
-            "Usage: prompt_tokens: {}, completion_tokens: {}, total_tokens: {}",
-            usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+            "Usage: session: {}, prompt_tokens: {}, completion_tokens: {}, total_tokens: {}, ttft_ms: {}, chunks: {}",
+            session_id.as_deref().unwrap_or("-"),
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.total_tokens,
+            timing
+                .time_to_first_token
+                .map_or("-".to_string(), |ttft| ttft.as_millis().to_string()),
+            timing.chunks
         );
+        if let Some(session_id) = &session_id {
+            sessions.record_usage(session_id, &usage_model, usage);
+        }
+        let cost = config.sessions.cost(&usage_model, usage);
+        let tokens = usage.total_tokens.max(0) as u64;
+        stats.record_spend(&caller, tokens, cost);
+        spend.record(&ledger, tokens, cost);
+        for team in &teams {
+            spend.record(team, tokens, cost);
+        }
+        if !event_sinks.is_empty() || archive.is_some() {
+            let event = UsageEvent {
+                id: Uuid::new_v4().to_string(),
+                timestamp: Utc::now().timestamp(),
+                key: caller.clone(),
+                model: usage_model.clone(),
+                prompt_tokens: usage.prompt_tokens.max(0) as u64,
+                completion_tokens: usage.completion_tokens.max(0) as u64,
+                total_tokens: tokens,
+                cost,
+                latency_ms: received.elapsed().as_millis() as u64,
+                time_to_first_token_ms: timing
+                    .time_to_first_token
+                    .map(|ttft| ttft.as_millis() as u64),
+                chunks: timing.chunks,
+                mean_inter_chunk_ms: timing.mean_inter_chunk().map(as_ms),
+                max_inter_chunk_ms: (timing.chunks > 1).then(|| as_ms(timing.max_inter_chunk)),
+                metadata: usage_metadata.clone(),
+            };
+            if let Some(archive) = &archive {
+                archive.record(ArchiveKind::Usage, &event);
+            }
+            for sink in event_sinks.iter() {
+                sink.record(event.clone());
+            }
+        }
+    };
+
+    let audit_record = match (&state.audit, &captured_request) {
+        (Some(_), Some(request)) => Some(AuditRecord {
+            request_id: request_id.clone(),
+            timestamp: Utc::now().timestamp(),
+            caller: captured_caller.clone(),
+            model: model_name.clone(),
+            request: request.clone(),
+            response: None,
+            metadata,
+            error: None,
+        }),
+        _ => None,
     };
 
-    let stream = if model_name.starts_with("gpt-") {
-        info!("Using OpenAI provider for model: {}", payload.model);
-        if let Some(openai_api_key) = state.openai_api_key {
-            if openai_api_key.is_empty() {
-                error!("OpenAI API key is empty but OpenAI model was requested");
-                return Err(AppError::from(anyhow::anyhow!(
-                    "OpenAI API key is empty but OpenAI model was requested"
-                )));
+    let permit = state.admission.acquire(priority).await?;
+    let started = Instant::now();
+    state.stats.record_request(&model_name);
+    let upstream = match prefixed {
+        Some(upstream) => upstream,
+        None => match state.config.routes.upstream_for(&model_name) {
+            Some(upstream) => upstream.clone(),
+            None => default_upstream(&state, &model_name),
+        },
+    };
+    let upstream_span = debug_span!(
+        "upstream",
+        otel.kind = "client",
+        upstream = ?upstream,
+        model = %payload.model,
+        otel.status_message = tracing::field::Empty,
+    );
+    if upstream == Upstream::OpenRouter
+        && let Some(model) = OpenRouterConfig::upstream_model(&payload.model)
+    {
+        debug!("Sending {} to OpenRouter as {}", payload.model, model);
+        payload.model = model.to_string();
+    }
+    // Converse enforces the stop sequences past its own limit.
+    let stop_sequences = match upstream {
+        Upstream::Bedrock if !is_invoke_model(&model_name) => None,
+        _ => payload.stop.clone().filter(|stop| !stop.is_empty()),
+    }
+    .map(|sequences| (sequences, payload.n.unwrap_or(1).max(1) as usize));
+    // A stream the proxy, a deadline or the client ends before the upstream
+    // reports its usage is accounted with an estimate.
+    let usage = UsageOnce::new(
+        usage_callback,
+        UsageEstimator::new(&payload, tokenizer.clone()),
+    );
+    let usage_callback = {
+        let usage = usage.clone();
+        move |reported: &Usage| usage.report(reported)
+    };
+    let stream = async { match upstream {
+        Upstream::Provider(name) => match state.providers.get(&name) {
+            Some(provider) => {
+                info!(
+                    "Using provider {} for model: {}",
+                    provider.name, payload.model
+                );
+                match provider.api_key() {
+                    Ok(api_key) => {
+                        OpenAIChatCompletionsProvider::new(
+                            &state.clients.http,
+                            api_key.as_deref().unwrap_or_default(),
+                        )
+                        .url(provider.chat_completions_url())
+                        .max_buffered_bytes(state.config.stream_buffer.max_stream_bytes)
+                        .image_limits(state.config.openai_images.clone())
+                        .tokenizer(tokenizer)
+                        .chat_completions_stream(payload, usage_callback)
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            None => {
+                error!(
+                    "Model {} is routed to unknown provider {}",
+                    payload.model, name
+                );
+                Err(anyhow::anyhow!(
+                    "Model {} is routed to unknown provider {}",
+                    payload.model,
+                    name
+                ))
+            }
+        },
+        Upstream::OpenRouter => match &state.config.openrouter {
+            Some(openrouter) => {
+                info!("Using OpenRouter provider for model: {}", payload.model);
+                OpenAIChatCompletionsProvider::openrouter(&state.clients.http, &openrouter.api_key)
+                    .max_buffered_bytes(state.config.stream_buffer.max_stream_bytes)
+                    .image_limits(state.config.openai_images.clone())
+                    .tokenizer(tokenizer)
+                    .chat_completions_stream(payload, usage_callback)
+                    .await
             }
-            OpenAIChatCompletionsProvider::new(&openai_api_key)
+            None => {
+                error!("OpenRouter is not configured but an OpenRouter model was requested");
+                Err(anyhow::anyhow!(
+                    "OpenRouter is not configured but an OpenRouter model was requested"
+                ))
+            }
+        },
+        Upstream::SageMaker => match state
+            .config
+            .sagemaker
+            .iter()
+            .find(|endpoint| endpoint.model.eq_ignore_ascii_case(&model_name))
+        {
+            Some(endpoint) => {
+                info!(
+                    "Using SageMaker endpoint {} for model: {}",
+                    endpoint.endpoint, payload.model
+                );
+                SageMakerChatCompletionsProvider::new(&state.clients, endpoint)
+                    .max_buffered_bytes(state.config.stream_buffer.max_stream_bytes)
+                    .image_limits(state.config.openai_images.clone())
+                    .tokenizer(tokenizer)
+                    .chat_completions_stream(payload, usage_callback)
+                    .await
+            }
+            None => {
+                error!(
+                    "No SageMaker endpoint is configured for model {}",
+                    payload.model
+                );
+                Err(anyhow::anyhow!(
+                    "No SageMaker endpoint is configured for model {}",
+                    payload.model
+                ))
+            }
+        },
+        Upstream::Mock if state.config.mock.enabled => {
+            info!("Using mock provider for model: {}", payload.model);
+            MockChatCompletionsProvider::new(state.config.mock.config)
+                .chat_completions_stream(payload, usage_callback)
+                .await
+        }
+        Upstream::Mock => {
+            error!("The mock provider is disabled but a mock model was requested");
+            Err(anyhow::anyhow!(
+                "The mock provider is disabled but a mock model was requested"
+            ))
+        }
+        Upstream::OpenAI => {
+            info!("Using OpenAI provider for model: {}", payload.model);
+            if let Some(openai_api_key) = &state.config.openai_api_key {
+                if openai_api_key.is_empty() {
+                    error!("OpenAI API key is empty but OpenAI model was requested");
+                    Err(anyhow::anyhow!(
+                        "OpenAI API key is empty but OpenAI model was requested"
+                    ))
+                } else {
+                    OpenAIChatCompletionsProvider::new(&state.clients.http, openai_api_key)
+                        .max_buffered_bytes(state.config.stream_buffer.max_stream_bytes)
+                        .image_limits(state.config.openai_images.clone())
+                        .tokenizer(tokenizer)
+                        .chat_completions_stream(payload, usage_callback)
+                        .await
+                }
+            } else {
+                error!("OpenAI API key is not configured but OpenAI model was requested");
+                Err(anyhow::anyhow!(
+                    "OpenAI API key is not configured but OpenAI model was requested"
+                ))
+            }
+        }
+        Upstream::Anthropic => {
+            info!("Using Anthropic provider for model: {}", payload.model);
+            match &state.config.anthropic_api_key {
+                Some(anthropic_api_key) => {
+                    AnthropicChatCompletionsProvider::new(&state.clients.http, anthropic_api_key)
+                        .max_buffered_bytes(state.config.stream_buffer.max_stream_bytes)
+                        .image_limits(state.config.bedrock_images.clone())
+                        .chat_completions_stream(payload, usage_callback)
+                        .await
+                }
+                None => {
+                    error!(
+                        "Anthropic API key is not configured but an Anthropic model was requested"
+                    );
+                    Err(anyhow::anyhow!(
+                        "Anthropic API key is not configured but an Anthropic model was requested"
+                    ))
+                }
+            }
+        }
+        Upstream::Vertex => match &state.vertex {
+            Some(vertex) => {
+                info!("Using Vertex AI provider for model: {}", payload.model);
+                match vertex.token().await {
+                    Ok(token) => {
+                        GeminiChatCompletionsProvider::new(&state.clients.http, "")
+                            .models_url(vertex.models_url())
+                            .auth(GeminiAuth::Bearer(token))
+                            .max_buffered_bytes(state.config.stream_buffer.max_stream_bytes)
+                            .image_limits(state.config.openai_images.clone())
+                            .chat_completions_stream(payload, usage_callback)
+                            .await
+                    }
+                    Err(e) => {
+                        error!("Failed to get a Vertex AI access token: {}", e);
+                        Err(e)
+                    }
+                }
+            }
+            None => {
+                error!("Vertex AI is not enabled but a Vertex AI model was requested");
+                Err(anyhow::anyhow!(
+                    "Vertex AI is not enabled but a Vertex AI model was requested"
+                ))
+            }
+        },
+        Upstream::Gemini => {
+            info!("Using Gemini provider for model: {}", payload.model);
+            match &state.config.gemini_api_key {
+                Some(gemini_api_key) => {
+                    GeminiChatCompletionsProvider::new(&state.clients.http, gemini_api_key)
+                        .max_buffered_bytes(state.config.stream_buffer.max_stream_bytes)
+                        .image_limits(state.config.openai_images.clone())
+                        .chat_completions_stream(payload, usage_callback)
+                        .await
+                }
+                None => {
+                    error!("Gemini API key is not configured but a Gemini model was requested");
+                    Err(anyhow::anyhow!(
+                        "Gemini API key is not configured but a Gemini model was requested"
+                    ))
+                }
+            }
+        }
+        Upstream::Bedrock if is_invoke_model(&model_name) => {
+            info!(
+                "Using Bedrock InvokeModel provider for model: {}",
+                payload.model
+            );
+            InvokeModelChatCompletionsProvider::new(bedrock)
                 .chat_completions_stream(payload, usage_callback)
-                .await?
-        } else {
-            error!("OpenAI API key is not configured but OpenAI model was requested");
-            return Err(AppError::from(anyhow::anyhow!(
-                "OpenAI API key is not configured but OpenAI model was requested"
-            )));
+                .await
+        }
+        Upstream::Bedrock => {
+            info!("Using Bedrock provider for model: {}", payload.model);
+            BedrockChatCompletionsProvider::new(bedrock)
+                .server_tools(ServerTools::new(&state.mcp, &state.builtin_tools, api_key))
+                .tool_emulation(tool_emulation)
+                .image_limits(state.config.bedrock_images.clone())
+                .image_fetcher(state.image_fetcher.clone())
+                .strict_parameters(state.config.strict_parameters)
+                .reasoning_content(state.config.reasoning_content)
+                .cache_system_prompt_tokens(state.config.cache_system_prompt_tokens)
+                .guardrail(state.config.guardrails.for_model(&model_name).cloned())
+                .service_tier(
+                    state
+                        .config
+                        .service_tiers
+                        .resolve(&model_name, payload.service_tier.as_deref()),
+                )
+                .chat_completions_stream(payload, usage_callback)
+                .await
+        }
+    } }
+    .instrument(upstream_span.clone())
+    .await;
+
+    let stream = stream.inspect_err(|e| {
+        upstream_span.record("otel.status_message", e.to_string());
+        state.stats.record_error(&model_name, e.to_string());
+        if let (Some(log), Some(record)) = (&state.audit, &audit_record) {
+            log.record(&AuditRecord {
+                error: Some(e.to_string()),
+                ..record.clone()
+            });
         }
+    })?;
+    let stream = traced(stream, upstream_span);
+    let stream = match stop_sequences {
+        Some((sequences, choices)) => {
+            enforce_stop_sequences(stream, sequences, choices, usage.clone())
+        }
+        None => stream,
+    };
+    let stream = match stream_timeout {
+        Some(timeout) => deadline(stream, timeout),
+        None => stream,
+    };
+    let stream = usage.observe(stream);
+    let stream = measure(
+        stream,
+        state.stats.clone(),
+        model_name.clone(),
+        started,
+        timing,
+    );
+    let stream = hold(stream, permit);
+
+    let stream = match stored_messages {
+        Some((owner, messages, model)) => {
+            record(stream, state.store.clone(), owner, messages, model)
+        }
+        None => stream,
+    };
+
+    let stream = match (&state.audit, audit_record) {
+        (Some(log), Some(record)) => audit(stream, log.clone(), record),
+        _ => stream,
+    };
+
+    let stream = match captured_request.filter(|_| state.replay.enabled()) {
+        Some(request) => capture(
+            stream,
+            state.replay.clone(),
+            request,
+            model_name,
+            captured_caller,
+        ),
+        None => stream,
+    };
+
+    let stream = match alias {
+        Some(alias) => rename_model(stream, alias),
+        None => stream,
+    };
+
+    if !streaming {
+        let completion = collect_completion(stream, &response_model).await?;
+        return Ok(Json(completion).into_response());
+    }
+
+    let stream = if state.config.smoothing.enabled {
+        smooth(stream, state.config.smoothing)
     } else {
-        info!("Using Bedrock provider for model: {}", payload.model);
-        BedrockChatCompletionsProvider::new()
-            .await
-            .chat_completions_stream(payload, usage_callback)
-            .await?
+        stream
     };
 
-    Ok((StatusCode::OK, Sse::new(stream)))
+    let memory = StreamMemory::new(state.config.stream_buffer.max_stream_bytes);
+    let stream = buffered(stream, state.config.stream_buffer, memory.clone());
+    let format = StreamFormat::from_accept(
+        headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok()),
+    );
+    let body = stream_body(
+        stream,
+        format,
+        state.config.flush_strategy,
+        memory,
+        request_id,
+    );
+    // The body is read after the handler returns, outside its span.
+    let body = instrumented(body, Span::current());
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, format.content_type()),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response())
+}
+
+async fn stored_completion(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let Caller { identity, .. } = authenticate(&state, &headers).await?;
+    let owner = store_owner(identity.as_ref(), &headers);
+    match state.store.get(&id, &owner).await {
+        Some(record) => Ok(Json(record)),
+        None => Err(AppError::from(
+            ProviderError::new(
+                ErrorKind::NotFound,
+                format!("No stored chat completion found with id {}", id),
+            )
+            .code("not_found"),
+        )),
+    }
+}
+
+async fn models(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    authenticate(&state, &headers).await?;
+    Ok(Json(state.config.models.list()))
+}
+
+/// The admin token is compared in constant time, so response timing does
+/// not tell how much of a guess was right.
+fn check_admin(state: &AppState, headers: &HeaderMap) -> Result<(), AppError> {
+    match (&state.config.admin_token, bearer_token(headers)) {
+        (Some(expected), Some(token))
+            if bool::from(token.as_bytes().ct_eq(expected.as_bytes())) =>
+        {
+            Ok(())
+        }
+        (Some(_), _) => Err(AppError::from(ProviderError::new(
+            ErrorKind::Authentication,
+            "Invalid admin token",
+        ))),
+        (None, _) => Err(AppError::from(ProviderError::new(
+            ErrorKind::NotFound,
+            "The admin dashboard is disabled",
+        ))),
+    }
+}
+
+async fn dashboard(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    if state.config.admin_token.is_none() {
+        return Err(AppError::from(ProviderError::new(
+            ErrorKind::NotFound,
+            "The admin dashboard is disabled",
+        )));
+    }
+    Ok(Html(include_str!("dashboard.html")))
+}
+
+/// The OpenAPI document for the routes below, kept by hand next to them.
+async fn openapi(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    authenticate(&state, &headers).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/json")],
+        include_str!("openapi.json"),
+    ))
+}
+
+async fn admin_stats(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    Ok(Json(state.stats.snapshot()))
+}
+
+async fn list_providers(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    let providers = state.providers.list();
+    Ok(Json(
+        providers
+            .iter()
+            .map(Arc::as_ref)
+            .cloned()
+            .collect::<Vec<_>>(),
+    ))
+}
+
+async fn put_provider(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    payload: Result<Json<DynamicProvider>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    let Json(mut provider) =
+        payload.map_err(|e| ProviderError::invalid_request(e.body_text(), None))?;
+    provider.name = name;
+    info!(
+        "Registering provider {} at {} for models {:?}",
+        provider.name, provider.base_url, provider.models
+    );
+    state.providers.upsert(provider.clone()).await?;
+    Ok(Json(provider))
+}
+
+async fn delete_provider(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    if !state.providers.remove(&name).await? {
+        return Err(AppError::from(
+            ProviderError::new(ErrorKind::NotFound, format!("No provider named {}", name))
+                .code("not_found"),
+        ));
+    }
+    info!("Removed provider {}", name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn key_not_found(id: &str) -> AppError {
+    AppError::from(
+        ProviderError::new(ErrorKind::NotFound, format!("No issued key with id {}", id))
+            .code("not_found"),
+    )
+}
+
+fn check_team(state: &AppState, settings: &KeyConfig) -> Result<(), AppError> {
+    match settings.team.as_deref() {
+        Some(team) if !state.config.teams.teams.contains_key(team) => Err(AppError::from(
+            ProviderError::invalid_request(format!("No team named {}", team), Some("team")),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// An issued key with what its caller has spent so far.
+fn issued_key_json(state: &AppState, key: &IssuedKey) -> serde_json::Value {
+    serde_json::json!({
+        "id": key.id,
+        "created": key.created,
+        "spent": state.spend.spent(&key.ledger()),
+        "settings": key.settings,
+    })
+}
+
+/// Issued keys, and the names and settings of the configured ones, whose
+/// secrets are never listed.
+async fn list_keys(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    let issued: Vec<_> = state
+        .keys
+        .issued()
+        .iter()
+        .map(|key| issued_key_json(&state, key))
+        .collect();
+    let configured: Vec<_> = state
+        .keys
+        .configured()
+        .iter()
+        .map(|(ledger, settings)| {
+            serde_json::json!({
+                "spent": state.spend.spent(ledger),
+                "settings": settings,
+            })
+        })
+        .collect();
+    Ok(Json(serde_json::json!({
+        "issued": issued,
+        "configured": configured,
+    })))
+}
+
+/// Issues a key. The response holds its secret, which is not shown again.
+async fn create_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    payload: Result<Json<KeyConfig>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    let Json(settings) =
+        payload.map_err(|e| ProviderError::invalid_request(e.body_text(), None))?;
+    check_team(&state, &settings)?;
+    let (api_key, key) = state.keys.issue(settings).await?;
+    info!("Issued key {}", key.id);
+    let mut body = issued_key_json(&state, &key);
+    body["key"] = serde_json::Value::from(api_key);
+    Ok((StatusCode::CREATED, Json(body)))
 }
 
-async fn load_config() -> anyhow::Result<(String, u16, Option<String>)> {
-    let settings = Config::builder()
-        .add_source(File::with_name("config"))
-        .build()?;
+async fn get_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    let key = state.keys.find(&id).ok_or_else(|| key_not_found(&id))?;
+    Ok(Json(issued_key_json(&state, &key)))
+}
 
-    let host: String = settings
-        .get("host")
-        .unwrap_or_else(|_| "127.0.0.1".to_string());
-    let port: u16 = settings.get("port").unwrap_or(3000);
+/// Replaces the settings of an issued key, such as its budget or quotas.
+async fn put_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    payload: Result<Json<KeyConfig>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    let Json(settings) =
+        payload.map_err(|e| ProviderError::invalid_request(e.body_text(), None))?;
+    check_team(&state, &settings)?;
+    let key = state
+        .keys
+        .update(&id, settings)
+        .await?
+        .ok_or_else(|| key_not_found(&id))?;
+    info!("Updated the settings of key {}", id);
+    Ok(Json(issued_key_json(&state, &key)))
+}
 
-    let openai_api_key = settings.get::<String>("openai_api_key").ok();
+async fn revoke_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    if !state.keys.revoke(&id).await? {
+        return Err(key_not_found(&id));
+    }
+    info!("Revoked key {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    if openai_api_key.is_some() {
-        info!("OpenAI API key found in configuration");
+async fn ready(State(state): State<AppState>) -> impl IntoResponse {
+    let report = state.warmup.report();
+    let status = if report.ready {
+        StatusCode::OK
     } else {
-        info!("No OpenAI API key found in configuration, OpenAI models will not be available");
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+async fn list_replays(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    let captures = state.replay.list();
+    Ok(Json(
+        captures
+            .iter()
+            .rev()
+            .map(|capture| capture.summary())
+            .collect::<Vec<_>>(),
+    )
+    .into_response())
+}
+
+fn replay_not_found(id: &str) -> AppError {
+    AppError::from(
+        ProviderError::new(ErrorKind::NotFound, format!("No capture with id {}", id))
+            .code("not_found"),
+    )
+}
+
+async fn get_replay(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    let capture = state.replay.get(&id).ok_or_else(|| replay_not_found(&id))?;
+    Ok(Json(capture))
+}
+
+/// Sends a captured request again and compares the new completion with the
+/// captured one. A `model` in the body sends it to another model, and so
+/// possibly another provider.
+async fn replay_capture(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    let capture = state.replay.get(&id).ok_or_else(|| replay_not_found(&id))?;
+    let options: serde_json::Value = if body.is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| ProviderError::invalid_request(e.to_string(), None))?
+    };
+
+    let mut request: ChatCompletionsRequest = serde_json::from_value(capture.request.clone())?;
+    if let Some(model) = options["model"].as_str() {
+        request.model = model.to_string();
+    }
+    // Replays read the NDJSON stream even if the capture was not streamed.
+    request.stream = Some(true);
+    let model = request.model.clone();
+    info!("Replaying capture {} with model {}", id, model);
+
+    let mut replay_headers = HeaderMap::new();
+    replay_headers.insert(
+        header::ACCEPT,
+        header::HeaderValue::from_static(StreamFormat::NDJSON_CONTENT_TYPE),
+    );
+    let response = complete(state.clone(), replay_headers, request, None, true).await?;
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read the replayed response: {}", e))?;
+
+    let mut accumulator = CompletionAccumulator::default();
+    let mut error = None;
+    for line in body
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+    {
+        let value: serde_json::Value = serde_json::from_slice(line)?;
+        if let Some(e) = value.get("error") {
+            error = Some(e.clone());
+            break;
+        }
+        accumulator.push(serde_json::from_value(value)?);
+    }
+    let replayed = accumulator.into_completion(&model);
+    let differences = match &capture.response {
+        Some(original) => diff(original, &replayed),
+        None => Vec::new(),
+    };
+
+    Ok(Json(serde_json::json!({
+        "id": capture.id,
+        "model": model,
+        "original": capture.response,
+        "replayed": replayed,
+        "error": error,
+        "differences": differences,
+    })))
+}
+
+/// The Converse request the proxy would send to Bedrock for an OpenAI
+/// request, without invoking the model.
+async fn debug_convert(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    payload: Result<Json<ChatCompletionsRequest>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    check_admin(&state, &headers)?;
+    let Json(mut payload) =
+        payload.map_err(|e| ProviderError::invalid_request(e.body_text(), None))?;
+    state.config.prompt_templates.apply(&mut payload, None)?;
+    payload
+        .validate()
+        .map_err(|e| ProviderError::invalid_request(e.message, Some(&e.param)))?;
+    state.config.system_prompts.apply(&mut payload, None);
+
+    let model_name = payload.model.to_lowercase();
+    state.config.model_limits.clamp(&mut payload, &model_name);
+    state
+        .config
+        .system_messages
+        .apply(&mut payload, &model_name)?;
+    let completion = BedrockChatCompletionsProvider::new(&state.clients.bedrock)
+        .server_tools(ServerTools::new(&state.mcp, &state.builtin_tools, None))
+        .tool_emulation(state.config.tool_emulation.format_for(&model_name))
+        .image_limits(state.config.bedrock_images.clone())
+        .image_fetcher(state.image_fetcher.clone())
+        .strict_parameters(state.config.strict_parameters)
+        .guardrail(state.config.guardrails.for_model(&model_name).cloned())
+        .cache_system_prompt_tokens(state.config.cache_system_prompt_tokens)
+        .service_tier(
+            state
+                .config
+                .service_tiers
+                .resolve(&model_name, payload.service_tier.as_deref()),
+        )
+        .converse_request(payload)
+        .await?;
+    Ok(Json(completion.to_json()))
+}
+
+async fn metrics(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    match &state.metrics {
+        Some(metrics) => Ok(metrics.render()),
+        None => {
+            Err(ProviderError::new(ErrorKind::NotFound, "Prometheus metrics are disabled").into())
+        }
     }
+}
 
-    Ok((host, port, openai_api_key))
+/// Installs the Prometheus recorder, the StatsD one, or both.
+async fn install_metrics(config: &ServerConfig) -> anyhow::Result<Option<PrometheusHandle>> {
+    let statsd = match &config.statsd {
+        Some(statsd) => Some(StatsdRecorder::new(statsd.clone()).await?),
+        None => None,
+    };
+    if !config.prometheus {
+        info!("Prometheus metrics are disabled");
+        if let Some(statsd) = statsd {
+            metrics::set_global_recorder(statsd)?;
+        }
+        return Ok(None);
+    }
+    let Some(statsd) = statsd else {
+        return Ok(Some(PrometheusBuilder::new().install_recorder()?));
+    };
+    let prometheus = PrometheusBuilder::new().build_recorder();
+    let handle = prometheus.handle();
+    metrics::set_global_recorder(
+        FanoutBuilder::default()
+            .add_recorder(prometheus)
+            .add_recorder(statsd)
+            .build(),
+    )
+    .map_err(|_| anyhow::anyhow!("A metrics recorder is already installed"))?;
+    Ok(Some(handle))
+}
+
+/// How long shutdown waits for queued usage events to be sent.
+const EVENT_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolves on Ctrl-C or SIGTERM, after which in-flight requests finish.
+async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    info!("Shutting down");
+}
+
+/// The request as replay and audit capture it, with the fields its upstream
+/// body leaves out, so a replay reproduces it.
+fn capture_request(payload: &ChatCompletionsRequest) -> anyhow::Result<serde_json::Value> {
+    let mut request = serde_json::to_value(payload)?;
+    if let Some(extra_body) = &payload.extra_body {
+        request["extra_body"] = serde_json::Value::Object(extra_body.clone());
+    }
+    if let Some(guardrail) = &payload.guardrail {
+        request["guardrail"] = serde_json::to_value(guardrail)?;
+    }
+    Ok(request)
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO))
+        .with(OtlpLayer.with_filter(OtlpLayer::filter()))
+        .init();
     info!("Initializing LLM proxy server");
 
-    let (host, port, openai_api_key) = load_config().await?;
+    let mut config = load_config().await?;
+    if std::env::args().any(|arg| arg == "--dump-models") {
+        println!("{}", serde_json::to_string_pretty(config.models.models())?);
+        return Ok(());
+    }
+    let (host, port) = (config.host.clone(), config.port);
     info!("Starting server on {}:{}", host, port);
+    // Bound before MCP servers are started, so they are not handed the
+    // socket activation environment.
+    let listener = listener::bind(&host, port).await?;
+
+    let clients = UpstreamClients::new(&config.upstream, &config.bedrock).await?;
+    if config.bedrock.list_models {
+        match clients.list_foundation_models().await {
+            Ok(models) => {
+                let added = config.models.add_foundation_models(models);
+                info!("Listing {} more Bedrock models in /v1/models", added);
+            }
+            Err(e) => warn!("Failed to list Bedrock foundation models: {}", e),
+        }
+    }
+    let mcp = McpRegistry::connect(&config.mcp, &clients.http).await;
+    let builtin_tools = BuiltinTools::new(&config.tools, &clients.http)?;
+    let metrics_handle = install_metrics(&config).await?;
 
-    let app_state = AppState { openai_api_key };
+    let store = ConversationStore::new(config.store.clone());
+    let sessions = SessionTracker::new(config.sessions.clone());
+    let spend = Arc::new(SpendLedger::load(config.spend_file.clone()).await?);
+    let admission = Admission::new(config.admission.clone());
+    let providers =
+        ProviderRegistry::load(config.providers_store.clone(), config.providers.clone()).await?;
+    let keys = KeyStore::load(config.keys_store.clone(), config.keys.clone()).await?;
+
+    let warmup = if config.warmup.enabled {
+        let warmup = Arc::new(Warmup::default());
+        tokio::spawn(warmup.clone().run(
+            config.warmup.clone(),
+            clients.clone(),
+            config.openai_api_key.clone(),
+            providers.list(),
+        ));
+        warmup
+    } else {
+        Arc::new(Warmup::ready())
+    };
+
+    let replay = ReplayBuffer::load(config.replay.clone()).await?;
+    let embeddings_cache = EmbeddingsCache::new(config.embeddings_cache.clone());
+    let vertex = match &config.vertex {
+        Some(vertex) => Some(Arc::new(
+            VertexClient::new(vertex.clone(), &clients.http).await?,
+        )),
+        None => None,
+    };
+
+    let jwt = config
+        .jwt
+        .clone()
+        .map(|jwt| Arc::new(JwtVerifier::new(jwt, &clients.http)));
+
+    let exporter = config
+        .telemetry
+        .clone()
+        .map(|telemetry| OtlpExporter::start(telemetry, &clients.http));
+
+    let image_fetcher = config
+        .image_fetch
+        .clone()
+        .map(ImageFetcher::new)
+        .transpose()?;
+
+    let access_log = match config.access_log.clone() {
+        Some(access_log) => Some(Arc::new(AccessLog::open(access_log).await?)),
+        None => None,
+    };
+
+    let archive = config
+        .archive
+        .clone()
+        .map(|archive| Arc::new(Archiver::new(archive, &clients)));
+
+    let audit = match config.audit.clone() {
+        Some(audit) => Some(Arc::new(AuditLog::open(audit, archive.clone()).await?)),
+        None => None,
+    };
+
+    let event_sinks = Arc::new(
+        config
+            .event_sinks
+            .iter()
+            .map(|sink| EventSink::new(sink.clone(), &clients))
+            .collect::<Vec<_>>(),
+    );
+
+    let app_state = AppState {
+        config: Arc::new(config),
+        clients,
+        mcp: Arc::new(mcp),
+        builtin_tools: Arc::new(builtin_tools),
+        store: Arc::new(store),
+        sessions: Arc::new(sessions),
+        spend: spend.clone(),
+        stats: Arc::new(RequestStats::default()),
+        admission: Arc::new(admission),
+        providers: Arc::new(providers),
+        keys: Arc::new(keys),
+        warmup,
+        replay: Arc::new(replay),
+        embeddings_cache: Arc::new(embeddings_cache),
+        vertex,
+        jwt,
+        event_sinks: event_sinks.clone(),
+        audit,
+        archive: archive.clone(),
+        access_log,
+        image_fetcher,
+        metrics: metrics_handle,
+    };
 
     let app = Router::new()
         .route("/chat/completions", post(chat_completions))
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/chat/completions/{id}", get(stored_completion))
+        .route("/embeddings", post(embeddings))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/models", get(models))
+        .route("/admin", get(dashboard))
+        .route("/admin/stats", get(admin_stats))
+        .route("/admin/providers", get(list_providers))
+        .route(
+            "/admin/providers/{name}",
+            put(put_provider).delete(delete_provider),
+        )
+        .route("/admin/keys", get(list_keys).post(create_key))
+        .route(
+            "/admin/keys/{id}",
+            get(get_key).put(put_key).delete(revoke_key),
+        )
+        .route("/admin/replay", get(list_replays))
+        .route("/admin/replay/{id}", get(get_replay).post(replay_capture))
+        .route("/debug/convert", post(debug_convert))
+        .route("/openapi.json", get(openapi))
+        .route("/ready", get(ready))
+        .route("/metrics", get(metrics))
+        .layer(middleware::from_fn_with_state(app_state.clone(), filter_ip))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            log_access,
+        ))
+        .layer(middleware::from_fn(request_id))
+        .layer(middleware::from_fn(trace_request))
         .with_state(app_state);
 
-    info!("Routes configured, binding to {}:{}", host, port);
-    let listener = tokio::net::TcpListener::bind(format!("{}:{}", host, port)).await?;
     info!("Server started successfully, listening for requests");
 
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
+
+    info!("Server stopped, flushing usage events");
+    for sink in event_sinks.iter() {
+        sink.close(EVENT_FLUSH_TIMEOUT).await;
+    }
+    if let Some(archive) = archive {
+        archive.close(EVENT_FLUSH_TIMEOUT).await;
+    }
+    if let Some(exporter) = exporter {
+        exporter.close(EVENT_FLUSH_TIMEOUT).await;
+    }
+    spend.flush().await;
 
     Ok(())
 }
@@ -0,0 +1,216 @@
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{net::UdpSocket, sync::mpsc};
+use tracing::{info, warn};
+
+/// Lines are packed into datagrams of at most this many bytes, which fits
+/// a typical MTU.
+const MAX_PACKET_BYTES: usize = 1432;
+const QUEUE_CAPACITY: usize = 65_536;
+
+#[derive(Clone, Debug)]
+pub struct StatsdConfig {
+    /// `host:port` of the StatsD server or Datadog agent, over UDP.
+    pub address: String,
+    /// Prepended to every metric name, followed by a dot.
+    pub prefix: Option<String>,
+    /// Send labels as DogStatsD tags; plain StatsD has no tags, so labels
+    /// are dropped.
+    pub datadog: bool,
+    /// Tags added to every metric, like `env:prod`. DogStatsD only.
+    pub tags: Vec<String>,
+    pub flush_interval: Duration,
+}
+
+impl StatsdConfig {
+    pub fn new(address: String) -> Self {
+        Self {
+            address,
+            prefix: None,
+            datadog: false,
+            tags: Vec::new(),
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A metrics recorder that pushes every update to StatsD as it happens:
+/// counters as `c`, gauges as `g` and histograms as timers (`ms`), with
+/// the `_seconds` ones converted to milliseconds. DogStatsD gets the other
+/// histograms as `h`. Updates are queued and
+/// sent in packets once a packet is full or the flush interval passes; a
+/// full queue drops them.
+pub struct StatsdRecorder {
+    config: StatsdConfig,
+    lines: mpsc::Sender<String>,
+}
+
+impl StatsdRecorder {
+    pub async fn new(config: StatsdConfig) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(&config.address).await?;
+        info!(
+            "Pushing metrics to {} {}",
+            if config.datadog {
+                "DogStatsD"
+            } else {
+                "StatsD"
+            },
+            config.address
+        );
+        let (lines, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(send(socket, receiver, config.flush_interval));
+        Ok(Self { config, lines })
+    }
+
+    fn metric(&self, key: &Key, kind: &'static str) -> Arc<StatsdMetric> {
+        let name = match &self.config.prefix {
+            Some(prefix) => format!("{}.{}", prefix, key.name()),
+            None => key.name().to_string(),
+        };
+        let tags: Vec<String> = if self.config.datadog {
+            self.config
+                .tags
+                .iter()
+                .cloned()
+                .chain(
+                    key.labels()
+                        .map(|label| format!("{}:{}", label.key(), label.value())),
+                )
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Arc::new(StatsdMetric {
+            seconds: key.name().ends_with("_seconds"),
+            name,
+            suffix: if tags.is_empty() {
+                format!("|{}", kind)
+            } else {
+                format!("|{}|#{}", kind, tags.join(","))
+            },
+            lines: self.lines.clone(),
+        })
+    }
+}
+
+/// One metric with its labels, formatted once when it is registered.
+struct StatsdMetric {
+    name: String,
+    /// The type and tags that follow the value.
+    suffix: String,
+    seconds: bool,
+    lines: mpsc::Sender<String>,
+}
+
+impl StatsdMetric {
+    fn push(&self, value: impl std::fmt::Display) {
+        // Dropped when the queue is full, like UDP would.
+        let _ = self
+            .lines
+            .try_send(format!("{}:{}{}", self.name, value, self.suffix));
+    }
+}
+
+impl CounterFn for StatsdMetric {
+    fn increment(&self, value: u64) {
+        self.push(value);
+    }
+
+    /// StatsD counters only count up from each flush, so an absolute value
+    /// has nothing to be sent as.
+    fn absolute(&self, _value: u64) {}
+}
+
+impl GaugeFn for StatsdMetric {
+    fn increment(&self, value: f64) {
+        self.push(format_args!("+{}", value));
+    }
+
+    fn decrement(&self, value: f64) {
+        self.push(format_args!("-{}", value));
+    }
+
+    fn set(&self, value: f64) {
+        // A leading sign would make it relative, so a negative value is
+        // sent as zero followed by the decrement.
+        if value < 0.0 {
+            self.push(0);
+            self.decrement(-value);
+        } else {
+            self.push(value);
+        }
+    }
+}
+
+impl HistogramFn for StatsdMetric {
+    fn record(&self, value: f64) {
+        if self.seconds {
+            self.push(value * 1000.0);
+        } else {
+            self.push(value);
+        }
+    }
+}
+
+impl Recorder for StatsdRecorder {
+    fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+        Counter::from_arc(self.metric(key, "c"))
+    }
+
+    fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(self.metric(key, "g"))
+    }
+
+    fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+        // DogStatsD has histograms for what are not durations.
+        let kind = if self.config.datadog && !key.name().ends_with("_seconds") {
+            "h"
+        } else {
+            "ms"
+        };
+        Histogram::from_arc(self.metric(key, kind))
+    }
+}
+
+async fn flush(socket: &UdpSocket, packet: &mut String) {
+    if packet.is_empty() {
+        return;
+    }
+    if let Err(e) = socket.send(packet.as_bytes()).await {
+        warn!("Failed to send metrics to StatsD: {}", e);
+    }
+    packet.clear();
+}
+
+async fn send(socket: UdpSocket, mut lines: mpsc::Receiver<String>, flush_interval: Duration) {
+    let mut packet = String::with_capacity(MAX_PACKET_BYTES);
+    let mut interval = tokio::time::interval(flush_interval);
+    loop {
+        tokio::select! {
+            line = lines.recv() => {
+                let Some(line) = line else {
+                    break;
+                };
+                if packet.len() + line.len() + 1 > MAX_PACKET_BYTES {
+                    flush(&socket, &mut packet).await;
+                }
+                if !packet.is_empty() {
+                    packet.push('\n');
+                }
+                packet.push_str(&line);
+            }
+            _ = interval.tick() => flush(&socket, &mut packet).await,
+        }
+    }
+    flush(&socket, &mut packet).await;
+}
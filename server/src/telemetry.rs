@@ -0,0 +1,576 @@
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{
+    Event, Level, Metadata, Subscriber,
+    field::{Field, Visit},
+    info, span, warn,
+};
+use tracing_subscriber::{
+    Layer,
+    filter::{FilterFn, filter_fn},
+    layer::Context,
+    registry::LookupSpan,
+};
+use uuid::Uuid;
+
+/// Spans carrying more events than this drop the rest, counting them.
+const MAX_SPAN_EVENTS: usize = 1024;
+const BATCH_SIZE: usize = 512;
+const QUEUE_CAPACITY: usize = 4096;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// OTLP span kinds.
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+const KIND_CLIENT: u8 = 3;
+const STATUS_ERROR: u8 = 2;
+
+#[derive(Clone, Debug)]
+pub struct TelemetryConfig {
+    /// The OTLP/HTTP endpoint; spans are posted to `/v1/traces` under it.
+    pub otlp_endpoint: String,
+    /// Sent with every export, for collectors that want an API key.
+    pub headers: HashMap<String, String>,
+    pub service_name: String,
+}
+
+impl TelemetryConfig {
+    pub fn new(otlp_endpoint: String) -> Self {
+        Self {
+            otlp_endpoint,
+            headers: HashMap::new(),
+            service_name: "llm-proxy".to_string(),
+        }
+    }
+}
+
+/// Where finished spans go, once an exporter is started.
+static SPANS: OnceLock<mpsc::Sender<Value>> = OnceLock::new();
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+fn string_value(value: impl Into<String>) -> Value {
+    json!({ "stringValue": value.into() })
+}
+
+/// Span and event fields as OTLP attributes.
+#[derive(Default)]
+struct Attributes(Vec<(String, Value)>);
+
+impl Attributes {
+    fn set(&mut self, key: &str, value: Value) {
+        match self.0.iter_mut().find(|(existing, _)| existing == key) {
+            Some((_, existing)) => *existing = value,
+            None => self.0.push((key.to_string(), value)),
+        }
+    }
+
+    fn take_string(&mut self, key: &str) -> Option<String> {
+        let index = self.0.iter().position(|(existing, _)| existing == key)?;
+        let (_, value) = self.0.remove(index);
+        value["stringValue"].as_str().map(str::to_string)
+    }
+
+    fn to_json(&self) -> Value {
+        self.0
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect()
+    }
+}
+
+impl Visit for Attributes {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), string_value(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field.name(), json!({ "boolValue": value }));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field.name(), json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field.name(), json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field.name(), json!({ "doubleValue": value }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field.name(), string_value(format!("{:?}", value)));
+    }
+}
+
+struct SpanEvent {
+    time: u64,
+    name: String,
+    attributes: Attributes,
+}
+
+/// A span being recorded, kept in the registry's extensions until it closes.
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: &'static str,
+    kind: u8,
+    start: u64,
+    attributes: Attributes,
+    events: Vec<SpanEvent>,
+    dropped_events: usize,
+    error: Option<String>,
+}
+
+impl SpanData {
+    fn to_json(&self, end: u64) -> Value {
+        let mut span = json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "name": self.name,
+            "kind": self.kind,
+            "startTimeUnixNano": self.start.to_string(),
+            "endTimeUnixNano": end.to_string(),
+            "attributes": self.attributes.to_json(),
+            "events": self.events.iter().map(|event| json!({
+                "timeUnixNano": event.time.to_string(),
+                "name": event.name,
+                "attributes": event.attributes.to_json(),
+            })).collect::<Vec<_>>(),
+            "droppedEventsCount": self.dropped_events,
+        });
+        if let Some(parent) = &self.parent_span_id {
+            span["parentSpanId"] = json!(parent);
+        }
+        if let Some(message) = &self.error {
+            span["status"] = json!({ "code": STATUS_ERROR, "message": message });
+        }
+        span
+    }
+}
+
+/// The trace and parent span named by a W3C `traceparent` header.
+fn parse_traceparent(traceparent: &str) -> Option<(String, String)> {
+    let mut parts = traceparent.trim().split('-');
+    let (Some(version), Some(trace_id), Some(parent_id), Some(_flags)) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    let is_id = |id: &str, len: usize| {
+        id.len() == len
+            && id.bytes().all(|byte| byte.is_ascii_hexdigit())
+            && id.bytes().any(|byte| byte != b'0')
+    };
+    if version.len() != 2 || version == "ff" || !is_id(trace_id, 32) || !is_id(parent_id, 16) {
+        return None;
+    }
+    Some((
+        trace_id.to_ascii_lowercase(),
+        parent_id.to_ascii_lowercase(),
+    ))
+}
+
+/// Records the spans of this server and the chat crate as OpenTelemetry
+/// spans. Until an exporter is started it only checks that none is.
+///
+/// A span without a parent starts a trace, continuing the one named by its
+/// `traceparent` field when that is valid. `otel.kind` ("server" or
+/// "client") sets the span kind; recording `otel.status_message` or an
+/// error event marks it failed.
+pub struct OtlpLayer;
+
+impl OtlpLayer {
+    /// What the layer sees: debug and above, from our own crates only, so
+    /// the exporter's requests are never traced.
+    pub fn filter() -> FilterFn<impl Fn(&Metadata<'_>) -> bool> {
+        filter_fn(|metadata| {
+            *metadata.level() <= Level::DEBUG
+                && ["server", "chat"].iter().any(|target| {
+                    metadata
+                        .target()
+                        .strip_prefix(target)
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+                })
+        })
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if SPANS.get().is_none() {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut attributes = Attributes::default();
+        attrs.record(&mut attributes);
+        let traceparent = attributes.take_string("traceparent");
+        let kind = match attributes.take_string("otel.kind").as_deref() {
+            Some("server") => KIND_SERVER,
+            Some("client") => KIND_CLIENT,
+            _ => KIND_INTERNAL,
+        };
+
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| (data.trace_id.clone(), data.span_id.clone()))
+        });
+        let (trace_id, parent_span_id) =
+            match parent.or_else(|| traceparent.as_deref().and_then(parse_traceparent)) {
+                Some((trace_id, parent_span_id)) => (trace_id, Some(parent_span_id)),
+                None => (format!("{:032x}", Uuid::new_v4().as_u128()), None),
+            };
+        span.extensions_mut().insert(SpanData {
+            trace_id,
+            span_id: format!("{:016x}", Uuid::new_v4().as_u64_pair().0),
+            parent_span_id,
+            name: attrs.metadata().name(),
+            kind,
+            start: unix_nanos(),
+            attributes,
+            events: Vec::new(),
+            dropped_events: 0,
+            error: None,
+        });
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id)
+            && let Some(data) = span.extensions_mut().get_mut::<SpanData>()
+        {
+            values.record(&mut data.attributes);
+            if let Some(message) = data.attributes.take_string("otel.status_message") {
+                data.error = Some(message);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        let Some(data) = extensions.get_mut::<SpanData>() else {
+            return;
+        };
+        let mut attributes = Attributes::default();
+        event.record(&mut attributes);
+        let name = attributes
+            .take_string("message")
+            .unwrap_or_else(|| event.metadata().name().to_string());
+        if *event.metadata().level() == Level::ERROR {
+            data.error = Some(name.clone());
+        }
+        if data.events.len() >= MAX_SPAN_EVENTS {
+            data.dropped_events += 1;
+            return;
+        }
+        attributes.set("level", string_value(event.metadata().level().as_str()));
+        data.events.push(SpanEvent {
+            time: unix_nanos(),
+            name,
+            attributes,
+        });
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(spans) = SPANS.get() else {
+            return;
+        };
+        if let Some(span) = ctx.span(&id)
+            && let Some(data) = span.extensions_mut().remove::<SpanData>()
+        {
+            // A full queue drops the span rather than hold up the request.
+            let _ = spans.try_send(data.to_json(unix_nanos()));
+        }
+    }
+}
+
+/// Sends finished spans to an OTLP collector in batches, as OTLP/HTTP JSON.
+/// Spans that fail to send are dropped; closing sends what is still queued.
+///
+/// Written by hand rather than with `opentelemetry-otlp`, which the build's
+/// vendored dependencies do not include. Moving to it keeps the config: the
+/// layer becomes `tracing-opentelemetry` over an OTLP/HTTP JSON exporter with
+/// a batch processor, built from the same endpoint, headers and service name.
+pub struct OtlpExporter {
+    shutdown: Mutex<Option<oneshot::Sender<()>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl OtlpExporter {
+    /// Starts exporting the spans [`OtlpLayer`] records. Only the first
+    /// exporter started receives them.
+    pub fn start(config: TelemetryConfig, http: &reqwest::Client) -> Self {
+        info!(
+            "Exporting traces to {} as {}",
+            config.otlp_endpoint, config.service_name
+        );
+        let (spans, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let worker = SPANS
+            .set(spans)
+            .is_ok()
+            .then(|| tokio::spawn(export(config, http.clone(), receiver, shutdown_receiver)));
+        Self {
+            shutdown: Mutex::new(Some(shutdown)),
+            worker: Mutex::new(worker),
+        }
+    }
+
+    /// Stops exporting and waits up to `timeout` for the queued spans to
+    /// be sent.
+    pub async fn close(&self, timeout: Duration) {
+        if let Some(shutdown) = self.shutdown.lock().unwrap().take() {
+            let _ = shutdown.send(());
+        }
+        let Some(worker) = self.worker.lock().unwrap().take() else {
+            return;
+        };
+        if tokio::time::timeout(timeout, worker).await.is_err() {
+            warn!("Timed out exporting the remaining traces");
+        }
+    }
+}
+
+/// An `ExportTraceServiceRequest` for a batch of spans.
+fn export_body(service_name: &str, spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{
+                    "key": "service.name",
+                    "value": string_value(service_name),
+                }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+async fn export(
+    config: TelemetryConfig,
+    http: reqwest::Client,
+    mut receiver: mpsc::Receiver<Value>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let url = format!("{}/v1/traces", config.otlp_endpoint.trim_end_matches('/'));
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut open = true;
+    let mut closing = false;
+    while open {
+        let deadline = tokio::time::sleep(FLUSH_INTERVAL);
+        tokio::pin!(deadline);
+        while batch.len() < BATCH_SIZE {
+            tokio::select! {
+                span = receiver.recv() => match span {
+                    Some(span) => batch.push(span),
+                    None => {
+                        open = false;
+                        break;
+                    }
+                },
+                _ = &mut shutdown, if !closing => {
+                    closing = true;
+                    receiver.close();
+                }
+                _ = &mut deadline => break,
+            }
+        }
+        if batch.is_empty() {
+            continue;
+        }
+        let body = export_body(&config.service_name, std::mem::take(&mut batch));
+        let mut request = http.post(&url).json(&body);
+        for (name, value) in &config.headers {
+            request = request.header(name, value);
+        }
+        if let Err(e) = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            warn!("Failed to export traces to {}: {}", url, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn parses_traceparent() {
+        let header = format!("00-{}-{}-01", TRACE_ID, PARENT_ID);
+        assert_eq!(
+            parse_traceparent(&header),
+            Some((TRACE_ID.to_string(), PARENT_ID.to_string()))
+        );
+    }
+
+    #[test]
+    fn lowercases_uppercase_ids() {
+        let header = format!(
+            "00-{}-{}-01",
+            TRACE_ID.to_ascii_uppercase(),
+            PARENT_ID.to_ascii_uppercase()
+        );
+        assert_eq!(
+            parse_traceparent(&header),
+            Some((TRACE_ID.to_string(), PARENT_ID.to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_all_zero_ids() {
+        let zero_trace = format!("00-{}-{}-01", "0".repeat(32), PARENT_ID);
+        let zero_parent = format!("00-{}-{}-01", TRACE_ID, "0".repeat(16));
+        assert_eq!(parse_traceparent(&zero_trace), None);
+        assert_eq!(parse_traceparent(&zero_parent), None);
+    }
+
+    #[test]
+    fn rejects_version_ff() {
+        let header = format!("ff-{}-{}-01", TRACE_ID, PARENT_ID);
+        assert_eq!(parse_traceparent(&header), None);
+    }
+
+    #[test]
+    fn rejects_malformed_traceparent() {
+        for header in [
+            "",
+            "00",
+            &format!("00-{}-{}", TRACE_ID, PARENT_ID),
+            &format!("0-{}-{}-01", TRACE_ID, PARENT_ID),
+            &format!("00-{}-{}-01", &TRACE_ID[1..], PARENT_ID),
+            &format!("00-{}-{}-01", TRACE_ID, "zz".repeat(8)),
+        ] {
+            assert_eq!(parse_traceparent(header), None, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn attributes_keep_one_value_per_key() {
+        let mut attributes = Attributes::default();
+        attributes.set("model", string_value("a"));
+        attributes.set("tokens", json!({ "intValue": "12" }));
+        attributes.set("model", string_value("b"));
+        assert_eq!(
+            attributes.to_json(),
+            json!([
+                { "key": "model", "value": { "stringValue": "b" } },
+                { "key": "tokens", "value": { "intValue": "12" } },
+            ])
+        );
+        assert_eq!(attributes.take_string("model").as_deref(), Some("b"));
+        assert_eq!(attributes.take_string("model"), None);
+    }
+
+    #[test]
+    fn span_json_follows_otlp() {
+        let mut attributes = Attributes::default();
+        attributes.set("level", string_value("INFO"));
+        let span = SpanData {
+            trace_id: TRACE_ID.to_string(),
+            span_id: PARENT_ID.to_string(),
+            parent_span_id: Some("b7ad6b7169203331".to_string()),
+            name: "upstream",
+            kind: KIND_CLIENT,
+            start: 1_000,
+            attributes: Attributes::default(),
+            events: vec![SpanEvent {
+                time: 1_500,
+                name: "Sent".to_string(),
+                attributes,
+            }],
+            dropped_events: 2,
+            error: Some("timed out".to_string()),
+        };
+        assert_eq!(
+            span.to_json(2_000),
+            json!({
+                "traceId": TRACE_ID,
+                "spanId": PARENT_ID,
+                "parentSpanId": "b7ad6b7169203331",
+                "name": "upstream",
+                "kind": 3,
+                "startTimeUnixNano": "1000",
+                "endTimeUnixNano": "2000",
+                "attributes": [],
+                "events": [{
+                    "timeUnixNano": "1500",
+                    "name": "Sent",
+                    "attributes": [{ "key": "level", "value": { "stringValue": "INFO" } }],
+                }],
+                "droppedEventsCount": 2,
+                "status": { "code": 2, "message": "timed out" },
+            })
+        );
+    }
+
+    #[test]
+    fn root_span_json_has_no_parent_or_status() {
+        let span = SpanData {
+            trace_id: TRACE_ID.to_string(),
+            span_id: PARENT_ID.to_string(),
+            parent_span_id: None,
+            name: "request",
+            kind: KIND_SERVER,
+            start: 0,
+            attributes: Attributes::default(),
+            events: Vec::new(),
+            dropped_events: 0,
+            error: None,
+        };
+        let json = span.to_json(1);
+        assert!(json.get("parentSpanId").is_none());
+        assert!(json.get("status").is_none());
+    }
+
+    #[test]
+    fn export_body_names_the_service() {
+        let body = export_body("llm-proxy", vec![json!({ "name": "request" })]);
+        let resource_spans = &body["resourceSpans"][0];
+        assert_eq!(
+            resource_spans["resource"]["attributes"],
+            json!([{ "key": "service.name", "value": { "stringValue": "llm-proxy" } }])
+        );
+        assert_eq!(
+            resource_spans["scopeSpans"][0]["spans"],
+            json!([{ "name": "request" }])
+        );
+        assert_eq!(
+            resource_spans["scopeSpans"][0]["scope"]["name"],
+            env!("CARGO_PKG_NAME")
+        );
+    }
+}